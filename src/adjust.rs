@@ -5,6 +5,9 @@ use log::{debug, info};
 use rust_htslib::bam::record::{Aux, AuxArray};
 use rust_htslib::bam::{self, Read};
 
+use rustc_hash::FxHashMap;
+
+use crate::calibrate::ProbabilityCalibration;
 use crate::errs::{InputError, RunError};
 use crate::mod_bam::{
     format_mm_ml_tag, CollapseMethod, EdgeFilter, ModBaseInfo,
@@ -30,6 +33,7 @@ pub fn adjust_mod_probs(
     mut record: bam::Record,
     methods: &[CollapseMethod],
     caller: Option<&MultipleThresholdModCaller>,
+    calibration: Option<&ProbabilityCalibration>,
     edge_filter: Option<&EdgeFilter>,
 ) -> Result<bam::Record, RunError> {
     let _ok = record_is_valid(&record)?;
@@ -43,9 +47,23 @@ pub fn adjust_mod_probs(
 
     let record_name = get_query_name_string(&record)
         .unwrap_or("FAILED-UTF8-DECODE".to_string());
+    let site_motifs = match calibration {
+        Some(calibration) if calibration.uses_motifs() => {
+            calibration.site_motifs(&get_forward_sequence(&record)?)
+        }
+        _ => FxHashMap::default(),
+    };
     let (converters, mod_prob_iter) = mod_base_info.into_iter_base_mod_probs();
-    for (base, strand, seq_pos_mod_probs) in mod_prob_iter {
+    for (base, strand, mut seq_pos_mod_probs) in mod_prob_iter {
         let converter = converters.get(&base).unwrap();
+        if let Some(calibration) = calibration {
+            calibration.calibrate(
+                base,
+                strand,
+                &site_motifs,
+                &mut seq_pos_mod_probs,
+            );
+        }
         let filtered_seq_pos_mod_probs = if let Some(edge_filter) = edge_filter
         {
             let forward_sequence = get_forward_sequence(&record)?;
@@ -137,6 +155,7 @@ pub fn adjust_modbam(
     writer: &mut bam::Writer,
     collapse_methods: &[CollapseMethod],
     threshold_caller: Option<&MultipleThresholdModCaller>,
+    calibration: Option<&ProbabilityCalibration>,
     edge_filter: Option<&EdgeFilter>,
     fail_fast: bool,
     verb: &'static str,
@@ -158,6 +177,7 @@ pub fn adjust_modbam(
                 record,
                 &collapse_methods,
                 threshold_caller,
+                calibration,
                 edge_filter,
             ) {
                 Err(RunError::BadInput(InputError(err)))
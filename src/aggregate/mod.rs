@@ -0,0 +1,530 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+use itertools::Itertools;
+use log::{debug, info};
+use rayon::prelude::*;
+use rust_htslib::bam::{self, ext::BamRecordExtensions, FetchDefinition, Read};
+use rust_htslib::faidx;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::dmr::bam::BamPileupParams;
+use crate::dmr::bedmethyl::BedMethylLine;
+use crate::dmr::group::{read_replicate_lines, Replicate};
+use crate::dmr::model::AggregatedCounts;
+use crate::dmr::pairwise::aggregate_counts;
+use crate::dmr::util::DmrInterval;
+use crate::mod_bam::BaseModCall;
+use crate::mod_base_code::{DnaBase, ModCodeRepr};
+use crate::position_filter::{Iv, StrandedPositionFilter};
+use crate::read_cache::ReadCache;
+use crate::util::{record_is_secondary, Strand};
+
+pub mod subcommand;
+
+/// Counts for a single mod code summed over the sites in a region.
+#[derive(Debug, Default, Copy, Clone)]
+struct ModCodeSummary {
+    n_sites: usize,
+    valid_coverage: usize,
+    n_modified: usize,
+    site_fraction_sum: f64,
+}
+
+impl ModCodeSummary {
+    /// Percent modified of all of the calls in the region, each call has the
+    /// same weight.
+    fn percent_modified(&self) -> f64 {
+        self.n_modified as f64 / self.valid_coverage as f64 * 100f64
+    }
+
+    /// Mean of the percent modified at each site, each site has the same
+    /// weight regardless of its coverage.
+    fn mean_site_percent_modified(&self) -> f64 {
+        self.site_fraction_sum / self.n_sites as f64 * 100f64
+    }
+}
+
+/// Fraction of the calls in each read that are modified, for a single mod
+/// code, over the reads that overlap the sites in a region.
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct ReadLevelSummary {
+    n_reads: usize,
+    read_fraction_sum: f64,
+    n_reads_modified: usize,
+}
+
+impl ReadLevelSummary {
+    fn add(&mut self, fraction_modified: f64) {
+        self.n_reads += 1;
+        self.read_fraction_sum += fraction_modified;
+        if fraction_modified > 0.5 {
+            self.n_reads_modified += 1;
+        }
+    }
+
+    /// Mean of the percent modified of each read, each read has the same
+    /// weight regardless of how many sites it covers.
+    fn mean_read_percent_modified(&self) -> f64 {
+        self.read_fraction_sum / self.n_reads as f64 * 100f64
+    }
+
+    /// Percent of the reads with more than half of their calls modified.
+    fn percent_reads_modified(&self) -> f64 {
+        self.n_reads_modified as f64 / self.n_reads as f64 * 100f64
+    }
+}
+
+/// Modification counts over all of the sites in a region, for each mod code.
+/// The read-level summaries are only available with modBAM input.
+pub(crate) struct RegionSummary {
+    region: DmrInterval,
+    mod_codes: BTreeMap<ModCodeRepr, ModCodeSummary>,
+    reads: Option<FxHashMap<ModCodeRepr, ReadLevelSummary>>,
+}
+
+impl RegionSummary {
+    /// Summarize the counts at each site in the region, sites without valid
+    /// coverage are ignored.
+    fn from_sites(
+        region: &DmrInterval,
+        sites: &[AggregatedCounts],
+        reads: Option<FxHashMap<ModCodeRepr, ReadLevelSummary>>,
+    ) -> Self {
+        let mut mod_codes = BTreeMap::<ModCodeRepr, ModCodeSummary>::new();
+        for site in sites.iter().filter(|site| site.total() > 0) {
+            for mod_code in site.mod_codes() {
+                let n_modified = site.mod_code_count(mod_code);
+                let summary = mod_codes.entry(*mod_code).or_default();
+                summary.n_sites += 1;
+                summary.valid_coverage += site.total();
+                summary.n_modified += n_modified;
+                summary.site_fraction_sum +=
+                    n_modified as f64 / site.total() as f64;
+            }
+        }
+        Self {
+            region: region.clone(),
+            mod_codes,
+            reads,
+        }
+    }
+
+    /// Write one row per mod code, returns the number of rows written.
+    pub(crate) fn write<W: Write>(
+        &self,
+        writer: &mut W,
+    ) -> anyhow::Result<u64> {
+        let tab = '\t';
+        let mut rows_written = 0u64;
+        for (mod_code, summary) in self.mod_codes.iter() {
+            let read_level = match self.reads.as_ref() {
+                Some(reads) => {
+                    let reads =
+                        reads.get(mod_code).copied().unwrap_or_default();
+                    if reads.n_reads > 0 {
+                        format!(
+                            "{}{tab}{:.2}{tab}{:.2}",
+                            reads.n_reads,
+                            reads.mean_read_percent_modified(),
+                            reads.percent_reads_modified()
+                        )
+                    } else {
+                        format!("0{tab}.{tab}.")
+                    }
+                }
+                None => format!(".{tab}.{tab}."),
+            };
+            let row = format!(
+                "{}{tab}\
+                {}{tab}\
+                {}{tab}\
+                {}{tab}\
+                {mod_code}{tab}\
+                {}{tab}\
+                {}{tab}\
+                {}{tab}\
+                {:.2}{tab}\
+                {:.2}{tab}\
+                {read_level}\n",
+                self.region.chrom,
+                self.region.start(),
+                self.region.stop(),
+                self.region.name,
+                summary.n_sites,
+                summary.valid_coverage,
+                summary.n_modified,
+                summary.percent_modified(),
+                summary.mean_site_percent_modified(),
+            );
+            writer.write_all(row.as_bytes())?;
+            rows_written += 1;
+        }
+        Ok(rows_written)
+    }
+}
+
+/// Summarize the counts at each site, fails when there are no sites with
+/// valid coverage in the region.
+pub(crate) fn summarize_region(
+    region: &DmrInterval,
+    sites: &[AggregatedCounts],
+    reads: Option<FxHashMap<ModCodeRepr, ReadLevelSummary>>,
+) -> anyhow::Result<RegionSummary> {
+    let summary = RegionSummary::from_sites(region, sites, reads);
+    if summary.mod_codes.is_empty() {
+        bail!("no valid coverage for {region}")
+    }
+    Ok(summary)
+}
+
+/// Summarize each region in the batch from the bedMethyl records that
+/// overlap it. Each position is a site, the `position_filter` is used to
+/// check the records against the reference base.
+pub(crate) fn summarize_bedmethyl_batch(
+    regions: &[DmrInterval],
+    bedmethyl: &Replicate,
+    chrom_ids: &[u32],
+    position_filter: &StrandedPositionFilter<DnaBase>,
+    min_valid_coverage: u64,
+) -> anyhow::Result<Vec<anyhow::Result<RegionSummary>>> {
+    let lines = read_replicate_lines(bedmethyl, regions, min_valid_coverage)?;
+    let results = regions
+        .par_iter()
+        .zip(chrom_ids.par_iter())
+        .map(|(region, chrom_id)| {
+            let sites = lines
+                .get(&region.chrom)
+                .map(|lines| {
+                    lines
+                        .iter()
+                        .filter(|l| {
+                            region.interval.overlap(l.start(), l.stop())
+                        })
+                        .into_group_map_by(|l| l.start())
+                })
+                .unwrap_or_default()
+                .into_values()
+                .map(|site_lines: Vec<&BedMethylLine>| {
+                    aggregate_counts(&site_lines, *chrom_id, position_filter)
+                })
+                .collect::<anyhow::Result<Vec<AggregatedCounts>>>()?;
+            summarize_region(region, &sites, None)
+        })
+        .collect();
+    Ok(results)
+}
+
+/// Tally the calls of each read at the `sites` of the region (the positions
+/// kept by the pileup) and summarize the fraction of each read's calls that
+/// are modified. A read contributes to every mod code in its tags, the
+/// fraction is taken over the read's calls at the primary base of the code.
+pub(crate) fn read_level_summaries(
+    bam_fps: &[PathBuf],
+    chrom_tid: u32,
+    region: &DmrInterval,
+    sites: &FxHashSet<u32>,
+    params: &BamPileupParams,
+) -> anyhow::Result<FxHashMap<ModCodeRepr, ReadLevelSummary>> {
+    let mut summaries = FxHashMap::<ModCodeRepr, ReadLevelSummary>::default();
+    for bam_fp in bam_fps {
+        let mut reader = bam::IndexedReader::from_path(bam_fp)?;
+        reader.fetch(FetchDefinition::Region(
+            chrom_tid as i32,
+            region.start() as i64,
+            region.stop() as i64,
+        ))?;
+        let mut read_cache = ReadCache::new(
+            params.pileup_options.get_collapse_method(),
+            &params.caller,
+            params.edge_filter.as_ref(),
+            params.force_allow,
+        );
+        for record in reader.records().filter_map(|r| r.ok()) {
+            if record.is_unmapped()
+                || record_is_secondary(&record)
+                || record.seq_len() == 0
+            {
+                continue;
+            }
+            let alignment_strand = if record.is_reverse() {
+                Strand::Negative
+            } else {
+                Strand::Positive
+            };
+            let seq = record.seq();
+            let mut n_calls = FxHashMap::<DnaBase, usize>::default();
+            let mut n_modified =
+                FxHashMap::<(DnaBase, ModCodeRepr), usize>::default();
+            for [q_pos, r_pos] in record.aligned_pairs() {
+                let position = r_pos as u32;
+                let at_motif = params
+                    .motif_locations
+                    .as_ref()
+                    .map(|locations| {
+                        locations
+                            .motif_idxs_for_position(
+                                chrom_tid,
+                                position,
+                                alignment_strand,
+                            )
+                            .is_some()
+                    })
+                    .unwrap_or(true);
+                if !sites.contains(&position) || !at_motif {
+                    continue;
+                }
+                let Ok(read_base) = DnaBase::parse(seq[q_pos as usize] as char)
+                else {
+                    continue;
+                };
+                let primary_base = match alignment_strand {
+                    Strand::Positive => read_base,
+                    Strand::Negative => read_base.complement(),
+                };
+                let call = read_cache
+                    .get_mod_call(&record, position, primary_base.char())
+                    .0;
+                let mod_code = match call {
+                    Some(BaseModCall::Canonical(_)) => None,
+                    Some(BaseModCall::Modified(_, mod_code)) => Some(mod_code),
+                    Some(BaseModCall::Filtered) | None => continue,
+                };
+                *n_calls.entry(primary_base).or_insert(0) += 1;
+                if let Some(mod_code) = mod_code {
+                    *n_modified.entry((primary_base, mod_code)).or_insert(0) +=
+                        1;
+                }
+            }
+            if n_calls.is_empty() {
+                continue;
+            }
+            let mut pos_mod_codes =
+                FxHashMap::<DnaBase, HashSet<ModCodeRepr>>::default();
+            let mut neg_mod_codes =
+                FxHashMap::<DnaBase, HashSet<ModCodeRepr>>::default();
+            read_cache.add_mod_codes_for_record(
+                &record,
+                &mut pos_mod_codes,
+                &mut neg_mod_codes,
+            );
+            for (primary_base, n_calls) in n_calls {
+                let mod_codes = pos_mod_codes
+                    .get(&primary_base)
+                    .into_iter()
+                    .chain(neg_mod_codes.get(&primary_base))
+                    .flatten()
+                    .collect::<FxHashSet<&ModCodeRepr>>();
+                for mod_code in mod_codes {
+                    let n_mod = n_modified
+                        .get(&(primary_base, *mod_code))
+                        .copied()
+                        .unwrap_or(0);
+                    summaries
+                        .entry(*mod_code)
+                        .or_default()
+                        .add(n_mod as f64 / n_calls as f64);
+                }
+            }
+        }
+    }
+    Ok(summaries)
+}
+
+/// Split sequences into consecutive tiles of `tile_size` base pairs, the
+/// last tile on each sequence may be shorter.
+pub(crate) fn make_tiles(
+    sequence_lengths: &[(String, u64)],
+    tile_size: u64,
+) -> Vec<DmrInterval> {
+    sequence_lengths
+        .iter()
+        .flat_map(|(chrom, length)| {
+            (0..*length).step_by(tile_size as usize).map(move |start| {
+                let stop = (start + tile_size).min(*length);
+                let interval = Iv {
+                    start,
+                    stop,
+                    val: (),
+                };
+                let name = format!("{chrom}:{start}-{stop}");
+                DmrInterval::new(interval, chrom.to_owned(), name)
+            })
+        })
+        .collect()
+}
+
+/// Names and lengths of the sequences in a FASTA, in file order, from the
+/// FASTA index (.fai). The index is made when it doesn't exist.
+pub(crate) fn reference_lengths(
+    reference_fasta: &Path,
+) -> anyhow::Result<Vec<(String, u64)>> {
+    let fai_fp = PathBuf::from(format!("{}.fai", reference_fasta.display()));
+    if !fai_fp.exists() {
+        info!("indexing {reference_fasta:?}");
+        faidx::Reader::from_path(reference_fasta).map_err(|e| {
+            anyhow!("failed to index reference {reference_fasta:?}, {e}")
+        })?;
+    }
+    BufReader::new(File::open(&fai_fp)?)
+        .lines()
+        .map(|line| {
+            let line = line?;
+            let mut parts = line.split('\t');
+            match (parts.next(), parts.next().map(|l| l.parse::<u64>())) {
+                (Some(name), Some(Ok(length))) => Ok((name.to_owned(), length)),
+                _ => bail!("invalid FASTA index line {line} in {fai_fp:?}"),
+            }
+        })
+        .collect()
+}
+
+/// Write the summaries of a batch of regions, returns the number of rows
+/// written and the number of regions without valid coverage or that failed.
+pub(crate) fn write_summaries<W: Write>(
+    writer: &mut W,
+    summaries: Vec<anyhow::Result<RegionSummary>>,
+) -> anyhow::Result<(u64, usize)> {
+    let mut rows_written = 0u64;
+    let mut failed = 0usize;
+    for summary in summaries {
+        match summary {
+            Ok(summary) => rows_written += summary.write(writer)?,
+            Err(e) => {
+                debug!("region failed, error: {e}");
+                failed += 1;
+            }
+        }
+    }
+    Ok((rows_written, failed))
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use rustc_hash::FxHashMap;
+
+    use crate::aggregate::{
+        make_tiles, reference_lengths, summarize_region, ReadLevelSummary,
+    };
+    use crate::dmr::model::AggregatedCounts;
+    use crate::dmr::util::DmrInterval;
+    use crate::mod_base_code::{HYDROXY_METHYL_CYTOSINE, METHYL_CYTOSINE};
+
+    #[test]
+    fn test_summarize_region_site_and_call_level() {
+        let region = DmrInterval::parse_str("chr1\t0\t100\tpromoter").unwrap();
+        let site = |n_m: usize, n_h: usize, total: usize| {
+            AggregatedCounts::try_new(
+                HashMap::from([
+                    (METHYL_CYTOSINE, n_m),
+                    (HYDROXY_METHYL_CYTOSINE, n_h),
+                ]),
+                total,
+            )
+            .unwrap()
+        };
+        // a well-covered unmodified site and a low-coverage modified site
+        let sites =
+            [site(0, 0, 90), site(10, 0, 10), AggregatedCounts::default()];
+        let summary = summarize_region(&region, &sites, None).unwrap();
+        let m = summary.mod_codes.get(&METHYL_CYTOSINE).unwrap();
+        assert_eq!(m.n_sites, 2);
+        assert_eq!(m.valid_coverage, 100);
+        assert_eq!(m.n_modified, 10);
+        assert!((m.percent_modified() - 10f64).abs() < 1e-9);
+        assert!((m.mean_site_percent_modified() - 50f64).abs() < 1e-9);
+        let h = summary.mod_codes.get(&HYDROXY_METHYL_CYTOSINE).unwrap();
+        assert_eq!(h.n_modified, 0);
+
+        let mut out = Vec::new();
+        assert_eq!(summary.write(&mut out).unwrap(), 2);
+        let out = String::from_utf8(out).unwrap();
+        let rows = out.lines().collect::<Vec<&str>>();
+        assert_eq!(
+            rows[0],
+            "chr1\t0\t100\tpromoter\th\t2\t100\t0\t0.00\t0.00\t.\t.\t."
+        );
+        assert_eq!(
+            rows[1],
+            "chr1\t0\t100\tpromoter\tm\t2\t100\t10\t10.00\t50.00\t.\t.\t."
+        );
+
+        assert!(summarize_region(
+            &region,
+            &[AggregatedCounts::default()],
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_summarize_region_per_read() {
+        let region = DmrInterval::parse_str("chr1\t0\t100\tpromoter").unwrap();
+        let site = AggregatedCounts::try_new(
+            HashMap::from([(METHYL_CYTOSINE, 4)]),
+            10,
+        )
+        .unwrap();
+        // three reads mostly modified and one read with a single call that
+        // is canonical
+        let mut m = ReadLevelSummary::default();
+        for fraction in [1f64, 0.75, 0.6, 0f64] {
+            m.add(fraction);
+        }
+        assert!((m.mean_read_percent_modified() - 58.75).abs() < 1e-9);
+        assert!((m.percent_reads_modified() - 75f64).abs() < 1e-9);
+        // a read exactly half modified isn't counted as modified
+        let mut h = ReadLevelSummary::default();
+        h.add(0.5);
+        assert_eq!(h.percent_reads_modified(), 0f64);
+
+        let reads = FxHashMap::from_iter([(METHYL_CYTOSINE, m)]);
+        let summary = summarize_region(&region, &[site], Some(reads)).unwrap();
+        let mut out = Vec::new();
+        summary.write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "chr1\t0\t100\tpromoter\tm\t1\t10\t4\t40.00\t40.00\t4\t58.75\t\
+             75.00\n"
+        );
+    }
+
+    #[test]
+    fn test_make_tiles() {
+        let tiles = make_tiles(
+            &[("chr1".to_string(), 25), ("chr2".to_string(), 10)],
+            10,
+        );
+        let coords = tiles
+            .iter()
+            .map(|t| (t.chrom.as_str(), t.start(), t.stop()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            coords,
+            vec![
+                ("chr1", 0, 10),
+                ("chr1", 10, 20),
+                ("chr1", 20, 25),
+                ("chr2", 0, 10)
+            ]
+        );
+        assert_eq!(tiles[2].name, "chr1:20-25");
+    }
+
+    #[test]
+    fn test_reference_lengths_from_index() {
+        let lengths = reference_lengths(Path::new(
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        ))
+        .unwrap();
+        assert_eq!(lengths.len(), 34);
+        assert_eq!(lengths[0], ("oligo_1512_adapters".to_string(), 156));
+        assert_eq!(lengths[33], ("lambda_3-6kb".to_string(), 3591));
+    }
+}
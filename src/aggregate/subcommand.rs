@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context};
+use clap::Args;
+use indicatif::MultiProgress;
+use log::{debug, info};
+use rayon::prelude::*;
+use rust_htslib::bam::{self, Read};
+
+use crate::aggregate::{
+    make_tiles, read_level_summaries, reference_lengths,
+    summarize_bedmethyl_batch, summarize_region, write_summaries,
+    RegionSummary,
+};
+use crate::command_utils::ModCallerArgs;
+use crate::dmr::bam::{position_counts, BamPileupParams};
+use crate::dmr::group::Replicate;
+use crate::dmr::subcommands::PairwiseDmr;
+use crate::dmr::util::{parse_roi_bed, ContigLookup, DmrInterval};
+use crate::logging::init_logging;
+use crate::mod_base_code::DnaBase;
+use crate::motif_bed::{MotifLocations, MultipleMotifLocations, RegexMotif};
+use crate::pileup::PileupNumericOptions;
+use crate::util::{
+    create_out_directory, get_master_progress_bar, get_targets, get_ticker,
+};
+
+#[derive(Args)]
+#[command(group = clap::ArgGroup::new("region_source").required(true))]
+pub struct AggregateRegions {
+    /// Input modBAM (sorted and indexed) or bgzipped bedMethyl file with a
+    /// tabix index next to it (same name with .tbi).
+    in_file: PathBuf,
+    /// Path to file to direct output, optional, no argument will direct output to stdout.
+    #[arg(short = 'o', long)]
+    out_path: Option<String>,
+    /// BED file of regions to summarize, for example promoters or CpG islands.
+    /// Should be tab-separated (spaces allowed in the "name" column). Requires
+    /// chrom, chromStart and chromEnd. The Name column is optional. Strand is
+    /// ignored.
+    #[arg(long, short = 'r', alias = "regions", group = "region_source")]
+    regions_bed: Option<PathBuf>,
+    /// Summarize consecutive, non-overlapping tiles of this many base pairs
+    /// across each reference sequence instead of regions from a BED file.
+    #[arg(long, group = "region_source")]
+    tile_size: Option<u64>,
+    /// Path to reference fasta. Required with bedMethyl input and when
+    /// using --cpg or --motif with modBAM input.
+    #[arg(long = "ref")]
+    reference_fasta: Option<PathBuf>,
+    /// Bases to summarize from bedMethyl input, may be multiple. For example,
+    /// to summarize cytosine modifications use --base C. Required with
+    /// bedMethyl input.
+    #[arg(short, alias = "base")]
+    modified_bases: Vec<char>,
+    /// Only count CpG sites from modBAM input, short hand for --motif CG 0.
+    #[arg(long, group = "motif_options", default_value_t = false)]
+    cpg: bool,
+    /// Only count sites at this sequence motif from modBAM input. The first
+    /// argument should be the sequence motif and the second argument is the
+    /// 0-based offset to the base to use.
+    #[arg(long, group = "motif_options", num_args = 2)]
+    motif: Option<Vec<String>>,
+    /// Respect soft masking in the reference FASTA.
+    #[arg(long, short = 'k', default_value_t = false)]
+    mask: bool,
+    /// Minimum valid coverage required to use a site. See the help for
+    /// pileup for the specification and description of valid coverage.
+    #[arg(long, alias = "min-coverage", default_value_t = 0)]
+    min_valid_coverage: u64,
+    /// File to write logs to, it's recommended to use this option.
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Number of threads to use.
+    #[arg(short = 't', long, default_value_t = 4)]
+    threads: usize,
+    /// Control the batch size. The batch size is the number of regions to
+    /// process concurrently. Default will be 50% more than the number of
+    /// threads assigned.
+    #[arg(long, alias = "batch")]
+    batch_size: Option<usize>,
+    /// Maximum number of records to use when calculating pileup from modBAM
+    /// input. This argument is passed to the pileup engine.
+    #[arg(long, default_value_t = 8000, hide_short_help = true)]
+    max_depth: u32,
+    /// Don't show progress bars
+    #[arg(long, default_value_t = false)]
+    suppress_progress: bool,
+    /// Force overwrite of output file, if it already exists.
+    #[arg(short = 'f', long, default_value_t = false)]
+    force: bool,
+
+    #[command(flatten)]
+    mod_caller_args: ModCallerArgs,
+}
+
+impl AggregateRegions {
+    fn load_regions(
+        &self,
+        sequence_lengths: impl FnOnce() -> anyhow::Result<Vec<(String, u64)>>,
+    ) -> anyhow::Result<Vec<DmrInterval>> {
+        match (self.regions_bed.as_ref(), self.tile_size) {
+            (Some(roi_bed), _) => {
+                let rois = parse_roi_bed(roi_bed)?;
+                info!("loaded {} regions", rois.len());
+                Ok(rois)
+            }
+            (None, Some(tile_size)) => {
+                if tile_size == 0 {
+                    bail!("tile size must be greater than 0")
+                }
+                let tiles = make_tiles(&sequence_lengths()?, tile_size);
+                info!("made {} tiles of {tile_size} bp", tiles.len());
+                Ok(tiles)
+            }
+            (None, None) => {
+                bail!("need to specify either --regions or --tile-size")
+            }
+        }
+    }
+
+    fn get_writer(&self) -> anyhow::Result<Box<dyn Write>> {
+        match self.out_path.as_ref() {
+            None => Ok(Box::new(BufWriter::new(std::io::stdout()))),
+            Some(fp) => {
+                let p = Path::new(fp);
+                create_out_directory(p)?;
+                if p.exists() && !self.force {
+                    bail!("refusing to overwrite existing file {}", fp)
+                } else {
+                    let fh = File::create(p)?;
+                    Ok(Box::new(BufWriter::new(fh)))
+                }
+            }
+        }
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        if !self.in_file.exists() {
+            bail!("input file {:?} not found", &self.in_file)
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .context("failed to make threadpool")?;
+        let mpb = MultiProgress::new();
+        if self.suppress_progress {
+            mpb.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+        let batch_size = self
+            .batch_size
+            .unwrap_or_else(|| (self.threads as f32 * 1.5f32).floor() as usize)
+            .max(1);
+
+        let mut writer = self.get_writer()?;
+        let (rows_written, failed) =
+            match bam::IndexedReader::from_path(&self.in_file) {
+                Ok(reader) => {
+                    info!("summarizing regions from modBAM");
+                    let header = reader.header().to_owned();
+                    self.aggregate_bam(
+                        &header,
+                        &pool,
+                        &mpb,
+                        batch_size,
+                        &mut writer,
+                    )?
+                }
+                Err(_) => {
+                    info!(
+                        "input is not a modBAM, summarizing regions from \
+                         bedMethyl"
+                    );
+                    self.aggregate_bedmethyl(
+                        &pool,
+                        &mpb,
+                        batch_size,
+                        &mut writer,
+                    )?
+                }
+            };
+        writer.flush()?;
+        info!("wrote {rows_written} rows, {failed} regions had no valid coverage or failed");
+
+        Ok(())
+    }
+
+    fn aggregate_bedmethyl(
+        &self,
+        pool: &rayon::ThreadPool,
+        mpb: &MultiProgress,
+        batch_size: usize,
+        writer: &mut Box<dyn Write>,
+    ) -> anyhow::Result<(u64, usize)> {
+        let reference_fasta =
+            self.reference_fasta.as_ref().ok_or_else(|| {
+                anyhow!("--ref is required to aggregate bedMethyl input")
+            })?;
+        if self.cpg || self.motif.is_some() {
+            bail!("--cpg and --motif are only used with modBAM input, use --base with bedMethyl")
+        }
+        PairwiseDmr::validate_modified_bases(&self.modified_bases)?;
+        let (index, index_fp) = PairwiseDmr::load_index(&self.in_file, None)?;
+        let contig_lookup = ContigLookup::new(&index, &index_fp, None)?;
+        let bedmethyl = Replicate {
+            bedmethyl_fp: self.in_file.clone(),
+            index,
+            contig_lookup,
+        };
+        let chrom_lookup =
+            Arc::new(ContigLookup::new(&bedmethyl.index, &index_fp, None)?);
+        let motifs = self
+            .modified_bases
+            .iter()
+            .map(|c| DnaBase::parse(*c))
+            .collect::<anyhow::Result<Vec<DnaBase>>>()?;
+        let position_filter = pool.install(|| {
+            PairwiseDmr::get_stranded_position_filter(
+                reference_fasta,
+                self.mask,
+                chrom_lookup.clone(),
+                mpb,
+                &motifs,
+            )
+        })?;
+
+        let regions =
+            self.load_regions(|| reference_lengths(reference_fasta))?;
+        let failures = mpb.add(get_ticker());
+        failures.set_message("regions not found in bedMethyl");
+        let (regions, chrom_ids): (Vec<DmrInterval>, Vec<u32>) = regions
+            .into_iter()
+            .filter_map(|region| {
+                let chrom_id = chrom_lookup.inner.get(&region.chrom);
+                if chrom_id.is_none() {
+                    debug!("{} not found in bedMethyl index", &region.chrom);
+                    failures.inc(1);
+                }
+                chrom_id.map(|chrom_id| (region, *chrom_id as u32))
+            })
+            .unzip();
+        if failures.position() > 0 {
+            info!(
+                "{} regions are on sequences not found in the bedMethyl index",
+                failures.position()
+            );
+        }
+
+        let pb = mpb.add(get_master_progress_bar(regions.len()));
+        pb.set_message("regions processed");
+        let mut rows_written = 0u64;
+        let mut failed = 0usize;
+        for (batch, batch_chrom_ids) in
+            regions.chunks(batch_size).zip(chrom_ids.chunks(batch_size))
+        {
+            let results = pool.install(|| {
+                summarize_bedmethyl_batch(
+                    batch,
+                    &bedmethyl,
+                    batch_chrom_ids,
+                    &position_filter,
+                    self.min_valid_coverage,
+                )
+            })?;
+            let (batch_rows, batch_failed) = write_summaries(writer, results)?;
+            rows_written += batch_rows;
+            failed += batch_failed;
+            pb.inc(batch.len() as u64);
+        }
+        pb.finish_and_clear();
+
+        Ok((rows_written, failed))
+    }
+
+    fn aggregate_bam(
+        &self,
+        header: &bam::HeaderView,
+        pool: &rayon::ThreadPool,
+        mpb: &MultiProgress,
+        batch_size: usize,
+        writer: &mut Box<dyn Write>,
+    ) -> anyhow::Result<(u64, usize)> {
+        if !self.modified_bases.is_empty() {
+            bail!("--base is only used with bedMethyl input, use --cpg or --motif with modBAM")
+        }
+        let edge_filter = self.mod_caller_args.edge_filter()?;
+        let collapse_method = self.mod_caller_args.collapse_method()?;
+        let pileup_options = match collapse_method.as_ref() {
+            Some(method) => PileupNumericOptions::Collapse(method.clone()),
+            None => PileupNumericOptions::Passthrough,
+        };
+
+        let targets = get_targets(header, None);
+        let regex_motif = match (&self.motif, self.cpg) {
+            (Some(raw_motif_parts), _) => Some(
+                RegexMotif::from_raw_parts(raw_motif_parts, false)?
+                    .into_iter()
+                    .next()
+                    .ok_or(anyhow!("illegal number of parts for motif"))?,
+            ),
+            (None, true) => Some(RegexMotif::parse_string("CG", 0).unwrap()),
+            (None, false) => None,
+        };
+        let motif_locations = regex_motif
+            .map(|regex_motif| {
+                let reference_fasta =
+                    self.reference_fasta.as_ref().ok_or_else(|| {
+                        anyhow!("--ref is required with --cpg or --motif")
+                    })?;
+                let names_to_tid = targets
+                    .iter()
+                    .map(|target| (target.name.as_str(), target.tid))
+                    .collect::<HashMap<&str, u32>>();
+                pool.install(|| {
+                    MotifLocations::from_fasta(
+                        reference_fasta,
+                        regex_motif,
+                        &names_to_tid,
+                        self.mask,
+                        None,
+                        mpb,
+                    )
+                })
+                .map(|locations| MultipleMotifLocations::new(vec![locations]))
+            })
+            .transpose()?;
+
+        let regions = self.load_regions(|| {
+            Ok(targets
+                .iter()
+                .map(|target| (target.name.clone(), target.length as u64))
+                .collect())
+        })?;
+        let failures = mpb.add(get_ticker());
+        failures.set_message("regions not found in BAM header");
+        let work = regions
+            .into_iter()
+            .filter_map(|region| {
+                let tid = header.tid(region.chrom.as_bytes());
+                if tid.is_none() {
+                    debug!("{} not found in BAM header", &region.chrom);
+                    failures.inc(1);
+                }
+                tid.map(|tid| (tid, region))
+            })
+            .collect::<Vec<(u32, DmrInterval)>>();
+        if failures.position() > 0 {
+            info!(
+                "{} regions are on sequences not found in the BAM header",
+                failures.position()
+            );
+        }
+
+        let in_bams = [self.in_file.clone()];
+        let threshold_caller = self.mod_caller_args.build_caller(
+            &in_bams,
+            pool,
+            None,
+            edge_filter.as_ref(),
+            collapse_method.as_ref(),
+            None,
+            self.suppress_progress,
+        )?;
+        let params = BamPileupParams::new(
+            threshold_caller,
+            pileup_options,
+            self.mod_caller_args.force_allow_implicit,
+            self.max_depth,
+            motif_locations,
+            edge_filter,
+            self.min_valid_coverage,
+        );
+
+        let pb = mpb.add(get_master_progress_bar(work.len()));
+        pb.set_message("regions processed");
+        let mut rows_written = 0u64;
+        let mut failed = 0usize;
+        for batch in work.chunks(batch_size) {
+            let results = pool.install(|| {
+                batch
+                    .par_iter()
+                    .map(|(tid, region)| {
+                        let counts =
+                            position_counts(&in_bams, *tid, region, &params)?;
+                        let reads = read_level_summaries(
+                            &in_bams,
+                            *tid,
+                            region,
+                            &counts.iter().map(|(pos, _)| *pos).collect(),
+                            &params,
+                        )?;
+                        let sites = counts
+                            .into_iter()
+                            .map(|(_, counts)| counts.into_aggregated_counts())
+                            .collect::<anyhow::Result<Vec<_>>>()?;
+                        summarize_region(region, &sites, Some(reads))
+                    })
+                    .collect::<Vec<anyhow::Result<RegionSummary>>>()
+            });
+            let (batch_rows, batch_failed) = write_summaries(writer, results)?;
+            rows_written += batch_rows;
+            failed += batch_failed;
+            pb.inc(batch.len() as u64);
+        }
+        pb.finish_and_clear();
+
+        Ok((rows_written, failed))
+    }
+}
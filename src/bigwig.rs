@@ -0,0 +1,512 @@
+//! Minimal streaming bigWig writer. Intervals are written to compressed data
+//! sections as they arrive and the chromosome B+ tree and R tree index are
+//! written when the file is finished. No zoom levels are produced. Format
+//! details: https://doi.org/10.1093/bioinformatics/btq351 (supplement).
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result as AnyhowResult};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use rustc_hash::FxHashMap;
+
+const BIGWIG_MAGIC: u32 = 0x888F_FC26;
+const BPT_MAGIC: u32 = 0x78CA_8C91;
+const CIR_TREE_MAGIC: u32 = 0x2468_ACE0;
+const VERSION: u16 = 4;
+const HEADER_SIZE: u64 = 64;
+const SUMMARY_SIZE: u64 = 40;
+const BLOCK_SIZE: usize = 256;
+const ITEMS_PER_SLOT: usize = 1024;
+/// bedGraph section type, each item is (start, end, value)
+const SECTION_BED_GRAPH: u8 = 1;
+
+#[derive(Debug, Copy, Clone)]
+struct BlockIndexItem {
+    chrom_id: u32,
+    start: u32,
+    end: u32,
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Default)]
+struct Summary {
+    bases_covered: u64,
+    min_val: f64,
+    max_val: f64,
+    sum_data: f64,
+    sum_squares: f64,
+}
+
+impl Summary {
+    fn add(&mut self, start: u32, end: u32, value: f32) {
+        let size = (end - start) as u64;
+        let value = value as f64;
+        if self.bases_covered == 0 {
+            self.min_val = value;
+            self.max_val = value;
+        } else {
+            self.min_val = self.min_val.min(value);
+            self.max_val = self.max_val.max(value);
+        }
+        self.bases_covered += size;
+        self.sum_data += value * size as f64;
+        self.sum_squares += value * value * size as f64;
+    }
+}
+
+/// Number of nodes at each level of a tree with `n_items` leaf items and
+/// `block_size` items per node, leaf level first.
+fn nodes_per_level(n_items: usize, block_size: usize) -> Vec<usize> {
+    let n_chunks = |n: usize| (0..n).step_by(block_size).len();
+    // always at least one (possibly empty) leaf node
+    let mut levels = vec![std::cmp::max(n_chunks(n_items), 1)];
+    while *levels.last().unwrap() > 1 {
+        levels.push(n_chunks(*levels.last().unwrap()));
+    }
+    levels
+}
+
+/// Byte offsets of every node, indexed by level (leaf first), when the levels
+/// are written root first starting at `start`.
+fn node_offsets(
+    n_items: usize,
+    block_size: usize,
+    leaf_item_size: u64,
+    branch_item_size: u64,
+    start: u64,
+) -> Vec<Vec<u64>> {
+    let levels = nodes_per_level(n_items, block_size);
+    let mut offsets = vec![Vec::new(); levels.len()];
+    let mut offset = start;
+    for level in (0..levels.len()).rev() {
+        let (n_children, item_size) = if level == 0 {
+            (n_items, leaf_item_size)
+        } else {
+            (levels[level - 1], branch_item_size)
+        };
+        for node in 0..levels[level] {
+            offsets[level].push(offset);
+            let n_entries =
+                std::cmp::min(block_size, n_children - node * block_size);
+            offset += 4 + n_entries as u64 * item_size;
+        }
+    }
+    offsets
+}
+
+/// The range of leaf items covered by `node` at `level`.
+fn node_item_range(
+    level: usize,
+    node: usize,
+    n_items: usize,
+    block_size: usize,
+) -> (usize, usize) {
+    let span = block_size.pow(level as u32 + 1);
+    (node * span, std::cmp::min((node + 1) * span, n_items))
+}
+
+pub(crate) struct BigWigFile {
+    writer: BufWriter<File>,
+    chrom_sizes: Vec<(String, u32)>,
+    data_start: u64,
+    blocks: Vec<BlockIndexItem>,
+    pending: Vec<(u32, u32, f32)>,
+    pending_chrom: Option<u32>,
+    last_position: Option<(u32, u32)>,
+    max_uncompressed_size: usize,
+    summary: Summary,
+}
+
+impl BigWigFile {
+    /// Create a new bigWig file, `chrom_sizes` are the names and lengths of
+    /// the reference sequences, the index in this slice is used as the
+    /// chromosome id.
+    pub(crate) fn create<P: AsRef<Path>>(
+        path: P,
+        chrom_sizes: Vec<(String, u32)>,
+    ) -> AnyhowResult<Self> {
+        let fh = File::create(path.as_ref()).with_context(|| {
+            format!(
+                "failed to create bigWig file at {}",
+                path.as_ref().to_string_lossy()
+            )
+        })?;
+        let mut writer = BufWriter::new(fh);
+        // header and total summary are filled in when the file is finished
+        writer.write_all(&[0u8; (HEADER_SIZE + SUMMARY_SIZE) as usize])?;
+        Self::write_chrom_tree(&mut writer, &chrom_sizes)?;
+        let data_start = writer.stream_position()?;
+        // placeholder for the number of data sections
+        writer.write_all(&0u64.to_le_bytes())?;
+
+        Ok(Self {
+            writer,
+            chrom_sizes,
+            data_start,
+            blocks: Vec::new(),
+            pending: Vec::with_capacity(ITEMS_PER_SLOT),
+            pending_chrom: None,
+            last_position: None,
+            max_uncompressed_size: 0,
+            summary: Summary::default(),
+        })
+    }
+
+    fn write_chrom_tree<W: Write>(
+        writer: &mut W,
+        chrom_sizes: &[(String, u32)],
+    ) -> AnyhowResult<()> {
+        let mut items = chrom_sizes
+            .iter()
+            .enumerate()
+            .map(|(id, (name, size))| (name.as_bytes(), id as u32, *size))
+            .collect::<Vec<(&[u8], u32, u32)>>();
+        items.sort_by(|a, b| a.0.cmp(b.0));
+        let n_items = items.len();
+        let key_size = std::cmp::max(
+            items.iter().map(|x| x.0.len()).max().unwrap_or(1),
+            1,
+        );
+        let block_size = n_items.clamp(1, BLOCK_SIZE);
+        let item_size = key_size as u64 + 8;
+
+        writer.write_all(&BPT_MAGIC.to_le_bytes())?;
+        writer.write_all(&(block_size as u32).to_le_bytes())?;
+        writer.write_all(&(key_size as u32).to_le_bytes())?;
+        writer.write_all(&8u32.to_le_bytes())?;
+        writer.write_all(&(n_items as u64).to_le_bytes())?;
+        writer.write_all(&0u64.to_le_bytes())?;
+
+        // we need to know where we are to write child offsets, but can't
+        // require `Seek` here, so the caller always has us directly after the
+        // header and summary.
+        let tree_start = HEADER_SIZE + SUMMARY_SIZE + 32;
+        let offsets =
+            node_offsets(n_items, block_size, item_size, item_size, tree_start);
+        let write_key = |w: &mut W, key: &[u8]| -> std::io::Result<()> {
+            w.write_all(key)?;
+            w.write_all(&vec![0u8; key_size - key.len()])
+        };
+        for level in (0..offsets.len()).rev() {
+            for node in 0..offsets[level].len() {
+                let is_leaf = level == 0;
+                let (children_start, children_end) = if is_leaf {
+                    node_item_range(0, node, n_items, block_size)
+                } else {
+                    let n_children = offsets[level - 1].len();
+                    (
+                        node * block_size,
+                        std::cmp::min((node + 1) * block_size, n_children),
+                    )
+                };
+                let count = children_end - children_start;
+                writer.write_all(&[is_leaf as u8, 0u8])?;
+                writer.write_all(&(count as u16).to_le_bytes())?;
+                for child in children_start..children_end {
+                    if is_leaf {
+                        let (key, id, size) = items[child];
+                        write_key(writer, key)?;
+                        writer.write_all(&id.to_le_bytes())?;
+                        writer.write_all(&size.to_le_bytes())?;
+                    } else {
+                        let (first, _) = node_item_range(
+                            level - 1,
+                            child,
+                            n_items,
+                            block_size,
+                        );
+                        write_key(writer, items[first].0)?;
+                        writer.write_all(
+                            &offsets[level - 1][child].to_le_bytes(),
+                        )?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add an interval, intervals must be added in order of chromosome id
+    /// and then by position and must not overlap.
+    pub(crate) fn add_interval(
+        &mut self,
+        chrom_id: u32,
+        start: u32,
+        end: u32,
+        value: f32,
+    ) -> AnyhowResult<()> {
+        if chrom_id as usize >= self.chrom_sizes.len() {
+            bail!("unknown chromosome id {chrom_id}")
+        }
+        if end <= start {
+            bail!("interval end ({end}) must be greater than start ({start})")
+        }
+        if let Some((last_chrom, last_end)) = self.last_position {
+            if chrom_id < last_chrom
+                || (chrom_id == last_chrom && start < last_end)
+            {
+                bail!(
+                    "intervals must be sorted, got {}:{start} after {}:{last_end}",
+                    self.chrom_sizes[chrom_id as usize].0,
+                    self.chrom_sizes[last_chrom as usize].0
+                )
+            }
+        }
+        if self.pending_chrom.map(|c| c != chrom_id).unwrap_or(false)
+            || self.pending.len() >= ITEMS_PER_SLOT
+        {
+            self.flush_section()?;
+        }
+        self.pending_chrom = Some(chrom_id);
+        self.pending.push((start, end, value));
+        self.last_position = Some((chrom_id, end));
+        self.summary.add(start, end, value);
+        Ok(())
+    }
+
+    fn flush_section(&mut self) -> AnyhowResult<()> {
+        let chrom_id = match self.pending_chrom {
+            Some(chrom_id) if !self.pending.is_empty() => chrom_id,
+            _ => return Ok(()),
+        };
+        let start = self.pending.first().map(|x| x.0).unwrap();
+        let end = self.pending.last().map(|x| x.1).unwrap();
+        let mut raw = Vec::with_capacity(24 + self.pending.len() * 12);
+        raw.extend_from_slice(&chrom_id.to_le_bytes());
+        raw.extend_from_slice(&start.to_le_bytes());
+        raw.extend_from_slice(&end.to_le_bytes());
+        raw.extend_from_slice(&0u32.to_le_bytes()); // item step
+        raw.extend_from_slice(&0u32.to_le_bytes()); // item span
+        raw.push(SECTION_BED_GRAPH);
+        raw.push(0u8);
+        raw.extend_from_slice(&(self.pending.len() as u16).to_le_bytes());
+        for (s, e, v) in self.pending.drain(..) {
+            raw.extend_from_slice(&s.to_le_bytes());
+            raw.extend_from_slice(&e.to_le_bytes());
+            raw.extend_from_slice(&v.to_le_bytes());
+        }
+        self.max_uncompressed_size =
+            std::cmp::max(self.max_uncompressed_size, raw.len());
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&raw)?;
+        let compressed = encoder.finish()?;
+
+        let offset = self.writer.stream_position()?;
+        self.writer.write_all(&compressed)?;
+        self.blocks.push(BlockIndexItem {
+            chrom_id,
+            start,
+            end,
+            offset,
+            size: compressed.len() as u64,
+        });
+        Ok(())
+    }
+
+    fn write_index(&mut self, index_start: u64) -> AnyhowResult<()> {
+        let n_items = self.blocks.len();
+        let block_size = BLOCK_SIZE;
+        let (first, last) = (self.blocks.first(), self.blocks.last());
+        let end_file_offset = index_start;
+        let w = &mut self.writer;
+        w.write_all(&CIR_TREE_MAGIC.to_le_bytes())?;
+        w.write_all(&(block_size as u32).to_le_bytes())?;
+        w.write_all(&(n_items as u64).to_le_bytes())?;
+        w.write_all(&first.map(|b| b.chrom_id).unwrap_or(0).to_le_bytes())?;
+        w.write_all(&first.map(|b| b.start).unwrap_or(0).to_le_bytes())?;
+        w.write_all(&last.map(|b| b.chrom_id).unwrap_or(0).to_le_bytes())?;
+        w.write_all(&last.map(|b| b.end).unwrap_or(0).to_le_bytes())?;
+        w.write_all(&end_file_offset.to_le_bytes())?;
+        w.write_all(&(ITEMS_PER_SLOT as u32).to_le_bytes())?;
+        w.write_all(&0u32.to_le_bytes())?;
+
+        // leaf items also have the size of the data block
+        let offsets =
+            node_offsets(n_items, block_size, 32, 24, index_start + 48);
+        for level in (0..offsets.len()).rev() {
+            for node in 0..offsets[level].len() {
+                let is_leaf = level == 0;
+                let (children_start, children_end) = if is_leaf {
+                    node_item_range(0, node, n_items, block_size)
+                } else {
+                    let n_children = offsets[level - 1].len();
+                    (
+                        node * block_size,
+                        std::cmp::min((node + 1) * block_size, n_children),
+                    )
+                };
+                let count = children_end - children_start;
+                w.write_all(&[is_leaf as u8, 0u8])?;
+                w.write_all(&(count as u16).to_le_bytes())?;
+                let child_offsets = if is_leaf {
+                    &[]
+                } else {
+                    &offsets[level - 1][children_start..children_end]
+                };
+                for (i, child) in (children_start..children_end).enumerate() {
+                    let (first, last) = if is_leaf {
+                        (child, child + 1)
+                    } else {
+                        node_item_range(level - 1, child, n_items, block_size)
+                    };
+                    let first = &self.blocks[first];
+                    let last = &self.blocks[last - 1];
+                    w.write_all(&first.chrom_id.to_le_bytes())?;
+                    w.write_all(&first.start.to_le_bytes())?;
+                    w.write_all(&last.chrom_id.to_le_bytes())?;
+                    w.write_all(&last.end.to_le_bytes())?;
+                    if is_leaf {
+                        w.write_all(&first.offset.to_le_bytes())?;
+                        w.write_all(&first.size.to_le_bytes())?;
+                    } else {
+                        w.write_all(&child_offsets[i].to_le_bytes())?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the index and header, must be called for the file to be valid.
+    pub(crate) fn finish(mut self) -> AnyhowResult<()> {
+        self.flush_section()?;
+        let index_start = self.writer.stream_position()?;
+        self.write_index(index_start)?;
+
+        let chrom_tree_offset = HEADER_SIZE + SUMMARY_SIZE;
+        let w = &mut self.writer;
+        w.seek(SeekFrom::Start(0))?;
+        w.write_all(&BIGWIG_MAGIC.to_le_bytes())?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&0u16.to_le_bytes())?; // zoom levels
+        w.write_all(&chrom_tree_offset.to_le_bytes())?;
+        w.write_all(&self.data_start.to_le_bytes())?;
+        w.write_all(&index_start.to_le_bytes())?;
+        w.write_all(&0u16.to_le_bytes())?; // field count
+        w.write_all(&0u16.to_le_bytes())?; // defined field count
+        w.write_all(&0u64.to_le_bytes())?; // autoSql offset
+        w.write_all(&HEADER_SIZE.to_le_bytes())?; // total summary offset
+        w.write_all(&(self.max_uncompressed_size as u32).to_le_bytes())?;
+        w.write_all(&0u64.to_le_bytes())?; // extension offset
+
+        let summary = &self.summary;
+        w.write_all(&summary.bases_covered.to_le_bytes())?;
+        w.write_all(&summary.min_val.to_le_bytes())?;
+        w.write_all(&summary.max_val.to_le_bytes())?;
+        w.write_all(&summary.sum_data.to_le_bytes())?;
+        w.write_all(&summary.sum_squares.to_le_bytes())?;
+
+        w.seek(SeekFrom::Start(self.data_start))?;
+        w.write_all(&(self.blocks.len() as u64).to_le_bytes())?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+/// Lookup of reference sequence name to bigWig chromosome id.
+pub(crate) fn chrom_ids(
+    chrom_sizes: &[(String, u32)],
+) -> FxHashMap<String, u32> {
+    chrom_sizes
+        .iter()
+        .enumerate()
+        .map(|(id, (name, _))| (name.to_owned(), id as u32))
+        .collect()
+}
+
+#[cfg(test)]
+mod bigwig_tests {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use crate::bigwig::{
+        nodes_per_level, BigWigFile, BIGWIG_MAGIC, CIR_TREE_MAGIC,
+    };
+
+    fn read_u16(buf: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn read_u32(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_u64(buf: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+    }
+
+    // walk the R tree, returning the (chrom_id, start, end) of every interval
+    fn read_intervals(buf: &[u8], node_offset: usize) -> Vec<(u32, u32, u32)> {
+        let is_leaf = buf[node_offset] == 1;
+        let count = read_u16(buf, node_offset + 2) as usize;
+        let mut intervals = Vec::new();
+        let mut offset = node_offset + 4;
+        for _ in 0..count {
+            if is_leaf {
+                let data_offset = read_u64(buf, offset + 16) as usize;
+                let data_size = read_u64(buf, offset + 24) as usize;
+                let mut raw = Vec::new();
+                ZlibDecoder::new(&buf[data_offset..data_offset + data_size])
+                    .read_to_end(&mut raw)
+                    .unwrap();
+                let chrom_id = read_u32(&raw, 0);
+                let n_items = read_u16(&raw, 22) as usize;
+                for i in 0..n_items {
+                    let item = 24 + i * 12;
+                    intervals.push((
+                        chrom_id,
+                        read_u32(&raw, item),
+                        read_u32(&raw, item + 4),
+                    ));
+                }
+                offset += 32;
+            } else {
+                let child = read_u64(buf, offset + 16) as usize;
+                intervals.extend(read_intervals(buf, child));
+                offset += 24;
+            }
+        }
+        intervals
+    }
+
+    #[test]
+    fn test_bigwig_nodes_per_level() {
+        assert_eq!(nodes_per_level(0, 256), vec![1]);
+        assert_eq!(nodes_per_level(256, 256), vec![1]);
+        assert_eq!(nodes_per_level(257, 256), vec![2, 1]);
+        assert_eq!(nodes_per_level(70_000, 256), vec![274, 2, 1]);
+    }
+
+    #[test]
+    fn test_bigwig_write_read_back() {
+        let fp = std::env::temp_dir().join("test_bigwig_write_read_back.bw");
+        let chrom_sizes = vec![
+            ("chr2".to_string(), 1_000_000u32),
+            ("chr1".to_string(), 1_000_000u32),
+        ];
+        let mut bw = BigWigFile::create(&fp, chrom_sizes).unwrap();
+        let mut expected = Vec::new();
+        for chrom_id in 0..2u32 {
+            // enough intervals for a multi-level index
+            for pos in (0..300_000u32).step_by(2) {
+                bw.add_interval(chrom_id, pos, pos + 1, 0.5).unwrap();
+                expected.push((chrom_id, pos, pos + 1));
+            }
+        }
+        assert!(bw.add_interval(0, 10, 11, 0.5).is_err());
+        bw.finish().unwrap();
+
+        let buf = std::fs::read(&fp).unwrap();
+        assert_eq!(read_u32(&buf, 0), BIGWIG_MAGIC);
+        let index_offset = read_u64(&buf, 24) as usize;
+        assert_eq!(read_u32(&buf, index_offset), CIR_TREE_MAGIC);
+        let total_summary_offset = read_u64(&buf, 44) as usize;
+        assert_eq!(read_u64(&buf, total_summary_offset), expected.len() as u64);
+        let intervals = read_intervals(&buf, index_offset + 48);
+        assert_eq!(intervals, expected);
+    }
+}
@@ -0,0 +1,524 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context};
+use clap::ValueEnum;
+use rust_htslib::bam;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::errs::RunError;
+use crate::mod_bam::{EdgeFilter, ModBaseInfo, SeqPosBaseModProbs};
+use crate::mod_base_code::ModCodeRepr;
+use crate::motif_bed::{find_motif_hits, RegexMotif};
+use crate::util::{get_forward_sequence, Strand};
+
+pub mod subcommand;
+
+/// Number of distinct values in the ML tag, calibration curves map each of
+/// these to a new probability.
+const NUM_ML_VALUES: usize = 256;
+/// Motif column value when the calibration isn't split by motif.
+const ALL_CONTEXTS: &str = "*";
+pub(crate) const CALIBRATION_HEADER: &str = "primary_base\tmod_code\tmotif\t\
+                                             ml_value\tn_modified\t\
+                                             n_unmodified\tcalibrated_prob";
+
+/// The ML value that a probability is written as, the inverse of the
+/// (ml + 0.5) / 256 used when parsing the tag.
+#[inline]
+fn ml_value(prob: f32) -> usize {
+    ((prob * NUM_ML_VALUES as f32).floor() as usize).min(NUM_ML_VALUES - 1)
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum CalibrationMethod {
+    /// Isotonic regression (pool adjacent violators), the calibrated
+    /// probability is monotonically non-decreasing in the raw probability.
+    Isotonic,
+    /// Binned reliability curve, each ML value is given the empirical
+    /// fraction modified of the equal-width bin it falls in.
+    Binned,
+}
+
+/// Calibration curves are fit and applied separately for each primary base,
+/// mod code, and (optionally) motif.
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub(crate) struct CalibrationKey {
+    pub(crate) primary_base: char,
+    pub(crate) mod_code: ModCodeRepr,
+    pub(crate) motif_idx: Option<usize>,
+}
+
+/// Forward read positions and strands of the motif hits in a read, mapped to
+/// the index of the first motif that matches.
+fn site_motifs(
+    motifs: &[RegexMotif],
+    forward_sequence: &str,
+) -> FxHashMap<(usize, Strand), usize> {
+    let mut site_motifs = FxHashMap::default();
+    for (idx, motif) in motifs.iter().enumerate() {
+        for hit in find_motif_hits(forward_sequence, motif) {
+            site_motifs.entry(hit).or_insert(idx);
+        }
+    }
+    site_motifs
+}
+
+/// The motif context of a call, `None` when the call isn't at a motif site
+/// and should be ignored, `Some(None)` when no motifs are used.
+#[inline]
+fn motif_context(
+    motifs: &[RegexMotif],
+    site_motifs: &FxHashMap<(usize, Strand), usize>,
+    position: usize,
+    strand: Strand,
+) -> Option<Option<usize>> {
+    if motifs.is_empty() {
+        Some(None)
+    } else {
+        site_motifs.get(&(position, strand)).map(|idx| Some(*idx))
+    }
+}
+
+/// Counts of each ML value in the modified and unmodified controls.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MlValueCounts {
+    pub(crate) n_modified: Vec<u64>,
+    pub(crate) n_unmodified: Vec<u64>,
+}
+
+impl Default for MlValueCounts {
+    fn default() -> Self {
+        Self {
+            n_modified: vec![0; NUM_ML_VALUES],
+            n_unmodified: vec![0; NUM_ML_VALUES],
+        }
+    }
+}
+
+impl MlValueCounts {
+    /// Fit a calibration curve, the probability that a call with each ML
+    /// value is correct given `prior` fraction of the sites are modified. The
+    /// counts from each control are normalized to their totals so that the
+    /// depth of the controls doesn't change the curve. Returns None if
+    /// either control has no calls.
+    pub(crate) fn fit(
+        &self,
+        prior: f64,
+        method: CalibrationMethod,
+        num_bins: usize,
+    ) -> Option<Vec<f32>> {
+        let total_modified = self.n_modified.iter().sum::<u64>();
+        let total_unmodified = self.n_unmodified.iter().sum::<u64>();
+        if total_modified == 0 || total_unmodified == 0 {
+            return None;
+        }
+        // (weight of modified calls, total weight) for each ML value
+        let weights = self
+            .n_modified
+            .iter()
+            .zip(self.n_unmodified.iter())
+            .map(|(n_mod, n_unmod)| {
+                let w_mod = prior * *n_mod as f64 / total_modified as f64;
+                let w_unmod =
+                    (1f64 - prior) * *n_unmod as f64 / total_unmodified as f64;
+                (w_mod, w_mod + w_unmod)
+            })
+            .collect::<Vec<(f64, f64)>>();
+        let calibrated = match method {
+            CalibrationMethod::Isotonic => isotonic_fit(&weights),
+            CalibrationMethod::Binned => binned_fit(&weights, num_bins),
+        };
+        Some(calibrated)
+    }
+}
+
+/// Pool adjacent violators over the ML values with any calls, ML values
+/// without calls take the value of the closest lower ML value with calls (or
+/// the closest higher one at the start).
+fn isotonic_fit(weights: &[(f64, f64)]) -> Vec<f32> {
+    // blocks of (sum modified weight, sum weight, first ML value, last ML
+    // value)
+    let mut blocks: Vec<(f64, f64, usize, usize)> = Vec::new();
+    for (ml, (w_mod, w)) in weights.iter().enumerate() {
+        if *w <= 0f64 {
+            continue;
+        }
+        blocks.push((*w_mod, *w, ml, ml));
+        while blocks.len() > 1 {
+            let (m_b, w_b, _, last) = blocks[blocks.len() - 1];
+            let (m_a, w_a, first, _) = blocks[blocks.len() - 2];
+            if m_a / w_a <= m_b / w_b {
+                break;
+            }
+            blocks.pop();
+            *blocks.last_mut().unwrap() = (m_a + m_b, w_a + w_b, first, last);
+        }
+    }
+    let mut fitted = vec![None; weights.len()];
+    for (w_mod, w, first, last) in blocks {
+        for ml in first..=last {
+            if weights[ml].1 > 0f64 {
+                fitted[ml] = Some((w_mod / w) as f32);
+            }
+        }
+    }
+    let first_fitted = fitted.iter().flatten().next().copied();
+    let mut prev = first_fitted;
+    fitted
+        .into_iter()
+        .enumerate()
+        .map(|(ml, value)| {
+            prev = value.or(prev);
+            prev.unwrap_or((ml as f32 + 0.5f32) / NUM_ML_VALUES as f32)
+        })
+        .collect()
+}
+
+/// Empirical fraction modified in equal-width bins of ML values, bins
+/// without calls keep the raw probability.
+fn binned_fit(weights: &[(f64, f64)], num_bins: usize) -> Vec<f32> {
+    let num_bins = num_bins.clamp(1, weights.len());
+    let bin = |ml: usize| ml * num_bins / weights.len();
+    let mut bin_weights = vec![(0f64, 0f64); num_bins];
+    for (ml, (w_mod, w)) in weights.iter().enumerate() {
+        let (bin_mod, bin_w) = &mut bin_weights[bin(ml)];
+        *bin_mod += w_mod;
+        *bin_w += w;
+    }
+    (0..weights.len())
+        .map(|ml| {
+            let (w_mod, w) = bin_weights[bin(ml)];
+            if w > 0f64 {
+                (w_mod / w) as f32
+            } else {
+                (ml as f32 + 0.5f32) / NUM_ML_VALUES as f32
+            }
+        })
+        .collect()
+}
+
+/// Tallies of the ML values of the calibrated mod codes from the two
+/// controls.
+#[derive(Debug, Default)]
+pub(crate) struct CalibrationCounts {
+    pub(crate) counts: BTreeMap<CalibrationKey, MlValueCounts>,
+}
+
+impl CalibrationCounts {
+    /// Add the calls for `mod_codes` in a record, all calls from the
+    /// modified control are counted as modified and all calls from the
+    /// unmodified control as unmodified. Returns the number of calls added.
+    pub(crate) fn add_record(
+        &mut self,
+        record: &bam::Record,
+        modified_control: bool,
+        mod_codes: &FxHashSet<ModCodeRepr>,
+        motifs: &[RegexMotif],
+        edge_filter: Option<&EdgeFilter>,
+    ) -> Result<usize, RunError> {
+        let mod_base_info = ModBaseInfo::new_from_record(record)?;
+        let site_motifs = if motifs.is_empty() {
+            FxHashMap::default()
+        } else {
+            site_motifs(motifs, &get_forward_sequence(record)?)
+        };
+        let mut n_calls = 0usize;
+        for (primary_base, strand, seq_pos_mod_probs) in
+            mod_base_info.iter_seq_base_mod_probs()
+        {
+            let positions = seq_pos_mod_probs
+                .pos_to_base_mod_probs
+                .iter()
+                .filter(|(pos, _)| match edge_filter {
+                    Some(edge_filter) => edge_filter
+                        .keep_position(**pos, record.seq_len())
+                        .unwrap_or(false),
+                    None => true,
+                });
+            for (pos, base_mod_probs) in positions {
+                let motif_idx =
+                    match motif_context(motifs, &site_motifs, *pos, strand) {
+                        Some(motif_idx) => motif_idx,
+                        None => continue,
+                    };
+                for (mod_code, prob) in base_mod_probs
+                    .iter_probs()
+                    .filter(|(mod_code, _)| mod_codes.contains(*mod_code))
+                {
+                    let key = CalibrationKey {
+                        primary_base: *primary_base,
+                        mod_code: *mod_code,
+                        motif_idx,
+                    };
+                    let counts = self.counts.entry(key).or_default();
+                    if modified_control {
+                        counts.n_modified[ml_value(*prob)] += 1;
+                    } else {
+                        counts.n_unmodified[ml_value(*prob)] += 1;
+                    }
+                    n_calls += 1;
+                }
+            }
+        }
+        Ok(n_calls)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CalibrationCurve {
+    pub(crate) counts: MlValueCounts,
+    pub(crate) calibrated: Vec<f32>,
+}
+
+/// Calibration curves for each primary base, mod code, and motif, mapping
+/// the raw ML values to calibrated probabilities.
+#[derive(Debug)]
+pub struct ProbabilityCalibration {
+    pub(crate) motifs: Vec<RegexMotif>,
+    pub(crate) curves: BTreeMap<CalibrationKey, CalibrationCurve>,
+}
+
+impl ProbabilityCalibration {
+    fn motif_label(&self, motif_idx: Option<usize>) -> String {
+        motif_idx
+            .and_then(|idx| self.motifs.get(idx))
+            .map(|motif| motif.to_string())
+            .unwrap_or(ALL_CONTEXTS.to_string())
+    }
+
+    /// Rows of the calibration table, one for each ML value of each curve.
+    pub(crate) fn to_rows(&self) -> Vec<String> {
+        let tab = '\t';
+        self.curves
+            .iter()
+            .flat_map(|(key, curve)| {
+                let motif = self.motif_label(key.motif_idx);
+                (0..NUM_ML_VALUES).map(move |ml| {
+                    format!(
+                        "{}{tab}{}{tab}{motif}{tab}{ml}{tab}{}{tab}{}{tab}{}",
+                        key.primary_base,
+                        key.mod_code,
+                        curve.counts.n_modified[ml],
+                        curve.counts.n_unmodified[ml],
+                        curve.calibrated[ml],
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Read a calibration table written by `modkit calibrate`.
+    pub fn from_path(path: &PathBuf) -> anyhow::Result<Self> {
+        let fh = std::fs::File::open(path)
+            .with_context(|| format!("failed to open calibration {path:?}"))?;
+        let mut motifs = Vec::<RegexMotif>::new();
+        let mut curves = BTreeMap::<CalibrationKey, CalibrationCurve>::new();
+        let mut all_contexts = false;
+        for (i, line) in BufReader::new(fh).lines().enumerate() {
+            let line = line?;
+            if i == 0 && line.starts_with("primary_base") {
+                continue;
+            }
+            let parts = line.split('\t').collect::<Vec<&str>>();
+            if parts.len() != 7 {
+                bail!("invalid calibration row {line}, expected 7 columns")
+            }
+            let parse_err =
+                |col: &str| anyhow!("invalid {col} in calibration row {line}");
+            let primary_base = parts[0]
+                .parse::<char>()
+                .map_err(|_| parse_err("primary base"))?;
+            let mod_code = ModCodeRepr::parse(parts[1])?;
+            let motif_idx = if parts[2] == ALL_CONTEXTS {
+                all_contexts = true;
+                None
+            } else {
+                let idx = match motifs
+                    .iter()
+                    .position(|motif| motif.to_string() == parts[2])
+                {
+                    Some(idx) => idx,
+                    None => {
+                        let (raw_motif, offset) = parts[2]
+                            .split_once(',')
+                            .ok_or_else(|| parse_err("motif"))?;
+                        let offset = offset
+                            .parse::<usize>()
+                            .map_err(|_| parse_err("motif offset"))?;
+                        motifs
+                            .push(RegexMotif::parse_string(raw_motif, offset)?);
+                        motifs.len() - 1
+                    }
+                };
+                Some(idx)
+            };
+            let ml = parts[3]
+                .parse::<usize>()
+                .ok()
+                .filter(|ml| *ml < NUM_ML_VALUES)
+                .ok_or_else(|| parse_err("ML value"))?;
+            let n_modified = parts[4]
+                .parse::<u64>()
+                .map_err(|_| parse_err("n_modified"))?;
+            let n_unmodified = parts[5]
+                .parse::<u64>()
+                .map_err(|_| parse_err("n_unmodified"))?;
+            let calibrated_prob = parts[6]
+                .parse::<f32>()
+                .ok()
+                .filter(|p| (0f32..=1f32).contains(p))
+                .ok_or_else(|| parse_err("calibrated probability"))?;
+            let key = CalibrationKey {
+                primary_base,
+                mod_code,
+                motif_idx,
+            };
+            let curve = curves.entry(key).or_insert(CalibrationCurve {
+                counts: MlValueCounts::default(),
+                calibrated: vec![f32::NAN; NUM_ML_VALUES],
+            });
+            curve.counts.n_modified[ml] = n_modified;
+            curve.counts.n_unmodified[ml] = n_unmodified;
+            curve.calibrated[ml] = calibrated_prob;
+        }
+        if all_contexts && !motifs.is_empty() {
+            bail!("calibration cannot have both motif and all-context curves")
+        }
+        if curves.is_empty() {
+            bail!("no calibration curves in {path:?}")
+        }
+        for (key, curve) in curves.iter() {
+            if curve.calibrated.iter().any(|p| p.is_nan()) {
+                bail!(
+                    "calibration curve for {}{} is missing ML values",
+                    key.primary_base,
+                    key.mod_code
+                )
+            }
+        }
+        Ok(Self { motifs, curves })
+    }
+
+    /// Motif hits in the forward sequence of a read, empty if the
+    /// calibration doesn't use motifs.
+    pub(crate) fn site_motifs(
+        &self,
+        forward_sequence: &str,
+    ) -> FxHashMap<(usize, Strand), usize> {
+        if self.motifs.is_empty() {
+            FxHashMap::default()
+        } else {
+            site_motifs(&self.motifs, forward_sequence)
+        }
+    }
+
+    pub(crate) fn uses_motifs(&self) -> bool {
+        !self.motifs.is_empty()
+    }
+
+    /// Replace the probabilities of the calibrated mod codes with their
+    /// calibrated values. Inferred calls and calls outside of the motifs are
+    /// unchanged. If the probabilities of a position sum to more than 1
+    /// afterwards, they are scaled down to sum to 1.
+    pub(crate) fn calibrate(
+        &self,
+        primary_base: char,
+        strand: Strand,
+        site_motifs: &FxHashMap<(usize, Strand), usize>,
+        seq_pos_mod_probs: &mut SeqPosBaseModProbs,
+    ) {
+        for (pos, base_mod_probs) in
+            seq_pos_mod_probs.pos_to_base_mod_probs.iter_mut()
+        {
+            if base_mod_probs.inferred {
+                continue;
+            }
+            let motif_idx =
+                match motif_context(&self.motifs, site_motifs, *pos, strand) {
+                    Some(motif_idx) => motif_idx,
+                    None => continue,
+                };
+            for (mod_code, prob) in base_mod_probs.iter_mut() {
+                let key = CalibrationKey {
+                    primary_base,
+                    mod_code: *mod_code,
+                    motif_idx,
+                };
+                if let Some(curve) = self.curves.get(&key) {
+                    *prob = curve.calibrated[ml_value(*prob)];
+                }
+            }
+            let total =
+                base_mod_probs.iter_probs().map(|(_, p)| *p).sum::<f32>();
+            if total > 1f32 {
+                base_mod_probs.iter_mut_probs().for_each(|p| *p /= total);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod calibrate_tests {
+    use crate::calibrate::{
+        binned_fit, isotonic_fit, ml_value, CalibrationMethod, MlValueCounts,
+    };
+
+    #[test]
+    fn test_ml_value() {
+        for ml in 0..256usize {
+            let prob = (ml as f32 + 0.5f32) / 256f32;
+            assert_eq!(ml_value(prob), ml);
+        }
+        assert_eq!(ml_value(1f32), 255);
+        assert_eq!(ml_value(0f32), 0);
+    }
+
+    #[test]
+    fn test_isotonic_fit() {
+        // (modified weight, total weight), the 3rd and 4th values violate
+        // the ordering and are pooled, the 2nd has no calls
+        let weights = [
+            (0.0, 1.0),
+            (0.0, 0.0),
+            (0.8, 1.0),
+            (0.4, 1.0),
+            (0.9, 1.0),
+            (0.0, 0.0),
+        ];
+        let fit = isotonic_fit(&weights);
+        let expected = [0.0, 0.0, 0.6, 0.6, 0.9, 0.9];
+        assert_eq!(fit.len(), expected.len());
+        for (x, y) in fit.iter().zip(expected) {
+            assert!((x - y).abs() < 1e-6, "{fit:?}");
+        }
+        // leading ML values without calls take the first value
+        let fit = isotonic_fit(&[(0.0, 0.0), (0.5, 1.0)]);
+        assert_eq!(fit, vec![0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_binned_fit() {
+        let weights = [(0.0, 1.0), (1.0, 1.0), (0.0, 0.0), (0.0, 0.0)];
+        let fit = binned_fit(&weights, 2);
+        assert_eq!(fit[0], 0.5);
+        assert_eq!(fit[1], 0.5);
+        // no calls, raw probability
+        assert_eq!(fit[2], 2.5 / 256.0);
+    }
+
+    #[test]
+    fn test_fit_normalizes_controls() {
+        let mut counts = MlValueCounts::default();
+        // 10x more unmodified calls, all calls with the same ML value
+        counts.n_modified[200] = 10;
+        counts.n_unmodified[200] = 100;
+        let fit = counts.fit(0.5, CalibrationMethod::Isotonic, 20).unwrap();
+        assert!(fit.iter().all(|p| (p - 0.5).abs() < 1e-6));
+        let fit = counts.fit(0.2, CalibrationMethod::Isotonic, 20).unwrap();
+        assert!(fit.iter().all(|p| (p - 0.2).abs() < 1e-6));
+        let empty = MlValueCounts::default();
+        assert!(empty.fit(0.5, CalibrationMethod::Isotonic, 20).is_none());
+    }
+}
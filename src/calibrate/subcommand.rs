@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use clap::Args;
+use log::{debug, info, warn};
+use rust_htslib::bam::{self, Read};
+use rustc_hash::FxHashSet;
+
+use crate::adjust::record_is_valid;
+use crate::calibrate::{
+    CalibrationCounts, CalibrationCurve, CalibrationMethod,
+    ProbabilityCalibration, CALIBRATION_HEADER,
+};
+use crate::command_utils::{get_serial_reader, parse_edge_filter_input};
+use crate::errs::{InputError, RunError};
+use crate::logging::init_logging;
+use crate::mod_bam::EdgeFilter;
+use crate::mod_base_code::ModCodeRepr;
+use crate::motif_bed::RegexMotif;
+use crate::util::{get_query_name_string, get_spinner};
+use crate::writers::TsvWriter;
+
+#[derive(Args)]
+pub struct CalibrateModProbs {
+    // running args
+    /// Fully modified control modBAM, for example M.SssI-treated DNA for 5mC
+    /// at CpGs. All calls for the --mod-code codes in this BAM are taken to
+    /// be modified.
+    modified_bam: String,
+    /// Unmodified control modBAM, for example PCR-amplified or whole-genome
+    /// amplified DNA. All calls in this BAM are taken to be canonical.
+    unmodified_bam: String,
+    /// Output path for the calibration table, use with `modkit adjust-mods
+    /// --calibration`.
+    out_path: PathBuf,
+    /// Mod code that is fully modified in the modified control, for example
+    /// m for 5mC. Can be passed multiple times, calls for other mod codes are
+    /// not calibrated.
+    #[arg(long, required = true, action = clap::ArgAction::Append)]
+    mod_code: Vec<String>,
+    /// Fit a separate calibration for calls at this sequence motif. The first
+    /// argument should be the sequence motif and the second argument is the
+    /// 0-based offset to the base of the motif, for example: --motif CG 0.
+    /// Matched against the read sequence. This argument can be passed
+    /// multiple times, when motifs are used only calls at a motif site are
+    /// used for fitting and adjusted. One of --motif, --cpg, or
+    /// --all-contexts is required.
+    #[arg(long, action = clap::ArgAction::Append, num_args = 2)]
+    motif: Option<Vec<String>>,
+    /// Fit the calibration only at CpG sites, short hand for --motif CG 0.
+    #[arg(long, default_value_t = false)]
+    cpg: bool,
+    /// Fit a single calibration from calls in every sequence context. Only
+    /// use this when the modified control is modified in every context, for
+    /// example M.EcoGII-treated DNA for 6mA. Otherwise the canonical calls
+    /// outside of the enzyme's motif are counted as modified (e.g. non-CpG
+    /// calls in an M.SssI-treated sample) and the curves are biased.
+    #[arg(long, conflicts_with_all = ["motif", "cpg"], default_value_t = false)]
+    all_contexts: bool,
+    /// Method to fit the calibration curves with.
+    #[arg(long, value_enum, default_value_t = CalibrationMethod::Isotonic)]
+    method: CalibrationMethod,
+    /// Number of equal-width probability bins with --method binned.
+    #[arg(long, default_value_t = 20)]
+    num_bins: usize,
+    /// Prior fraction of sites that are modified, the calibrated probability
+    /// is the posterior probability of the call given this prior. The counts
+    /// from each control are normalized so the relative depth of the
+    /// controls doesn't change the curves.
+    #[arg(long, default_value_t = 0.5)]
+    prior: f64,
+    /// Use at most this many records from each control.
+    #[arg(short = 'n', long)]
+    num_reads: Option<usize>,
+    /// Discard base modification calls that are this many bases from the
+    /// start or the end of the read. Two comma-separated values may be
+    /// provided to asymmetrically filter out base modification calls from
+    /// the start and end of the reads. For example, 4,8 will filter out base
+    /// modification calls in the first 4 and last 8 bases of the read.
+    #[arg(long)]
+    edge_filter: Option<String>,
+    /// Invert the edge filter, instead of filtering out base modification
+    /// calls at the ends of reads, only _keep_ base modification calls at
+    /// the ends of reads.
+    #[arg(long, requires = "edge_filter", default_value_t = false)]
+    invert_edge_filter: bool,
+    /// Number of threads to use for reading the BAMs.
+    #[arg(short, long, default_value_t = 4)]
+    threads: usize,
+    /// Output debug logs to file at this path.
+    #[arg(long)]
+    log_filepath: Option<PathBuf>,
+    /// Force overwrite of output file.
+    #[arg(long, default_value_t = false)]
+    force: bool,
+    /// Hide the progress bar.
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    suppress_progress: bool,
+}
+
+impl CalibrateModProbs {
+    fn count_calls(
+        &self,
+        bam_fp: &str,
+        modified_control: bool,
+        counts: &mut CalibrationCounts,
+        mod_codes: &FxHashSet<ModCodeRepr>,
+        motifs: &[RegexMotif],
+        edge_filter: Option<&EdgeFilter>,
+    ) -> anyhow::Result<()> {
+        let mut reader = get_serial_reader(bam_fp)?;
+        reader.set_threads(self.threads)?;
+        let spinner = get_spinner();
+        if self.suppress_progress {
+            spinner.set_draw_target(indicatif::ProgressDrawTarget::hidden())
+        }
+        spinner.set_message(format!("Counting calls in {bam_fp}"));
+        let mut n_used = 0usize;
+        let mut n_calls = 0usize;
+        let mut n_failed = 0usize;
+        let mut n_skipped = 0usize;
+        for result in reader.records() {
+            if self.num_reads.map(|n| n_used >= n).unwrap_or(false) {
+                break;
+            }
+            let record: bam::Record = match result {
+                Ok(record) => record,
+                Err(e) => {
+                    debug!("failed to read record, {e}");
+                    n_failed += 1;
+                    continue;
+                }
+            };
+            let added = record_is_valid(&record).and_then(|_| {
+                counts.add_record(
+                    &record,
+                    modified_control,
+                    mod_codes,
+                    motifs,
+                    edge_filter,
+                )
+            });
+            match added {
+                Ok(n) => {
+                    n_used += 1;
+                    n_calls += n;
+                }
+                Err(RunError::BadInput(InputError(err)))
+                | Err(RunError::Failed(err)) => {
+                    let record_name = get_query_name_string(&record)
+                        .unwrap_or("???".to_owned());
+                    debug!("read {record_name} failed, {err}");
+                    n_failed += 1;
+                }
+                Err(RunError::Skipped(_reason)) => {
+                    n_skipped += 1;
+                }
+            }
+            spinner.inc(1);
+        }
+        spinner.finish_and_clear();
+        info!(
+            "counted {n_calls} calls from {n_used} records in {bam_fp}, \
+             {n_failed} failed, {n_skipped} skipped"
+        );
+        Ok(())
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        if !(self.prior > 0f64 && self.prior < 1f64) {
+            bail!("prior must be between 0 and 1 (exclusive)")
+        }
+        if self.num_bins == 0 {
+            bail!("num-bins must be greater than 0")
+        }
+        if self.out_path.exists() && !self.force {
+            bail!("refusing to write over existing file {:?}", self.out_path)
+        }
+        let mod_codes = self
+            .mod_code
+            .iter()
+            .map(|raw| ModCodeRepr::parse(raw))
+            .collect::<anyhow::Result<FxHashSet<ModCodeRepr>>>()?;
+        let motifs = match (&self.motif, self.cpg) {
+            (Some(raw_motif_parts), cpg) => {
+                RegexMotif::from_raw_parts(raw_motif_parts, cpg)?
+            }
+            (None, true) => vec![RegexMotif::parse_string("CG", 0)?],
+            (None, false) if self.all_contexts => Vec::new(),
+            (None, false) => bail!(
+                "calls in the modified control are all counted as modified, \
+                 pass the motifs that are modified with --motif or --cpg, or \
+                 --all-contexts if the control is modified in every context"
+            ),
+        };
+        let edge_filter = self
+            .edge_filter
+            .as_ref()
+            .map(|raw| parse_edge_filter_input(raw, self.invert_edge_filter))
+            .transpose()?;
+
+        let mut counts = CalibrationCounts::default();
+        self.count_calls(
+            &self.modified_bam,
+            true,
+            &mut counts,
+            &mod_codes,
+            &motifs,
+            edge_filter.as_ref(),
+        )?;
+        self.count_calls(
+            &self.unmodified_bam,
+            false,
+            &mut counts,
+            &mod_codes,
+            &motifs,
+            edge_filter.as_ref(),
+        )?;
+
+        let mut curves = BTreeMap::new();
+        for (key, counts) in counts.counts {
+            match counts.fit(self.prior, self.method, self.num_bins) {
+                Some(calibrated) => {
+                    curves.insert(key, CalibrationCurve { counts, calibrated });
+                }
+                None => {
+                    warn!(
+                        "calls for {}{} are missing from one of the controls, \
+                         cannot calibrate",
+                        key.primary_base, key.mod_code
+                    );
+                }
+            }
+        }
+        if curves.is_empty() {
+            bail!(
+                "no calibration curves could be fit, both controls need calls \
+                 for the mod codes"
+            )
+        }
+        let calibration = ProbabilityCalibration { motifs, curves };
+        let mut writer = TsvWriter::new_path(
+            &self.out_path,
+            self.force,
+            Some(CALIBRATION_HEADER.to_string()),
+        )?;
+        for row in calibration.to_rows() {
+            writer.write(format!("{row}\n").as_bytes())?;
+        }
+        info!(
+            "wrote {} calibration curves to {:?}",
+            calibration.curves.len(),
+            self.out_path
+        );
+        Ok(())
+    }
+}
@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 
 use anyhow::{anyhow, bail, Context};
+use clap::Args;
 use itertools::Itertools;
 use log::{debug, info};
 use rust_htslib::bam::{self, Header};
@@ -9,8 +10,9 @@ use rust_htslib::bam::{self, Header};
 use crate::mod_bam::{CollapseMethod, EdgeFilter};
 use crate::mod_base_code::{DnaBase, ModCodeRepr};
 use crate::position_filter::StrandedPositionFilter;
+use crate::record_filter::{RecordFilter, RecordSelection};
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
-use crate::thresholds::calc_threshold_from_bam;
+use crate::thresholds::calc_threshold_from_bams;
 use crate::util::{create_out_directory, Region};
 
 pub(crate) fn parse_per_mod_thresholds(
@@ -65,8 +67,10 @@ pub(crate) fn parse_thresholds(
     ))
 }
 
+/// Estimate the pass threshold from the reads of all of the `in_bams`
+/// together.
 pub(crate) fn get_threshold_from_options(
-    in_bam: &PathBuf,
+    in_bams: &[PathBuf],
     threads: usize,
     interval_size: u32,
     sample_frac: Option<f64>,
@@ -79,7 +83,7 @@ pub(crate) fn get_threshold_from_options(
     edge_filter: Option<&EdgeFilter>,
     collapse_method: Option<&CollapseMethod>,
     position_filter: Option<&StrandedPositionFilter<()>>,
-    only_mapped: bool,
+    record_selection: RecordSelection,
     suppress_progress: bool,
 ) -> anyhow::Result<MultipleThresholdModCaller> {
     if no_filtering {
@@ -97,8 +101,8 @@ pub(crate) fn get_threshold_from_options(
             (None, Some(num_reads))
         }
     };
-    let per_base_thresholds = calc_threshold_from_bam(
-        in_bam,
+    let per_base_thresholds = calc_threshold_from_bams(
+        in_bams,
         threads,
         interval_size,
         sample_frac,
@@ -109,7 +113,7 @@ pub(crate) fn get_threshold_from_options(
         edge_filter,
         collapse_method,
         position_filter,
-        only_mapped,
+        record_selection,
         suppress_progress,
     )?;
 
@@ -127,6 +131,187 @@ pub(crate) fn get_threshold_from_options(
     ))
 }
 
+/// Arguments for estimating the pass threshold, collapsing mod codes, and
+/// filtering calls at the ends of reads. Shared by the subcommands that call
+/// base modifications from modBAM input.
+#[derive(Args, Debug, Clone)]
+pub struct ModCallerArgs {
+    // sampling args
+    /// Sample this many reads when estimating the filtering threshold. Reads
+    /// will be sampled evenly across aligned genome. If a region is specified
+    /// with the --region option, then reads will be sampled evenly across the
+    /// region given.
+    #[arg(
+        group = "sampling_options",
+        short = 'n',
+        long,
+        default_value_t = 10_042,
+        hide_short_help = true
+    )]
+    pub(crate) num_reads: usize,
+    /// Sample this fraction of the reads when estimating the pass-threshold.
+    /// In practice, 10-100 thousand reads is sufficient to estimate the model
+    /// output distribution and determine the filtering threshold. See
+    /// filtering.md for details on filtering.
+    #[arg(group = "sampling_options", long, hide_short_help = true)]
+    pub(crate) sampling_frac: Option<f64>,
+    /// Set a random seed for deterministic running, the default is
+    /// non-deterministic.
+    #[arg(
+        long,
+        conflicts_with = "num_reads",
+        requires = "sampling_frac",
+        hide_short_help = true
+    )]
+    pub(crate) seed: Option<u64>,
+    /// Do not perform any filtering, include all mod base calls in output.
+    /// See filtering.md for details on filtering.
+    #[arg(group = "thresholds", long, default_value_t = false)]
+    pub(crate) no_filtering: bool,
+    /// Filter out modified base calls where the probability of the predicted
+    /// variant is below this confidence percentile. For example, 0.1 will
+    /// filter out the 10% lowest confidence modification calls.
+    #[arg(
+        group = "thresholds",
+        short = 'p',
+        long,
+        default_value_t = 0.1,
+        hide_short_help = true
+    )]
+    pub(crate) filter_percentile: f32,
+    /// Specify the filter threshold globally or per-base. Global filter
+    /// threshold can be specified with by a decimal number (e.g. 0.75).
+    /// Per-base thresholds can be specified by colon-separated values, for
+    /// example C:0.75 specifies a threshold value of 0.75 for cytosine
+    /// modification calls.
+    #[arg(
+        long,
+        group = "thresholds",
+        action = clap::ArgAction::Append,
+        alias = "pass_threshold"
+    )]
+    pub(crate) filter_threshold: Option<Vec<String>>,
+    /// Specify a passing threshold to use for a base modification, independent
+    /// of the threshold for the primary sequence base or the default. For
+    /// example, to set the pass threshold for 5hmC to 0.8 use
+    /// `--mod-threshold h:0.8`.
+    #[arg(
+        long,
+        alias = "mod-threshold",
+        action = clap::ArgAction::Append,
+        hide_short_help = true
+    )]
+    pub(crate) mod_thresholds: Option<Vec<String>>,
+    /// Interval chunk size in base pairs to process concurrently when
+    /// estimating the threshold probability.
+    #[arg(long, default_value_t = 1_000_000, hide_short_help = true)]
+    pub(crate) sampling_interval_size: u32,
+
+    // collapsing args
+    /// Ignore a modified base class  _in_situ_ by redistributing base
+    /// modification probability equally across other options. For example,
+    /// if collapsing 'h', with 'm' and canonical options, half of the
+    /// probability of 'h' will be added to both 'm' and 'C'. A full
+    /// description of the methods can be found in collapse.md.
+    #[arg(long, hide_short_help = true)]
+    pub(crate) ignore: Option<String>,
+    /// Force allow implicit-canonical mode. By default modkit does not allow
+    /// pileup with the implicit mode (e.g. C+m, no '.' or '?'). This option
+    /// allows the interpretation of implicit mode tags: residues without
+    /// modified base probability will be interpreted as being the
+    /// non-modified base.
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    pub(crate) force_allow_implicit: bool,
+    /// Discard base modification calls that are this many bases from the
+    /// start or the end of the read. Two comma-separated values may be
+    /// provided to asymmetrically filter out base modification calls from the
+    /// start and end of the reads. For example, 4,8 will filter out base
+    /// modification calls in the first 4 and last 8 bases of the read.
+    #[arg(long, hide_short_help = true)]
+    pub(crate) edge_filter: Option<String>,
+    /// Invert the edge filter, instead of filtering out base modification
+    /// calls at the ends of reads, only _keep_ base modification calls at the
+    /// ends of reads.
+    #[arg(
+        long,
+        requires = "edge_filter",
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    pub(crate) invert_edge_filter: bool,
+}
+
+impl ModCallerArgs {
+    pub(crate) fn edge_filter(&self) -> anyhow::Result<Option<EdgeFilter>> {
+        self.edge_filter
+            .as_ref()
+            .map(|trims| {
+                parse_edge_filter_input(trims, self.invert_edge_filter)
+            })
+            .transpose()
+    }
+
+    pub(crate) fn collapse_method(
+        &self,
+    ) -> anyhow::Result<Option<CollapseMethod>> {
+        self.ignore
+            .as_ref()
+            .map(|raw_mod_code| {
+                info!("ignoring mod code {}", raw_mod_code);
+                ModCodeRepr::parse(raw_mod_code)
+                    .map(CollapseMethod::ReDistribute)
+            })
+            .transpose()
+    }
+
+    /// Make the pass threshold caller, either from the thresholds given on
+    /// the command line or estimated by sampling the reads of all of the
+    /// `in_bams` together. Sampling runs in the `pool`.
+    pub(crate) fn build_caller(
+        &self,
+        in_bams: &[PathBuf],
+        pool: &rayon::ThreadPool,
+        region: Option<&Region>,
+        edge_filter: Option<&EdgeFilter>,
+        collapse_method: Option<&CollapseMethod>,
+        record_filter: Option<&RecordFilter>,
+        suppress_progress: bool,
+    ) -> anyhow::Result<MultipleThresholdModCaller> {
+        if self.filter_percentile > 1.0 {
+            bail!("filter percentile must be <= 1.0")
+        }
+        let per_mod_thresholds = self
+            .mod_thresholds
+            .as_ref()
+            .map(|raw_per_mod_thresholds| {
+                parse_per_mod_thresholds(raw_per_mod_thresholds)
+            })
+            .transpose()?;
+        if let Some(raw_threshold) = &self.filter_threshold {
+            return parse_thresholds(raw_threshold, per_mod_thresholds);
+        }
+        pool.install(|| {
+            get_threshold_from_options(
+                in_bams,
+                pool.current_num_threads(),
+                self.sampling_interval_size,
+                self.sampling_frac,
+                self.num_reads,
+                self.no_filtering,
+                self.filter_percentile,
+                self.seed,
+                region,
+                per_mod_thresholds,
+                edge_filter,
+                collapse_method,
+                None,
+                RecordSelection::new(true, record_filter),
+                suppress_progress,
+            )
+        })
+    }
+}
+
 fn parse_raw_threshold(raw: &str) -> anyhow::Result<(DnaBase, f32)> {
     let parts = raw.split(':').collect::<Vec<&str>>();
     if parts.len() != 2 {
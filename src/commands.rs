@@ -12,14 +12,20 @@ use rust_htslib::bam::record::{Aux, AuxArray};
 use rust_htslib::bam::Read;
 
 use crate::adjust::{adjust_modbam, record_is_valid};
+use crate::aggregate::subcommand::AggregateRegions;
+use crate::calibrate::subcommand::CalibrateModProbs;
+use crate::calibrate::ProbabilityCalibration;
 use crate::command_utils::{
     get_bam_writer, get_serial_reader, get_threshold_from_options,
     parse_edge_filter_input, parse_per_mod_thresholds, parse_thresholds,
     using_stream,
 };
 use crate::dmr::subcommands::BedMethylDmr;
+use crate::entropy::subcommand::MethylationEntropy;
 use crate::errs::{InputError, RunError};
 use crate::extract::subcommand::ExtractMods;
+use crate::fiber::subcommand::CallFiberSegments;
+use crate::linkage::subcommand::MethylationLinkage;
 use crate::logging::init_logging;
 use crate::mod_bam::{
     format_mm_ml_tag, CollapseMethod, ModBaseInfo, SkipMode, ML_TAGS, MM_TAGS,
@@ -29,9 +35,12 @@ use crate::monoid::Moniod;
 use crate::motif_bed::motif_bed;
 use crate::pileup::subcommand::{DuplexModBamPileup, ModBamPileup};
 use crate::position_filter::StrandedPositionFilter;
+use crate::profile::subcommand::MethylationProfile;
 use crate::read_ids_to_base_mod_probs::ReadIdsToBaseModProbs;
+use crate::read_matrix::subcommand::ReadByPositionMatrix;
 use crate::reads_sampler::get_sampled_read_ids_to_base_mod_probs;
 use crate::reads_sampler::record_sampler::RecordSampler;
+use crate::record_filter::{RecordFilterArgs, RecordSelection};
 use crate::record_processor::RecordProcessor;
 use crate::repair_tags::RepairTags;
 use crate::summarize::{sampled_reads_to_summary, ModSummary};
@@ -89,6 +98,38 @@ pub enum Commands {
     /// genomic motif positions. This command produces a bedMethyl file, the schema can be
     /// found in the online documentation.
     PileupHemi(DuplexModBamPileup),
+    /// Calculate the methylation entropy (epiallele diversity) over windows of
+    /// consecutive motif sites, such as CpGs. Produces a bedGraph-like file with
+    /// the entropy, number of reads, and number of distinct patterns in each window.
+    Entropy(MethylationEntropy),
+    /// Summarize methylation over regions, such as promoters or CpG islands, or
+    /// fixed-size tiles from a modBAM or bedMethyl file. Produces a table with
+    /// the counts and the percent modified per mod code in each region, pooled
+    /// over all calls, averaged over sites, and (with a modBAM) averaged over
+    /// reads.
+    Aggregate(AggregateRegions),
+    /// Build average methylation profiles around features such as TSSs or CTCF
+    /// sites, with binned flanks and optionally scaled feature bodies. Produces a
+    /// matrix of features by bins and an aggregate profile for each mod code.
+    Profile(MethylationProfile),
+    /// Export a read by position matrix of base modification calls or
+    /// probabilities at motif sites in a region, with read metadata (read ID,
+    /// strand, and haplotype). Produces a dense table or a sparse Matrix
+    /// Market file.
+    ReadMatrix(ReadByPositionMatrix),
+    /// Calculate pairwise co-methylation (linkage disequilibrium) between motif
+    /// sites, such as CpGs, from read-level calls. Produces a table of the joint
+    /// modification state counts, r-squared, and D' for pairs of sites within a
+    /// maximum distance, and optionally calls methylation haplotype blocks.
+    Linkage(MethylationLinkage),
+    /// Segment the 6mA calls of Fiber-seq reads into nucleosome footprints and
+    /// methylase-sensitive patches (MSPs). Writes the segments as BAM tags or
+    /// BED12 and optionally an aggregate accessibility track.
+    Fiber(CallFiberSegments),
+    /// Fit calibration curves mapping raw modification probabilities to
+    /// empirical accuracy from fully modified and unmodified control modBAMs.
+    /// Produces a calibration table to use with `adjust-mods --calibration`.
+    Calibrate(CalibrateModProbs),
 }
 
 impl Commands {
@@ -105,6 +146,13 @@ impl Commands {
             Self::Repair(x) => x.run(),
             Self::Dmr(x) => x.run(),
             Self::PileupHemi(x) => x.run(),
+            Self::Entropy(x) => x.run(),
+            Self::Aggregate(x) => x.run(),
+            Self::Profile(x) => x.run(),
+            Self::ReadMatrix(x) => x.run(),
+            Self::Linkage(x) => x.run(),
+            Self::Fiber(x) => x.run(),
+            Self::Calibrate(x) => x.run(),
         }
     }
 }
@@ -175,6 +223,11 @@ pub struct Adjust {
     /// 4 and last 8 bases.
     #[arg(long, requires = "edge_filter", default_value_t = false)]
     invert_edge_filter: bool,
+    /// Rewrite the ML values with a calibration table made with `modkit
+    /// calibrate`. The calibration is applied to the probabilities in the
+    /// input, before any --ignore or --convert.
+    #[arg(long)]
+    calibration: Option<PathBuf>,
     /// Output SAM format instead of BAM.
     #[arg(long, default_value_t = false)]
     output_sam: bool,
@@ -258,9 +311,22 @@ impl Adjust {
             .map(|raw| parse_edge_filter_input(raw, self.invert_edge_filter))
             .transpose()?;
 
-        let methods = if edge_filter.is_none() && methods.is_empty() {
-            bail!("no edge-filter, ignore, or convert was provided, no work to do. Provide \
-            --edge-filter, --ignore, or --convert option to use modkit adjust-mods")
+        let calibration = self
+            .calibration
+            .as_ref()
+            .map(|fp| {
+                info!("calibrating probabilities with {fp:?}");
+                ProbabilityCalibration::from_path(fp)
+            })
+            .transpose()?;
+
+        let methods = if edge_filter.is_none()
+            && methods.is_empty()
+            && calibration.is_none()
+        {
+            bail!("no edge-filter, ignore, convert, or calibration was provided, no work to do. \
+            Provide --edge-filter, --ignore, --convert, or --calibration option to use modkit \
+            adjust-mods")
         } else {
             methods
         };
@@ -270,6 +336,7 @@ impl Adjust {
             &mut out_bam,
             &methods,
             None,
+            calibration.as_ref(),
             edge_filter.as_ref(),
             self.fail_fast,
             "Adjusting modBAM",
@@ -345,6 +412,9 @@ pub struct SampleModBaseProbs {
     /// 4 and last 8 bases.
     #[arg(long, requires = "edge_filter", default_value_t = false)]
     invert_edge_filter: bool,
+    // record filter args
+    #[command(flatten)]
+    record_filter_args: RecordFilterArgs,
 
     // probability histogram options
     /// Output histogram of base modification prediction probabilities.
@@ -418,6 +488,7 @@ impl SampleModBaseProbs {
             .as_ref()
             .map(|raw| parse_edge_filter_input(raw, self.invert_edge_filter))
             .transpose()?;
+        let record_filter = self.record_filter_args.to_record_filter()?;
 
         let (sample_frac, num_reads) = get_sampling_options(
             self.no_sampling,
@@ -474,8 +545,12 @@ impl SampleModBaseProbs {
                         collapse_method.as_ref(),
                         edge_filter.as_ref(),
                         position_filter.as_ref(),
-                        self.only_mapped || position_filter.is_some(),
+                        RecordSelection::new(
+                            self.only_mapped || position_filter.is_some(),
+                            record_filter.as_ref(),
+                        ),
                         None,
+                        &[],
                     )?;
                 debug!("sampled {} records", read_ids_to_base_mod_probs.len());
                 read_ids_to_base_mod_probs
@@ -492,7 +567,10 @@ impl SampleModBaseProbs {
                     collapse_method.as_ref(),
                     edge_filter.as_ref(),
                     position_filter.as_ref(),
-                    self.only_mapped || position_filter.is_some(),
+                    RecordSelection::new(
+                        self.only_mapped || position_filter.is_some(),
+                        record_filter.as_ref(),
+                    ),
                     self.suppress_progress,
                 )?
             };
@@ -657,6 +735,9 @@ pub struct ModSummarize {
     /// 4 and last 8 bases.
     #[arg(long, requires = "edge_filter", default_value_t = false)]
     invert_edge_filter: bool,
+    // record filter args
+    #[command(flatten)]
+    record_filter_args: RecordFilterArgs,
     /// Only summarize base modification probabilities that are aligned
     /// to the positions in this BED file. (alias: include-positions)
     #[arg(long, alias = "include-positions")]
@@ -696,6 +777,7 @@ impl ModSummarize {
             .as_ref()
             .map(|raw| parse_edge_filter_input(raw, self.invert_edge_filter))
             .transpose()?;
+        let record_filter = self.record_filter_args.to_record_filter()?;
 
         let (sample_frac, num_reads) = get_sampling_options(
             self.no_sampling,
@@ -768,8 +850,12 @@ impl ModSummarize {
                         collapse_method.as_ref(),
                         edge_filter.as_ref(),
                         position_filter.as_ref(),
-                        self.only_mapped || position_filter.is_some(),
+                        RecordSelection::new(
+                            self.only_mapped || position_filter.is_some(),
+                            record_filter.as_ref(),
+                        ),
                         None,
+                        &[],
                     )?;
                 debug!("sampled {} records", read_ids_to_base_mod_probs.len());
                 read_ids_to_base_mod_probs
@@ -786,7 +872,10 @@ impl ModSummarize {
                     collapse_method.as_ref(),
                     edge_filter.as_ref(),
                     position_filter.as_ref(),
-                    self.only_mapped || position_filter.is_some(),
+                    RecordSelection::new(
+                        self.only_mapped || position_filter.is_some(),
+                        record_filter.as_ref(),
+                    ),
                     self.suppress_progress,
                 )?
             };
@@ -1180,7 +1269,9 @@ impl CallMods {
                 .with_context(|| "failed to make threadpool")?;
             pool.install(|| {
                 get_threshold_from_options(
-                    &Path::new(&self.in_bam).to_path_buf(),
+                    std::slice::from_ref(
+                        &Path::new(&self.in_bam).to_path_buf(),
+                    ),
                     self.threads,
                     self.sampling_interval_size,
                     self.sampling_frac,
@@ -1193,7 +1284,7 @@ impl CallMods {
                     edge_filter.as_ref(),
                     None,
                     None,
-                    false,
+                    RecordSelection::new(false, None),
                     self.suppress_progress,
                 )
             })?
@@ -1204,6 +1295,7 @@ impl CallMods {
             &mut out_bam,
             &[],
             Some(&caller),
+            None,
             edge_filter.as_ref(),
             self.fail_fast,
             "Calling Mods",
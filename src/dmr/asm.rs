@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::anyhow;
+use derive_new::new;
+use itertools::Itertools;
+
+use crate::dmr::fdr::QValueWriter;
+use crate::dmr::model::{ModificationCounts, PileupCounts};
+use crate::dmr::util::DmrInterval;
+use crate::mod_bam::EdgeFilter;
+use crate::motif_bed::MultipleMotifLocations;
+use crate::pileup::{
+    haplotype_partition_tags, parse_haplotype_key, process_region, Haplotype,
+    ModBasePileup, PartitionKey, PileupNumericOptions, PileupReadOptions,
+};
+use crate::position_filter::Iv;
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+
+/// Phase block name used for phased reads that don't have a PS tag.
+const NO_PHASE_BLOCK: &str = ".";
+
+/// Haplotype 1 and haplotype 2 counts for each phase block.
+type PhaseBlockCounts = BTreeMap<String, [PileupCounts; 2]>;
+
+/// Tally the haplotype 1 and haplotype 2 counts in each phase block at each
+/// position, positions where a haplotype has less than `min_valid_coverage`
+/// are not counted for that haplotype. Unphased reads are ignored.
+fn phase_block_counts_by_position(
+    pileup: &ModBasePileup,
+    min_valid_coverage: u64,
+) -> BTreeMap<u32, PhaseBlockCounts> {
+    pileup
+        .iter_counts_sorted()
+        .filter_map(|(pos, partitioned_counts)| {
+            let mut phase_block_counts = PhaseBlockCounts::new();
+            for (partition_key, feature_counts) in partitioned_counts {
+                let (haplotype, phase_block) = match partition_key {
+                    PartitionKey::NoKey => continue,
+                    PartitionKey::Key(idx) => {
+                        match pileup.partition_keys.get_index(*idx) {
+                            Some(key) => parse_haplotype_key(key),
+                            None => continue,
+                        }
+                    }
+                };
+                let idx = match haplotype {
+                    Haplotype::Hp1 | Haplotype::Hp2 => haplotype as usize,
+                    Haplotype::Unphased => continue,
+                };
+                let mut counts = PileupCounts::default();
+                counts.add_position(feature_counts);
+                let phase_block =
+                    phase_block.unwrap_or(NO_PHASE_BLOCK).to_string();
+                phase_block_counts.entry(phase_block).or_default()[idx]
+                    .combine(&counts);
+            }
+            // apply the coverage filter after combining, the reads for a
+            // haplotype can be split over multiple partition keys
+            for haplotype_counts in phase_block_counts.values_mut() {
+                for counts in haplotype_counts.iter_mut() {
+                    if (counts.total() as u64) < min_valid_coverage {
+                        *counts = PileupCounts::default();
+                    }
+                }
+            }
+            if phase_block_counts.is_empty() {
+                None
+            } else {
+                Some((*pos, phase_block_counts))
+            }
+        })
+        .collect()
+}
+
+/// Allele-specific methylation score between haplotype 1 (the "control"
+/// in the output) and haplotype 2 in a single phase block.
+pub(super) struct AsmResult {
+    counts: ModificationCounts,
+    phase_block: String,
+}
+
+impl AsmResult {
+    pub(super) fn write<W: Write>(
+        &self,
+        writer: &mut QValueWriter<W>,
+    ) -> anyhow::Result<()> {
+        writer.write_counts(&self.counts, &[&self.phase_block])
+    }
+}
+
+fn score_phase_blocks(
+    phase_block_counts: PhaseBlockCounts,
+    interval: &DmrInterval,
+) -> anyhow::Result<Vec<AsmResult>> {
+    phase_block_counts
+        .into_iter()
+        .filter(|(_, [hp1, hp2])| hp1.total() > 0 && hp2.total() > 0)
+        .map(|(phase_block, [hp1, hp2])| {
+            let counts = ModificationCounts::new(
+                interval.start(),
+                interval.stop(),
+                hp1.into_aggregated_counts()?,
+                hp2.into_aggregated_counts()?,
+                interval.clone(),
+            )?;
+            Ok(AsmResult {
+                counts,
+                phase_block,
+            })
+        })
+        .collect()
+}
+
+/// Settings used to pileup and score each region.
+#[derive(new)]
+pub(super) struct AsmParams {
+    caller: MultipleThresholdModCaller,
+    pileup_options: PileupNumericOptions,
+    force_allow: bool,
+    max_depth: u32,
+    motif_locations: MultipleMotifLocations,
+    edge_filter: Option<EdgeFilter>,
+    min_valid_coverage: u64,
+}
+
+/// Pileup the reads in the region by haplotype and score the difference
+/// between haplotypes within each phase block. When `dmr_interval` is
+/// given all of the sites in the region are aggregated, otherwise each
+/// site is scored individually.
+pub(super) fn process_asm_region<T: AsRef<Path>>(
+    bam_fp: T,
+    chrom_tid: u32,
+    start: u32,
+    end: u32,
+    dmr_interval: Option<&DmrInterval>,
+    params: &AsmParams,
+) -> anyhow::Result<Vec<AsmResult>> {
+    let partition_tags = haplotype_partition_tags();
+    let pileup = process_region(
+        &[bam_fp],
+        chrom_tid,
+        start,
+        end,
+        &params.caller,
+        &params.pileup_options,
+        params.force_allow,
+        false,
+        params.max_depth,
+        Some(&params.motif_locations),
+        params.edge_filter.as_ref(),
+        None,
+        &PileupReadOptions::new(Some(&partition_tags), false, None, false),
+    )
+    .map_err(|e| anyhow!("failed to pileup region, {e}"))?;
+    let by_position =
+        phase_block_counts_by_position(&pileup, params.min_valid_coverage);
+
+    if let Some(dmr_interval) = dmr_interval {
+        let mut region_counts = PhaseBlockCounts::new();
+        for (phase_block, [hp1, hp2]) in by_position.into_values().flatten() {
+            let counts = region_counts.entry(phase_block).or_default();
+            counts[0].combine(&hp1);
+            counts[1].combine(&hp2);
+        }
+        score_phase_blocks(region_counts, dmr_interval)
+    } else {
+        by_position
+            .into_iter()
+            .map(|(pos, phase_block_counts)| {
+                let interval = Iv {
+                    start: pos as u64,
+                    stop: pos as u64 + 1,
+                    val: (),
+                };
+                let name =
+                    format!("{}:{}-{}", &pileup.chrom_name, pos, pos + 1);
+                let site =
+                    DmrInterval::new(interval, pileup.chrom_name.clone(), name);
+                score_phase_blocks(phase_block_counts, &site)
+            })
+            .flatten_ok()
+            .collect()
+    }
+}
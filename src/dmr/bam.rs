@@ -0,0 +1,140 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail};
+use derive_new::new;
+use rust_htslib::bam::{self, Read};
+
+use crate::dmr::model::{ModificationCounts, PileupCounts};
+use crate::dmr::util::DmrInterval;
+use crate::mod_bam::EdgeFilter;
+use crate::motif_bed::MultipleMotifLocations;
+use crate::pileup::{process_region, PileupNumericOptions, PileupReadOptions};
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+
+/// Check that all of the BAMs have the same reference sequences (in the same
+/// order) so that the tids from the first header can be used for all of them.
+pub(super) fn check_bam_headers(bam_fps: &[PathBuf]) -> anyhow::Result<()> {
+    let mut headers = bam_fps.iter().map(|fp| {
+        bam::IndexedReader::from_path(fp)
+            .map(|reader| (fp, reader.header().to_owned()))
+            .map_err(|e| {
+                anyhow!(
+                    "failed to open {fp:?}, must be sorted and indexed, {e}"
+                )
+            })
+    });
+    let (first_fp, first) = match headers.next() {
+        Some(header) => header?,
+        None => bail!("no input BAMs"),
+    };
+    for header in headers {
+        let (fp, header) = header?;
+        let same = header.target_names() == first.target_names()
+            && (0..first.target_count())
+                .all(|tid| header.target_len(tid) == first.target_len(tid));
+        if !same {
+            bail!(
+                "reference sequences in {fp:?} are different from {first_fp:?}"
+            )
+        }
+    }
+    Ok(())
+}
+
+/// Settings used to pileup the reads in each region.
+#[derive(new)]
+pub(crate) struct BamPileupParams {
+    pub(crate) caller: MultipleThresholdModCaller,
+    pub(crate) pileup_options: PileupNumericOptions,
+    pub(crate) force_allow: bool,
+    pub(crate) max_depth: u32,
+    pub(crate) motif_locations: Option<MultipleMotifLocations>,
+    pub(crate) edge_filter: Option<EdgeFilter>,
+    pub(crate) min_valid_coverage: u64,
+}
+
+/// Pileup the reads from all of the BAMs in the region and collect the
+/// (position, counts) at each position, positions with less than
+/// `min_valid_coverage` are discarded. When `motif_locations` is None all
+/// positions with modification calls are used.
+pub(crate) fn position_counts(
+    bam_fps: &[PathBuf],
+    chrom_tid: u32,
+    region: &DmrInterval,
+    params: &BamPileupParams,
+) -> anyhow::Result<Vec<(u32, PileupCounts)>> {
+    let pileup = process_region(
+        bam_fps,
+        chrom_tid,
+        region.start() as u32,
+        region.stop() as u32,
+        &params.caller,
+        &params.pileup_options,
+        params.force_allow,
+        false,
+        params.max_depth,
+        params.motif_locations.as_ref(),
+        params.edge_filter.as_ref(),
+        None,
+        &PileupReadOptions::default(),
+    )
+    .map_err(|e| anyhow!("failed to pileup region, {e}"))?;
+    let counts = pileup
+        .iter_counts_sorted()
+        .map(|(pos, partitioned_counts)| {
+            let mut position_counts = PileupCounts::default();
+            for feature_counts in partitioned_counts.values() {
+                position_counts.add_position(feature_counts);
+            }
+            (*pos, position_counts)
+        })
+        .filter(|(_, counts)| {
+            counts.total() as u64 >= params.min_valid_coverage
+        })
+        .collect();
+    Ok(counts)
+}
+
+/// Pileup the reads from all of the BAMs for one sample in the region and
+/// sum the counts over all of the motif positions. Positions with less than
+/// `min_valid_coverage` are not counted.
+fn sample_counts(
+    bam_fps: &[PathBuf],
+    chrom_tid: u32,
+    region: &DmrInterval,
+    params: &BamPileupParams,
+) -> anyhow::Result<PileupCounts> {
+    let counts = position_counts(bam_fps, chrom_tid, region, params)?
+        .into_iter()
+        .fold(PileupCounts::default(), |mut acc, (_, position_counts)| {
+            acc.combine(&position_counts);
+            acc
+        });
+    Ok(counts)
+}
+
+/// Compare the reads from the control and experiment BAMs over the region.
+/// The same `caller` is used for both samples, so that modification calls
+/// are filtered consistently.
+pub(super) fn process_bam_region(
+    control_bams: &[PathBuf],
+    exp_bams: &[PathBuf],
+    chrom_tid: u32,
+    region: &DmrInterval,
+    params: &BamPileupParams,
+) -> anyhow::Result<ModificationCounts> {
+    let counts =
+        |bam_fps: &[PathBuf]| sample_counts(bam_fps, chrom_tid, region, params);
+    let control_counts = counts(control_bams)?;
+    let exp_counts = counts(exp_bams)?;
+    if control_counts.total() == 0 || exp_counts.total() == 0 {
+        bail!("no valid coverage in one sample for {region}")
+    }
+    ModificationCounts::new(
+        region.start(),
+        region.stop(),
+        control_counts.into_aggregated_counts()?,
+        exp_counts.into_aggregated_counts()?,
+        region.clone(),
+    )
+}
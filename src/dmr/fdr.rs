@@ -0,0 +1,198 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Lines, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{anyhow, Context};
+use itertools::Itertools;
+
+use crate::dmr::model::{DmrRow, ModificationCounts};
+
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Directory to hold the temporary rows for an output file, next to the
+/// output or the system temporary directory when writing to stdout.
+pub(super) fn temp_dir_for_output<P: AsRef<Path>>(
+    out_path: Option<P>,
+) -> PathBuf {
+    out_path
+        .and_then(|p| p.as_ref().parent().map(|d| d.to_path_buf()))
+        .map(|d| {
+            if d.as_os_str().is_empty() {
+                PathBuf::from(".")
+            } else {
+                d
+            }
+        })
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+/// Benjamini-Hochberg adjusted p-values (q-values), in the same order as
+/// the input p-values.
+pub(super) fn benjamini_hochberg(p_values: &[f64]) -> Vec<f64> {
+    let n = p_values.len();
+    let mut q_values = vec![1f64; n];
+    let mut running_min = 1f64;
+    for (rank, idx) in (0..n)
+        .sorted_by(|a, b| p_values[*a].total_cmp(&p_values[*b]))
+        .enumerate()
+        .rev()
+    {
+        let q = p_values[idx] * n as f64 / (rank + 1) as f64;
+        running_min = running_min.min(q);
+        q_values[idx] = running_min;
+    }
+    q_values
+}
+
+/// Writes DMR rows with a q-value column. Rows are held in a temporary file
+/// until all of the p-values are known, then `finish` writes them to the
+/// output with their q-values. The temporary file is removed when the writer
+/// is dropped.
+pub(super) struct QValueWriter<W: Write> {
+    out: W,
+    tmp_fp: PathBuf,
+    tmp_writer: BufWriter<File>,
+    p_values: Vec<f64>,
+}
+
+impl<W: Write> QValueWriter<W> {
+    pub(super) fn new(out: W, tmp_dir: &Path) -> anyhow::Result<Self> {
+        let tmp_fp = tmp_dir.join(format!(
+            ".modkit_dmr_{}_{}.rows.tmp",
+            std::process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let tmp_writer =
+            File::create(&tmp_fp).map(BufWriter::new).with_context(|| {
+                format!("failed to make temporary file at {tmp_fp:?}")
+            })?;
+        Ok(Self {
+            out,
+            tmp_fp,
+            tmp_writer,
+            p_values: Vec::new(),
+        })
+    }
+
+    pub(super) fn write_counts(
+        &mut self,
+        counts: &ModificationCounts,
+        extra_columns: &[&str],
+    ) -> anyhow::Result<()> {
+        let row = counts.to_row(extra_columns);
+        writeln!(self.tmp_writer, "{}", row.before_q_value)?;
+        writeln!(self.tmp_writer, "{}", row.after_q_value)?;
+        self.p_values.push(counts.p_value);
+        Ok(())
+    }
+
+    /// Compute the q-values and write all of the rows to the output,
+    /// returns the number of rows written.
+    pub(super) fn finish(mut self) -> anyhow::Result<usize> {
+        self.tmp_writer.flush()?;
+        let q_values = benjamini_hochberg(&self.p_values);
+        let mut lines = BufReader::new(File::open(&self.tmp_fp)?).lines();
+        let next_line = |lines: &mut Lines<BufReader<File>>| {
+            lines
+                .next()
+                .ok_or_else(|| anyhow!("temporary rows file is truncated"))?
+                .context("failed to read temporary rows file")
+        };
+        for q_value in q_values.iter() {
+            let row = DmrRow {
+                before_q_value: next_line(&mut lines)?,
+                after_q_value: next_line(&mut lines)?,
+            };
+            self.out.write_all(row.with_q_value(*q_value).as_bytes())?;
+        }
+        self.out.flush()?;
+        Ok(q_values.len())
+    }
+}
+
+impl<W: Write> Drop for QValueWriter<W> {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.tmp_fp) {
+            log::debug!(
+                "failed to remove temporary file {:?}, {e}",
+                self.tmp_fp
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod fdr_tests {
+    use std::collections::HashMap;
+
+    use crate::dmr::fdr::{benjamini_hochberg, QValueWriter};
+    use crate::dmr::model::{AggregatedCounts, ModificationCounts};
+    use crate::dmr::util::DmrInterval;
+    use crate::position_filter::Iv;
+
+    #[test]
+    fn test_benjamini_hochberg() {
+        let p_values = [0.01, 0.04, 0.03, 0.005, 0.5];
+        let expected = [0.025, 0.05, 0.05, 0.025, 0.5];
+        let q_values = benjamini_hochberg(&p_values);
+        for (q, e) in q_values.iter().zip(expected) {
+            assert!((q - e).abs() < 1e-12, "{q_values:?}");
+        }
+        assert!(benjamini_hochberg(&[]).is_empty());
+        assert_eq!(benjamini_hochberg(&[0.9, 0.95]), vec![0.95, 0.95]);
+    }
+
+    fn counts(n_mod_a: usize, n_mod_b: usize) -> ModificationCounts {
+        let interval = DmrInterval::new(
+            Iv {
+                start: 0,
+                stop: 10,
+                val: (),
+            },
+            "chr1".to_string(),
+            "chr1:0-10".to_string(),
+        );
+        let agg = |n_mod: usize| {
+            AggregatedCounts::try_new(HashMap::from([('m'.into(), n_mod)]), 20)
+                .unwrap()
+        };
+        ModificationCounts::new(0, 10, agg(n_mod_a), agg(n_mod_b), interval)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_q_value_writer() {
+        let tmp_dir = std::env::temp_dir().join("test_q_value_writer");
+        std::fs::create_dir_all(&tmp_dir).unwrap();
+        let rows = [counts(2, 18), counts(10, 10)];
+
+        let mut out = Vec::new();
+        let mut writer = QValueWriter::new(&mut out, &tmp_dir).unwrap();
+        for row in rows.iter() {
+            writer.write_counts(row, &["extra"]).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), 2);
+        let q_values = benjamini_hochberg(
+            &rows.iter().map(|r| r.p_value).collect::<Vec<f64>>(),
+        );
+        let lines = String::from_utf8(out).unwrap();
+        for ((line, row), q_value) in
+            lines.lines().zip(rows.iter()).zip(q_values)
+        {
+            let parts = line.split('\t').collect::<Vec<&str>>();
+            assert_eq!(parts.len(), 15, "{line}");
+            assert_eq!(parts[11].parse::<f64>().unwrap(), row.p_value);
+            assert_eq!(parts[12].parse::<f64>().unwrap(), q_value);
+            assert_eq!(parts[14], "extra");
+        }
+        assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 0);
+
+        // the temporary file is removed when the writer isn't finished
+        let mut writer = QValueWriter::new(Vec::new(), &tmp_dir).unwrap();
+        writer.write_counts(&rows[0], &[]).unwrap();
+        assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 1);
+        drop(writer);
+        assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,143 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail};
+use noodles::csi::Index as CsiIndex;
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+
+use crate::dmr::bedmethyl::BedMethylLine;
+use crate::dmr::model::{
+    beta_binomial_test, AggregatedCounts, BetaBinomialTest, ModificationCounts,
+};
+use crate::dmr::pairwise::{aggregate_counts, read_bedmethyl};
+use crate::dmr::util::{merge_index_chunks, ContigLookup, DmrInterval};
+use crate::mod_base_code::DnaBase;
+use crate::position_filter::StrandedPositionFilter;
+
+/// A single replicate bedMethyl and its index.
+pub(crate) struct Replicate {
+    pub(crate) bedmethyl_fp: PathBuf,
+    pub(crate) index: CsiIndex,
+    pub(crate) contig_lookup: ContigLookup,
+}
+
+/// Comparison of the two groups of replicates over a single region, the
+/// score and p-value of `counts` are from the pooled replicates, the same as
+/// `dmr pair`, and `test` allows for over-dispersion between replicates.
+pub(super) struct GroupResult {
+    pub(super) counts: ModificationCounts,
+    pub(super) test: BetaBinomialTest,
+}
+
+/// Read the bedMethyl lines overlapping any of the regions in the batch
+/// from a single replicate, keyed by chrom.
+pub(crate) fn read_replicate_lines(
+    replicate: &Replicate,
+    regions: &[DmrInterval],
+    min_valid_coverage: u64,
+) -> anyhow::Result<FxHashMap<String, Vec<BedMethylLine>>> {
+    let chunks = regions
+        .iter()
+        .filter_map(|region| {
+            replicate.contig_lookup.inner.get(&region.chrom).and_then(
+                |chrom_id| {
+                    region.get_index_chunks(&replicate.index, *chrom_id).ok()
+                },
+            )
+        })
+        .flatten()
+        .collect::<Vec<_>>();
+    let chunks = merge_index_chunks(chunks);
+    let lines = read_bedmethyl(&replicate.bedmethyl_fp, &chunks)?
+        .into_iter()
+        .filter(|bm| bm.valid_coverage >= min_valid_coverage)
+        .fold(FxHashMap::default(), |mut acc, bm_line| {
+            acc.entry(bm_line.chrom.clone())
+                .or_insert(Vec::new())
+                .push(bm_line);
+            acc
+        });
+    Ok(lines)
+}
+
+fn replicate_counts(
+    lines: &FxHashMap<String, Vec<BedMethylLine>>,
+    region: &DmrInterval,
+    chrom_id: u32,
+    position_filter: &StrandedPositionFilter<DnaBase>,
+) -> anyhow::Result<AggregatedCounts> {
+    let overlapping = lines
+        .get(&region.chrom)
+        .map(|lines| {
+            lines
+                .iter()
+                .filter(|l| region.interval.overlap(l.start(), l.stop()))
+                .collect::<Vec<&BedMethylLine>>()
+        })
+        .unwrap_or_default();
+    aggregate_counts(&overlapping, chrom_id, position_filter)
+}
+
+/// Compare the control and experiment replicates over each region in the
+/// batch. `chrom_lookup` gives the chrom ids used by the `position_filter`.
+pub(super) fn process_group_batch(
+    regions: &[DmrInterval],
+    control_replicates: &[Replicate],
+    exp_replicates: &[Replicate],
+    chrom_lookup: &ContigLookup,
+    position_filter: &StrandedPositionFilter<DnaBase>,
+    min_valid_coverage: u64,
+) -> anyhow::Result<Vec<anyhow::Result<GroupResult>>> {
+    let read_group = |replicates: &[Replicate]| {
+        replicates
+            .par_iter()
+            .map(|replicate| {
+                read_replicate_lines(replicate, regions, min_valid_coverage)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+    let control_lines = read_group(control_replicates)?;
+    let exp_lines = read_group(exp_replicates)?;
+
+    let results = regions
+        .par_iter()
+        .map(|region| {
+            let chrom_id =
+                *chrom_lookup.inner.get(&region.chrom).ok_or_else(|| {
+                    anyhow!("didn't find chrom id for {}", &region.chrom)
+                })? as u32;
+            let group_counts =
+                |lines: &[FxHashMap<String, Vec<BedMethylLine>>]| {
+                    lines
+                        .iter()
+                        .map(|lines| {
+                            replicate_counts(
+                                lines,
+                                region,
+                                chrom_id,
+                                position_filter,
+                            )
+                        })
+                        .collect::<anyhow::Result<Vec<AggregatedCounts>>>()
+                };
+            let control_counts = group_counts(&control_lines)?;
+            let exp_counts = group_counts(&exp_lines)?;
+            if control_counts.iter().all(|c| c.total() == 0)
+                || exp_counts.iter().all(|c| c.total() == 0)
+            {
+                bail!("no valid coverage in one group for {region}")
+            }
+            let test = beta_binomial_test(&control_counts, &exp_counts)?;
+            let counts = ModificationCounts::new(
+                region.start(),
+                region.stop(),
+                AggregatedCounts::pooled(&control_counts),
+                AggregatedCounts::pooled(&exp_counts),
+                region.clone(),
+            )?;
+            Ok(GroupResult { counts, test })
+        })
+        .collect::<Vec<anyhow::Result<GroupResult>>>();
+
+    Ok(results)
+}
@@ -1,6 +1,11 @@
+mod asm;
+pub(crate) mod bam;
 pub mod bedmethyl;
-mod model;
+mod fdr;
+pub(crate) mod group;
+pub(crate) mod model;
 mod multi_sample;
-mod pairwise;
+pub(crate) mod pairwise;
+mod segment;
 pub mod subcommands;
-mod util;
+pub(crate) mod util;
@@ -3,19 +3,22 @@ use std::fmt::{Display, Formatter};
 
 use anyhow::{anyhow, bail};
 use itertools::Itertools;
+use rv::misc::ln_gammafn;
 use rv::prelude::*;
 
 use crate::dmr::util::DmrInterval;
 use crate::mod_base_code::ModCodeRepr;
+use crate::pileup::PileupFeatureCounts;
+use crate::position_filter::Iv;
 
-#[derive(Debug, Default)]
-pub(super) struct AggregatedCounts {
+#[derive(Debug, Default, Clone)]
+pub(crate) struct AggregatedCounts {
     mod_code_counts: HashMap<ModCodeRepr, usize>,
     total: usize,
 }
 
 impl AggregatedCounts {
-    pub(super) fn try_new(
+    pub(crate) fn try_new(
         mod_code_counts: HashMap<ModCodeRepr, usize>,
         total: usize,
     ) -> anyhow::Result<Self> {
@@ -38,6 +41,26 @@ impl AggregatedCounts {
         self.total - self.mod_code_counts.values().sum::<usize>()
     }
 
+    /// Pool the counts of several samples (e.g. replicates).
+    pub(super) fn pooled(samples: &[Self]) -> Self {
+        samples
+            .iter()
+            .fold(Self::default(), |acc, sample| acc.combine(sample))
+    }
+
+    pub(crate) fn total(&self) -> usize {
+        self.total
+    }
+
+    pub(crate) fn mod_code_count(&self, mod_code: &ModCodeRepr) -> usize {
+        *self.mod_code_counts.get(mod_code).unwrap_or(&0)
+    }
+
+    /// The mod codes with counts, including those with zero modified calls.
+    pub(crate) fn mod_codes(&self) -> impl Iterator<Item = &ModCodeRepr> {
+        self.mod_code_counts.keys()
+    }
+
     fn combine(&self, other: &Self) -> Self {
         let total = self.total + other.total;
         let mut counts = self.mod_code_counts.clone();
@@ -104,6 +127,56 @@ impl AggregatedCounts {
     }
 }
 
+/// Counts of modification calls accumulated from the pileup at one or more
+/// positions, see `AggregatedCounts`.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PileupCounts {
+    mod_code_counts: HashMap<ModCodeRepr, usize>,
+    total: usize,
+}
+
+impl PileupCounts {
+    /// Add the counts from the pileup at a single position, there is one
+    /// `PileupFeatureCounts` per mod code and strand.
+    pub(super) fn add_position(
+        &mut self,
+        feature_counts: &[PileupFeatureCounts],
+    ) {
+        for (_strand, counts) in &feature_counts
+            .iter()
+            .sorted_by_key(|fc| fc.raw_strand)
+            .group_by(|fc| fc.raw_strand)
+        {
+            let mut counts = counts.peekable();
+            // valid coverage is the same for all mod codes on a strand
+            if let Some(first) = counts.peek() {
+                self.total += first.filtered_coverage as usize;
+            }
+            for fc in counts {
+                *self.mod_code_counts.entry(fc.raw_mod_code).or_insert(0) +=
+                    fc.n_modified as usize;
+            }
+        }
+    }
+
+    pub(super) fn total(&self) -> usize {
+        self.total
+    }
+
+    pub(super) fn combine(&mut self, other: &Self) {
+        self.total += other.total;
+        for (mod_code, count) in other.mod_code_counts.iter() {
+            *self.mod_code_counts.entry(*mod_code).or_insert(0) += *count;
+        }
+    }
+
+    pub(crate) fn into_aggregated_counts(
+        self,
+    ) -> anyhow::Result<AggregatedCounts> {
+        AggregatedCounts::try_new(self.mod_code_counts, self.total)
+    }
+}
+
 impl Display for AggregatedCounts {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.string_counts())
@@ -118,6 +191,7 @@ pub(super) struct ModificationCounts {
     exp_counts: AggregatedCounts,
     interval: DmrInterval,
     pub(crate) score: f64,
+    pub(super) p_value: f64,
 }
 
 impl ModificationCounts {
@@ -129,6 +203,10 @@ impl ModificationCounts {
         interval: DmrInterval,
     ) -> anyhow::Result<Self> {
         let score = llk_ratio(&control_counts, &exp_counts)?;
+        let p_value = chi_square_p_value(
+            2f64 * score,
+            n_categories(&control_counts, &exp_counts) - 1,
+        );
         Ok(Self {
             start,
             stop,
@@ -136,12 +214,101 @@ impl ModificationCounts {
             exp_counts,
             interval,
             score,
+            p_value,
         })
     }
 
-    pub(super) fn to_row(&self) -> anyhow::Result<String> {
+    pub(super) fn interval(&self) -> &DmrInterval {
+        &self.interval
+    }
+
+    /// Schwarz (BIC) approximation of the log Bayes factor of the samples
+    /// having different modification frequencies versus the same, positive
+    /// values favor different.
+    pub(super) fn bic_log_bayes_factor(&self) -> f64 {
+        let (statistic, dof) =
+            g_test_statistic(&self.control_counts, &self.exp_counts);
+        if dof == 0 {
+            return 0f64;
+        }
+        let n = (self.control_counts.total + self.exp_counts.total) as f64;
+        statistic / 2f64 - dof as f64 / 2f64 * n.ln()
+    }
+
+    /// Combine the counts of consecutive sites (or regions) into a single
+    /// region spanning all of them, the score and p-value are recalculated
+    /// from the combined counts.
+    pub(super) fn merge(sites: &[Self]) -> anyhow::Result<Self> {
+        let (first, last) = match (sites.first(), sites.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => bail!("cannot merge zero sites"),
+        };
+        let (control_counts, exp_counts) = sites.iter().skip(1).fold(
+            (first.control_counts.clone(), first.exp_counts.clone()),
+            |(control, exp), site| {
+                (
+                    control.combine(&site.control_counts),
+                    exp.combine(&site.exp_counts),
+                )
+            },
+        );
+        let chrom = first.interval.chrom.clone();
+        let interval = Iv {
+            start: first.start,
+            stop: last.stop,
+            val: (),
+        };
+        let name = format!("{}:{}-{}", &chrom, first.start, last.stop);
+        Self::new(
+            first.start,
+            last.stop,
+            control_counts,
+            exp_counts,
+            DmrInterval::new(interval, chrom, name),
+        )
+    }
+
+    /// Difference in percent modified (experiment minus control) for each
+    /// modification code, comma-separated.
+    fn string_percent_differences(&self) -> String {
+        let percent = |counts: &AggregatedCounts, code: &ModCodeRepr| {
+            if counts.total == 0 {
+                0f32
+            } else {
+                let count = counts.mod_code_counts.get(code).unwrap_or(&0);
+                *count as f32 / counts.total as f32 * 100f32
+            }
+        };
+        let diffs = self
+            .control_counts
+            .mod_code_counts
+            .keys()
+            .chain(self.exp_counts.mod_code_counts.keys())
+            .unique()
+            .sorted()
+            .map(|code| {
+                let diff = percent(&self.exp_counts, code)
+                    - percent(&self.control_counts, code);
+                format!("{}:{:.2}", code, diff)
+            })
+            .join(",");
+        if diffs.is_empty() {
+            ".".to_string()
+        } else {
+            diffs
+        }
+    }
+
+    /// Format the BED row, `extra_columns` are appended to the end. The
+    /// q-value column is filled in once all of the p-values are known, see
+    /// [`DmrRow::with_q_value`].
+    pub(super) fn to_row(&self, extra_columns: &[&str]) -> DmrRow {
         let sep = '\t';
-        let line = format!(
+        let extra = extra_columns
+            .iter()
+            .map(|col| format!("{sep}{col}"))
+            .collect::<String>();
+        let before_q_value = format!(
             "\
         {}{sep}\
         {}{sep}\
@@ -153,7 +320,8 @@ impl ModificationCounts {
         {}{sep}\
         {}{sep}\
         {}{sep}\
-        {}\n\
+        {}{sep}\
+        {}\
         ",
             self.interval.chrom,
             self.start,
@@ -166,8 +334,32 @@ impl ModificationCounts {
             self.exp_counts.total,
             self.control_counts.string_percentages(),
             self.exp_counts.string_percentages(),
+            format_p_value(self.p_value),
         );
-        Ok(line)
+        let after_q_value =
+            format!("{}{extra}", self.string_percent_differences());
+        DmrRow {
+            before_q_value,
+            after_q_value,
+        }
+    }
+}
+
+/// A DMR BED row without its q-value, the columns before and after the
+/// q-value column.
+pub(super) struct DmrRow {
+    pub(super) before_q_value: String,
+    pub(super) after_q_value: String,
+}
+
+impl DmrRow {
+    pub(super) fn with_q_value(&self, q_value: f64) -> String {
+        format!(
+            "{}\t{}\t{}\n",
+            self.before_q_value,
+            format_p_value(q_value),
+            self.after_q_value
+        )
     }
 }
 
@@ -260,14 +452,325 @@ fn llk_beta(
     Ok(llk_control + llk_exp - llk_same)
 }
 
-pub(super) fn llk_ratio(
+/// Likelihood-ratio (G-test) statistic for the 2 x k contingency table of
+/// canonical and per-modification counts in the two samples, along with the
+/// degrees of freedom. Categories without any counts in either sample are
+/// not counted towards the degrees of freedom.
+fn g_test_statistic(
     control_counts: &AggregatedCounts,
     exp_counts: &AggregatedCounts,
-) -> anyhow::Result<f64> {
-    let n_categories = std::cmp::max(
+) -> (f64, usize) {
+    let rows = [control_counts, exp_counts].map(|counts| {
+        let mut row = counts
+            .mod_code_counts
+            .iter()
+            .map(|(code, count)| (Some(*code), *count))
+            .collect::<HashMap<Option<ModCodeRepr>, usize>>();
+        row.insert(None, counts.get_canonical_counts());
+        row
+    });
+    let categories = rows
+        .iter()
+        .flat_map(|row| row.keys().copied())
+        .collect::<HashSet<Option<ModCodeRepr>>>();
+    let column_totals = categories
+        .iter()
+        .map(|category| {
+            let total = rows
+                .iter()
+                .map(|row| *row.get(category).unwrap_or(&0))
+                .sum::<usize>();
+            (*category, total)
+        })
+        .filter(|(_, total)| *total > 0)
+        .collect::<HashMap<Option<ModCodeRepr>, usize>>();
+    let row_totals = [control_counts.total, exp_counts.total];
+    let grand_total = row_totals.iter().sum::<usize>() as f64;
+    if column_totals.len() < 2 || row_totals.contains(&0) {
+        return (0f64, 0);
+    }
+
+    let g = rows
+        .iter()
+        .zip(row_totals)
+        .map(|(row, row_total)| {
+            column_totals
+                .iter()
+                .filter_map(|(category, column_total)| {
+                    let observed = *row.get(category).unwrap_or(&0) as f64;
+                    if observed > 0f64 {
+                        let expected = row_total as f64 * *column_total as f64
+                            / grand_total;
+                        Some(observed * (observed / expected).ln())
+                    } else {
+                        None
+                    }
+                })
+                .sum::<f64>()
+        })
+        .sum::<f64>();
+    // (rows - 1) * (columns - 1) with 2 rows
+    (2f64 * g, column_totals.len() - 1)
+}
+
+/// Natural log of the regularized upper incomplete gamma function, Q(a, x),
+/// calculated without leaving log space so that it doesn't underflow to
+/// -inf far out in the tail. Uses the series expansion of P(a, x) when
+/// x < a + 1 and the continued fraction for Q(a, x) otherwise (Numerical
+/// Recipes, 6.2).
+fn ln_upper_regularized_gamma(a: f64, x: f64) -> f64 {
+    const MAX_ITER: usize = 1000;
+    const EPS: f64 = 1e-15;
+    const FPMIN: f64 = 1e-300;
+    if x <= 0f64 {
+        return 0f64;
+    }
+    let ln_prefactor = -x + a * x.ln() - ln_gammafn(a);
+    if x < a + 1f64 {
+        let mut ap = a;
+        let mut del = 1f64 / a;
+        let mut sum = del;
+        for _ in 0..MAX_ITER {
+            ap += 1f64;
+            del *= x / ap;
+            sum += del;
+            if del.abs() < sum.abs() * EPS {
+                break;
+            }
+        }
+        let lower = (ln_prefactor + sum.ln()).exp();
+        (-lower.min(1f64)).ln_1p()
+    } else {
+        // modified Lentz's method
+        let mut b = x + 1f64 - a;
+        let mut c = 1f64 / FPMIN;
+        let mut d = 1f64 / b;
+        let mut h = d;
+        for i in 1..=MAX_ITER {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2f64;
+            d = an * d + b;
+            if d.abs() < FPMIN {
+                d = FPMIN;
+            }
+            c = b + an / c;
+            if c.abs() < FPMIN {
+                c = FPMIN;
+            }
+            d = 1f64 / d;
+            let del = d * c;
+            h *= del;
+            if (del - 1f64).abs() < EPS {
+                break;
+            }
+        }
+        ln_prefactor + h.ln()
+    }
+}
+
+/// Asymptotic p-value of a likelihood-ratio statistic (twice the log
+/// likelihood ratio) under the null hypothesis, from the chi-square
+/// distribution with `dof` degrees of freedom. The survival function is
+/// calculated in log space, p-values too small to be represented are
+/// reported as the smallest positive (normal) `f64` rather than 0.
+pub(super) fn chi_square_p_value(statistic: f64, dof: usize) -> f64 {
+    if dof == 0 {
+        return 1f64;
+    }
+    // negative statistics, from floating point error or a log likelihood
+    // ratio favoring the null, are treated as 0
+    let ln_p = ln_upper_regularized_gamma(
+        dof as f64 / 2f64,
+        statistic.max(0f64) / 2f64,
+    );
+    ln_p.exp().clamp(f64::MIN_POSITIVE, 1f64)
+}
+
+/// Format a p-value (or q-value), small values use scientific notation
+/// instead of a long run of leading zeros.
+pub(super) fn format_p_value(p_value: f64) -> String {
+    if p_value < 1e-4 {
+        format!("{p_value:e}")
+    } else {
+        format!("{p_value}")
+    }
+}
+
+/// Result of comparing two groups of replicates with a beta-binomial model.
+#[derive(Debug)]
+pub(super) struct BetaBinomialTest {
+    /// Sum of the squared Wald statistics for each modification code.
+    pub(super) statistic: f64,
+    pub(super) p_value: f64,
+    /// Estimated over-dispersion for each modification code.
+    pub(super) dispersions: Vec<(ModCodeRepr, f64)>,
+}
+
+impl BetaBinomialTest {
+    pub(super) fn string_dispersions(&self) -> String {
+        if self.dispersions.is_empty() {
+            ".".to_string()
+        } else {
+            self.dispersions
+                .iter()
+                .map(|(code, rho)| format!("{code}:{rho:.4}"))
+                .join(",")
+        }
+    }
+
+    /// The dispersion, statistic, and p-value columns added to the `dmr
+    /// group` output after the columns shared with `dmr pair`.
+    pub(super) fn extra_columns(&self) -> [String; 3] {
+        [
+            self.string_dispersions(),
+            self.statistic.to_string(),
+            format_p_value(self.p_value),
+        ]
+    }
+}
+
+/// Method of moments estimate of the beta-binomial over-dispersion
+/// (intra-class correlation, rho) of the fraction of calls that are
+/// `mod_code`. Each group has its own mean, the dispersion is pooled across
+/// the groups. Zero when there are no replicates to estimate it from.
+fn estimate_dispersion(
+    groups: &[&[AggregatedCounts]],
+    mod_code: &ModCodeRepr,
+) -> f64 {
+    let (numerator, denominator) =
+        groups.iter().fold((0f64, 0f64), |(num, den), replicates| {
+            let covered = replicates
+                .iter()
+                .filter(|r| r.total > 0)
+                .collect::<Vec<&AggregatedCounts>>();
+            let m = covered.len() as f64;
+            let n_total = covered.iter().map(|r| r.total).sum::<usize>() as f64;
+            let x_total = covered
+                .iter()
+                .map(|r| r.mod_code_count(mod_code))
+                .sum::<usize>() as f64;
+            if m < 2f64 {
+                return (num, den);
+            }
+            let p = x_total / n_total;
+            if p <= 0f64 || p >= 1f64 {
+                return (num, den);
+            }
+            let s = covered
+                .iter()
+                .map(|r| {
+                    let n = r.total as f64;
+                    let p_i = r.mod_code_count(mod_code) as f64 / n;
+                    n * (p_i - p).powi(2)
+                })
+                .sum::<f64>();
+            let sum_n_sq = covered
+                .iter()
+                .map(|r| (r.total as f64).powi(2))
+                .sum::<f64>();
+            (
+                num + s / (p * (1f64 - p)) - (m - 1f64),
+                den + n_total - sum_n_sq / n_total - (m - 1f64),
+            )
+        });
+    if denominator <= 0f64 {
+        0f64
+    } else {
+        (numerator / denominator).clamp(0f64, 1f64)
+    }
+}
+
+/// Mean fraction of `mod_code` calls in a group of replicates and the
+/// variance of the mean under a beta-binomial model with dispersion `rho`.
+/// The variance uses a pseudo-count so that it's not zero when all calls
+/// are (or none are) `mod_code`.
+fn group_mean_and_variance(
+    replicates: &[AggregatedCounts],
+    mod_code: &ModCodeRepr,
+    rho: f64,
+) -> Option<(f64, f64)> {
+    let n_total = replicates.iter().map(|r| r.total).sum::<usize>() as f64;
+    if n_total == 0f64 {
+        return None;
+    }
+    let x_total = replicates
+        .iter()
+        .map(|r| r.mod_code_count(mod_code))
+        .sum::<usize>() as f64;
+    let mean = x_total / n_total;
+    let smoothed = (x_total + 0.5) / (n_total + 1f64);
+    let inflation = replicates
+        .iter()
+        .map(|r| {
+            let n = r.total as f64;
+            n * (1f64 + (n - 1f64).max(0f64) * rho)
+        })
+        .sum::<f64>();
+    let variance = smoothed * (1f64 - smoothed) * inflation / n_total.powi(2);
+    Some((mean, variance))
+}
+
+/// Compare two groups of replicates with a Wald test on the difference in
+/// the fraction of each modification, allowing for over-dispersion between
+/// replicates with a beta-binomial model. The per-modification statistics
+/// are summed and compared to a chi-square distribution, treating the
+/// modifications as independent.
+pub(super) fn beta_binomial_test(
+    control_replicates: &[AggregatedCounts],
+    exp_replicates: &[AggregatedCounts],
+) -> anyhow::Result<BetaBinomialTest> {
+    let mod_codes = control_replicates
+        .iter()
+        .chain(exp_replicates.iter())
+        .flat_map(|r| r.mod_code_counts.keys().copied())
+        .unique()
+        .sorted()
+        .collect::<Vec<ModCodeRepr>>();
+    let mut statistic = 0f64;
+    let mut dof = 0usize;
+    let mut dispersions = Vec::with_capacity(mod_codes.len());
+    for mod_code in mod_codes {
+        let rho = estimate_dispersion(
+            &[control_replicates, exp_replicates],
+            &mod_code,
+        );
+        dispersions.push((mod_code, rho));
+        let control =
+            group_mean_and_variance(control_replicates, &mod_code, rho);
+        let exp = group_mean_and_variance(exp_replicates, &mod_code, rho);
+        if let (Some((control_mean, control_var)), Some((exp_mean, exp_var))) =
+            (control, exp)
+        {
+            let z = (exp_mean - control_mean) / (control_var + exp_var).sqrt();
+            statistic += z.powi(2);
+            dof += 1;
+        }
+    }
+    let p_value = chi_square_p_value(statistic, dof);
+    Ok(BetaBinomialTest {
+        statistic,
+        p_value,
+        dispersions,
+    })
+}
+
+/// Number of states (canonical and each modification) in the model used by
+/// [`llk_ratio`].
+fn n_categories(
+    control_counts: &AggregatedCounts,
+    exp_counts: &AggregatedCounts,
+) -> usize {
+    std::cmp::max(
         control_counts.mod_code_counts.keys().len(),
         exp_counts.mod_code_counts.keys().len(),
-    ) + 1; // plus 1 for canonical
+    ) + 1 // plus 1 for canonical
+}
+
+pub(super) fn llk_ratio(
+    control_counts: &AggregatedCounts,
+    exp_counts: &AggregatedCounts,
+) -> anyhow::Result<f64> {
+    let n_categories = n_categories(control_counts, exp_counts);
     if n_categories < 2 {
         return Ok(0f64);
     }
@@ -280,10 +783,17 @@ pub(super) fn llk_ratio(
 
 #[cfg(test)]
 mod dmr_model_tests {
-    use crate::dmr::model::{llk_beta, llk_dirichlet, AggregatedCounts};
+    use crate::dmr::model::{
+        beta_binomial_test, chi_square_p_value, estimate_dispersion,
+        format_p_value, g_test_statistic, llk_beta, llk_dirichlet, llk_ratio,
+        AggregatedCounts, ModificationCounts, PileupCounts,
+    };
+    use crate::dmr::util::DmrInterval;
     use crate::mod_base_code::{
         ModCodeRepr, HYDROXY_METHYL_CYTOSINE, METHYL_CYTOSINE,
     };
+    use crate::pileup::PileupFeatureCounts;
+    use crate::position_filter::Iv;
     use itertools::Itertools;
     use rand::prelude::*;
     use rand::rngs::StdRng;
@@ -348,4 +858,164 @@ mod dmr_model_tests {
         let llk_b = llk_dirichlet(&control, &exp).unwrap();
         assert!(llk_a > llk_b);
     }
+
+    #[test]
+    fn test_chi_square_p_value() {
+        let counts = |n_mod: usize, total: usize| {
+            AggregatedCounts::try_new(
+                HashMap::from([(METHYL_CYTOSINE, n_mod)]),
+                total,
+            )
+            .unwrap()
+        };
+        let (g, dof) = g_test_statistic(&counts(30, 40), &counts(10, 40));
+        assert_eq!(dof, 1);
+        assert!((g - 20.929925750581912).abs() < 1e-9);
+        let p = chi_square_p_value(g, dof);
+        assert!((p - 4.763938479565467e-06).abs() < 1e-9, "{p}");
+        // with 2 degrees of freedom the survival function is exp(-x / 2),
+        // covers both the series and the continued fraction
+        for statistic in [0.5f64, 1f64, 10f64, 1000f64] {
+            let p = chi_square_p_value(statistic, 2);
+            let expected = (-statistic / 2f64).exp();
+            assert!(((p - expected) / expected).abs() < 1e-9, "{p}");
+        }
+        // far out in the tail the p-value is small, but never 0
+        let p = chi_square_p_value(2f64 * 257.34514203447543, 1);
+        assert!(p > 1e-115 && p < 1e-110, "{p}");
+        assert_eq!(chi_square_p_value(1e5, 1), f64::MIN_POSITIVE);
+        // no degrees of freedom, or a log likelihood ratio favoring the null
+        assert_eq!(chi_square_p_value(10f64, 0), 1f64);
+        assert_eq!(chi_square_p_value(-1f64, 1), 1f64);
+        assert_eq!(format_p_value(0.05), "0.05");
+        assert_eq!(format_p_value(1.5e-112), "1.5e-112");
+
+        // the p-value is for the score, twice the log likelihood ratio
+        let interval = DmrInterval::new(
+            Iv {
+                start: 0,
+                stop: 10,
+                val: (),
+            },
+            "chr1".to_string(),
+            "chr1:0-10".to_string(),
+        );
+        let row = ModificationCounts::new(
+            0,
+            10,
+            counts(30, 40),
+            counts(10, 40),
+            interval.clone(),
+        )
+        .unwrap();
+        let score = llk_ratio(&counts(30, 40), &counts(10, 40)).unwrap();
+        assert_eq!(row.score, score);
+        assert_eq!(row.p_value, chi_square_p_value(2f64 * score, 1));
+        assert!(row.p_value < 1e-3, "{row:?}");
+        // same frequencies
+        let row = ModificationCounts::new(
+            0,
+            10,
+            counts(10, 20),
+            counts(20, 40),
+            interval,
+        )
+        .unwrap();
+        assert!(row.p_value > 0.5, "{row:?}");
+    }
+
+    #[test]
+    fn test_beta_binomial_replicates() {
+        let counts = |n_mod: usize, total: usize| {
+            AggregatedCounts::try_new(
+                HashMap::from([(METHYL_CYTOSINE, n_mod)]),
+                total,
+            )
+            .unwrap()
+        };
+        // replicates that agree have no over-dispersion
+        let control = [counts(50, 100), counts(50, 100)];
+        let exp = [counts(20, 100), counts(20, 100)];
+        assert_eq!(
+            estimate_dispersion(&[&control, &exp], &METHYL_CYTOSINE),
+            0f64
+        );
+        let consistent = beta_binomial_test(&control, &exp).unwrap();
+        assert!(consistent.p_value < 1e-5, "{consistent:?}");
+
+        // the same pooled counts, but driven by an outlier replicate
+        let control = [counts(50, 100), counts(50, 100)];
+        let exp = [counts(40, 100), counts(0, 100)];
+        let rho = estimate_dispersion(&[&control, &exp], &METHYL_CYTOSINE);
+        assert!(rho > 0.1, "{rho}");
+        let outlier = beta_binomial_test(&control, &exp).unwrap();
+        assert!(outlier.p_value > consistent.p_value, "{outlier:?}");
+        // the pooled test doesn't see the difference
+        let pooled_score = llk_ratio(
+            &AggregatedCounts::pooled(&control),
+            &AggregatedCounts::pooled(&exp),
+        )
+        .unwrap();
+        let pooled_p = chi_square_p_value(2f64 * pooled_score, 1);
+        assert!(pooled_p < outlier.p_value);
+
+        // no difference
+        let same = beta_binomial_test(&control, &control).unwrap();
+        assert!((same.p_value - 1f64).abs() < 1e-9, "{same:?}");
+        assert_eq!(same.string_dispersions(), "m:0.0000");
+    }
+
+    #[test]
+    fn test_pileup_counts_add_position() {
+        let feature_counts = [
+            PileupFeatureCounts::new(
+                '+',
+                10,
+                METHYL_CYTOSINE,
+                0.5,
+                3,
+                5,
+                2,
+                0,
+                0,
+                0,
+                0,
+                None,
+            ),
+            PileupFeatureCounts::new(
+                '+',
+                10,
+                HYDROXY_METHYL_CYTOSINE,
+                0.2,
+                3,
+                2,
+                5,
+                0,
+                0,
+                0,
+                0,
+                None,
+            ),
+            PileupFeatureCounts::new(
+                '-',
+                4,
+                METHYL_CYTOSINE,
+                0.25,
+                3,
+                1,
+                0,
+                0,
+                0,
+                0,
+                0,
+                None,
+            ),
+        ];
+        let mut counts = PileupCounts::default();
+        counts.add_position(&feature_counts);
+        // coverage is counted once per strand
+        assert_eq!(counts.total, 14);
+        assert_eq!(counts.mod_code_counts[&METHYL_CYTOSINE], 6);
+        assert_eq!(counts.mod_code_counts[&HYDROXY_METHYL_CYTOSINE], 2);
+    }
 }
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufRead;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::bail;
 use indicatif::ProgressBar;
@@ -14,13 +14,15 @@ use rayon::prelude::*;
 use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::dmr::bedmethyl::BedMethylLine;
+use crate::dmr::fdr::QValueWriter;
 use crate::dmr::model::{AggregatedCounts, ModificationCounts};
+use crate::dmr::segment::{SegmentationParams, Segmenter};
 use crate::dmr::util::{DmrBatch, DmrIntervalIter};
 use crate::mod_base_code::DnaBase;
 use crate::position_filter::StrandedPositionFilter;
 use crate::util::{Strand, StrandRule};
 
-fn aggregate_counts(
+pub(crate) fn aggregate_counts(
     bm_lines: &[&BedMethylLine],
     chrom_id: u32,
     position_filter: &StrandedPositionFilter<DnaBase>,
@@ -114,7 +116,7 @@ fn aggregate_counts(
     }
 }
 
-fn read_bedmethyl(
+pub(super) fn read_bedmethyl(
     fp: &PathBuf,
     chunks: &[IndexChunk],
 ) -> anyhow::Result<Vec<BedMethylLine>> {
@@ -245,10 +247,12 @@ pub(super) fn run_pairwise_dmr(
     exp_bed_fp: &PathBuf,
     dmr_interval_iter: DmrIntervalIter,
     position_filter: StrandedPositionFilter<DnaBase>,
-    mut writer: Box<dyn std::io::Write>,
+    writer: Box<dyn std::io::Write>,
+    tmp_dir: &Path,
     pb: ProgressBar,
     min_valid_coverage: u64,
     failure_counter: ProgressBar,
+    segmentation: Option<SegmentationParams>,
 ) -> anyhow::Result<usize> {
     let (snd, rcv) = crossbeam_channel::bounded(1000);
     let control_bedmethyl_fp = control_bed_fp.clone();
@@ -304,6 +308,8 @@ pub(super) fn run_pairwise_dmr(
         }
     });
 
+    let mut writer = QValueWriter::new(writer, tmp_dir)?;
+    let mut segmenter = segmentation.map(Segmenter::new);
     let mut success_count = 0;
     for batch_result in rcv {
         match batch_result {
@@ -311,7 +317,13 @@ pub(super) fn run_pairwise_dmr(
                 for result in results {
                     match result {
                         Ok(counts) => {
-                            writer.write(counts.to_row()?.as_bytes())?;
+                            if let Some(segmenter) = segmenter.as_mut() {
+                                for region in segmenter.push(counts)? {
+                                    writer.write_counts(&region, &[])?;
+                                }
+                            } else {
+                                writer.write_counts(&counts, &[])?;
+                            }
                             success_count += 1;
                             pb.inc(1);
                         }
@@ -333,6 +345,12 @@ pub(super) fn run_pairwise_dmr(
     }
 
     pb.finish_and_clear();
+    if let Some(segmenter) = segmenter {
+        for region in segmenter.finish()? {
+            writer.write_counts(&region, &[])?;
+        }
+    }
+    writer.finish()?;
 
     Ok(success_count)
 }
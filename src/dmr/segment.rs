@@ -0,0 +1,180 @@
+use derive_new::new;
+
+use crate::dmr::model::ModificationCounts;
+
+/// Parameters for de novo segmentation of site-level scores into
+/// differentially methylated regions.
+#[derive(new, Debug, Copy, Clone)]
+pub(super) struct SegmentationParams {
+    /// Sites further apart than this are never in the same region.
+    max_gap: u64,
+    /// Minimum number of sites in a reported region.
+    min_sites: usize,
+    /// Probability of switching between the "same" and "different" states
+    /// between adjacent sites.
+    switch_prob: f64,
+}
+
+/// Collects consecutive site-level results and segments them into regions
+/// with a two-state HMM. The states are "same" and "different" between the
+/// two samples, the emission of the "different" state relative to the
+/// "same" state at each site is the BIC-penalized log-likelihood ratio of
+/// the two states (see `ModificationCounts::bic_log_bayes_factor`).
+pub(super) struct Segmenter {
+    params: SegmentationParams,
+    chain: Vec<ModificationCounts>,
+}
+
+impl Segmenter {
+    pub(super) fn new(params: SegmentationParams) -> Self {
+        Self {
+            params,
+            chain: Vec::new(),
+        }
+    }
+
+    fn breaks_chain(&self, site: &ModificationCounts) -> bool {
+        match self.chain.last() {
+            Some(prev) => {
+                let (prev, site) = (prev.interval(), site.interval());
+                prev.chrom != site.chrom
+                    || site.start() < prev.start()
+                    || site.start().saturating_sub(prev.stop())
+                        > self.params.max_gap
+            }
+            None => false,
+        }
+    }
+
+    /// Add the next site, sites must be added in sorted order. Returns
+    /// any regions that are complete.
+    pub(super) fn push(
+        &mut self,
+        site: ModificationCounts,
+    ) -> anyhow::Result<Vec<ModificationCounts>> {
+        let regions = if self.breaks_chain(&site) {
+            self.segment_chain()?
+        } else {
+            Vec::new()
+        };
+        self.chain.push(site);
+        Ok(regions)
+    }
+
+    /// Segment any remaining sites.
+    pub(super) fn finish(mut self) -> anyhow::Result<Vec<ModificationCounts>> {
+        self.segment_chain()
+    }
+
+    fn segment_chain(&mut self) -> anyhow::Result<Vec<ModificationCounts>> {
+        let chain = std::mem::take(&mut self.chain);
+        let scores = chain
+            .iter()
+            .map(|site| site.bic_log_bayes_factor())
+            .collect::<Vec<f64>>();
+        let states = viterbi(&scores, self.params.switch_prob);
+        different_runs(&states)
+            .into_iter()
+            .filter(|(start, end)| end - start >= self.params.min_sites)
+            .map(|(start, end)| ModificationCounts::merge(&chain[start..end]))
+            .collect()
+    }
+}
+
+/// Most likely "different" (true) or "same" (false) state for each site.
+fn viterbi(scores: &[f64], switch_prob: f64) -> Vec<bool> {
+    if scores.is_empty() {
+        return Vec::new();
+    }
+    let log_switch = switch_prob.ln();
+    let log_stay = (1f64 - switch_prob).ln();
+    let emission = |score: f64| {
+        // the "same" state is the reference
+        let score = if score.is_finite() { score } else { 0f64 };
+        [0f64, score]
+    };
+
+    // chains start in the "same" state, so entering a region at the first
+    // site costs the same as entering one later
+    let first = emission(scores[0]);
+    let mut llks = [first[0] + log_stay, first[1] + log_switch];
+    let mut back_pointers = Vec::with_capacity(scores.len());
+    for score in scores.iter().skip(1) {
+        let emissions = emission(*score);
+        let mut next = [0f64; 2];
+        let mut pointers = [0usize; 2];
+        for state in 0..2 {
+            let stay = llks[state] + log_stay;
+            let switch = llks[1 - state] + log_switch;
+            let (prev, llk) = if stay >= switch {
+                (state, stay)
+            } else {
+                (1 - state, switch)
+            };
+            next[state] = llk + emissions[state];
+            pointers[state] = prev;
+        }
+        back_pointers.push(pointers);
+        llks = next;
+    }
+
+    let mut state = if llks[1] > llks[0] { 1 } else { 0 };
+    let mut states = vec![state == 1];
+    for pointers in back_pointers.iter().rev() {
+        state = pointers[state];
+        states.push(state == 1);
+    }
+    states.reverse();
+    states
+}
+
+/// Half-open index ranges of consecutive "different" states.
+fn different_runs(states: &[bool]) -> Vec<(usize, usize)> {
+    let mut runs = Vec::new();
+    let mut run_start = None;
+    for (i, different) in states.iter().enumerate() {
+        match (run_start, *different) {
+            (None, true) => run_start = Some(i),
+            (Some(start), false) => {
+                runs.push((start, i));
+                run_start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(start) = run_start {
+        runs.push((start, states.len()));
+    }
+    runs
+}
+
+#[cfg(test)]
+mod segment_tests {
+    use crate::dmr::segment::{different_runs, viterbi};
+
+    #[test]
+    fn test_viterbi_segmentation() {
+        let scores = [-2.0, -1.0, 5.0, 4.0, -0.5, 6.0, -3.0, -2.0, -4.0];
+        let states = viterbi(&scores, 0.01);
+        // the weakly negative site between the strongly different sites is
+        // included in the region
+        assert_eq!(
+            states,
+            vec![false, false, true, true, true, true, false, false, false]
+        );
+        assert_eq!(different_runs(&states), vec![(2, 6)]);
+
+        // a single positive site isn't enough to pay for two switches
+        let scores = [-2.0, -1.0, 3.0, -1.0, -2.0];
+        assert!(viterbi(&scores, 0.01).iter().all(|s| !s));
+        // but is with a permissive switch probability
+        assert_eq!(different_runs(&viterbi(&scores, 0.4)), vec![(2, 3)]);
+        assert!(viterbi(&[], 0.01).is_empty());
+    }
+
+    #[test]
+    fn test_different_runs() {
+        let states = [true, true, false, true, false, false, true];
+        assert_eq!(different_runs(&states), vec![(0, 2), (3, 4), (6, 7)]);
+    }
+}
@@ -12,22 +12,33 @@ use itertools::Itertools;
 use log::{debug, error, info};
 use noodles::csi::Index as CsiIndex;
 use rayon::prelude::*;
+use rust_htslib::bam::{self, Read};
 use rustc_hash::{FxHashMap, FxHashSet};
 
+use crate::command_utils::ModCallerArgs;
+use crate::dmr::asm::{process_asm_region, AsmParams, AsmResult};
+use crate::dmr::bam::{check_bam_headers, process_bam_region, BamPileupParams};
 use crate::dmr::bedmethyl::load_regions_from_bedmethyl;
+use crate::dmr::fdr::{temp_dir_for_output, QValueWriter};
+use crate::dmr::group::{process_group_batch, Replicate};
+use crate::dmr::model::ModificationCounts;
 use crate::dmr::multi_sample::{
     get_reference_modified_base_positions, n_choose_2, DmrSample,
 };
 use crate::dmr::pairwise::run_pairwise_dmr;
+use crate::dmr::segment::SegmentationParams;
 use crate::dmr::util::{
     parse_roi_bed, ContigLookup, DmrInterval, DmrIntervalIter, HandleMissing,
 };
+use crate::interval_chunks::IntervalChunks;
 use crate::logging::init_logging;
 use crate::mod_base_code::{DnaBase, ParseChar};
+use crate::motif_bed::{MotifLocations, MultipleMotifLocations, RegexMotif};
+use crate::pileup::PileupNumericOptions;
 use crate::position_filter::{BaseIv, GenomeLapper, StrandedPositionFilter};
 use crate::util::{
     create_out_directory, get_master_progress_bar, get_subroutine_progress_bar,
-    get_ticker,
+    get_targets, get_ticker,
 };
 
 #[derive(Subcommand)]
@@ -47,6 +58,26 @@ pub enum BedMethylDmr {
     /// two samples indicated in the file name. See the online documentation for
     /// additional details.
     Multi(MultiSampleDmr),
+    /// Compare methylation between the two haplotypes of a single haplotagged
+    /// modBAM (allele-specific methylation). Reads are partitioned by their
+    /// HP tag and compared within each phase block (PS tag), unphased reads
+    /// are ignored. Output is a BED file with the same columns as `pair`,
+    /// haplotype 1 in place of the "a" sample and haplotype 2 in place of the
+    /// "b" sample, and an additional column with the phase block.
+    Asm(AlleleSpecificDmr),
+    /// Compare regions between two samples directly from modBAMs, without
+    /// making bedMethyl files first. Each sample can be one or more modBAMs.
+    /// The pass threshold is estimated from the reads of both samples together
+    /// so that modification calls are filtered the same way in each sample.
+    /// Output is a BED file with the same columns as `pair`.
+    Bam(BamDmr),
+    /// Compare regions between two groups of replicates (for example, several
+    /// tumor and normal samples). Each replicate is a bgzip pileup bedMethyl with
+    /// an associated tabix index. Unlike `pair`, replicates are not pooled,
+    /// variability between replicates is modeled with a beta-binomial
+    /// distribution so that a single outlier replicate doesn't produce a
+    /// significant difference. See the online documentation for additional details.
+    Group(GroupDmr),
 }
 
 impl BedMethylDmr {
@@ -54,6 +85,9 @@ impl BedMethylDmr {
         match self {
             Self::Pair(x) => x.run(),
             Self::Multi(x) => x.run(),
+            Self::Asm(x) => x.run(),
+            Self::Bam(x) => x.run(),
+            Self::Group(x) => x.run(),
         }
     }
 }
@@ -128,23 +162,44 @@ pub struct PairwiseDmr {
     /// pileup for the specification and description of valid coverage.
     #[arg(long, alias = "min-coverage", default_value_t = 0)]
     min_valid_coverage: u64,
+    /// Find differentially methylated regions de novo instead of reporting
+    /// individual sites. Adjacent sites are segmented into regions with a two-state
+    /// (same/different) HMM over the per-site scores, each region is reported with
+    /// the aggregated counts of its sites. Cannot be used with --regions.
+    #[arg(long, conflicts_with = "regions_bed", default_value_t = false)]
+    segment: bool,
+    /// Minimum number of sites in a segmented region.
+    #[arg(long, requires = "segment", default_value_t = 3)]
+    min_sites: usize,
+    /// Maximum distance in base pairs between adjacent sites in a segmented region.
+    #[arg(long, requires = "segment", default_value_t = 500)]
+    max_gap: u64,
+    /// Probability of switching between the same and different states between
+    /// adjacent sites during segmentation. Smaller values give fewer, longer regions.
+    #[arg(
+        long,
+        requires = "segment",
+        default_value_t = 0.01,
+        hide_short_help = true
+    )]
+    switch_prob: f64,
 }
 
 impl PairwiseDmr {
-    fn get_stranded_position_filter(
-        &self,
+    pub(crate) fn get_stranded_position_filter(
+        reference_fasta: &PathBuf,
+        mask: bool,
         name_to_id: Arc<ContigLookup>,
         multi_pb: &MultiProgress,
         modified_bases: &[DnaBase],
     ) -> anyhow::Result<StrandedPositionFilter<DnaBase>> {
-        let fasta_reader = FastaReader::from_file(&self.reference_fasta)?;
+        let fasta_reader = FastaReader::from_file(reference_fasta)?;
         let reader_pb = multi_pb.add(get_ticker());
         reader_pb.set_message("sequences read");
         let positions_pb = multi_pb.add(get_ticker());
         positions_pb.set_message("positions found");
 
         let (snd, rcv) = crossbeam_channel::unbounded();
-        let mask = self.mask;
 
         std::thread::spawn(move || {
             fasta_reader
@@ -232,7 +287,7 @@ impl PairwiseDmr {
         })
     }
 
-    fn load_index(
+    pub(crate) fn load_index(
         bedmethyl_path: &PathBuf,
         specified_index: Option<&PathBuf>,
     ) -> anyhow::Result<(CsiIndex, PathBuf)> {
@@ -266,7 +321,9 @@ impl PairwiseDmr {
         Self::validate_modified_bases(&self.modified_bases)
     }
 
-    fn validate_modified_bases(bases: &[char]) -> anyhow::Result<()> {
+    pub(crate) fn validate_modified_bases(
+        bases: &[char],
+    ) -> anyhow::Result<()> {
         if bases.is_empty() {
             bail!("need to specify at least 1 modified base")
         }
@@ -345,7 +402,9 @@ impl PairwiseDmr {
         let exp_contig_lookup =
             ContigLookup::new(&exp_index, &exp_index_fp, None)?;
 
-        let position_filter = self.get_stranded_position_filter(
+        let position_filter = Self::get_stranded_position_filter(
+            &self.reference_fasta,
+            self.mask,
             control_contig_lookup.clone(),
             &mpb,
             &motifs,
@@ -388,15 +447,34 @@ impl PairwiseDmr {
             self.handle_missing,
         )?;
 
+        let segmentation = if self.segment {
+            if self.switch_prob <= 0f64 || self.switch_prob >= 1f64 {
+                bail!("switch probability must be between 0 and 1")
+            }
+            info!(
+                "segmenting sites into regions with at least {} sites",
+                self.min_sites
+            );
+            Some(SegmentationParams::new(
+                self.max_gap,
+                self.min_sites,
+                self.switch_prob,
+            ))
+        } else {
+            None
+        };
+
         let success_count = run_pairwise_dmr(
             &self.control_bed_methyl,
             &self.exp_bed_methyl,
             dmr_interval_iter,
             position_filter,
             writer,
+            &temp_dir_for_output(self.out_path.as_ref()),
             pb,
             self.min_valid_coverage,
             failures.clone(),
+            segmentation,
         )?;
 
         info!(
@@ -706,9 +784,11 @@ impl MultiSampleDmr {
                         dmr_interval_iter,
                         position_filter,
                         writer,
+                        &self.out_dir,
                         pb,
                         self.min_valid_coverage,
                         failures.clone(),
+                        None,
                     )?;
                     debug!(
                         "{} regions processed successfully and {} regions failed for pair {} {}",
@@ -734,3 +814,676 @@ impl MultiSampleDmr {
         Ok(())
     }
 }
+
+#[derive(Args)]
+pub struct AlleleSpecificDmr {
+    /// Input modBAM with haplotagged reads (HP and optionally PS tags), should
+    /// be sorted and have associated index available.
+    in_bam: PathBuf,
+    /// Path to file to direct output, optional, no argument will direct output to stdout.
+    #[arg(short = 'o', long)]
+    out_path: Option<String>,
+    /// BED file of regions over which to compare methylation levels between
+    /// haplotypes. Should be tab-separated (spaces allowed in the "name" column).
+    /// Requires chrom, chromStart and chromEnd. The Name column is optional. When
+    /// omitted, methylation levels are compared at each motif site.
+    #[arg(long, short = 'r', alias = "regions")]
+    regions_bed: Option<PathBuf>,
+    /// Path to reference fasta used to find motif sites.
+    #[arg(long = "ref")]
+    reference_fasta: PathBuf,
+    /// Compare methylation at CpG sites, short hand for --motif CG 0.
+    #[arg(long, group = "motif_options", default_value_t = false)]
+    cpg: bool,
+    /// Compare methylation at this sequence motif. The first argument should be
+    /// the sequence motif and the second argument is the 0-based offset to the
+    /// base to use.
+    #[arg(long, group = "motif_options", num_args = 2)]
+    motif: Option<Vec<String>>,
+    /// Respect soft masking in the reference FASTA.
+    #[arg(long, short = 'k', default_value_t = false)]
+    mask: bool,
+    /// Minimum valid coverage required on each haplotype to use a site. See the
+    /// help for pileup for the specification and description of valid coverage.
+    #[arg(long, alias = "min-coverage", default_value_t = 0)]
+    min_valid_coverage: u64,
+    /// File to write logs to, it's recommended to use this option.
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Number of threads to use.
+    #[arg(short = 't', long, default_value_t = 4)]
+    threads: usize,
+    /// Control the batch size. The batch size is the number of regions (or
+    /// interval chunks, when scoring sites) to process concurrently. Default
+    /// will be 50% more than the number of threads assigned.
+    #[arg(long, alias = "batch")]
+    batch_size: Option<usize>,
+    /// Interval chunk size in base pairs to process concurrently when scoring
+    /// individual sites.
+    #[arg(
+        short = 'i',
+        long,
+        default_value_t = 100_000,
+        hide_short_help = true
+    )]
+    interval_size: u32,
+    /// Maximum number of records to use when calculating pileup. This argument is
+    /// passed to the pileup engine.
+    #[arg(long, default_value_t = 8000, hide_short_help = true)]
+    max_depth: u32,
+    /// Don't show progress bars
+    #[arg(long, default_value_t = false)]
+    suppress_progress: bool,
+    /// Force overwrite of output file, if it already exists.
+    #[arg(short = 'f', long, default_value_t = false)]
+    force: bool,
+
+    #[command(flatten)]
+    mod_caller_args: ModCallerArgs,
+}
+
+impl AlleleSpecificDmr {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        let header = bam::IndexedReader::from_path(&self.in_bam)
+            .map(|reader| reader.header().to_owned())
+            .context("failed to open input BAM, must be sorted and indexed")?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .context("failed to make threadpool")?;
+        let mpb = MultiProgress::new();
+        if self.suppress_progress {
+            mpb.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+
+        let writer: Box<dyn Write> = match self.out_path.as_ref() {
+            None => Box::new(BufWriter::new(std::io::stdout())),
+            Some(fp) => {
+                let p = Path::new(fp);
+                create_out_directory(p)?;
+                if p.exists() && !self.force {
+                    bail!("refusing to overwrite existing file {}", fp)
+                } else {
+                    let fh = File::create(p)?;
+                    Box::new(BufWriter::new(fh))
+                }
+            }
+        };
+
+        let edge_filter = self.mod_caller_args.edge_filter()?;
+        let collapse_method = self.mod_caller_args.collapse_method()?;
+        let pileup_options = match collapse_method.as_ref() {
+            Some(method) => PileupNumericOptions::Collapse(method.clone()),
+            None => PileupNumericOptions::Passthrough,
+        };
+
+        let regex_motif = match (&self.motif, self.cpg) {
+            (Some(raw_motif_parts), _) => {
+                RegexMotif::from_raw_parts(raw_motif_parts, false)?
+                    .into_iter()
+                    .next()
+                    .ok_or(anyhow!("illegal number of parts for motif"))?
+            }
+            (None, true) => RegexMotif::parse_string("CG", 0).unwrap(),
+            (None, false) => bail!("need to specify either --motif or --cpg"),
+        };
+        let targets = get_targets(&header, None);
+        let names_to_tid = targets
+            .iter()
+            .map(|target| (target.name.as_str(), target.tid))
+            .collect::<HashMap<&str, u32>>();
+        let motif_locations = pool.install(|| {
+            MotifLocations::from_fasta(
+                &self.reference_fasta,
+                regex_motif,
+                &names_to_tid,
+                self.mask,
+                None,
+                &mpb,
+            )
+        })?;
+        let targets = motif_locations.filter_reference_records(targets);
+        let motif_locations =
+            MultipleMotifLocations::new(vec![motif_locations]);
+
+        let failures = mpb.add(get_ticker());
+        failures.set_message("regions failed to process");
+        // (tid, start, end, region) for each unit of work, when scoring
+        // individual sites the region is None
+        let (work, what) = if let Some(roi_bed) = self.regions_bed.as_ref() {
+            let rois = parse_roi_bed(roi_bed)?;
+            info!("loaded {} regions", rois.len());
+            let work = rois
+                .into_iter()
+                .filter_map(|roi| {
+                    let tid = header.tid(roi.chrom.as_bytes());
+                    if tid.is_none() {
+                        debug!("{} not found in BAM header", &roi.chrom);
+                        failures.inc(1);
+                    }
+                    tid.map(|tid| {
+                        (tid, roi.start() as u32, roi.stop() as u32, Some(roi))
+                    })
+                })
+                .collect::<Vec<(u32, u32, u32, Option<DmrInterval>)>>();
+            (work, "regions")
+        } else {
+            let work = targets
+                .iter()
+                .flat_map(|target| {
+                    IntervalChunks::new_with_multiple_motifs(
+                        target.start,
+                        target.length,
+                        self.interval_size,
+                        target.tid,
+                        Some(&motif_locations),
+                    )
+                    .map(|(start, end)| (target.tid, start, end, None))
+                })
+                .collect::<Vec<(u32, u32, u32, Option<DmrInterval>)>>();
+            (work, "interval chunks")
+        };
+
+        let threshold_caller = self.mod_caller_args.build_caller(
+            std::slice::from_ref(&self.in_bam),
+            &pool,
+            None,
+            edge_filter.as_ref(),
+            collapse_method.as_ref(),
+            None,
+            self.suppress_progress,
+        )?;
+        let params = AsmParams::new(
+            threshold_caller,
+            pileup_options,
+            self.mod_caller_args.force_allow_implicit,
+            self.max_depth,
+            motif_locations,
+            edge_filter,
+            self.min_valid_coverage,
+        );
+
+        let batch_size = self
+            .batch_size
+            .unwrap_or_else(|| (self.threads as f32 * 1.5f32).floor() as usize);
+        info!("processing {batch_size} {what} at a time");
+        let pb = mpb.add(get_master_progress_bar(work.len()));
+        pb.set_message(format!("{what} processed"));
+        let rows_written = mpb.add(get_ticker());
+        rows_written.set_message("rows written");
+
+        let mut writer = QValueWriter::new(
+            writer,
+            &temp_dir_for_output(self.out_path.as_ref()),
+        )?;
+        for batch in work.chunks(batch_size.max(1)) {
+            let results = pool.install(|| {
+                batch
+                    .par_iter()
+                    .map(|(tid, start, end, region)| {
+                        process_asm_region(
+                            &self.in_bam,
+                            *tid,
+                            *start,
+                            *end,
+                            region.as_ref(),
+                            &params,
+                        )
+                    })
+                    .collect::<Vec<anyhow::Result<Vec<AsmResult>>>>()
+            });
+            for result in results {
+                match result {
+                    Ok(asm_results) => {
+                        for asm_result in asm_results {
+                            asm_result.write(&mut writer)?;
+                            rows_written.inc(1);
+                        }
+                    }
+                    Err(e) => {
+                        debug!("region failed, error: {e}");
+                        failures.inc(1);
+                    }
+                }
+                pb.inc(1);
+            }
+        }
+        writer.finish()?;
+
+        info!(
+            "wrote {} rows, {} {what} failed",
+            rows_written.position(),
+            failures.position()
+        );
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct BamDmr {
+    /// Input modBAM for the first (usually control) sample, should be sorted
+    /// and have associated index available. Repeat to pool the reads from
+    /// multiple modBAMs into the sample.
+    #[arg(short = 'a', required = true)]
+    control_bams: Vec<PathBuf>,
+    /// Input modBAM for the second (usually experimental) sample, should be
+    /// sorted and have associated index available. Repeat to pool the reads
+    /// from multiple modBAMs into the sample.
+    #[arg(short = 'b', required = true)]
+    exp_bams: Vec<PathBuf>,
+    /// Path to file to direct output, optional, no argument will direct output to stdout.
+    #[arg(short = 'o', long)]
+    out_path: Option<String>,
+    /// BED file of regions over which to compare methylation levels. Should be
+    /// tab-separated (spaces allowed in the "name" column). Requires chrom,
+    /// chromStart and chromEnd. The Name column is optional.
+    #[arg(long, short = 'r', alias = "regions")]
+    regions_bed: PathBuf,
+    /// Path to reference fasta used to find motif sites.
+    #[arg(long = "ref")]
+    reference_fasta: PathBuf,
+    /// Compare methylation at CpG sites, short hand for --motif CG 0.
+    #[arg(long, group = "motif_options", default_value_t = false)]
+    cpg: bool,
+    /// Compare methylation at this sequence motif. The first argument should be
+    /// the sequence motif and the second argument is the 0-based offset to the
+    /// base to use.
+    #[arg(long, group = "motif_options", num_args = 2)]
+    motif: Option<Vec<String>>,
+    /// Respect soft masking in the reference FASTA.
+    #[arg(long, short = 'k', default_value_t = false)]
+    mask: bool,
+    /// Minimum valid coverage required in each sample to use a site. See the
+    /// help for pileup for the specification and description of valid coverage.
+    #[arg(long, alias = "min-coverage", default_value_t = 0)]
+    min_valid_coverage: u64,
+    /// File to write logs to, it's recommended to use this option.
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Number of threads to use.
+    #[arg(short = 't', long, default_value_t = 4)]
+    threads: usize,
+    /// Control the batch size. The batch size is the number of regions to
+    /// process concurrently. Default will be 50% more than the number of
+    /// threads assigned.
+    #[arg(long, alias = "batch")]
+    batch_size: Option<usize>,
+    /// Maximum number of records to use when calculating pileup. This argument is
+    /// passed to the pileup engine.
+    #[arg(long, default_value_t = 8000, hide_short_help = true)]
+    max_depth: u32,
+    /// Don't show progress bars
+    #[arg(long, default_value_t = false)]
+    suppress_progress: bool,
+    /// Force overwrite of output file, if it already exists.
+    #[arg(short = 'f', long, default_value_t = false)]
+    force: bool,
+
+    #[command(flatten)]
+    mod_caller_args: ModCallerArgs,
+}
+
+impl BamDmr {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        let all_bams = self
+            .control_bams
+            .iter()
+            .chain(self.exp_bams.iter())
+            .cloned()
+            .collect::<Vec<PathBuf>>();
+        check_bam_headers(&all_bams)?;
+        let header = bam::IndexedReader::from_path(&self.control_bams[0])
+            .map(|reader| reader.header().to_owned())
+            .context("failed to open input BAM, must be sorted and indexed")?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .context("failed to make threadpool")?;
+        let mpb = MultiProgress::new();
+        if self.suppress_progress {
+            mpb.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+
+        let writer: Box<dyn Write> = match self.out_path.as_ref() {
+            None => Box::new(BufWriter::new(std::io::stdout())),
+            Some(fp) => {
+                let p = Path::new(fp);
+                create_out_directory(p)?;
+                if p.exists() && !self.force {
+                    bail!("refusing to overwrite existing file {}", fp)
+                } else {
+                    let fh = File::create(p)?;
+                    Box::new(BufWriter::new(fh))
+                }
+            }
+        };
+
+        let edge_filter = self.mod_caller_args.edge_filter()?;
+        let collapse_method = self.mod_caller_args.collapse_method()?;
+        let pileup_options = match collapse_method.as_ref() {
+            Some(method) => PileupNumericOptions::Collapse(method.clone()),
+            None => PileupNumericOptions::Passthrough,
+        };
+
+        let regex_motif = match (&self.motif, self.cpg) {
+            (Some(raw_motif_parts), _) => {
+                RegexMotif::from_raw_parts(raw_motif_parts, false)?
+                    .into_iter()
+                    .next()
+                    .ok_or(anyhow!("illegal number of parts for motif"))?
+            }
+            (None, true) => RegexMotif::parse_string("CG", 0).unwrap(),
+            (None, false) => bail!("need to specify either --motif or --cpg"),
+        };
+        let targets = get_targets(&header, None);
+        let names_to_tid = targets
+            .iter()
+            .map(|target| (target.name.as_str(), target.tid))
+            .collect::<HashMap<&str, u32>>();
+        let motif_locations = pool.install(|| {
+            MotifLocations::from_fasta(
+                &self.reference_fasta,
+                regex_motif,
+                &names_to_tid,
+                self.mask,
+                None,
+                &mpb,
+            )
+        })?;
+        let motif_locations =
+            MultipleMotifLocations::new(vec![motif_locations]);
+
+        let failures = mpb.add(get_ticker());
+        failures.set_message("regions failed to process");
+        let rois = parse_roi_bed(&self.regions_bed)?;
+        info!("loaded {} regions", rois.len());
+        let work = rois
+            .into_iter()
+            .filter_map(|roi| {
+                let tid = header.tid(roi.chrom.as_bytes());
+                if tid.is_none() {
+                    debug!("{} not found in BAM header", &roi.chrom);
+                    failures.inc(1);
+                }
+                tid.map(|tid| (tid, roi))
+            })
+            .collect::<Vec<(u32, DmrInterval)>>();
+
+        let threshold_caller = self.mod_caller_args.build_caller(
+            &all_bams,
+            &pool,
+            None,
+            edge_filter.as_ref(),
+            collapse_method.as_ref(),
+            None,
+            self.suppress_progress,
+        )?;
+        let params = BamPileupParams::new(
+            threshold_caller,
+            pileup_options,
+            self.mod_caller_args.force_allow_implicit,
+            self.max_depth,
+            Some(motif_locations),
+            edge_filter,
+            self.min_valid_coverage,
+        );
+
+        let batch_size = self
+            .batch_size
+            .unwrap_or_else(|| (self.threads as f32 * 1.5f32).floor() as usize);
+        info!("processing {batch_size} regions at a time");
+        let pb = mpb.add(get_master_progress_bar(work.len()));
+        pb.set_message("regions processed");
+
+        let mut writer = QValueWriter::new(
+            writer,
+            &temp_dir_for_output(self.out_path.as_ref()),
+        )?;
+        for batch in work.chunks(batch_size.max(1)) {
+            let results = pool.install(|| {
+                batch
+                    .par_iter()
+                    .map(|(tid, region)| {
+                        process_bam_region(
+                            &self.control_bams,
+                            &self.exp_bams,
+                            *tid,
+                            region,
+                            &params,
+                        )
+                    })
+                    .collect::<Vec<anyhow::Result<ModificationCounts>>>()
+            });
+            for result in results {
+                match result {
+                    Ok(counts) => writer.write_counts(&counts, &[])?,
+                    Err(e) => {
+                        debug!("region failed, error: {e}");
+                        failures.inc(1);
+                    }
+                }
+                pb.inc(1);
+            }
+        }
+        let rows_written = writer.finish()?;
+
+        info!(
+            "wrote {rows_written} rows, {} regions failed",
+            failures.position()
+        );
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct GroupDmr {
+    /// Bgzipped bedMethyl file for a replicate of the first (usually control)
+    /// group, repeat for each replicate. There should be a tabix index with the
+    /// same name and .tbi next to each file.
+    #[arg(short = 'a', required = true)]
+    control_bed_methyls: Vec<PathBuf>,
+    /// Bgzipped bedMethyl file for a replicate of the second (usually
+    /// experimental) group, repeat for each replicate. There should be a tabix
+    /// index with the same name and .tbi next to each file.
+    #[arg(short = 'b', required = true)]
+    exp_bed_methyls: Vec<PathBuf>,
+    /// Path to file to direct output, optional, no argument will direct output to stdout.
+    #[arg(short = 'o', long)]
+    out_path: Option<String>,
+    /// BED file of regions over which to compare methylation levels. Should be tab-separated (spaces
+    /// allowed in the "name" column). Requires chrom, chromStart and chromEnd. The Name column is
+    /// optional. Strand is currently ignored. When omitted, methylation levels are compared at
+    /// each site in the first `-a` bedMethyl file.
+    #[arg(long, short = 'r', alias = "regions")]
+    regions_bed: Option<PathBuf>,
+    /// Path to reference fasta for the pileup.
+    #[arg(long = "ref")]
+    reference_fasta: PathBuf,
+    /// Bases to use to calculate DMR, may be multiple. For example, to calculate
+    /// differentially methylated regions using only cytosine modifications use --base C.
+    #[arg(short, alias = "base")]
+    modified_bases: Vec<char>,
+    /// File to write logs to, it's recommended to use this option.
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Number of threads to use.
+    #[arg(short = 't', long, default_value_t = 4)]
+    threads: usize,
+    /// Control the  batch size. The batch size is the number of regions to load at a time. Each
+    /// region will be processed concurrently. Loading more regions at a time will decrease
+    /// IO to load data, but will use more memory. Default will be 50% more than the number of
+    /// threads assigned.
+    #[arg(long, alias = "batch")]
+    batch_size: Option<usize>,
+    /// Respect soft masking in the reference FASTA.
+    #[arg(long, short = 'k', default_value_t = false)]
+    mask: bool,
+    /// Don't show progress bars
+    #[arg(long, default_value_t = false)]
+    suppress_progress: bool,
+    /// Force overwrite of output file, if it already exists.
+    #[arg(short = 'f', long, default_value_t = false)]
+    force: bool,
+    /// Minimum valid coverage required to use an entry from a bedMethyl. See the help for
+    /// pileup for the specification and description of valid coverage.
+    #[arg(long, alias = "min-coverage", default_value_t = 0)]
+    min_valid_coverage: u64,
+}
+
+impl GroupDmr {
+    fn load_replicates(fps: &[PathBuf]) -> anyhow::Result<Vec<Replicate>> {
+        fps.iter()
+            .map(|fp| {
+                if !fp.exists() {
+                    bail!("input file {fp:?} not found")
+                }
+                let (index, index_fp) = PairwiseDmr::load_index(fp, None)?;
+                let contig_lookup = ContigLookup::new(&index, &index_fp, None)?;
+                Ok(Replicate {
+                    bedmethyl_fp: fp.clone(),
+                    index,
+                    contig_lookup,
+                })
+            })
+            .collect()
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        PairwiseDmr::validate_modified_bases(&self.modified_bases)?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .context("failed to make threadpool")?;
+        let mpb = MultiProgress::new();
+        if self.suppress_progress {
+            mpb.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+
+        let control_replicates =
+            Self::load_replicates(&self.control_bed_methyls)?;
+        let exp_replicates = Self::load_replicates(&self.exp_bed_methyls)?;
+        info!(
+            "comparing {} control replicates to {} experiment replicates",
+            control_replicates.len(),
+            exp_replicates.len()
+        );
+
+        let writer: Box<dyn Write> = match self.out_path.as_ref() {
+            None => Box::new(BufWriter::new(std::io::stdout())),
+            Some(fp) => {
+                let p = Path::new(fp);
+                create_out_directory(p)?;
+                if p.exists() && !self.force {
+                    bail!("refusing to overwrite existing file {}", fp)
+                } else {
+                    let fh = File::create(p)?;
+                    Box::new(BufWriter::new(fh))
+                }
+            }
+        };
+
+        let motifs = self
+            .modified_bases
+            .iter()
+            .map(|c| DnaBase::parse(*c))
+            .collect::<anyhow::Result<Vec<DnaBase>>>()?;
+        // chrom ids from the first control replicate are used to look up
+        // positions in the reference
+        let chrom_lookup = Arc::new(ContigLookup::new(
+            &control_replicates[0].index,
+            &self.control_bed_methyls[0],
+            None,
+        )?);
+        let position_filter = PairwiseDmr::get_stranded_position_filter(
+            &self.reference_fasta,
+            self.mask,
+            chrom_lookup.clone(),
+            &mpb,
+            &motifs,
+        )?;
+
+        let (regions, what) = if let Some(roi_bed) = self.regions_bed.as_ref() {
+            let rois = parse_roi_bed(roi_bed)?;
+            info!("loaded {} regions", rois.len());
+            (rois, "regions")
+        } else {
+            info!("loading sites from first 'a' bedMethyl");
+            let regions =
+                load_regions_from_bedmethyl(&self.control_bed_methyls[0])?;
+            info!("loaded {} sites", regions.len());
+            (regions, "sites")
+        };
+
+        let batch_size = self
+            .batch_size
+            .unwrap_or_else(|| (self.threads as f32 * 1.5f32).floor() as usize);
+        info!("loading {batch_size} {what} at a time");
+        let pb = mpb.add(get_master_progress_bar(regions.len()));
+        pb.set_message(format!("{what} processed"));
+        let failures = mpb.add(get_ticker());
+        failures.set_message(format!("{what} failed to process"));
+
+        let mut writer = QValueWriter::new(
+            writer,
+            &temp_dir_for_output(self.out_path.as_ref()),
+        )?;
+        let mut success_count = 0usize;
+        for batch in regions.chunks(batch_size.max(1)) {
+            let results = pool.install(|| {
+                process_group_batch(
+                    batch,
+                    &control_replicates,
+                    &exp_replicates,
+                    &chrom_lookup,
+                    &position_filter,
+                    self.min_valid_coverage,
+                )
+            });
+            match results {
+                Ok(results) => {
+                    for result in results {
+                        match result {
+                            Ok(group_result) => {
+                                let extra_columns =
+                                    group_result.test.extra_columns();
+                                writer.write_counts(
+                                    &group_result.counts,
+                                    &extra_columns
+                                        .iter()
+                                        .map(|c| c.as_str())
+                                        .collect::<Vec<&str>>(),
+                                )?;
+                                success_count += 1;
+                            }
+                            Err(e) => {
+                                debug!("region failed, error: {e}");
+                                failures.inc(1);
+                            }
+                        }
+                        pb.inc(1);
+                    }
+                }
+                Err(e) => {
+                    debug!("failed entire dmr batch, {e}");
+                    failures.inc(batch.len() as u64);
+                    pb.inc(batch.len() as u64);
+                }
+            }
+        }
+        pb.finish_and_clear();
+        writer.finish()?;
+
+        info!(
+            "{} {what} processed successfully and {} {what} failed",
+            success_count,
+            failures.position()
+        );
+
+        Ok(())
+    }
+}
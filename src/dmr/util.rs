@@ -44,10 +44,10 @@ impl Display for HandleMissing {
 }
 
 #[derive(new, Clone, Debug, Eq, PartialEq)]
-pub(super) struct DmrInterval {
-    pub(super) interval: Iv,
-    pub(super) chrom: String,
-    pub(super) name: String,
+pub(crate) struct DmrInterval {
+    pub(crate) interval: Iv,
+    pub(crate) chrom: String,
+    pub(crate) name: String,
 }
 
 impl DmrInterval {
@@ -86,17 +86,17 @@ impl DmrInterval {
         ))
     }
 
-    pub(super) fn parse_str(line: &str) -> anyhow::Result<Self> {
+    pub(crate) fn parse_str(line: &str) -> anyhow::Result<Self> {
         Self::parse_bed_line(line)
             .map(|(_, this)| this)
             .map_err(|e| anyhow!("{}", e.to_string()))
     }
 
-    pub(super) fn start(&self) -> u64 {
+    pub(crate) fn start(&self) -> u64 {
         self.interval.start
     }
 
-    pub(super) fn stop(&self) -> u64 {
+    pub(crate) fn stop(&self) -> u64 {
         self.interval.stop
     }
 
@@ -336,6 +336,16 @@ impl DmrBatch {
     }
 }
 
+/// Merge overlapping index chunks so that each part of the bedMethyl is only
+/// read once.
+pub(super) fn merge_index_chunks(chunks: Vec<IndexChunk>) -> Vec<IndexChunk> {
+    let chunks = chunks
+        .into_iter()
+        .map(ProtoIndexChunk::from)
+        .collect::<BTreeSet<ProtoIndexChunk>>();
+    DmrBatch::proto_iter(&chunks)
+}
+
 impl Iterator for DmrIntervalIter {
     type Item = DmrBatch;
 
@@ -419,7 +429,7 @@ impl Iterator for DmrIntervalIter {
     }
 }
 
-pub(super) fn parse_roi_bed<P: AsRef<Path>>(
+pub(crate) fn parse_roi_bed<P: AsRef<Path>>(
     fp: P,
 ) -> anyhow::Result<Vec<DmrInterval>> {
     let intervals = BufReader::new(File::open(fp)?)
@@ -444,14 +454,14 @@ pub(super) fn parse_roi_bed<P: AsRef<Path>>(
     }
 }
 
-pub(super) struct ContigLookup {
+pub(crate) struct ContigLookup {
     pub(super) sample_name: Option<String>,
     pub(super) file_path: PathBuf,
-    pub(super) inner: HashMap<String, usize>,
+    pub(crate) inner: HashMap<String, usize>,
 }
 
 impl ContigLookup {
-    pub(super) fn new(
+    pub(crate) fn new(
         index: &CsiIndex,
         index_fp: &PathBuf,
         sample_name: Option<&String>,
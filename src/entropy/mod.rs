@@ -0,0 +1,331 @@
+use std::path::Path;
+
+use derive_new::new;
+use log::debug;
+use rust_htslib::bam::{self, ext::BamRecordExtensions, FetchDefinition, Read};
+use rustc_hash::FxHashMap;
+
+use crate::mod_bam::{BaseModCall, CollapseMethod, EdgeFilter};
+use crate::mod_base_code::{DnaBase, ModCodeRepr};
+use crate::motif_bed::MotifLocations;
+use crate::read_cache::ReadCache;
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::util::{record_is_secondary, Strand, StrandRule};
+
+pub mod subcommand;
+mod writer;
+
+/// The state of a single motif site in a single read, filtered calls are
+/// not represented, the read is simply removed from windows containing the
+/// filtered site.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+enum SiteState {
+    Canonical,
+    Modified(ModCodeRepr),
+}
+
+impl SiteState {
+    fn from_base_mod_call(base_mod_call: BaseModCall) -> Option<Self> {
+        match base_mod_call {
+            BaseModCall::Canonical(_) => Some(Self::Canonical),
+            BaseModCall::Modified(_, mod_code) => {
+                Some(Self::Modified(mod_code))
+            }
+            BaseModCall::Filtered => None,
+        }
+    }
+}
+
+/// Sorted motif positions on each strand of a single contig, built once
+/// per contig so that windows of consecutive sites can be found with a
+/// binary search.
+pub(crate) struct MotifSites {
+    positive: Vec<u32>,
+    negative: Vec<u32>,
+}
+
+impl MotifSites {
+    pub(crate) fn new(motif_locations: &MotifLocations, tid: u32) -> Self {
+        let (mut positive, mut negative) =
+            motif_locations.get_locations_unchecked(tid).iter().fold(
+                (Vec::new(), Vec::new()),
+                |(mut pos, mut neg), (position, strand_rule)| {
+                    match strand_rule {
+                        StrandRule::Positive => pos.push(*position),
+                        StrandRule::Negative => neg.push(*position),
+                        StrandRule::Both => {
+                            pos.push(*position);
+                            neg.push(*position);
+                        }
+                    }
+                    (pos, neg)
+                },
+            );
+        positive.sort();
+        negative.sort();
+        Self { positive, negative }
+    }
+
+    fn get(&self, strand: Strand) -> &[u32] {
+        match strand {
+            Strand::Positive => &self.positive,
+            Strand::Negative => &self.negative,
+        }
+    }
+
+    /// Indices into the sites for `strand` of windows that _start_ in the
+    /// interval [start, end). Windows may extend past `end`, windows that
+    /// would run off the end of the contig are omitted.
+    fn window_starts(
+        &self,
+        strand: Strand,
+        start: u32,
+        end: u32,
+        num_positions: usize,
+    ) -> std::ops::Range<usize> {
+        let sites = self.get(strand);
+        let first = sites.partition_point(|&p| p < start);
+        let last = sites.partition_point(|&p| p < end);
+        let n_windows = sites.len().saturating_sub(num_positions - 1);
+        first.min(n_windows)..last.min(n_windows)
+    }
+}
+
+/// Entropy of the patterns of modification states observed across reads
+/// spanning `num_positions` consecutive motif sites.
+#[derive(new, Debug)]
+pub(crate) struct WindowEntropy {
+    /// Reference position of the first site in the window
+    pub(crate) start: u32,
+    /// Reference position of the last site in the window, plus 1
+    pub(crate) end: u32,
+    pub(crate) strand: Strand,
+    pub(crate) entropy: f32,
+    pub(crate) num_reads: u32,
+    pub(crate) num_patterns: u32,
+}
+
+pub(crate) struct RegionEntropy {
+    pub(crate) chrom_name: String,
+    pub(crate) windows: Vec<WindowEntropy>,
+    pub(crate) processed_records: usize,
+    pub(crate) skipped_records: usize,
+}
+
+/// Methylation entropy (Xie et al. 2011) of a set of pattern counts,
+/// normalized by the number of sites in the window:
+/// `1/n * sum_i(-p_i * log2(p_i))`.
+fn calc_entropy<'a>(
+    pattern_counts: impl Iterator<Item = &'a u32>,
+    num_reads: u32,
+    num_positions: usize,
+) -> f32 {
+    let total = num_reads as f32;
+    let sum = pattern_counts
+        .map(|&count| {
+            let p = count as f32 / total;
+            p * (1f32 / p).log2()
+        })
+        .sum::<f32>();
+    sum / num_positions as f32
+}
+
+/// Settings that are the same for every region, how the windows are made
+/// and how reads are called.
+#[derive(new)]
+pub(crate) struct EntropyParams {
+    motif_base: DnaBase,
+    num_positions: usize,
+    min_coverage: u32,
+    caller: MultipleThresholdModCaller,
+    collapse_method: Option<CollapseMethod>,
+    edge_filter: Option<EdgeFilter>,
+    force_allow: bool,
+}
+
+pub(crate) fn process_region_entropy<T: AsRef<Path>>(
+    bam_fp: T,
+    chrom_tid: u32,
+    start_pos: u32,
+    end_pos: u32,
+    motif_sites: &MotifSites,
+    params: &EntropyParams,
+) -> Result<RegionEntropy, String> {
+    let motif_base = params.motif_base;
+    let num_positions = params.num_positions;
+    let mut bam_reader =
+        bam::IndexedReader::from_path(bam_fp).map_err(|e| e.to_string())?;
+    let chrom_name =
+        String::from_utf8_lossy(bam_reader.header().tid2name(chrom_tid))
+            .to_string();
+
+    let strands = [Strand::Positive, Strand::Negative];
+    let window_starts = strands.map(|strand| {
+        motif_sites.window_starts(strand, start_pos, end_pos, num_positions)
+    });
+    // fetch the span of all of the windows that start in this region, this
+    // will usually extend a little past the end of the interval
+    let fetch_end = strands
+        .iter()
+        .zip(window_starts.iter())
+        .filter(|(_, starts)| !starts.is_empty())
+        .map(|(strand, starts)| {
+            motif_sites.get(*strand)[starts.end - 1 + num_positions - 1] + 1
+        })
+        .max();
+    let fetch_end = if let Some(fetch_end) = fetch_end {
+        fetch_end
+    } else {
+        return Ok(RegionEntropy {
+            chrom_name,
+            windows: Vec::new(),
+            processed_records: 0,
+            skipped_records: 0,
+        });
+    };
+    bam_reader
+        .fetch(FetchDefinition::Region(
+            chrom_tid as i32,
+            start_pos as i64,
+            fetch_end as i64,
+        ))
+        .map_err(|e| e.to_string())?;
+
+    let mut read_cache = ReadCache::new(
+        params.collapse_method.as_ref(),
+        &params.caller,
+        params.edge_filter.as_ref(),
+        params.force_allow,
+    );
+    // window index (into the sites for the strand) to pattern counts
+    let mut pattern_counts = [
+        FxHashMap::<usize, FxHashMap<Vec<SiteState>, u32>>::default(),
+        FxHashMap::<usize, FxHashMap<Vec<SiteState>, u32>>::default(),
+    ];
+
+    for record in bam_reader.records().filter_map(|r| r.ok()) {
+        if record.is_unmapped()
+            || record_is_secondary(&record)
+            || record.seq_len() == 0
+        {
+            continue;
+        }
+        let (strand_idx, expected_ref_base) = if record.is_reverse() {
+            (1usize, motif_base.complement())
+        } else {
+            (0usize, motif_base)
+        };
+        let starts = &window_starts[strand_idx];
+        if starts.is_empty() {
+            continue;
+        }
+        let sites = motif_sites.get(strands[strand_idx]);
+        let first_site = starts.start;
+        let last_site = starts.end - 1 + num_positions - 1;
+        let (lo, hi) = (sites[first_site], sites[last_site]);
+
+        let seq = record.seq();
+        let aligned_ref_base = record
+            .aligned_pairs()
+            .filter(|[_, r_pos]| *r_pos >= lo as i64 && *r_pos <= hi as i64)
+            .filter_map(|[q_pos, r_pos]| {
+                DnaBase::parse(seq[q_pos as usize] as char)
+                    .ok()
+                    .map(|base| (r_pos as u32, base))
+            })
+            .collect::<FxHashMap<u32, DnaBase>>();
+        if aligned_ref_base.is_empty() {
+            continue;
+        }
+
+        let states = (first_site..=last_site)
+            .map(|site_idx| {
+                let pos = sites[site_idx];
+                match aligned_ref_base.get(&pos) {
+                    Some(base) if *base == expected_ref_base => read_cache
+                        .get_mod_call(&record, pos, motif_base.char())
+                        .0
+                        .and_then(SiteState::from_base_mod_call),
+                    _ => None,
+                }
+            })
+            .collect::<Vec<Option<SiteState>>>();
+
+        for (offset, window) in states.windows(num_positions).enumerate() {
+            let pattern =
+                window.iter().copied().collect::<Option<Vec<SiteState>>>();
+            if let Some(pattern) = pattern {
+                *pattern_counts[strand_idx]
+                    .entry(first_site + offset)
+                    .or_insert(FxHashMap::default())
+                    .entry(pattern)
+                    .or_insert(0) += 1;
+            }
+        }
+    }
+
+    let mut windows = strands
+        .iter()
+        .zip(pattern_counts)
+        .flat_map(|(strand, counts)| {
+            let sites = motif_sites.get(*strand);
+            counts.into_iter().filter_map(move |(site_idx, patterns)| {
+                let num_reads = patterns.values().sum::<u32>();
+                if num_reads < params.min_coverage {
+                    None
+                } else {
+                    let entropy = calc_entropy(
+                        patterns.values(),
+                        num_reads,
+                        num_positions,
+                    );
+                    Some(WindowEntropy::new(
+                        sites[site_idx],
+                        sites[site_idx + num_positions - 1] + 1,
+                        *strand,
+                        entropy,
+                        num_reads,
+                        patterns.len() as u32,
+                    ))
+                }
+            })
+        })
+        .collect::<Vec<WindowEntropy>>();
+    windows.sort_by(|a, b| {
+        a.start
+            .cmp(&b.start)
+            .then(a.strand.to_char().cmp(&b.strand.to_char()))
+    });
+
+    let (processed_records, skipped_records) =
+        read_cache.get_records_used_and_skipped();
+    debug!(
+        "processed {processed_records} reads, skipped {skipped_records} on \
+        {chrom_name}:{start_pos}-{end_pos}"
+    );
+
+    Ok(RegionEntropy {
+        chrom_name,
+        windows,
+        processed_records,
+        skipped_records,
+    })
+}
+
+#[cfg(test)]
+mod entropy_tests {
+    use crate::entropy::calc_entropy;
+
+    #[test]
+    fn test_calc_entropy() {
+        // all reads have the same pattern
+        let counts = [10u32];
+        assert_eq!(calc_entropy(counts.iter(), 10, 4), 0f32);
+        // 16 equally likely patterns over 4 sites is maximum entropy
+        let counts = [2u32; 16];
+        assert_eq!(calc_entropy(counts.iter(), 32, 4), 1f32);
+        // two equally likely patterns
+        let counts = [5u32, 5u32];
+        assert_eq!(calc_entropy(counts.iter(), 10, 4), 0.25f32);
+    }
+}
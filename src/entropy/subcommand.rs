@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context};
+use clap::Args;
+use crossbeam_channel::bounded;
+use indicatif::{MultiProgress, ParallelProgressIterator};
+use log::{debug, error, info};
+use rayon::prelude::*;
+use rust_htslib::bam::{self, Read};
+
+use crate::command_utils::ModCallerArgs;
+use crate::entropy::writer::EntropyWriter;
+use crate::entropy::{
+    process_region_entropy, EntropyParams, MotifSites, RegionEntropy,
+};
+use crate::interval_chunks::IntervalChunks;
+use crate::logging::init_logging;
+use crate::mod_base_code::DnaBase;
+use crate::motif_bed::{MotifLocations, MultipleMotifLocations, RegexMotif};
+use crate::util::{
+    create_out_directory, get_master_progress_bar, get_subroutine_progress_bar,
+    get_targets, get_ticker, Region,
+};
+use crate::writers::OutWriter;
+
+#[derive(Args)]
+pub struct MethylationEntropy {
+    // running args
+    /// Input BAM, should be sorted and have associated index available.
+    in_bam: PathBuf,
+    /// Output file to write results into. Specify "-" or "stdout" to direct
+    /// output to stdout.
+    out_bed: String,
+    /// Specify a file for debug logs to be written to, otherwise ignore them.
+    /// Setting a file is recommended. (alias: log)
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Process only the specified region of the BAM when calculating entropy.
+    /// Format should be <chrom_name>:<start>-<end> or <chrom_name>. Commas are allowed.
+    #[arg(long)]
+    region: Option<String>,
+    /// Reference sequence in FASTA format, required to find motif sites.
+    #[arg(long = "ref", alias = "reference", short = 'r')]
+    reference_fasta: PathBuf,
+    /// Calculate entropy over windows of CpG sites, short hand for --motif CG 0.
+    #[arg(long, group = "motif_options", default_value_t = false)]
+    cpg: bool,
+    /// Calculate entropy over windows of this sequence motif. The first argument should
+    /// be the sequence motif and the second argument is the 0-based offset to the base
+    /// to use. For example: --motif CGCG 0 indicates to use the first C on the top
+    /// strand and the last C (complement to G) on the bottom strand.
+    #[arg(long, group = "motif_options", num_args = 2)]
+    motif: Option<Vec<String>>,
+    /// Respect soft masking in the reference FASTA.
+    #[arg(long, short = 'k', default_value_t = false, hide_short_help = true)]
+    mask: bool,
+    /// Number of consecutive motif sites in each window, the entropy is calculated
+    /// over the patterns of modification states at these sites.
+    #[arg(long, short = 'w', default_value_t = 4)]
+    num_positions: usize,
+    /// Minimum number of reads that must span all sites of a window with
+    /// passing modification calls for the window to be reported.
+    #[arg(long, short = 'm', default_value_t = 4)]
+    min_coverage: u32,
+
+    // processing args
+    /// Number of threads to use while processing chunks concurrently.
+    #[arg(short, long, default_value_t = 4)]
+    threads: usize,
+    /// Interval chunk size in base pairs to process concurrently. Smaller
+    /// interval chunk sizes will use less memory but incur more overhead.
+    #[arg(
+        short = 'i',
+        long,
+        default_value_t = 100_000,
+        hide_short_help = true
+    )]
+    interval_size: u32,
+    /// Hide the progress bar.
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    suppress_progress: bool,
+
+    #[command(flatten)]
+    mod_caller_args: ModCallerArgs,
+}
+
+impl MethylationEntropy {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        let header = bam::IndexedReader::from_path(&self.in_bam)
+            .map(|reader| reader.header().to_owned())
+            .context("failed to open input BAM, must be sorted and indexed")?;
+
+        if self.num_positions < 2 {
+            bail!("num positions must be at least 2")
+        }
+        let region = self
+            .region
+            .as_ref()
+            .map(|raw_region| {
+                info!("parsing region {raw_region}");
+                Region::parse_str(raw_region, &header)
+            })
+            .transpose()?;
+        let edge_filter = self.mod_caller_args.edge_filter()?;
+        let collapse_method = self.mod_caller_args.collapse_method()?;
+
+        let regex_motif = match (&self.motif, self.cpg) {
+            (Some(raw_motif_parts), _) => {
+                RegexMotif::from_raw_parts(raw_motif_parts, false)?
+                    .into_iter()
+                    .next()
+                    .ok_or(anyhow!("illegal number of parts for motif"))?
+            }
+            (None, true) => RegexMotif::parse_string("CG", 0).unwrap(),
+            (None, false) => bail!("need to specify either --motif or --cpg"),
+        };
+        let motif_base = regex_motif
+            .raw_motif
+            .chars()
+            .nth(regex_motif.forward_offset)
+            .ok_or(anyhow!("motif offset is out of bounds"))
+            .and_then(DnaBase::parse)
+            .context("motif base must be one of A, C, G, or T")?;
+
+        let out_fp_str = self.out_bed.clone();
+        let mut writer: Box<dyn OutWriter<RegionEntropy>> =
+            match out_fp_str.as_str() {
+                "stdout" | "-" => {
+                    let writer = BufWriter::new(std::io::stdout());
+                    Box::new(EntropyWriter::new(writer))
+                }
+                _ => {
+                    create_out_directory(&out_fp_str)?;
+                    let fh = std::fs::File::create(out_fp_str)
+                        .context("failed to make output file")?;
+                    Box::new(EntropyWriter::new(BufWriter::new(fh)))
+                }
+            };
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .with_context(|| "failed to make threadpool")?;
+
+        let master_progress = MultiProgress::new();
+        if self.suppress_progress {
+            master_progress
+                .set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+
+        let tids = get_targets(&header, region.as_ref());
+        let names_to_tid = tids
+            .iter()
+            .map(|target| (target.name.as_str(), target.tid))
+            .collect::<HashMap<&str, u32>>();
+        let motif_locations = pool.install(|| {
+            MotifLocations::from_fasta(
+                &self.reference_fasta,
+                regex_motif,
+                &names_to_tid,
+                self.mask,
+                None,
+                &master_progress,
+            )
+        })?;
+        let tids = motif_locations.filter_reference_records(tids);
+        let motif_locations =
+            MultipleMotifLocations::new(vec![motif_locations]);
+
+        let threshold_caller = self.mod_caller_args.build_caller(
+            std::slice::from_ref(&self.in_bam),
+            &pool,
+            region.as_ref(),
+            edge_filter.as_ref(),
+            collapse_method.as_ref(),
+            None,
+            self.suppress_progress,
+        )?;
+
+        let (snd, rx) = bounded(1_000);
+        let in_bam_fp = self.in_bam.clone();
+        let interval_size = self.interval_size;
+        let params = EntropyParams::new(
+            motif_base,
+            self.num_positions,
+            self.min_coverage,
+            threshold_caller,
+            collapse_method,
+            edge_filter,
+            self.mod_caller_args.force_allow_implicit,
+        );
+
+        let tid_progress =
+            master_progress.add(get_master_progress_bar(tids.len()));
+        tid_progress.set_message("contigs");
+        let write_progress = master_progress.add(get_ticker());
+        write_progress.set_message("windows written");
+        let skipped_reads = master_progress.add(get_ticker());
+        skipped_reads.set_message("~records skipped");
+        let processed_reads = master_progress.add(get_ticker());
+        processed_reads.set_message("~records processed");
+
+        std::thread::spawn(move || {
+            pool.install(|| {
+                for target in tids {
+                    let motif_sites = MotifSites::new(
+                        &motif_locations.motif_locations[0],
+                        target.tid,
+                    );
+                    let intervals = IntervalChunks::new_with_multiple_motifs(
+                        target.start,
+                        target.length,
+                        interval_size,
+                        target.tid,
+                        Some(&motif_locations),
+                    )
+                    .collect::<Vec<(u32, u32)>>();
+                    let interval_progress = master_progress
+                        .add(get_subroutine_progress_bar(intervals.len()));
+                    interval_progress
+                        .set_message(format!("processing {}", &target.name));
+                    let results = intervals
+                        .into_par_iter()
+                        .progress_with(interval_progress)
+                        .map(|(start, end)| {
+                            process_region_entropy(
+                                &in_bam_fp,
+                                target.tid,
+                                start,
+                                end,
+                                &motif_sites,
+                                &params,
+                            )
+                        })
+                        .collect::<Vec<Result<RegionEntropy, String>>>();
+                    for result in results {
+                        if let Err(e) = snd.send(result) {
+                            error!("failed to send results, {e}")
+                        }
+                    }
+                    tid_progress.inc(1);
+                }
+                tid_progress.finish_and_clear();
+            });
+        });
+
+        for result in rx.into_iter() {
+            match result {
+                Ok(region_entropy) => {
+                    processed_reads
+                        .inc(region_entropy.processed_records as u64);
+                    skipped_reads.inc(region_entropy.skipped_records as u64);
+                    let rows_written = writer.write(region_entropy)?;
+                    write_progress.inc(rows_written);
+                }
+                Err(message) => {
+                    debug!("unexpected error {message}");
+                }
+            }
+        }
+        let rows_processed = write_progress.position();
+        let n_skipped_reads = skipped_reads.position();
+        let n_processed_reads = processed_reads.position();
+        write_progress.finish_and_clear();
+        processed_reads.finish_and_clear();
+        skipped_reads.finish_and_clear();
+        info!(
+            "Done, wrote {rows_processed} windows. Processed ~{n_processed_reads} \
+            reads and skipped ~{n_skipped_reads} reads."
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,46 @@
+use std::io::{BufWriter, Write};
+
+use anyhow::Result as AnyhowResult;
+
+use crate::entropy::RegionEntropy;
+use crate::writers::OutWriter;
+
+/// Writes bedGraph-like rows: chrom, start, end, entropy, strand, number of
+/// reads, number of distinct patterns.
+pub(crate) struct EntropyWriter<T: Write> {
+    buf_writer: BufWriter<T>,
+}
+
+impl<T: Write> EntropyWriter<T> {
+    pub(crate) fn new(buf_writer: BufWriter<T>) -> Self {
+        Self { buf_writer }
+    }
+}
+
+impl<T: Write> OutWriter<RegionEntropy> for EntropyWriter<T> {
+    fn write(&mut self, item: RegionEntropy) -> AnyhowResult<u64> {
+        let tab = '\t';
+        let chrom_name = &item.chrom_name;
+        let mut rows_written = 0u64;
+        for window in item.windows {
+            let row = format!(
+                "{chrom_name}{tab}\
+                {}{tab}\
+                {}{tab}\
+                {}{tab}\
+                {}{tab}\
+                {}{tab}\
+                {}\n",
+                window.start,
+                window.end,
+                window.entropy,
+                window.strand.to_char(),
+                window.num_reads,
+                window.num_patterns,
+            );
+            self.buf_writer.write_all(row.as_bytes())?;
+            rows_written += 1;
+        }
+        Ok(rows_written)
+    }
+}
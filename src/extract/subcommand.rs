@@ -19,7 +19,11 @@ use crate::command_utils::{
     parse_per_mod_thresholds, parse_thresholds, using_stream,
 };
 use crate::errs::RunError;
-use crate::extract::writer::{OutwriterWithMemory, TsvWriterWithContigNames};
+use crate::extract::writer::{
+    projected_header_columns, tag_header_columns, OutwriterWithMemory,
+    ParquetWriterWithContigNames, ReadBed12Writer, ReadSummaryWriter,
+    TsvWriterWithContigNames,
+};
 use crate::interval_chunks::IntervalChunks;
 use crate::logging::init_logging;
 use crate::mod_bam::{CollapseMethod, EdgeFilter, TrackingModRecordIter};
@@ -33,12 +37,14 @@ use crate::read_ids_to_base_mod_probs::{
 use crate::reads_sampler::record_sampler::RecordSampler;
 use crate::reads_sampler::sample_reads_from_interval;
 use crate::reads_sampler::sampling_schedule::SamplingSchedule;
+use crate::record_filter::{RecordFilterArgs, RecordSelection};
 use crate::record_processor::WithRecords;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::transcriptome::TranscriptProjection;
 use crate::util::{
     get_master_progress_bar, get_reference_mod_strand, get_spinner,
-    get_subroutine_progress_bar, get_targets, get_ticker, ReferenceRecord,
-    Region, Strand,
+    get_subroutine_progress_bar, get_targets, get_ticker, parse_read_tags,
+    ReadTag, ReferenceRecord, Region, Strand,
 };
 use crate::writers::TsvWriter;
 
@@ -92,6 +98,30 @@ pub struct ExtractMods {
     /// a `--filter-threshold` value is passed to the command.
     #[arg(long, alias = "read-calls", hide_short_help = true)]
     read_calls_path: Option<PathBuf>,
+    /// Produce a table with one row per read summarizing the base modification calls
+    /// in the read: the alignment coordinates, read length, and for each mod code the
+    /// number of calls, mean probability, and fraction modified after thresholding.
+    /// Like --read-calls, the pass thresholds are estimated from the data unless a
+    /// `--filter-threshold` value is passed. Always written as TSV.
+    #[arg(long, alias = "read-summary", hide_short_help = true)]
+    read_summary_path: Option<PathBuf>,
+    /// Write the calls of each aligned read as a BED12 feature for viewing
+    /// in genome browsers. Each called reference site is a 1 bp block, the
+    /// score and item RGB of the feature are from the fraction of sites called
+    /// modified (blue for canonical to red for modified). Calls are made with
+    /// the pass thresholds as with --read-calls and respect the motif and
+    /// region options.
+    #[arg(long, alias = "bed12", hide_short_help = true)]
+    bed12_path: Option<PathBuf>,
+    /// With --bed12, write two features for each read, one with the modified
+    /// sites (red) and one with the canonical sites (blue).
+    #[arg(
+        long,
+        requires = "bed12_path",
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    bed12_split_states: bool,
 
     /// Path to reference FASTA to extract reference context information from.
     /// If no reference is provided, `ref_kmer` column will be "." in the output.
@@ -228,6 +258,9 @@ pub struct ExtractMods {
     /// 4 and last 8 bases.
     #[arg(long, requires = "edge_filter", default_value_t = false)]
     invert_edge_filter: bool,
+    // record filter args
+    #[command(flatten)]
+    record_filter_args: RecordFilterArgs,
 
     /// Ignore a modified base class  _in_situ_ by redistributing base modification
     /// probability equally across other options. For example, if collapsing 'h',
@@ -255,6 +288,32 @@ pub struct ExtractMods {
     /// details see the SAM spec: https://samtools.github.io/hts-specs/SAMtags.pdf.
     #[arg(long, hide_short_help = true)]
     ignore_implicit: bool,
+    /// GTF or GFF3 (optionally gzipped) annotation used to project the
+    /// positions of reads aligned to transcript sequences onto the genome.
+    /// The BAM reference sequences should be named by transcript ID. Adds
+    /// the columns genome_chrom, genome_position, genome_ref_mod_strand,
+    /// gene_id, and transcript_id to the output (and --read-calls) table.
+    #[arg(long, hide_short_help = true)]
+    annotation: Option<PathBuf>,
+    /// Write the output (and --read-calls) table to a Parquet file with typed
+    /// columns instead of TSV. String columns with few values (chrom,
+    /// strands, mod code, bases) are dictionary encoded and missing values
+    /// are null. Row groups are closed at interval chunk boundaries once
+    /// they have at least 10,000 rows.
+    #[arg(
+        long,
+        conflicts_with = "annotation",
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    parquet: bool,
+    /// Add the value of this SAM tag (e.g. HP, PS, RG, or CB) for each read
+    /// as a column of the output (and --read-calls) table, named by the tag.
+    /// Reads without the tag have "." (or null in Parquet output). Use MAPQ
+    /// or FLAG to add the mapping quality or the SAM flag of the record.
+    /// Can be passed multiple times.
+    #[arg(long, action = clap::ArgAction::Append)]
+    tag: Option<Vec<String>>,
 }
 
 type ReferenceAndIntervals = Vec<(ReferenceRecord, IntervalChunks)>;
@@ -456,6 +515,9 @@ impl ExtractMods {
         if self.kmer_size > 12 {
             bail!("kmer size must be less than or equal to 12")
         }
+        if self.parquet && matches!(self.out_path.as_str(), "stdout" | "-") {
+            bail!("cannot write parquet output to stdout")
+        }
 
         let pool =
             ThreadPoolBuilder::new().num_threads(self.threads).build()?;
@@ -472,6 +534,13 @@ impl ExtractMods {
             .as_ref()
             .map(|raw| parse_edge_filter_input(raw, self.invert_edge_filter))
             .transpose()?;
+        let record_filter = self.record_filter_args.to_record_filter()?;
+        let tags = self
+            .tag
+            .as_ref()
+            .map(|raw_tags| parse_read_tags(raw_tags))
+            .transpose()?
+            .unwrap_or_default();
 
         let mut reader = get_serial_reader(&self.in_bam)?;
         let header = reader.header().to_owned();
@@ -492,6 +561,17 @@ impl ExtractMods {
                 }
             })
             .collect::<HashMap<u32, String>>();
+        let projection = self
+            .annotation
+            .as_ref()
+            .map(|fp| {
+                let projection = TranscriptProjection::from_path(fp)?;
+                projection.check_references(
+                    &tid_to_name.values().collect::<Vec<&String>>(),
+                )?;
+                Ok::<_, anyhow::Error>(projection)
+            })
+            .transpose()?;
         let name_to_tid = tid_to_name
             .iter()
             .map(|(tid, name)| (name.as_str(), *tid))
@@ -543,7 +623,10 @@ impl ExtractMods {
                 &pool,
             )?;
 
-        let caller = if self.read_calls_path.is_some() {
+        let caller = if self.read_calls_path.is_some()
+            || self.read_summary_path.is_some()
+            || self.bed12_path.is_some()
+        {
             if self.no_filtering {
                 // need this here because input can be stdin
                 MultipleThresholdModCaller::new_passthrough()
@@ -567,7 +650,7 @@ impl ExtractMods {
                     }
                     pool.install(|| {
                         get_threshold_from_options(
-                            &in_bam,
+                            std::slice::from_ref(&in_bam),
                             self.threads,
                             self.sampling_interval_size,
                             self.sampling_frac,
@@ -580,7 +663,10 @@ impl ExtractMods {
                             edge_filter.as_ref(),
                             collapse_method.as_ref(),
                             reference_position_filter.include_pos.as_ref(),
-                            !reference_position_filter.include_unmapped,
+                            RecordSelection::new(
+                                !reference_position_filter.include_unmapped,
+                                record_filter.as_ref(),
+                            ),
                             self.suppress_progress,
                         )
                     })?
@@ -626,6 +712,7 @@ impl ExtractMods {
         let mapped_only = self.mapped_only;
         let in_bam = self.in_bam.clone();
         let kmer_size = self.kmer_size;
+        let writer_tags = tags.clone();
 
         thread::spawn(move || {
             pool.install(|| {
@@ -700,8 +787,9 @@ impl ExtractMods {
                                         collapse_method.as_ref(),
                                         edge_filter.as_ref(),
                                         None,
-                                        false,
+                                        RecordSelection::new(false, record_filter.as_ref()),
                                         Some(kmer_size),
+                                        &tags,
                                     ).map(|reads_base_mod_profile| {
                                         reference_position_filter.filter_read_base_mod_probs(reads_base_mod_profile)
                                     });
@@ -744,9 +832,10 @@ impl ExtractMods {
                                     n_unmapped_reads,
                                     collapse_method.as_ref(),
                                     edge_filter.as_ref(),
-                                    false,
+                                    RecordSelection::new(false, record_filter.as_ref()),
                                     "unmapped ",
                                         kmer_size,
+                                        &tags,
                                 );
                                 let _ = snd.send(Ok(ReadsBaseModProfile::new(Vec::new(), skip, fail)));
                             },
@@ -764,26 +853,84 @@ impl ExtractMods {
                         n_reads,
                         collapse_method.as_ref(),
                             edge_filter.as_ref(),
-                            mapped_only,
+                            RecordSelection::new(mapped_only, record_filter.as_ref()),
                             "",
                         kmer_size,
+                        &tags,
                     );
                     let _ = snd.send(Ok(ReadsBaseModProfile::new(Vec::new(), skip, fail)));
                 }
             })
         });
 
+        let header = if projection.is_some() {
+            format!(
+                "{}{}{}",
+                ModProfile::header(),
+                tag_header_columns(&writer_tags),
+                projected_header_columns()
+            )
+        } else {
+            format!(
+                "{}{}",
+                ModProfile::header(),
+                tag_header_columns(&writer_tags)
+            )
+        };
+        let mut read_summary_writer = self
+            .read_summary_path
+            .as_ref()
+            .map(|fp| {
+                ReadSummaryWriter::new(
+                    fp,
+                    tid_to_name.clone(),
+                    caller.clone(),
+                    &writer_tags,
+                    self.force,
+                )
+            })
+            .transpose()?;
+        let mut bed12_writer = self
+            .bed12_path
+            .as_ref()
+            .map(|fp| {
+                ReadBed12Writer::new(
+                    fp,
+                    tid_to_name.clone(),
+                    caller.clone(),
+                    self.bed12_split_states,
+                    self.force,
+                )
+            })
+            .transpose()?;
         let mut writer: Box<dyn OutwriterWithMemory<ReadsBaseModProfile>> =
             match self.out_path.as_str() {
+                out_path if self.parquet => {
+                    let out_path =
+                        (out_path != "null").then(|| Path::new(out_path));
+                    let writer = ParquetWriterWithContigNames::new(
+                        out_path,
+                        tid_to_name,
+                        chrom_to_seq,
+                        self.read_calls_path.as_ref(),
+                        caller,
+                        &writer_tags,
+                        self.force,
+                    )?;
+                    Box::new(writer)
+                }
                 "stdout" | "-" => {
-                    let tsv_writer =
-                        TsvWriter::new_stdout(Some(ModProfile::header()));
+                    let tsv_writer = TsvWriter::new_stdout(Some(header));
                     let writer = TsvWriterWithContigNames::new(
                         tsv_writer,
                         tid_to_name,
                         chrom_to_seq,
-                        self.read_calls_path.as_ref(),
                         caller,
+                        projection,
+                    )
+                    .with_read_calls(
+                        self.read_calls_path.as_ref(),
+                        &writer_tags,
                         self.force,
                     )?;
                     Box::new(writer)
@@ -794,8 +941,12 @@ impl ExtractMods {
                         tsv_writer,
                         tid_to_name,
                         chrom_to_seq,
-                        self.read_calls_path.as_ref(),
                         caller,
+                        projection,
+                    )
+                    .with_read_calls(
+                        self.read_calls_path.as_ref(),
+                        &writer_tags,
                         self.force,
                     )?;
                     Box::new(writer)
@@ -804,14 +955,18 @@ impl ExtractMods {
                     let tsv_writer = TsvWriter::new_file(
                         &self.out_path,
                         self.force,
-                        Some(ModProfile::header()),
+                        Some(header),
                     )?;
                     let writer = TsvWriterWithContigNames::new(
                         tsv_writer,
                         tid_to_name,
                         chrom_to_seq,
-                        self.read_calls_path.as_ref(),
                         caller,
+                        projection,
+                    )
+                    .with_read_calls(
+                        self.read_calls_path.as_ref(),
+                        &writer_tags,
                         self.force,
                     )?;
                     Box::new(writer)
@@ -830,6 +985,16 @@ impl ExtractMods {
                     n_used.inc(mod_profile.num_reads() as u64);
                     n_failed.inc(mod_profile.num_fails as u64);
                     n_skipped.inc(mod_profile.num_skips as u64);
+                    if let Some(summary_writer) = read_summary_writer.as_mut() {
+                        if let Err(e) = summary_writer.write(&mod_profile) {
+                            error!("failed to write read summary {e}");
+                        }
+                    }
+                    if let Some(bed12_writer) = bed12_writer.as_mut() {
+                        if let Err(e) = bed12_writer.write(&mod_profile) {
+                            error!("failed to write BED12 {e}");
+                        }
+                    }
                     match writer.write(mod_profile, kmer_size) {
                         Ok(n) => n_rows.inc(n),
                         Err(e) => {
@@ -846,6 +1011,8 @@ impl ExtractMods {
             }
         }
 
+        writer.finish()?;
+
         n_failed.finish_and_clear();
         n_skipped.finish_and_clear();
         n_used.finish_and_clear();
@@ -868,11 +1035,17 @@ impl ExtractMods {
         n_reads: Option<usize>,
         collapse_method: Option<&CollapseMethod>,
         edge_filter: Option<&EdgeFilter>,
-        only_mapped: bool,
+        record_selection: RecordSelection,
         message: &'static str,
         kmer_size: usize,
+        tags: &[ReadTag],
     ) -> (usize, usize) {
-        let mut mod_iter = TrackingModRecordIter::new(records, false);
+        let RecordSelection {
+            only_mapped,
+            record_filter,
+        } = record_selection;
+        let mut mod_iter =
+            TrackingModRecordIter::new(records, false, record_filter);
         let pb = multi_pb.add(get_spinner());
         pb.set_message(format!("{message}records processed"));
         for (record, read_id, mod_base_info) in &mut mod_iter {
@@ -886,6 +1059,7 @@ impl ExtractMods {
                 collapse_method,
                 edge_filter,
                 kmer_size,
+                tags,
             ) {
                 Ok(mod_profile) => {
                     ReadsBaseModProfile::new(vec![mod_profile], 0, 0)
@@ -972,6 +1146,8 @@ impl ReferencePositionFilter {
             .map(|read_base_mod_profile| {
                 let read_name = read_base_mod_profile.record_name;
                 let chrom_id = read_base_mod_profile.chrom_id;
+                let ref_span = read_base_mod_profile.ref_span;
+                let tag_values = read_base_mod_profile.tag_values;
                 let profile = read_base_mod_profile
                     .profile
                     .into_par_iter()
@@ -993,7 +1169,9 @@ impl ReferencePositionFilter {
                         }
                     })
                     .collect::<Vec<ModProfile>>();
-                ReadBaseModProfile::new(read_name, chrom_id, profile)
+                ReadBaseModProfile::new(
+                    read_name, chrom_id, ref_span, profile, tag_values,
+                )
             })
             .collect::<Vec<ReadBaseModProfile>>();
         let empty = profiles
@@ -1,7 +1,14 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arrow::array::{
+    ArrayRef, BooleanBuilder, Float32Builder, Int64Builder, StringBuilder,
+    StringDictionaryBuilder, UInt64Builder, UInt8Builder,
+};
+use arrow::datatypes::{DataType, Field, Int32Type, Schema};
 
 use derive_new::new;
 use itertools::Itertools;
@@ -10,13 +17,15 @@ use rustc_hash::FxHashMap;
 
 use crate::mod_bam::{BaseModCall, BaseModProbs};
 use crate::mod_base_code::{DnaBase, ModCodeRepr};
+use crate::parquet_writer::{categorical_field, ParquetTableWriter};
 use crate::read_ids_to_base_mod_probs::{
     ModProfile, ReadBaseModProfile, ReadsBaseModProfile,
 };
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::transcriptome::TranscriptProjection;
 use crate::util;
 use crate::util::{
-    create_out_directory, get_reference_mod_strand, Kmer, Strand,
+    create_out_directory, get_reference_mod_strand, Kmer, ReadTag, Strand,
 };
 use crate::writers::TsvWriter;
 
@@ -147,12 +156,57 @@ impl PositionModCalls {
         )
     }
 
+    fn ref_mod_strand(&self) -> Option<Strand> {
+        self.alignment_strand
+            .map(|x| get_reference_mod_strand(self.mod_strand, x))
+    }
+
+    /// Probability and code ("-" for canonical) of the most likely call.
+    fn argmax_call(&self) -> (f32, String) {
+        match self.base_mod_probs.argmax_base_mod_call() {
+            BaseModCall::Canonical(p) => (p, "-".to_string()),
+            BaseModCall::Modified(p, code) => (p, code.to_string()),
+            BaseModCall::Filtered => {
+                unreachable!("argmax should not output filtered calls")
+            }
+        }
+    }
+
+    fn ref_kmer(
+        &self,
+        chrom_name: &str,
+        reference_seqs: &HashMap<String, Vec<u8>>,
+    ) -> Option<String> {
+        self.ref_position
+            .filter(|ref_pos| *ref_pos >= 0)
+            .and_then(|ref_pos| {
+                reference_seqs.get(chrom_name).map(|s| {
+                    Kmer::from_seq(s, ref_pos as usize, self.query_kmer.size)
+                        .to_string()
+                })
+            })
+    }
+
+    fn modified_primary_base(&self) -> char {
+        if self.mod_strand == Strand::Negative {
+            self.canonical_base.complement().char()
+        } else {
+            self.canonical_base.char()
+        }
+    }
+
+    fn failed(&self, caller: &MultipleThresholdModCaller) -> bool {
+        caller.call(&self.canonical_base, &self.base_mod_probs)
+            == BaseModCall::Filtered
+    }
+
     pub(crate) fn to_row(
         &self,
         read_id: &str,
         chrom_name: Option<&String>,
         caller: &MultipleThresholdModCaller,
         reference_seqs: &HashMap<String, Vec<u8>>,
+        extra_columns: &str,
     ) -> String {
         let tab = '\t';
         let missing = ".".to_string();
@@ -162,44 +216,19 @@ impl PositionModCalls {
         let mod_strand = self.mod_strand.to_char();
         let ref_strand =
             self.alignment_strand.map(|x| x.to_char()).unwrap_or('.');
-        let ref_mod_strand = self
-            .alignment_strand
-            .map(|x| get_reference_mod_strand(self.mod_strand, x).to_char())
-            .unwrap_or('.');
+        let ref_mod_strand =
+            self.ref_mod_strand().map(|x| x.to_char()).unwrap_or('.');
         let fw_soft_clipped_start = self.num_soft_clipped_start;
         let fw_soft_clipped_end = self.num_soft_clipped_end;
-        let (mod_call_prob, mod_call_code) =
-            match self.base_mod_probs.argmax_base_mod_call() {
-                BaseModCall::Canonical(p) => (p, "-".to_string()),
-                BaseModCall::Modified(p, code) => (p, code.to_string()),
-                BaseModCall::Filtered => {
-                    unreachable!("argmax should not output filtered calls")
-                }
-            };
+        let (mod_call_prob, mod_call_code) = self.argmax_call();
         let read_length = self.read_length;
         let base_qual = self.q_base;
         let query_kmer = format!("{}", self.query_kmer);
-        let ref_kmer = if let Some(ref_pos) = self.ref_position {
-            if ref_pos < 0 {
-                None
-            } else {
-                reference_seqs.get(&chrom_name_label).map(|s| {
-                    Kmer::from_seq(s, ref_pos as usize, self.query_kmer.size)
-                        .to_string()
-                })
-            }
-        } else {
-            None
-        };
+        let ref_kmer = self.ref_kmer(&chrom_name_label, reference_seqs);
         let ref_kmer_rep = ref_kmer.as_ref().unwrap_or(&missing);
         let canonical_base = self.canonical_base.char();
-        let modified_primary_base = if self.mod_strand == Strand::Negative {
-            self.canonical_base.complement().char()
-        } else {
-            self.canonical_base.char()
-        };
-        let filtered = caller.call(&self.canonical_base, &self.base_mod_probs)
-            == BaseModCall::Filtered;
+        let modified_primary_base = self.modified_primary_base();
+        let filtered = self.failed(caller);
         let inferred = self.base_mod_probs.inferred;
         let within_alignment = chrom_name.is_some() && self.within_alignment();
 
@@ -224,14 +253,459 @@ impl PositionModCalls {
             {modified_primary_base}{tab}\
             {filtered}{tab}\
             {inferred}{tab}\
-            {within_alignment}\n"
+            {within_alignment}{extra_columns}\n"
         )
     }
 }
 
+/// Group the calls in a read by position and make a base modification call
+/// at each position with the thresholds.
+fn call_positions<'a>(
+    read_base_mod_profile: &'a ReadBaseModProfile,
+    caller: &MultipleThresholdModCaller,
+) -> Vec<(Vec<&'a ModProfile>, BaseModCall)> {
+    type Key = (usize, Strand, DnaBase);
+    let grouped = read_base_mod_profile.profile.iter().fold(
+        HashMap::<Key, Vec<&ModProfile>>::new(),
+        |mut acc, x| {
+            let k = (x.query_position, x.mod_strand, x.canonical_base);
+            acc.entry(k).or_insert(Vec::new()).push(x);
+            acc
+        },
+    );
+    grouped
+        .into_iter()
+        .map(|((_, _, canonical_base), mod_profiles)| {
+            let probs = mod_profiles
+                .iter()
+                .map(|x| (x.raw_mod_code, x.q_mod))
+                .collect::<FxHashMap<ModCodeRepr, f32>>();
+            let inferred = mod_profiles.iter().any(|x| x.inferred);
+            let call = caller
+                .call(&canonical_base, &BaseModProbs::new(probs, inferred));
+            (mod_profiles, call)
+        })
+        .collect()
+}
+
+/// Per mod code counts of a single read, see `ReadModSummary`.
+#[derive(Default, Debug, PartialEq)]
+struct ModCodeSummary {
+    n_calls: usize,
+    sum_prob: f32,
+    n_pass: usize,
+    n_mod: usize,
+}
+
+/// Summary of the base modification calls in a single read. For each mod
+/// code, the number of positions with a probability for the code, the mean
+/// probability, and the number of positions with a passing call (modified
+/// or canonical) and called as the code after thresholding.
+pub(crate) struct ReadModSummary {
+    read_length: usize,
+    mod_code_summaries: BTreeMap<ModCodeRepr, ModCodeSummary>,
+}
+
+impl ReadModSummary {
+    fn header() -> String {
+        let tab = '\t';
+        format!(
+            "\
+            read_id{tab}\
+            chrom{tab}\
+            ref_start{tab}\
+            ref_end{tab}\
+            ref_strand{tab}\
+            read_length{tab}\
+            mod_codes{tab}\
+            n_calls{tab}\
+            mean_prob{tab}\
+            n_pass{tab}\
+            n_mod{tab}\
+            frac_modified"
+        )
+    }
+
+    /// `None` when the read doesn't have any calls.
+    pub(crate) fn from_profile(
+        read_base_mod_profile: &ReadBaseModProfile,
+        caller: &MultipleThresholdModCaller,
+    ) -> Option<Self> {
+        let read_length = read_base_mod_profile.profile.first()?.read_length;
+        let mut mod_code_summaries =
+            BTreeMap::<ModCodeRepr, ModCodeSummary>::new();
+        for (mod_profiles, call) in
+            call_positions(read_base_mod_profile, caller)
+        {
+            for mod_profile in mod_profiles {
+                let summary = mod_code_summaries
+                    .entry(mod_profile.raw_mod_code)
+                    .or_default();
+                summary.n_calls += 1;
+                summary.sum_prob += mod_profile.q_mod;
+                match call {
+                    BaseModCall::Modified(_, code) => {
+                        summary.n_pass += 1;
+                        if code == mod_profile.raw_mod_code {
+                            summary.n_mod += 1;
+                        }
+                    }
+                    BaseModCall::Canonical(_) => summary.n_pass += 1,
+                    BaseModCall::Filtered => {}
+                }
+            }
+        }
+        Some(Self {
+            read_length,
+            mod_code_summaries,
+        })
+    }
+
+    fn join_values<F: Fn(&ModCodeSummary) -> String>(&self, f: F) -> String {
+        self.mod_code_summaries.values().map(f).join(",")
+    }
+
+    pub(crate) fn to_row(
+        &self,
+        read_base_mod_profile: &ReadBaseModProfile,
+        chrom_name: Option<&String>,
+        extra_columns: &str,
+    ) -> String {
+        let tab = '\t';
+        let read_id = &read_base_mod_profile.record_name;
+        let chrom_name = chrom_name.map(|s| s.as_str()).unwrap_or(".");
+        let (ref_start, ref_end) =
+            read_base_mod_profile.ref_span.unwrap_or((-1, -1));
+        let ref_strand = read_base_mod_profile
+            .profile
+            .first()
+            .and_then(|x| x.alignment_strand)
+            .map(|x| x.to_char())
+            .unwrap_or('.');
+        let read_length = self.read_length;
+        let mod_codes = self.mod_code_summaries.keys().join(",");
+        let n_calls = self.join_values(|x| x.n_calls.to_string());
+        let mean_prob = self
+            .join_values(|x| format!("{:.4}", x.sum_prob / x.n_calls as f32));
+        let n_pass = self.join_values(|x| x.n_pass.to_string());
+        let n_mod = self.join_values(|x| x.n_mod.to_string());
+        let frac_modified = self.join_values(|x| {
+            if x.n_pass > 0 {
+                format!("{:.4}", x.n_mod as f32 / x.n_pass as f32)
+            } else {
+                ".".to_string()
+            }
+        });
+        format!(
+            "\
+            {read_id}{tab}\
+            {chrom_name}{tab}\
+            {ref_start}{tab}\
+            {ref_end}{tab}\
+            {ref_strand}{tab}\
+            {read_length}{tab}\
+            {mod_codes}{tab}\
+            {n_calls}{tab}\
+            {mean_prob}{tab}\
+            {n_pass}{tab}\
+            {n_mod}{tab}\
+            {frac_modified}{extra_columns}\n"
+        )
+    }
+}
+
+/// Writes the `--read-summary` table, one row per read.
+pub(crate) struct ReadSummaryWriter {
+    tsv_writer: TsvWriter<File>,
+    tid_to_name: HashMap<u32, String>,
+    written_reads: HashSet<String>,
+    caller: MultipleThresholdModCaller,
+}
+
+impl ReadSummaryWriter {
+    pub(crate) fn new(
+        path: &PathBuf,
+        tid_to_name: HashMap<u32, String>,
+        caller: MultipleThresholdModCaller,
+        tags: &[ReadTag],
+        force: bool,
+    ) -> anyhow::Result<Self> {
+        create_out_directory(path)?;
+        let header =
+            format!("{}{}", ReadModSummary::header(), tag_header_columns(tags));
+        let tsv_writer = TsvWriter::new_path(path, force, Some(header))?;
+        Ok(Self {
+            tsv_writer,
+            tid_to_name,
+            written_reads: HashSet::new(),
+            caller,
+        })
+    }
+
+    pub(crate) fn write(
+        &mut self,
+        item: &ReadsBaseModProfile,
+    ) -> anyhow::Result<u64> {
+        let mut rows_written = 0u64;
+        for profile in item.profiles.iter() {
+            if self.written_reads.contains(&profile.record_name) {
+                continue;
+            }
+            self.written_reads.insert(profile.record_name.to_owned());
+            if let Some(summary) =
+                ReadModSummary::from_profile(profile, &self.caller)
+            {
+                let chrom_name = profile
+                    .chrom_id
+                    .and_then(|chrom_id| self.tid_to_name.get(&chrom_id));
+                let row =
+                    summary.to_row(profile, chrom_name, &tag_columns(profile));
+                self.tsv_writer.write(row.as_bytes())?;
+                rows_written += 1;
+            }
+        }
+        Ok(rows_written)
+    }
+}
+
+const CANONICAL_RGB: (u8, u8, u8) = (69, 117, 180);
+const MODIFIED_RGB: (u8, u8, u8) = (215, 48, 39);
+
+/// Color between canonical (blue) and modified (red) for the fraction of
+/// modified calls.
+fn fraction_modified_rgb(frac_modified: f32) -> String {
+    let interpolate = |canonical: u8, modified: u8| {
+        (canonical as f32
+            + (modified as f32 - canonical as f32) * frac_modified)
+            .round() as u8
+    };
+    format!(
+        "{},{},{}",
+        interpolate(CANONICAL_RGB.0, MODIFIED_RGB.0),
+        interpolate(CANONICAL_RGB.1, MODIFIED_RGB.1),
+        interpolate(CANONICAL_RGB.2, MODIFIED_RGB.2),
+    )
+}
+
+/// Writes the `--bed12` table. Each read with passing calls on the
+/// reference is a feature with a 1 bp block at every called site, the score
+/// and item RGB are from the fraction of sites called modified. With
+/// `split_states` each read is instead written as one feature with the
+/// modified sites and another with the canonical sites.
+pub(crate) struct ReadBed12Writer {
+    tsv_writer: TsvWriter<File>,
+    tid_to_name: HashMap<u32, String>,
+    written_reads: HashSet<String>,
+    caller: MultipleThresholdModCaller,
+    split_states: bool,
+}
+
+impl ReadBed12Writer {
+    pub(crate) fn new(
+        path: &PathBuf,
+        tid_to_name: HashMap<u32, String>,
+        caller: MultipleThresholdModCaller,
+        split_states: bool,
+        force: bool,
+    ) -> anyhow::Result<Self> {
+        create_out_directory(path)?;
+        let tsv_writer = TsvWriter::new_path(path, force, None)?;
+        Ok(Self {
+            tsv_writer,
+            tid_to_name,
+            written_reads: HashSet::new(),
+            caller,
+            split_states,
+        })
+    }
+
+    fn feature(
+        chrom_name: &str,
+        read_id: &str,
+        strand: char,
+        sites: &[i64],
+        score: u32,
+        rgb: &str,
+    ) -> Option<String> {
+        let start = *sites.first()?;
+        let end = *sites.last()? + 1;
+        let block_sizes = sites.iter().map(|_| "1").join(",");
+        let block_starts = sites.iter().map(|pos| pos - start).join(",");
+        let tab = '\t';
+        Some(format!(
+            "\
+            {chrom_name}{tab}\
+            {start}{tab}\
+            {end}{tab}\
+            {read_id}{tab}\
+            {score}{tab}\
+            {strand}{tab}\
+            {start}{tab}\
+            {end}{tab}\
+            {rgb}{tab}\
+            {}{tab}\
+            {block_sizes}{tab}\
+            {block_starts}\n",
+            sites.len()
+        ))
+    }
+
+    pub(crate) fn write(
+        &mut self,
+        item: &ReadsBaseModProfile,
+    ) -> anyhow::Result<u64> {
+        let mut rows_written = 0u64;
+        for profile in item.profiles.iter() {
+            if self.written_reads.contains(&profile.record_name) {
+                continue;
+            }
+            self.written_reads.insert(profile.record_name.to_owned());
+            let chrom_name = match profile
+                .chrom_id
+                .and_then(|chrom_id| self.tid_to_name.get(&chrom_id))
+            {
+                Some(chrom_name) => chrom_name,
+                None => continue,
+            };
+            // reference position to whether the site is called modified
+            let sites = call_positions(profile, &self.caller)
+                .into_iter()
+                .filter_map(|(mod_profiles, call)| {
+                    let ref_pos = mod_profiles[0].ref_position?;
+                    match call {
+                        BaseModCall::Modified(_, _) => Some((ref_pos, true)),
+                        BaseModCall::Canonical(_) => Some((ref_pos, false)),
+                        BaseModCall::Filtered => None,
+                    }
+                })
+                .fold(BTreeMap::<i64, bool>::new(), |mut acc, (pos, m)| {
+                    *acc.entry(pos).or_insert(false) |= m;
+                    acc
+                });
+            let strand = profile
+                .profile
+                .first()
+                .and_then(|x| x.alignment_strand)
+                .map(|x| x.to_char())
+                .unwrap_or('.');
+            let read_id = &profile.record_name;
+            let features = if self.split_states {
+                let (modified, canonical): (Vec<_>, Vec<_>) =
+                    sites.iter().partition(|(_, m)| **m);
+                let positions = |sites: Vec<(&i64, &bool)>| {
+                    sites.into_iter().map(|(pos, _)| *pos).collect::<Vec<i64>>()
+                };
+                [
+                    (positions(modified), 1000, MODIFIED_RGB),
+                    (positions(canonical), 0, CANONICAL_RGB),
+                ]
+                .into_iter()
+                .filter_map(|(positions, score, (r, g, b))| {
+                    Self::feature(
+                        chrom_name,
+                        read_id,
+                        strand,
+                        &positions,
+                        score,
+                        &format!("{r},{g},{b}"),
+                    )
+                })
+                .collect::<Vec<String>>()
+            } else {
+                let n_modified = sites.values().filter(|m| **m).count();
+                let frac_modified = n_modified as f32 / sites.len() as f32;
+                let positions = sites.keys().copied().collect::<Vec<i64>>();
+                Self::feature(
+                    chrom_name,
+                    read_id,
+                    strand,
+                    &positions,
+                    (frac_modified * 1000f32).round() as u32,
+                    &fraction_modified_rgb(frac_modified),
+                )
+                .into_iter()
+                .collect::<Vec<String>>()
+            };
+            for feature in features {
+                self.tsv_writer.write(feature.as_bytes())?;
+                rows_written += 1;
+            }
+        }
+        Ok(rows_written)
+    }
+}
+
+/// Header for the columns of SAM tag values, one per tag.
+pub(crate) fn tag_header_columns(tags: &[ReadTag]) -> String {
+    tags.iter().map(|tag| format!("\t{tag}")).collect()
+}
+
+/// Values of the SAM tags of a read, "." when the read doesn't have the tag.
+fn tag_columns(read_base_mod_profile: &ReadBaseModProfile) -> String {
+    read_base_mod_profile
+        .tag_values
+        .iter()
+        .map(|value| format!("\t{}", value.as_deref().unwrap_or(".")))
+        .collect()
+}
+
+/// Header for the columns added by `TsvWriterWithContigNames` when
+/// projecting transcriptome alignments to the genome.
+pub(crate) fn projected_header_columns() -> String {
+    let tab = '\t';
+    format!(
+        "\
+        {tab}genome_chrom\
+        {tab}genome_position\
+        {tab}genome_ref_mod_strand\
+        {tab}gene_id\
+        {tab}transcript_id"
+    )
+}
+
+/// Genomic chrom, position, reference mod strand, gene ID and transcript
+/// ID of a position on a transcript, empty when not projecting.
+fn projected_columns(
+    projection: Option<&TranscriptProjection>,
+    chrom_name: Option<&String>,
+    ref_position: Option<i64>,
+    ref_mod_strand: Option<Strand>,
+) -> String {
+    let projection = match projection {
+        Some(projection) => projection,
+        None => return String::new(),
+    };
+    let tab = '\t';
+    let transcript = chrom_name.and_then(|name| projection.get(name));
+    let genome_position =
+        transcript
+            .zip(ref_position)
+            .and_then(|(transcript, ref_position)| {
+                u64::try_from(ref_position)
+                    .ok()
+                    .and_then(|pos| transcript.project(pos))
+            });
+    match (transcript, genome_position) {
+        (Some(transcript), Some(genome_position)) => {
+            let strand = ref_mod_strand
+                .map(|strand| transcript.project_strand(strand).to_char())
+                .unwrap_or('.');
+            format!(
+                "{tab}{}{tab}{genome_position}{tab}{strand}{tab}{}{tab}{}",
+                transcript.chrom, transcript.gene_id, transcript.transcript_id
+            )
+        }
+        _ => format!("{tab}.{tab}-1{tab}.{tab}.{tab}."),
+    }
+}
+
 pub trait OutwriterWithMemory<T> {
     fn write(&mut self, item: T, kmer_size: usize) -> anyhow::Result<u64>;
     fn num_reads(&self) -> usize;
+    /// Called once after all items have been written.
+    fn finish(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 pub struct TsvWriterWithContigNames<W: Write> {
@@ -241,6 +715,7 @@ pub struct TsvWriterWithContigNames<W: Write> {
     written_reads: HashSet<String>,
     read_calls_writer: Option<TsvWriter<File>>,
     caller: MultipleThresholdModCaller,
+    projection: Option<TranscriptProjection>,
 }
 
 impl<W: Write> TsvWriterWithContigNames<W> {
@@ -248,23 +723,51 @@ impl<W: Write> TsvWriterWithContigNames<W> {
         output_writer: TsvWriter<W>,
         tid_to_name: HashMap<u32, String>,
         name_to_seq: HashMap<String, Vec<u8>>,
-        read_calls_path: Option<&PathBuf>,
         caller: MultipleThresholdModCaller,
+        projection: Option<TranscriptProjection>,
+    ) -> Self {
+        Self {
+            tsv_writer: output_writer,
+            tid_to_name,
+            name_to_seq,
+            written_reads: HashSet::new(),
+            read_calls_writer: None,
+            caller,
+            projection,
+        }
+    }
+
+    /// Also write the calls at each position to `read_calls_path` (when
+    /// given), with a column for each of the `tags`.
+    pub(crate) fn with_read_calls(
+        self,
+        read_calls_path: Option<&PathBuf>,
+        tags: &[ReadTag],
         force: bool,
     ) -> anyhow::Result<Self> {
+        let read_calls_header = if self.projection.is_some() {
+            format!(
+                "{}{}{}",
+                PositionModCalls::header(),
+                tag_header_columns(tags),
+                projected_header_columns()
+            )
+        } else {
+            format!(
+                "{}{}",
+                PositionModCalls::header(),
+                tag_header_columns(tags)
+            )
+        };
         let read_calls_writer = read_calls_path
             .map(|fp| {
                 create_out_directory(fp)?;
-                TsvWriter::new_path(fp, force, Some(PositionModCalls::header()))
+                TsvWriter::new_path(fp, force, Some(read_calls_header))
             })
             .transpose()?;
         Ok(Self {
-            tsv_writer: output_writer,
-            tid_to_name,
-            name_to_seq,
-            written_reads: HashSet::new(),
             read_calls_writer,
-            caller,
+            ..self
         })
     }
 }
@@ -286,12 +789,23 @@ impl<W: Write> OutwriterWithMemory<ReadsBaseModProfile>
                 let chrom_name = profile
                     .chrom_id
                     .and_then(|chrom_id| self.tid_to_name.get(&chrom_id));
+                let tag_columns = tag_columns(profile);
                 for mod_profile in profile.profile.iter() {
+                    let extra_columns = format!(
+                        "{tag_columns}{}",
+                        projected_columns(
+                            self.projection.as_ref(),
+                            chrom_name,
+                            mod_profile.ref_position,
+                            mod_profile.ref_mod_strand(),
+                        )
+                    );
                     let row = mod_profile.to_row(
                         &profile.record_name,
                         chrom_name.unwrap_or(&missing_chrom),
                         &self.name_to_seq,
                         kmer_size,
+                        &extra_columns,
                     );
                     self.tsv_writer.write(row.as_bytes())?;
                     rows_written += 1;
@@ -302,12 +816,22 @@ impl<W: Write> OutwriterWithMemory<ReadsBaseModProfile>
                     let position_calls =
                         PositionModCalls::from_profile(&profile);
                     for call in position_calls {
+                        let extra_columns = format!(
+                            "{tag_columns}{}",
+                            projected_columns(
+                                self.projection.as_ref(),
+                                chrom_name,
+                                call.ref_position,
+                                call.ref_mod_strand(),
+                            )
+                        );
                         read_calls_writer.write(
                             call.to_row(
                                 &profile.record_name,
                                 chrom_name,
                                 &self.caller,
                                 &self.name_to_seq,
+                                &extra_columns,
                             )
                             .as_bytes(),
                         )?;
@@ -322,3 +846,457 @@ impl<W: Write> OutwriterWithMemory<ReadsBaseModProfile>
         self.written_reads.len()
     }
 }
+
+/// Nullable string columns for the SAM tag values, named by the tag.
+fn tag_fields(tags: &[ReadTag]) -> Vec<Field> {
+    tags.iter()
+        .map(|tag| Field::new(tag.to_string(), DataType::Utf8, true))
+        .collect()
+}
+
+fn append_tag_values(
+    builders: &mut [StringBuilder],
+    tag_values: &[Option<String>],
+) {
+    for (builder, value) in builders.iter_mut().zip(tag_values) {
+        builder.append_option(value.as_ref());
+    }
+}
+
+fn finish_tag_columns(builders: &mut [StringBuilder]) -> Vec<ArrayRef> {
+    builders
+        .iter_mut()
+        .map(|builder| Arc::new(builder.finish()) as ArrayRef)
+        .collect()
+}
+
+/// Typed columns of the extract table, see `ModProfile::header`.
+struct ModProfileColumns {
+    read_id: StringBuilder,
+    forward_read_position: UInt64Builder,
+    ref_position: Int64Builder,
+    chrom: StringDictionaryBuilder<Int32Type>,
+    mod_strand: StringDictionaryBuilder<Int32Type>,
+    ref_strand: StringDictionaryBuilder<Int32Type>,
+    ref_mod_strand: StringDictionaryBuilder<Int32Type>,
+    fw_soft_clipped_start: UInt64Builder,
+    fw_soft_clipped_end: UInt64Builder,
+    read_length: UInt64Builder,
+    mod_qual: Float32Builder,
+    mod_code: StringDictionaryBuilder<Int32Type>,
+    base_qual: UInt8Builder,
+    ref_kmer: StringBuilder,
+    query_kmer: StringBuilder,
+    canonical_base: StringDictionaryBuilder<Int32Type>,
+    modified_primary_base: StringDictionaryBuilder<Int32Type>,
+    inferred: BooleanBuilder,
+    tags: Vec<StringBuilder>,
+}
+
+impl ModProfileColumns {
+    fn new(n_tags: usize) -> Self {
+        Self {
+            read_id: StringBuilder::new(),
+            forward_read_position: UInt64Builder::new(),
+            ref_position: Int64Builder::new(),
+            chrom: StringDictionaryBuilder::new(),
+            mod_strand: StringDictionaryBuilder::new(),
+            ref_strand: StringDictionaryBuilder::new(),
+            ref_mod_strand: StringDictionaryBuilder::new(),
+            fw_soft_clipped_start: UInt64Builder::new(),
+            fw_soft_clipped_end: UInt64Builder::new(),
+            read_length: UInt64Builder::new(),
+            mod_qual: Float32Builder::new(),
+            mod_code: StringDictionaryBuilder::new(),
+            base_qual: UInt8Builder::new(),
+            ref_kmer: StringBuilder::new(),
+            query_kmer: StringBuilder::new(),
+            canonical_base: StringDictionaryBuilder::new(),
+            modified_primary_base: StringDictionaryBuilder::new(),
+            inferred: BooleanBuilder::new(),
+            tags: (0..n_tags).map(|_| StringBuilder::new()).collect(),
+        }
+    }
+
+    fn schema(tags: &[ReadTag]) -> Schema {
+        let mut fields = vec![
+            Field::new("read_id", DataType::Utf8, false),
+            Field::new("forward_read_position", DataType::UInt64, false),
+            Field::new("ref_position", DataType::Int64, false),
+            categorical_field("chrom", true),
+            categorical_field("mod_strand", false),
+            categorical_field("ref_strand", true),
+            categorical_field("ref_mod_strand", true),
+            Field::new("fw_soft_clipped_start", DataType::UInt64, false),
+            Field::new("fw_soft_clipped_end", DataType::UInt64, false),
+            Field::new("read_length", DataType::UInt64, false),
+            Field::new("mod_qual", DataType::Float32, false),
+            categorical_field("mod_code", false),
+            Field::new("base_qual", DataType::UInt8, false),
+            Field::new("ref_kmer", DataType::Utf8, true),
+            Field::new("query_kmer", DataType::Utf8, false),
+            categorical_field("canonical_base", false),
+            categorical_field("modified_primary_base", false),
+            Field::new("inferred", DataType::Boolean, false),
+        ];
+        fields.extend(tag_fields(tags));
+        Schema::new(fields)
+    }
+
+    fn append(
+        &mut self,
+        read_id: &str,
+        chrom_name: Option<&String>,
+        mod_profile: &ModProfile,
+        reference_seqs: &HashMap<String, Vec<u8>>,
+        kmer_size: usize,
+        tag_values: &[Option<String>],
+    ) {
+        self.read_id.append_value(read_id);
+        self.forward_read_position
+            .append_value(mod_profile.query_position as u64);
+        self.ref_position
+            .append_value(mod_profile.ref_position.unwrap_or(-1));
+        self.chrom.append_option(chrom_name);
+        self.mod_strand
+            .append_value(mod_profile.mod_strand.to_char().to_string());
+        self.ref_strand.append_option(
+            mod_profile
+                .alignment_strand
+                .map(|s| s.to_char().to_string()),
+        );
+        self.ref_mod_strand.append_option(
+            mod_profile
+                .ref_mod_strand()
+                .map(|s| s.to_char().to_string()),
+        );
+        self.fw_soft_clipped_start
+            .append_value(mod_profile.num_soft_clipped_start as u64);
+        self.fw_soft_clipped_end
+            .append_value(mod_profile.num_soft_clipped_end as u64);
+        self.read_length
+            .append_value(mod_profile.read_length as u64);
+        self.mod_qual.append_value(mod_profile.q_mod);
+        self.mod_code
+            .append_value(mod_profile.raw_mod_code.to_string());
+        self.base_qual.append_value(mod_profile.q_base);
+        self.ref_kmer
+            .append_option(chrom_name.and_then(|chrom_name| {
+                mod_profile.ref_kmer(chrom_name, reference_seqs, kmer_size)
+            }));
+        self.query_kmer
+            .append_value(mod_profile.query_kmer.to_string());
+        self.canonical_base
+            .append_value(mod_profile.canonical_base.char().to_string());
+        self.modified_primary_base
+            .append_value(mod_profile.modified_primary_base().to_string());
+        self.inferred.append_value(mod_profile.inferred);
+        append_tag_values(&mut self.tags, tag_values);
+    }
+
+    fn finish(&mut self) -> Vec<ArrayRef> {
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(self.read_id.finish()),
+            Arc::new(self.forward_read_position.finish()),
+            Arc::new(self.ref_position.finish()),
+            Arc::new(self.chrom.finish()),
+            Arc::new(self.mod_strand.finish()),
+            Arc::new(self.ref_strand.finish()),
+            Arc::new(self.ref_mod_strand.finish()),
+            Arc::new(self.fw_soft_clipped_start.finish()),
+            Arc::new(self.fw_soft_clipped_end.finish()),
+            Arc::new(self.read_length.finish()),
+            Arc::new(self.mod_qual.finish()),
+            Arc::new(self.mod_code.finish()),
+            Arc::new(self.base_qual.finish()),
+            Arc::new(self.ref_kmer.finish()),
+            Arc::new(self.query_kmer.finish()),
+            Arc::new(self.canonical_base.finish()),
+            Arc::new(self.modified_primary_base.finish()),
+            Arc::new(self.inferred.finish()),
+        ];
+        columns.extend(finish_tag_columns(&mut self.tags));
+        columns
+    }
+}
+
+/// Typed columns of the read calls table, see `PositionModCalls::header`.
+struct PositionModCallsColumns {
+    read_id: StringBuilder,
+    forward_read_position: UInt64Builder,
+    ref_position: Int64Builder,
+    chrom: StringDictionaryBuilder<Int32Type>,
+    mod_strand: StringDictionaryBuilder<Int32Type>,
+    ref_strand: StringDictionaryBuilder<Int32Type>,
+    ref_mod_strand: StringDictionaryBuilder<Int32Type>,
+    fw_soft_clipped_start: UInt64Builder,
+    fw_soft_clipped_end: UInt64Builder,
+    read_length: UInt64Builder,
+    call_prob: Float32Builder,
+    call_code: StringDictionaryBuilder<Int32Type>,
+    base_qual: UInt8Builder,
+    ref_kmer: StringBuilder,
+    query_kmer: StringBuilder,
+    canonical_base: StringDictionaryBuilder<Int32Type>,
+    modified_primary_base: StringDictionaryBuilder<Int32Type>,
+    fail: BooleanBuilder,
+    inferred: BooleanBuilder,
+    within_alignment: BooleanBuilder,
+    tags: Vec<StringBuilder>,
+}
+
+impl PositionModCallsColumns {
+    fn new(n_tags: usize) -> Self {
+        Self {
+            read_id: StringBuilder::new(),
+            forward_read_position: UInt64Builder::new(),
+            ref_position: Int64Builder::new(),
+            chrom: StringDictionaryBuilder::new(),
+            mod_strand: StringDictionaryBuilder::new(),
+            ref_strand: StringDictionaryBuilder::new(),
+            ref_mod_strand: StringDictionaryBuilder::new(),
+            fw_soft_clipped_start: UInt64Builder::new(),
+            fw_soft_clipped_end: UInt64Builder::new(),
+            read_length: UInt64Builder::new(),
+            call_prob: Float32Builder::new(),
+            call_code: StringDictionaryBuilder::new(),
+            base_qual: UInt8Builder::new(),
+            ref_kmer: StringBuilder::new(),
+            query_kmer: StringBuilder::new(),
+            canonical_base: StringDictionaryBuilder::new(),
+            modified_primary_base: StringDictionaryBuilder::new(),
+            fail: BooleanBuilder::new(),
+            inferred: BooleanBuilder::new(),
+            within_alignment: BooleanBuilder::new(),
+            tags: (0..n_tags).map(|_| StringBuilder::new()).collect(),
+        }
+    }
+
+    fn schema(tags: &[ReadTag]) -> Schema {
+        let mut fields = vec![
+            Field::new("read_id", DataType::Utf8, false),
+            Field::new("forward_read_position", DataType::UInt64, false),
+            Field::new("ref_position", DataType::Int64, false),
+            categorical_field("chrom", true),
+            categorical_field("mod_strand", false),
+            categorical_field("ref_strand", true),
+            categorical_field("ref_mod_strand", true),
+            Field::new("fw_soft_clipped_start", DataType::UInt64, false),
+            Field::new("fw_soft_clipped_end", DataType::UInt64, false),
+            Field::new("read_length", DataType::UInt64, false),
+            Field::new("call_prob", DataType::Float32, false),
+            categorical_field("call_code", false),
+            Field::new("base_qual", DataType::UInt8, false),
+            Field::new("ref_kmer", DataType::Utf8, true),
+            Field::new("query_kmer", DataType::Utf8, false),
+            categorical_field("canonical_base", false),
+            categorical_field("modified_primary_base", false),
+            Field::new("fail", DataType::Boolean, false),
+            Field::new("inferred", DataType::Boolean, false),
+            Field::new("within_alignment", DataType::Boolean, false),
+        ];
+        fields.extend(tag_fields(tags));
+        Schema::new(fields)
+    }
+
+    fn append(
+        &mut self,
+        read_id: &str,
+        chrom_name: Option<&String>,
+        calls: &PositionModCalls,
+        caller: &MultipleThresholdModCaller,
+        reference_seqs: &HashMap<String, Vec<u8>>,
+        tag_values: &[Option<String>],
+    ) {
+        let (call_prob, call_code) = calls.argmax_call();
+        self.read_id.append_value(read_id);
+        self.forward_read_position
+            .append_value(calls.query_position as u64);
+        self.ref_position
+            .append_value(calls.ref_position.unwrap_or(-1));
+        self.chrom.append_option(chrom_name);
+        self.mod_strand
+            .append_value(calls.mod_strand.to_char().to_string());
+        self.ref_strand.append_option(
+            calls.alignment_strand.map(|s| s.to_char().to_string()),
+        );
+        self.ref_mod_strand.append_option(
+            calls.ref_mod_strand().map(|s| s.to_char().to_string()),
+        );
+        self.fw_soft_clipped_start
+            .append_value(calls.num_soft_clipped_start as u64);
+        self.fw_soft_clipped_end
+            .append_value(calls.num_soft_clipped_end as u64);
+        self.read_length.append_value(calls.read_length as u64);
+        self.call_prob.append_value(call_prob);
+        self.call_code.append_value(call_code);
+        self.base_qual.append_value(calls.q_base);
+        self.ref_kmer.append_option(
+            chrom_name.and_then(|chrom_name| {
+                calls.ref_kmer(chrom_name, reference_seqs)
+            }),
+        );
+        self.query_kmer.append_value(calls.query_kmer.to_string());
+        self.canonical_base
+            .append_value(calls.canonical_base.char().to_string());
+        self.modified_primary_base
+            .append_value(calls.modified_primary_base().to_string());
+        self.fail.append_value(calls.failed(caller));
+        self.inferred.append_value(calls.base_mod_probs.inferred);
+        self.within_alignment
+            .append_value(chrom_name.is_some() && calls.within_alignment());
+        append_tag_values(&mut self.tags, tag_values);
+    }
+
+    fn finish(&mut self) -> Vec<ArrayRef> {
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(self.read_id.finish()),
+            Arc::new(self.forward_read_position.finish()),
+            Arc::new(self.ref_position.finish()),
+            Arc::new(self.chrom.finish()),
+            Arc::new(self.mod_strand.finish()),
+            Arc::new(self.ref_strand.finish()),
+            Arc::new(self.ref_mod_strand.finish()),
+            Arc::new(self.fw_soft_clipped_start.finish()),
+            Arc::new(self.fw_soft_clipped_end.finish()),
+            Arc::new(self.read_length.finish()),
+            Arc::new(self.call_prob.finish()),
+            Arc::new(self.call_code.finish()),
+            Arc::new(self.base_qual.finish()),
+            Arc::new(self.ref_kmer.finish()),
+            Arc::new(self.query_kmer.finish()),
+            Arc::new(self.canonical_base.finish()),
+            Arc::new(self.modified_primary_base.finish()),
+            Arc::new(self.fail.finish()),
+            Arc::new(self.inferred.finish()),
+            Arc::new(self.within_alignment.finish()),
+        ];
+        columns.extend(finish_tag_columns(&mut self.tags));
+        columns
+    }
+}
+
+/// Writes the extract table (and optionally the read calls table) to
+/// Parquet files with typed columns. Row groups are closed at the end of a
+/// batch of reads (interval chunk, or a single read when streaming) once
+/// they have at least 10,000 rows. Missing values (e.g. the chrom of
+/// unmapped reads) are null instead of '.', the ref_position is -1 for
+/// unaligned positions as in the text output.
+pub struct ParquetWriterWithContigNames {
+    writer: Option<(ParquetTableWriter, ModProfileColumns)>,
+    read_calls_writer: Option<(ParquetTableWriter, PositionModCallsColumns)>,
+    tid_to_name: HashMap<u32, String>,
+    name_to_seq: HashMap<String, Vec<u8>>,
+    written_reads: HashSet<String>,
+    caller: MultipleThresholdModCaller,
+}
+
+impl ParquetWriterWithContigNames {
+    /// `out_path` is `None` when only the read calls table is wanted.
+    pub(crate) fn new(
+        out_path: Option<&Path>,
+        tid_to_name: HashMap<u32, String>,
+        name_to_seq: HashMap<String, Vec<u8>>,
+        read_calls_path: Option<&PathBuf>,
+        caller: MultipleThresholdModCaller,
+        tags: &[ReadTag],
+        force: bool,
+    ) -> anyhow::Result<Self> {
+        let writer = out_path
+            .map(|fp| {
+                create_out_directory(fp)?;
+                ParquetTableWriter::new(
+                    fp,
+                    ModProfileColumns::schema(tags),
+                    force,
+                )
+                .map(|writer| (writer, ModProfileColumns::new(tags.len())))
+            })
+            .transpose()?;
+        let read_calls_writer = read_calls_path
+            .map(|fp| {
+                create_out_directory(fp)?;
+                ParquetTableWriter::new(
+                    fp,
+                    PositionModCallsColumns::schema(tags),
+                    force,
+                )
+                .map(|writer| {
+                    (writer, PositionModCallsColumns::new(tags.len()))
+                })
+            })
+            .transpose()?;
+        Ok(Self {
+            writer,
+            read_calls_writer,
+            tid_to_name,
+            name_to_seq,
+            written_reads: HashSet::new(),
+            caller,
+        })
+    }
+}
+
+impl OutwriterWithMemory<ReadsBaseModProfile> for ParquetWriterWithContigNames {
+    fn write(
+        &mut self,
+        item: ReadsBaseModProfile,
+        kmer_size: usize,
+    ) -> anyhow::Result<u64> {
+        for profile in item.profiles.iter() {
+            if self.written_reads.contains(&profile.record_name) {
+                continue;
+            }
+            let chrom_name = profile
+                .chrom_id
+                .and_then(|chrom_id| self.tid_to_name.get(&chrom_id));
+            if let Some((_, columns)) = self.writer.as_mut() {
+                for mod_profile in profile.profile.iter() {
+                    columns.append(
+                        &profile.record_name,
+                        chrom_name,
+                        mod_profile,
+                        &self.name_to_seq,
+                        kmer_size,
+                        &profile.tag_values,
+                    );
+                }
+            }
+            if let Some((_, columns)) = self.read_calls_writer.as_mut() {
+                for call in PositionModCalls::from_profile(profile) {
+                    columns.append(
+                        &profile.record_name,
+                        chrom_name,
+                        &call,
+                        &self.caller,
+                        &self.name_to_seq,
+                        &profile.tag_values,
+                    );
+                }
+            }
+            self.written_reads.insert(profile.record_name.to_owned());
+        }
+        let mut rows_written = 0u64;
+        if let Some((writer, columns)) = self.writer.as_mut() {
+            rows_written += writer.write_columns(columns.finish())?;
+        }
+        if let Some((writer, columns)) = self.read_calls_writer.as_mut() {
+            writer.write_columns(columns.finish())?;
+        }
+        Ok(rows_written)
+    }
+
+    fn num_reads(&self) -> usize {
+        self.written_reads.len()
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if let Some((writer, _)) = self.writer.as_mut() {
+            writer.finish()?;
+        }
+        if let Some((writer, _)) = self.read_calls_writer.as_mut() {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+use derive_new::new;
+use rust_htslib::bam::{self, record::Aux, record::AuxArray};
+
+use crate::errs::RunError;
+use crate::mod_bam::{BaseModCall, ModBaseInfo};
+use crate::mod_base_code::{DnaBase, SIX_METHYL_ADENINE};
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::util::{get_aligned_pairs_forward, Strand};
+
+pub mod subcommand;
+mod writer;
+
+/// Tags used by Fiber-seq tools for nucleosome starts and lengths and
+/// methylase-sensitive patch (MSP) starts and lengths.
+const NUCLEOSOME_STARTS_TAG: &str = "ns";
+const NUCLEOSOME_LENGTHS_TAG: &str = "nl";
+const MSP_STARTS_TAG: &str = "as";
+const MSP_LENGTHS_TAG: &str = "al";
+
+/// Half-open interval [start, end).
+type Segment = (usize, usize);
+
+/// Nucleosome footprints and methylase-sensitive patches of a single read,
+/// in forward read coordinates.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct FiberSegments {
+    pub(crate) nucleosomes: Vec<Segment>,
+    pub(crate) msps: Vec<Segment>,
+}
+
+/// Requirements for a stretch of the read without 6mA calls to be called a
+/// nucleosome footprint.
+#[derive(new, Debug, Copy, Clone)]
+pub(crate) struct NucleosomeCriteria {
+    min_length: usize,
+    max_length: usize,
+    /// 6mA can only be at A/T bases, stretches with few of them don't have
+    /// evidence of being protected.
+    min_at_bases: usize,
+}
+
+/// A stretch of the read between two consecutive 6mA calls.
+enum Gap {
+    Nucleosome(Segment),
+    /// Too short to be a nucleosome, part of an MSP.
+    Accessible,
+    /// Too long or with too few A/T bases to call, MSPs don't span these.
+    Uncalled,
+}
+
+impl NucleosomeCriteria {
+    fn classify(&self, start: usize, end: usize, forward_seq: &[u8]) -> Gap {
+        let length = end - start;
+        if length < self.min_length {
+            return Gap::Accessible;
+        }
+        let n_at = forward_seq
+            .get(start..end)
+            .unwrap_or(&[])
+            .iter()
+            .filter(|b| matches!(b, b'A' | b'T' | b'a' | b't'))
+            .count();
+        if length > self.max_length || n_at < self.min_at_bases {
+            Gap::Uncalled
+        } else {
+            Gap::Nucleosome((start, end))
+        }
+    }
+}
+
+impl FiberSegments {
+    /// Segment a read from the sorted forward read positions of its 6mA
+    /// calls and the forward read sequence. Stretches without 6mA between
+    /// two calls that meet the `criteria` are nucleosome footprints, the
+    /// stretches between consecutive nucleosomes are MSPs unless there's an
+    /// uncalled stretch (too long or with too few A/T bases) between them.
+    /// The ends of the read, before the first call and after the last call,
+    /// are not segmented.
+    pub(crate) fn from_m6a_positions(
+        m6a_positions: &[usize],
+        forward_seq: &[u8],
+        criteria: &NucleosomeCriteria,
+    ) -> Self {
+        let mut nucleosomes = Vec::new();
+        let mut msps = Vec::new();
+        // end of the last nucleosome, if nothing uncalled has come after it
+        let mut msp_start = None;
+        for w in m6a_positions.windows(2) {
+            let (start, end) = (w[0] + 1, w[1]);
+            match criteria.classify(start, end, forward_seq) {
+                Gap::Nucleosome(nucleosome) => {
+                    if let Some(msp_start) = msp_start {
+                        msps.push((msp_start, nucleosome.0));
+                    }
+                    msp_start = Some(nucleosome.1);
+                    nucleosomes.push(nucleosome);
+                }
+                Gap::Accessible => {}
+                Gap::Uncalled => msp_start = None,
+            }
+        }
+        Self { nucleosomes, msps }
+    }
+}
+
+/// Sorted forward read positions with a passing 6mA call. Calls on the
+/// reverse complement strand (e.g. T-a) are included, Fiber-seq methylates
+/// both strands of the molecule.
+pub(crate) fn m6a_positions(
+    record: &bam::Record,
+    caller: &MultipleThresholdModCaller,
+) -> Result<Vec<usize>, RunError> {
+    let mod_base_info = ModBaseInfo::new_from_record(record)?;
+    let mut positions = mod_base_info
+        .iter_seq_base_mod_probs()
+        .filter(|(canonical_base, strand, _)| {
+            matches!(
+                (**canonical_base, strand),
+                ('A', Strand::Positive) | ('T', Strand::Negative)
+            )
+        })
+        .filter_map(|(canonical_base, _strand, seq_pos_base_mod_probs)| {
+            DnaBase::parse(*canonical_base)
+                .ok()
+                .map(|dna_base| (dna_base, seq_pos_base_mod_probs))
+        })
+        .flat_map(|(dna_base, seq_pos_base_mod_probs)| {
+            seq_pos_base_mod_probs
+                .pos_to_base_mod_probs
+                .iter()
+                .filter_map(move |(pos, base_mod_probs)| {
+                    match caller.call(&dna_base, base_mod_probs) {
+                        BaseModCall::Modified(_, SIX_METHYL_ADENINE) => {
+                            Some(*pos)
+                        }
+                        _ => None,
+                    }
+                })
+        })
+        .collect::<Vec<usize>>();
+    positions.sort();
+    positions.dedup();
+    Ok(positions)
+}
+
+fn replace_segment_tags(
+    record: &mut bam::Record,
+    starts_tag: &str,
+    lengths_tag: &str,
+    segments: &[Segment],
+) -> Result<(), RunError> {
+    for tag in [starts_tag, lengths_tag] {
+        // the tag may not be present
+        let _ = record.remove_aux(tag.as_bytes());
+    }
+    if segments.is_empty() {
+        return Ok(());
+    }
+    let starts = segments
+        .iter()
+        .map(|(start, _)| *start as u32)
+        .collect::<Vec<u32>>();
+    let lengths = segments
+        .iter()
+        .map(|(start, end)| (end - start) as u32)
+        .collect::<Vec<u32>>();
+    for (tag, values) in [(starts_tag, starts), (lengths_tag, lengths)] {
+        let arr: AuxArray<u32> = (&values).into();
+        record
+            .push_aux(tag.as_bytes(), Aux::ArrayU32(arr))
+            .map_err(|e| {
+                RunError::new_failed(format!("failed to add {tag} tag, {e}"))
+            })?;
+    }
+    Ok(())
+}
+
+/// Write the segments to the ns/nl (nucleosomes) and as/al (MSPs) tags,
+/// replacing any existing values.
+pub(crate) fn add_segment_tags(
+    record: &mut bam::Record,
+    segments: &FiberSegments,
+) -> Result<(), RunError> {
+    replace_segment_tags(
+        record,
+        NUCLEOSOME_STARTS_TAG,
+        NUCLEOSOME_LENGTHS_TAG,
+        &segments.nucleosomes,
+    )?;
+    replace_segment_tags(
+        record,
+        MSP_STARTS_TAG,
+        MSP_LENGTHS_TAG,
+        &segments.msps,
+    )
+}
+
+/// Segments of a read projected to reference coordinates.
+#[derive(Debug, Default)]
+pub(crate) struct ReferenceSegments {
+    pub(crate) nucleosomes: Vec<(u64, u64)>,
+    pub(crate) msps: Vec<(u64, u64)>,
+}
+
+impl ReferenceSegments {
+    /// Project the segments to the reference, each segment spans the
+    /// reference positions of its aligned bases. Segments without any
+    /// aligned bases are dropped.
+    pub(crate) fn project(
+        record: &bam::Record,
+        segments: &FiberSegments,
+    ) -> Self {
+        let mut ref_positions = vec![None; record.seq_len()];
+        for (q_pos, r_pos) in
+            get_aligned_pairs_forward(record).filter_map(|r| r.ok())
+        {
+            if let Some(x) = ref_positions.get_mut(q_pos) {
+                *x = Some(r_pos);
+            }
+        }
+        let project = |segments: &[Segment]| {
+            let mut projected = segments
+                .iter()
+                .filter_map(|(start, end)| {
+                    let aligned = ref_positions
+                        .get(*start..*end)
+                        .unwrap_or(&[])
+                        .iter()
+                        .flatten();
+                    let min = aligned.clone().min()?;
+                    let max = aligned.max()?;
+                    Some((*min, *max + 1))
+                })
+                .collect::<Vec<(u64, u64)>>();
+            projected.sort();
+            projected
+        };
+        Self {
+            nucleosomes: project(&segments.nucleosomes),
+            msps: project(&segments.msps),
+        }
+    }
+}
+
+#[cfg(test)]
+mod fiber_tests {
+    use crate::fiber::{FiberSegments, NucleosomeCriteria};
+
+    #[test]
+    fn test_segment_from_m6a_positions() {
+        let seq = "AT".repeat(500);
+        let criteria = NucleosomeCriteria::new(75, 250, 20);
+        let positions = [2, 5, 100, 103, 104, 200, 210, 290];
+        let segments = FiberSegments::from_m6a_positions(
+            &positions,
+            seq.as_bytes(),
+            &criteria,
+        );
+        assert_eq!(
+            segments.nucleosomes,
+            vec![(6, 100), (105, 200), (211, 290)]
+        );
+        assert_eq!(segments.msps, vec![(100, 105), (200, 211)]);
+
+        // a single nucleosome has no MSPs
+        let segments = FiberSegments::from_m6a_positions(
+            &[0, 80],
+            seq.as_bytes(),
+            &criteria,
+        );
+        assert_eq!(segments.nucleosomes, vec![(1, 80)]);
+        assert!(segments.msps.is_empty());
+
+        for positions in [&[0, 10, 20][..], &[]] {
+            let segments = FiberSegments::from_m6a_positions(
+                positions,
+                seq.as_bytes(),
+                &criteria,
+            );
+            assert_eq!(segments, FiberSegments::default());
+        }
+    }
+
+    #[test]
+    fn test_segment_max_nucleosome_length() {
+        let seq = "AT".repeat(500);
+        let criteria = NucleosomeCriteria::new(75, 250, 20);
+        // the 399 base stretch is too long to be a single nucleosome, so
+        // there are no MSPs across it
+        let positions = [0, 100, 110, 510, 520, 620];
+        let segments = FiberSegments::from_m6a_positions(
+            &positions,
+            seq.as_bytes(),
+            &criteria,
+        );
+        assert_eq!(segments.nucleosomes, vec![(1, 100), (521, 620)]);
+        assert!(segments.msps.is_empty());
+
+        let criteria = NucleosomeCriteria::new(75, 400, 20);
+        let segments = FiberSegments::from_m6a_positions(
+            &positions,
+            seq.as_bytes(),
+            &criteria,
+        );
+        assert_eq!(
+            segments.nucleosomes,
+            vec![(1, 100), (111, 510), (521, 620)]
+        );
+        assert_eq!(segments.msps, vec![(100, 111), (510, 521)]);
+    }
+
+    #[test]
+    fn test_segment_min_at_bases() {
+        // the stretch between 100 and 200 is all G/C, there are no bases
+        // where 6mA could have been seen
+        let seq = format!(
+            "{}{}{}",
+            "AT".repeat(50),
+            "GC".repeat(50),
+            "AT".repeat(50)
+        );
+        let criteria = NucleosomeCriteria::new(75, 250, 20);
+        let positions = [0, 90, 100, 199, 210, 299];
+        let segments = FiberSegments::from_m6a_positions(
+            &positions,
+            seq.as_bytes(),
+            &criteria,
+        );
+        assert_eq!(segments.nucleosomes, vec![(1, 90), (211, 299)]);
+        assert!(segments.msps.is_empty());
+
+        // 20 of the bases in the stretch are A/T
+        let criteria = NucleosomeCriteria::new(75, 250, 21);
+        let positions = [0, 180];
+        let seq = format!("{}{}", "GC".repeat(80), "AT".repeat(10));
+        let segments = FiberSegments::from_m6a_positions(
+            &positions,
+            seq.as_bytes(),
+            &criteria,
+        );
+        assert!(segments.nucleosomes.is_empty());
+        let criteria = NucleosomeCriteria::new(75, 250, 20);
+        let segments = FiberSegments::from_m6a_positions(
+            &positions,
+            seq.as_bytes(),
+            &criteria,
+        );
+        assert_eq!(segments.nucleosomes, vec![(1, 180)]);
+    }
+}
@@ -0,0 +1,402 @@
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use clap::Args;
+use log::{debug, info};
+use rust_htslib::bam::{self, Read};
+
+use crate::command_utils::{
+    get_bam_writer, get_serial_reader, get_threshold_from_options,
+    parse_per_mod_thresholds, parse_thresholds, using_stream,
+};
+use crate::errs::{InputError, RunError};
+use crate::fiber::writer::{AccessibilityWriter, Bed12Writer};
+use crate::fiber::{
+    add_segment_tags, m6a_positions, FiberSegments, NucleosomeCriteria,
+    ReferenceSegments,
+};
+use crate::logging::init_logging;
+use crate::record_filter::RecordSelection;
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::util::{
+    add_modkit_pg_records, create_out_directory, get_forward_sequence,
+    get_query_name_string, get_spinner, record_is_secondary, Region,
+};
+
+#[derive(Args)]
+pub struct CallFiberSegments {
+    // running args
+    /// Input Fiber-seq modBAM with 6mA calls. Can be a path to a file or one
+    /// of `-` or `stdin` to specify a stream from standard input.
+    in_bam: String,
+    /// Output BAM with the nucleosome (ns/nl) and MSP (as/al) tags added, or
+    /// BED12 when --bed12 is set. Can be a path to a file or one of `-` or
+    /// `stdout` to specify a stream to standard output.
+    out_path: String,
+    /// Write the segments of each read as BED12 rows in reference
+    /// coordinates instead of a BAM. One row is written for the nucleosomes
+    /// and one for the MSPs of each primary, aligned, read.
+    #[arg(long, default_value_t = false)]
+    bed12: bool,
+    /// Write an aggregate accessibility track to this file, the number of
+    /// reads with an MSP and with a nucleosome at each reference position.
+    /// Requires the input to be sorted by coordinate.
+    #[arg(long)]
+    accessibility: Option<PathBuf>,
+    /// Specify a file for debug logs to be written to, otherwise ignore them.
+    /// Setting a file is recommended.
+    #[arg(long)]
+    log_filepath: Option<PathBuf>,
+    /// Fast fail, stop processing at the first invalid sequence record. Default
+    /// behavior is to continue and report failed/skipped records at the end.
+    #[arg(long = "ff", default_value_t = false)]
+    fail_fast: bool,
+    /// Hide the progress bar.
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    suppress_progress: bool,
+    /// Output SAM format instead of BAM.
+    #[arg(long, default_value_t = false, conflicts_with = "bed12")]
+    output_sam: bool,
+
+    // segmentation args
+    /// Minimum length of a stretch of the read without 6mA calls, between two
+    /// 6mA calls, to be called a nucleosome footprint.
+    #[arg(long, default_value_t = 75)]
+    nucleosome_length: usize,
+    /// Maximum length of a stretch without 6mA calls to be called a
+    /// nucleosome footprint. Longer stretches can't be resolved into single
+    /// nucleosomes and are not called, MSPs aren't called across them.
+    #[arg(long, default_value_t = 250)]
+    max_nucleosome_length: usize,
+    /// Minimum number of A and T bases in a stretch without 6mA calls for it
+    /// to be called a nucleosome footprint. 6mA can only be seen at A/T
+    /// bases, so a stretch with few of them has no evidence of protection.
+    #[arg(long, default_value_t = 20)]
+    min_at_bases: usize,
+
+    // processing args
+    /// Number of threads to use for reading and writing BAMs and estimating
+    /// the threshold.
+    #[arg(short, long, default_value_t = 4)]
+    threads: usize,
+
+    // sampling args
+    /// Sample approximately this many reads when estimating the filtering threshold.
+    /// If alignments are present reads will be sampled evenly across aligned genome.
+    /// If a region is specified with the --sample-region option, then reads will be
+    /// sampled evenly across the region given.
+    #[arg(
+        group = "sampling_options",
+        short = 'n',
+        long,
+        default_value_t = 10_042
+    )]
+    num_reads: usize,
+    /// Sample this fraction of the reads when estimating the filter-percentile.
+    /// In practice, 50-100 thousand reads is sufficient to estimate the model output
+    /// distribution and determine the filtering threshold. See filtering.md for
+    /// details on filtering.
+    #[arg(
+        group = "sampling_options",
+        short = 'f',
+        long,
+        hide_short_help = true
+    )]
+    sampling_frac: Option<f64>,
+    /// Set a random seed for deterministic running, the default is non-deterministic,
+    /// only used when no BAM index is provided.
+    #[arg(
+        long,
+        conflicts_with = "num_reads",
+        requires = "sampling_frac",
+        hide_short_help = true
+    )]
+    seed: Option<u64>,
+    /// Specify a region for sampling reads from when estimating the threshold probability.
+    /// Format should be <chrom_name>:<start>-<end> or <chrom_name>.
+    #[arg(long)]
+    sample_region: Option<String>,
+    /// Interval chunk size to process concurrently when estimating the threshold
+    /// probability.
+    #[arg(long, default_value_t = 1_000_000, hide_short_help = true)]
+    sampling_interval_size: u32,
+
+    /// Filter out modified base calls where the probability of the predicted
+    /// variant is below this confidence percentile. For example, 0.1 will filter
+    /// out the 10% lowest confidence modification calls.
+    #[arg(
+        group = "thresholds",
+        short = 'p',
+        long,
+        default_value_t = 0.1,
+        hide_short_help = true
+    )]
+    filter_percentile: f32,
+    /// Specify the filter threshold globally or per primary base. A global filter
+    /// threshold can be specified with by a decimal number (e.g. 0.75). Per-base
+    /// thresholds can be specified by colon-separated values, for example A:0.75
+    /// specifies a threshold value of 0.75 for adenine modification calls.
+    #[arg(
+    long,
+    group = "thresholds",
+    action = clap::ArgAction::Append,
+    alias = "pass_threshold"
+    )]
+    filter_threshold: Option<Vec<String>>,
+    /// Specify a passing threshold to use for a base modification, independent of the
+    /// threshold for the primary sequence base or the default. For example, to set
+    /// the pass threshold for 6mA to 0.8 use `--mod-threshold a:0.8`.
+    #[arg(
+    long = "mod-threshold",
+    action = clap::ArgAction::Append
+    )]
+    mod_thresholds: Option<Vec<String>>,
+    /// Don't filter base modification calls, assign each base modification to the
+    /// highest probability prediction.
+    #[arg(long, default_value_t = false)]
+    no_filtering: bool,
+}
+
+/// Destination of the per-read segments.
+enum SegmentsOutput {
+    Bam(bam::Writer),
+    Bed12(Bed12Writer<Box<dyn std::io::Write>>),
+}
+
+fn segment_record(
+    record: &bam::Record,
+    caller: &MultipleThresholdModCaller,
+    criteria: &NucleosomeCriteria,
+) -> Result<FiberSegments, RunError> {
+    if record.seq_len() == 0 {
+        return Err(RunError::new_skipped("no sequence"));
+    }
+    let m6a_positions = m6a_positions(record, caller)?;
+    let forward_seq = get_forward_sequence(record)?;
+    Ok(FiberSegments::from_m6a_positions(
+        &m6a_positions,
+        forward_seq.as_bytes(),
+        criteria,
+    ))
+}
+
+impl CallFiberSegments {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        if self.nucleosome_length == 0 {
+            bail!("nucleosome length must be greater than 0")
+        }
+        if self.max_nucleosome_length < self.nucleosome_length {
+            bail!(
+                "max nucleosome length must be at least the nucleosome length"
+            )
+        }
+        let criteria = NucleosomeCriteria::new(
+            self.nucleosome_length,
+            self.max_nucleosome_length,
+            self.min_at_bases,
+        );
+        let mut reader = get_serial_reader(&self.in_bam)?;
+        reader.set_threads(self.threads)?;
+        let header_view = reader.header().to_owned();
+
+        let per_mod_thresholds = self
+            .mod_thresholds
+            .as_ref()
+            .map(|raw_per_mod_thresholds| {
+                parse_per_mod_thresholds(raw_per_mod_thresholds)
+            })
+            .transpose()?;
+        let sampling_region = self
+            .sample_region
+            .as_ref()
+            .map(|raw_region| {
+                info!("parsing sample region {raw_region}");
+                Region::parse_str(raw_region, &header_view)
+            })
+            .transpose()?;
+
+        let caller = if let Some(raw_threshold) = &self.filter_threshold {
+            parse_thresholds(raw_threshold, per_mod_thresholds)?
+        } else {
+            if using_stream(&self.in_bam) {
+                bail!(
+                    "must specify all thresholds with --filter-threshold and \
+                     (optionally) --mod-threshold when using stdin stream"
+                )
+            }
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(self.threads)
+                .build()
+                .with_context(|| "failed to make threadpool")?;
+            pool.install(|| {
+                get_threshold_from_options(
+                    std::slice::from_ref(
+                        &Path::new(&self.in_bam).to_path_buf(),
+                    ),
+                    self.threads,
+                    self.sampling_interval_size,
+                    self.sampling_frac,
+                    self.num_reads,
+                    self.no_filtering,
+                    self.filter_percentile,
+                    self.seed,
+                    sampling_region.as_ref(),
+                    per_mod_thresholds,
+                    None,
+                    None,
+                    None,
+                    RecordSelection::new(false, None),
+                    self.suppress_progress,
+                )
+            })?
+        };
+
+        let mut output = if self.bed12 {
+            let out: Box<dyn std::io::Write> = if using_stream(&self.out_path) {
+                Box::new(std::io::stdout())
+            } else {
+                create_out_directory(&self.out_path)?;
+                Box::new(
+                    std::fs::File::create(&self.out_path)
+                        .context("failed to make output file")?,
+                )
+            };
+            SegmentsOutput::Bed12(Bed12Writer::new(BufWriter::new(out)))
+        } else {
+            let mut header = bam::Header::from_template(&header_view);
+            add_modkit_pg_records(&mut header);
+            SegmentsOutput::Bam(get_bam_writer(
+                &self.out_path,
+                &header,
+                self.output_sam,
+            )?)
+        };
+        let mut accessibility_writer = self
+            .accessibility
+            .as_ref()
+            .map(|fp| {
+                create_out_directory(fp)?;
+                let fh = std::fs::File::create(fp)
+                    .context("failed to make accessibility output file")?;
+                Ok::<_, anyhow::Error>(AccessibilityWriter::new(
+                    BufWriter::new(fh),
+                ))
+            })
+            .transpose()?;
+
+        let spinner = get_spinner();
+        if self.suppress_progress {
+            spinner.set_draw_target(indicatif::ProgressDrawTarget::hidden())
+        }
+        spinner.set_message("Calling nucleosomes and MSPs");
+        let mut n_segmented = 0usize;
+        let mut n_failed = 0usize;
+        let mut n_skipped = 0usize;
+        let mut n_nucleosomes = 0usize;
+        let mut n_msps = 0usize;
+        for result in reader.records() {
+            let mut record = match result {
+                Ok(record) => record,
+                Err(e) => {
+                    if self.fail_fast {
+                        bail!("failed to read record, {e}")
+                    }
+                    n_failed += 1;
+                    continue;
+                }
+            };
+            let record_name =
+                get_query_name_string(&record).unwrap_or("???".to_owned());
+            let segments = match segment_record(&record, &caller, &criteria) {
+                Ok(segments) => Some(segments),
+                Err(RunError::BadInput(InputError(err)))
+                | Err(RunError::Failed(err)) => {
+                    if self.fail_fast {
+                        bail!("read {record_name} failed, {err}")
+                    }
+                    debug!("read {record_name} failed, {err}");
+                    n_failed += 1;
+                    None
+                }
+                Err(RunError::Skipped(_reason)) => {
+                    n_skipped += 1;
+                    None
+                }
+            };
+            if let Some(segments) = segments.as_ref() {
+                n_segmented += 1;
+                n_nucleosomes += segments.nucleosomes.len();
+                n_msps += segments.msps.len();
+            }
+
+            let primary_alignment = !record.is_unmapped()
+                && !record_is_secondary(&record)
+                && !record.is_supplementary();
+            let reference_segments = segments
+                .as_ref()
+                .filter(|_| primary_alignment)
+                .map(|segments| ReferenceSegments::project(&record, segments));
+            let chrom_name = if primary_alignment {
+                String::from_utf8_lossy(
+                    header_view.tid2name(record.tid() as u32),
+                )
+                .to_string()
+            } else {
+                String::new()
+            };
+
+            if let (Some(writer), Some(reference_segments)) =
+                (accessibility_writer.as_mut(), reference_segments.as_ref())
+            {
+                writer.add(
+                    record.tid(),
+                    &chrom_name,
+                    record.pos() as u64,
+                    reference_segments,
+                )?;
+            }
+            match &mut output {
+                SegmentsOutput::Bam(writer) => {
+                    if let Some(segments) = segments.as_ref() {
+                        add_segment_tags(&mut record, segments)
+                            .map_err(|e| anyhow!("{e}"))?;
+                    }
+                    writer.write(&record).map_err(|e| {
+                        anyhow!("failed to write {record_name}, {e}")
+                    })?;
+                }
+                SegmentsOutput::Bed12(writer) => {
+                    if let Some(reference_segments) =
+                        reference_segments.as_ref()
+                    {
+                        let strand =
+                            if record.is_reverse() { '-' } else { '+' };
+                        writer.write(
+                            &chrom_name,
+                            &record_name,
+                            strand,
+                            reference_segments,
+                        )?;
+                    }
+                }
+            }
+            spinner.inc(1);
+        }
+        spinner.finish_and_clear();
+        if let SegmentsOutput::Bed12(writer) = &mut output {
+            writer.finish()?;
+        }
+        if let Some(writer) = accessibility_writer.as_mut() {
+            let n_rows = writer.finish()?;
+            info!("wrote {n_rows} accessibility intervals");
+        }
+        info!(
+            "done, segmented {n_segmented} records into {n_nucleosomes} \
+             nucleosomes and {n_msps} MSPs, {n_failed} failed, {n_skipped} \
+             skipped"
+        );
+        Ok(())
+    }
+}
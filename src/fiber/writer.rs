@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Write};
+
+use anyhow::{bail, Result as AnyhowResult};
+
+use crate::fiber::ReferenceSegments;
+
+const NUCLEOSOME_RGB: &str = "169,169,169";
+const MSP_RGB: &str = "147,112,219";
+
+/// Writes one BED12 row per read for the nucleosomes and another for the
+/// MSPs, each segment is a block. The name is the read ID and the two
+/// kinds of segments are distinguished by the item RGB.
+pub(super) struct Bed12Writer<T: Write> {
+    buf_writer: BufWriter<T>,
+}
+
+impl<T: Write> Bed12Writer<T> {
+    pub(super) fn new(buf_writer: BufWriter<T>) -> Self {
+        Self { buf_writer }
+    }
+
+    fn write_row(
+        &mut self,
+        chrom: &str,
+        read_id: &str,
+        strand: char,
+        rgb: &str,
+        segments: &[(u64, u64)],
+    ) -> AnyhowResult<u64> {
+        let (start, end) = match (segments.first(), segments.last()) {
+            (Some((start, _)), Some((_, end))) => (*start, *end),
+            _ => return Ok(0),
+        };
+        let block_sizes = segments
+            .iter()
+            .map(|(s, e)| format!("{}", e - s))
+            .collect::<Vec<String>>()
+            .join(",");
+        let block_starts = segments
+            .iter()
+            .map(|(s, _)| format!("{}", s - start))
+            .collect::<Vec<String>>()
+            .join(",");
+        let tab = '\t';
+        let row = format!(
+            "{chrom}{tab}\
+            {start}{tab}\
+            {end}{tab}\
+            {read_id}{tab}\
+            0{tab}\
+            {strand}{tab}\
+            {start}{tab}\
+            {end}{tab}\
+            {rgb}{tab}\
+            {}{tab}\
+            {block_sizes}{tab}\
+            {block_starts}\n",
+            segments.len(),
+        );
+        self.buf_writer.write_all(row.as_bytes())?;
+        Ok(1)
+    }
+
+    pub(super) fn write(
+        &mut self,
+        chrom: &str,
+        read_id: &str,
+        strand: char,
+        segments: &ReferenceSegments,
+    ) -> AnyhowResult<u64> {
+        let n_nucleosome_rows = self.write_row(
+            chrom,
+            read_id,
+            strand,
+            NUCLEOSOME_RGB,
+            &segments.nucleosomes,
+        )?;
+        let n_msp_rows =
+            self.write_row(chrom, read_id, strand, MSP_RGB, &segments.msps)?;
+        Ok(n_nucleosome_rows + n_msp_rows)
+    }
+
+    pub(super) fn finish(&mut self) -> AnyhowResult<()> {
+        self.buf_writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Aggregates the reads' segments into runs of reference positions with the
+/// same number of reads in an MSP and in a nucleosome, written as rows of
+/// chrom, start, end, fraction accessible, MSP count, and nucleosome count.
+/// Reads must be added in coordinate-sorted order so that positions before
+/// the start of the current read can be written.
+pub(super) struct AccessibilityWriter<T: Write> {
+    buf_writer: BufWriter<T>,
+    chrom_name: String,
+    tid: i32,
+    last_read_start: u64,
+    // position to change in [msp, nucleosome] counts
+    deltas: BTreeMap<u64, [i64; 2]>,
+    counts: [i64; 2],
+    run_start: u64,
+    rows_written: u64,
+}
+
+impl<T: Write> AccessibilityWriter<T> {
+    pub(super) fn new(buf_writer: BufWriter<T>) -> Self {
+        Self {
+            buf_writer,
+            chrom_name: String::new(),
+            tid: -1,
+            last_read_start: 0,
+            deltas: BTreeMap::new(),
+            counts: [0, 0],
+            run_start: 0,
+            rows_written: 0,
+        }
+    }
+
+    /// Write the runs ending before `position`.
+    fn flush_until(&mut self, position: Option<u64>) -> AnyhowResult<()> {
+        let positions = self
+            .deltas
+            .keys()
+            .take_while(|pos| position.map(|p| **pos < p).unwrap_or(true))
+            .copied()
+            .collect::<Vec<u64>>();
+        for pos in positions {
+            let [msp_delta, nuc_delta] = self.deltas.remove(&pos).unwrap();
+            let counts =
+                [self.counts[0] + msp_delta, self.counts[1] + nuc_delta];
+            if counts == self.counts {
+                continue;
+            }
+            let [n_msp, n_nucleosome] = self.counts;
+            if n_msp + n_nucleosome > 0 {
+                let frac_accessible =
+                    n_msp as f32 / (n_msp + n_nucleosome) as f32;
+                let row = format!(
+                    "{}\t{}\t{pos}\t{frac_accessible}\t{n_msp}\t\
+                     {n_nucleosome}\n",
+                    self.chrom_name, self.run_start,
+                );
+                self.buf_writer.write_all(row.as_bytes())?;
+                self.rows_written += 1;
+            }
+            self.counts = counts;
+            self.run_start = pos;
+        }
+        Ok(())
+    }
+
+    pub(super) fn add(
+        &mut self,
+        tid: i32,
+        chrom_name: &str,
+        read_start: u64,
+        segments: &ReferenceSegments,
+    ) -> AnyhowResult<()> {
+        if tid < self.tid
+            || (tid == self.tid && read_start < self.last_read_start)
+        {
+            bail!("input must be sorted by coordinate to make accessibility track")
+        }
+        if tid != self.tid {
+            self.flush_until(None)?;
+            self.tid = tid;
+            self.chrom_name = chrom_name.to_string();
+        }
+        self.last_read_start = read_start;
+        // all segments from later reads start at or after this read's start
+        self.flush_until(Some(read_start))?;
+        for (idx, segments) in [&segments.msps, &segments.nucleosomes]
+            .into_iter()
+            .enumerate()
+        {
+            for (start, end) in segments {
+                self.deltas.entry(*start).or_insert([0, 0])[idx] += 1;
+                self.deltas.entry(*end).or_insert([0, 0])[idx] -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write all remaining positions, returns the number of rows written.
+    pub(super) fn finish(&mut self) -> AnyhowResult<u64> {
+        self.flush_until(None)?;
+        self.buf_writer.flush()?;
+        Ok(self.rows_written)
+    }
+}
+
+#[cfg(test)]
+mod fiber_writer_tests {
+    use std::io::BufWriter;
+
+    use crate::fiber::writer::AccessibilityWriter;
+    use crate::fiber::ReferenceSegments;
+
+    #[test]
+    fn test_accessibility_writer() {
+        let mut out = Vec::new();
+        {
+            let mut writer = AccessibilityWriter::new(BufWriter::new(&mut out));
+            let read_a = ReferenceSegments {
+                nucleosomes: vec![(10, 20), (30, 40)],
+                msps: vec![(20, 30)],
+            };
+            let read_b = ReferenceSegments {
+                nucleosomes: vec![(20, 30)],
+                msps: vec![(30, 40)],
+            };
+            writer.add(0, "chr1", 5, &read_a).unwrap();
+            writer.add(0, "chr1", 15, &read_b).unwrap();
+            // unsorted
+            assert!(writer.add(0, "chr1", 10, &read_b).is_err());
+            writer.add(1, "chr2", 0, &read_b).unwrap();
+            assert_eq!(writer.finish().unwrap(), 4);
+        }
+        let out = String::from_utf8(out).unwrap();
+        let expected = "chr1\t10\t20\t0\t0\t1\n\
+            chr1\t20\t40\t0.5\t1\t1\n\
+            chr2\t20\t30\t0\t0\t1\n\
+            chr2\t30\t40\t1\t1\t0\n";
+        assert_eq!(out, expected);
+    }
+}
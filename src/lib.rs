@@ -1,8 +1,13 @@
 pub mod adjust;
+pub mod aggregate;
+pub mod calibrate;
 pub mod commands;
+pub mod entropy;
 pub mod errs;
 pub mod extract;
+pub mod fiber;
 pub mod interval_chunks;
+pub mod linkage;
 pub mod logging;
 pub mod mod_bam;
 pub mod mod_base_code;
@@ -10,13 +15,19 @@ pub mod monoid;
 pub mod motif_bed;
 pub mod pileup;
 pub mod position_filter;
+pub mod profile;
+pub mod read_matrix;
+pub mod record_filter;
 pub mod summarize;
 pub mod threshold_mod_caller;
 pub mod thresholds;
+pub mod transcriptome;
 pub mod writers;
 
+mod bigwig;
 pub(crate) mod command_utils;
 pub mod dmr;
+mod parquet_writer;
 pub(crate) mod parsing_utils;
 mod read_cache;
 mod read_ids_to_base_mod_probs;
@@ -0,0 +1,363 @@
+use std::path::Path;
+
+use derive_new::new;
+use log::debug;
+use rust_htslib::bam::{self, ext::BamRecordExtensions, FetchDefinition, Read};
+use rustc_hash::FxHashMap;
+
+use crate::mod_bam::{CollapseMethod, EdgeFilter};
+use crate::mod_base_code::{DnaBase, ModCodeRepr};
+use crate::motif_bed::{motif_site, MotifLocations};
+use crate::read_cache::ReadCache;
+use crate::record_filter::RecordFilter;
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::util::{record_is_secondary, Strand};
+
+pub mod subcommand;
+mod writer;
+
+/// Sorted motif positions of a single contig. Without combining strands
+/// there are two groups of sites, one for each strand, when combining the
+/// strands of a palindromic motif there is a single group with negative
+/// strand positions moved to the positive strand position of the motif, see
+/// [`motif_site`].
+pub(crate) struct LinkageSites {
+    groups: Vec<(Option<Strand>, Vec<u32>)>,
+    combine_strands_offset: Option<i32>,
+}
+
+impl LinkageSites {
+    pub(crate) fn new(
+        motif_locations: &MotifLocations,
+        tid: u32,
+        combine_strands_offset: Option<i32>,
+    ) -> Self {
+        let mut groups = match combine_strands_offset {
+            Some(_) => vec![(None, Vec::new())],
+            None => vec![
+                (Some(Strand::Positive), Vec::new()),
+                (Some(Strand::Negative), Vec::new()),
+            ],
+        };
+        for (position, strand_rule) in
+            motif_locations.get_locations_unchecked(tid)
+        {
+            for strand in strand_rule.strands() {
+                let Some((position, strand)) =
+                    motif_site(*position, strand, combine_strands_offset)
+                else {
+                    continue;
+                };
+                if let Some((_, sites)) =
+                    groups.iter_mut().find(|(group, _)| *group == strand)
+                {
+                    sites.push(position);
+                }
+            }
+        }
+        for (_, sites) in groups.iter_mut() {
+            sites.sort();
+            sites.dedup();
+        }
+        Self {
+            groups,
+            combine_strands_offset,
+        }
+    }
+
+    /// Group index, index of the site in that group, and site position for a
+    /// call at `ref_pos` from a read aligned to `read_strand`.
+    fn site(
+        &self,
+        read_strand: Strand,
+        ref_pos: u32,
+    ) -> Option<(usize, usize, u32)> {
+        let (position, strand) =
+            motif_site(ref_pos, read_strand, self.combine_strands_offset)?;
+        let group =
+            self.groups.iter().position(|(group, _)| *group == strand)?;
+        self.groups[group]
+            .1
+            .binary_search(&position)
+            .ok()
+            .map(|site_idx| (group, site_idx, position))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.groups.iter().all(|(_, sites)| sites.is_empty())
+    }
+}
+
+/// Joint modification state counts and linkage between two motif sites.
+#[derive(new, Debug)]
+pub(crate) struct PairLinkage {
+    pub(crate) pos_a: u32,
+    pub(crate) pos_b: u32,
+    pub(crate) strand: Option<Strand>,
+    /// Counts of reads that are [modified at both sites, modified at A
+    /// and canonical at B, canonical at A and modified at B, canonical at
+    /// both sites].
+    pub(crate) counts: [u32; 4],
+    /// The sites are consecutive motif sites.
+    pub(crate) adjacent: bool,
+}
+
+impl PairLinkage {
+    pub(crate) fn num_reads(&self) -> u32 {
+        self.counts.iter().sum()
+    }
+
+    /// Marginal frequencies of modification at each site and the frequency
+    /// of modification at both sites.
+    fn frequencies(&self) -> (f64, f64, f64) {
+        let n = self.num_reads() as f64;
+        let [mm, mc, cm, _cc] = self.counts.map(|c| c as f64);
+        ((mm + mc) / n, (mm + cm) / n, mm / n)
+    }
+
+    /// Squared correlation of the modification states, `None` when either
+    /// site is invariant.
+    pub(crate) fn r_squared(&self) -> Option<f64> {
+        let (p_a, p_b, p_ab) = self.frequencies();
+        let d = p_ab - p_a * p_b;
+        let denom = p_a * (1f64 - p_a) * p_b * (1f64 - p_b);
+        if denom > 0f64 {
+            Some(d * d / denom)
+        } else {
+            None
+        }
+    }
+
+    /// Lewontin's D', the signed disequilibrium normalized by its maximum
+    /// given the marginal frequencies, `None` when either site is invariant.
+    pub(crate) fn d_prime(&self) -> Option<f64> {
+        let (p_a, p_b, p_ab) = self.frequencies();
+        let d = p_ab - p_a * p_b;
+        let d_max = if d >= 0f64 {
+            (p_a * (1f64 - p_b)).min((1f64 - p_a) * p_b)
+        } else {
+            (p_a * p_b).min((1f64 - p_a) * (1f64 - p_b))
+        };
+        if d_max > 0f64 {
+            Some(d / d_max)
+        } else {
+            None
+        }
+    }
+}
+
+pub(crate) struct RegionLinkage {
+    pub(crate) chrom_name: String,
+    pub(crate) pairs: Vec<PairLinkage>,
+    pub(crate) processed_records: usize,
+    pub(crate) skipped_records: usize,
+}
+
+/// Settings that are the same for every region, which pairs of sites are
+/// counted and how reads are called.
+pub(crate) struct LinkageParams {
+    pub(crate) motif_base: DnaBase,
+    pub(crate) mod_code: Option<ModCodeRepr>,
+    pub(crate) max_distance: u32,
+    pub(crate) min_coverage: u32,
+    pub(crate) caller: MultipleThresholdModCaller,
+    pub(crate) collapse_method: Option<CollapseMethod>,
+    pub(crate) edge_filter: Option<EdgeFilter>,
+    pub(crate) record_filter: Option<RecordFilter>,
+    pub(crate) force_allow: bool,
+}
+
+/// Count the joint states of pairs of sites no more than `max_distance`
+/// apart where the first site of the pair is in [start_pos, end_pos).
+pub(crate) fn process_region_linkage<T: AsRef<Path>>(
+    bam_fp: T,
+    chrom_tid: u32,
+    start_pos: u32,
+    end_pos: u32,
+    linkage_sites: &LinkageSites,
+    params: &LinkageParams,
+) -> Result<RegionLinkage, String> {
+    let motif_base = params.motif_base;
+    let mod_code = params.mod_code;
+    let max_distance = params.max_distance;
+    let mut bam_reader =
+        bam::IndexedReader::from_path(bam_fp).map_err(|e| e.to_string())?;
+    let chrom_name =
+        String::from_utf8_lossy(bam_reader.header().tid2name(chrom_tid))
+            .to_string();
+    if linkage_sites.is_empty() {
+        return Ok(RegionLinkage {
+            chrom_name,
+            pairs: Vec::new(),
+            processed_records: 0,
+            skipped_records: 0,
+        });
+    }
+    // calls at the second site of a pair can be past the end of the region
+    // and, when combining strands, calls on the negative strand are offset
+    // from the site position
+    let offset = linkage_sites
+        .combine_strands_offset
+        .map(|o| o.unsigned_abs())
+        .unwrap_or(0);
+    let fetch_start = start_pos.saturating_sub(offset) as i64;
+    let fetch_end = end_pos as i64 + max_distance as i64 + offset as i64 + 1;
+    bam_reader
+        .fetch(FetchDefinition::Region(
+            chrom_tid as i32,
+            fetch_start,
+            fetch_end,
+        ))
+        .map_err(|e| e.to_string())?;
+
+    let mut read_cache = ReadCache::new(
+        params.collapse_method.as_ref(),
+        &params.caller,
+        params.edge_filter.as_ref(),
+        params.force_allow,
+    );
+    // (group, site index A, site index B) to joint state counts
+    let mut pair_counts =
+        FxHashMap::<(usize, usize, usize), [u32; 4]>::default();
+
+    for record in bam_reader.records().filter_map(|r| r.ok()) {
+        if record.is_unmapped()
+            || record_is_secondary(&record)
+            || record.seq_len() == 0
+            || !params
+                .record_filter
+                .as_ref()
+                .map(|f| f.keep(&record))
+                .unwrap_or(true)
+        {
+            continue;
+        }
+        let (read_strand, expected_base) = if record.is_reverse() {
+            (Strand::Negative, motif_base.complement())
+        } else {
+            (Strand::Positive, motif_base)
+        };
+        let seq = record.seq();
+        let site_positions = record
+            .aligned_pairs()
+            .filter(|[_, r_pos]| *r_pos >= fetch_start && *r_pos < fetch_end)
+            .filter_map(|[q_pos, r_pos]| {
+                let base = DnaBase::parse(seq[q_pos as usize] as char).ok()?;
+                if base != expected_base {
+                    return None;
+                }
+                linkage_sites.site(read_strand, r_pos as u32).map(
+                    |(group, site_idx, key)| {
+                        (group, site_idx, key, r_pos as u32)
+                    },
+                )
+            })
+            .collect::<Vec<(usize, usize, u32, u32)>>();
+        if site_positions.len() < 2 {
+            continue;
+        }
+        let states = site_positions
+            .into_iter()
+            .filter_map(|(group, site_idx, key, r_pos)| {
+                read_cache
+                    .get_mod_call(&record, r_pos, motif_base.char())
+                    .0
+                    .and_then(|call| call.is_modified(mod_code))
+                    .map(|state| (group, site_idx, key, state))
+            })
+            .collect::<Vec<(usize, usize, u32, bool)>>();
+
+        // all of the calls in a read are in the same group and sorted by
+        // position
+        for (i, (group, site_a, key_a, state_a)) in states.iter().enumerate() {
+            if *key_a < start_pos || *key_a >= end_pos {
+                continue;
+            }
+            for (_, site_b, _, state_b) in
+                states[i + 1..].iter().take_while(|(_, _, key_b, _)| {
+                    key_b.saturating_sub(*key_a) <= max_distance
+                })
+            {
+                let count_idx = match (state_a, state_b) {
+                    (true, true) => 0,
+                    (true, false) => 1,
+                    (false, true) => 2,
+                    (false, false) => 3,
+                };
+                pair_counts
+                    .entry((*group, *site_a, *site_b))
+                    .or_insert([0u32; 4])[count_idx] += 1;
+            }
+        }
+    }
+
+    let mut pairs = pair_counts
+        .into_iter()
+        .filter_map(|((group, site_a, site_b), counts)| {
+            let (strand, sites) = &linkage_sites.groups[group];
+            let pair = PairLinkage::new(
+                sites[site_a],
+                sites[site_b],
+                *strand,
+                counts,
+                site_b == site_a + 1,
+            );
+            if pair.num_reads() < params.min_coverage {
+                None
+            } else {
+                Some(pair)
+            }
+        })
+        .collect::<Vec<PairLinkage>>();
+    pairs.sort_by(|a, b| {
+        a.pos_a
+            .cmp(&b.pos_a)
+            .then(a.strand.cmp(&b.strand))
+            .then(a.pos_b.cmp(&b.pos_b))
+    });
+
+    let (processed_records, skipped_records) =
+        read_cache.get_records_used_and_skipped();
+    debug!(
+        "processed {processed_records} reads, skipped {skipped_records} on \
+        {chrom_name}:{start_pos}-{end_pos}"
+    );
+
+    Ok(RegionLinkage {
+        chrom_name,
+        pairs,
+        processed_records,
+        skipped_records,
+    })
+}
+
+#[cfg(test)]
+mod linkage_tests {
+    use crate::linkage::PairLinkage;
+
+    #[test]
+    fn test_pair_linkage_stats() {
+        // perfect linkage
+        let pair = PairLinkage::new(10, 20, None, [5, 0, 0, 5], true);
+        assert_eq!(pair.r_squared(), Some(1f64));
+        assert_eq!(pair.d_prime(), Some(1f64));
+        // independent sites
+        let pair = PairLinkage::new(10, 20, None, [5, 5, 5, 5], true);
+        assert_eq!(pair.r_squared(), Some(0f64));
+        assert_eq!(pair.d_prime(), Some(0f64));
+        // perfect repulsion
+        let pair = PairLinkage::new(10, 20, None, [0, 5, 5, 0], true);
+        assert_eq!(pair.r_squared(), Some(1f64));
+        assert_eq!(pair.d_prime(), Some(-1f64));
+        // site A is always modified
+        let pair = PairLinkage::new(10, 20, None, [6, 4, 0, 0], true);
+        assert_eq!(pair.r_squared(), None);
+        assert_eq!(pair.d_prime(), None);
+        // complete but not perfect linkage, one haplotype is missing
+        let pair = PairLinkage::new(10, 20, None, [4, 0, 2, 4], true);
+        assert_eq!(pair.d_prime(), Some(1f64));
+        let r2 = pair.r_squared().unwrap();
+        // D = 0.4 - 0.4 * 0.6 = 0.16, r2 = 0.16^2 / (0.4 * 0.6 * 0.6 * 0.4)
+        assert!((r2 - 0.4444444).abs() < 1e-6, "{r2}");
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context};
+use clap::Args;
+use crossbeam_channel::bounded;
+use indicatif::{MultiProgress, ParallelProgressIterator};
+use log::{debug, error, info};
+use rayon::prelude::*;
+use rust_htslib::bam::{self, Read};
+
+use crate::command_utils::ModCallerArgs;
+use crate::interval_chunks::IntervalChunks;
+use crate::linkage::writer::{BlockWriter, LinkageWriter};
+use crate::linkage::{
+    process_region_linkage, LinkageParams, LinkageSites, RegionLinkage,
+};
+use crate::logging::init_logging;
+use crate::mod_base_code::{DnaBase, ModCodeRepr};
+use crate::motif_bed::{MotifLocations, MultipleMotifLocations, RegexMotif};
+use crate::record_filter::RecordFilterArgs;
+use crate::util::{
+    create_out_directory, get_master_progress_bar, get_subroutine_progress_bar,
+    get_targets, get_ticker, Region,
+};
+use crate::writers::OutWriter;
+
+#[derive(Args)]
+pub struct MethylationLinkage {
+    // running args
+    /// Input BAM, should be sorted and have associated index available.
+    in_bam: PathBuf,
+    /// Output file to write the pairwise linkage into. Specify "-" or "stdout"
+    /// to direct output to stdout.
+    out_path: String,
+    /// Specify a file for debug logs to be written to, otherwise ignore them.
+    /// Setting a file is recommended. (alias: log)
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Process only the specified region of the BAM when calculating linkage.
+    /// Format should be <chrom_name>:<start>-<end> or <chrom_name>. Commas are allowed.
+    #[arg(long)]
+    region: Option<String>,
+    /// Reference sequence in FASTA format, required to find motif sites.
+    #[arg(long = "ref", alias = "reference", short = 'r')]
+    reference_fasta: PathBuf,
+    /// Calculate linkage between CpG sites, short hand for --motif CG 0.
+    #[arg(long, group = "motif_options", default_value_t = false)]
+    cpg: bool,
+    /// Calculate linkage between sites of this sequence motif. The first argument
+    /// should be the sequence motif and the second argument is the 0-based offset
+    /// to the base to use. For example: --motif CGCG 0 indicates to use the first
+    /// C on the top strand and the last C (complement to G) on the bottom strand.
+    #[arg(long, group = "motif_options", num_args = 2)]
+    motif: Option<Vec<String>>,
+    /// Respect soft masking in the reference FASTA.
+    #[arg(long, short = 'k', default_value_t = false, hide_short_help = true)]
+    mask: bool,
+    /// Combine calls on the positive and negative strands of a palindromic
+    /// motif (such as CpG) so that reads aligned to both strands are used for
+    /// each pair of sites, sites are reported at the positive strand position.
+    #[arg(long, default_value_t = false)]
+    combine_strands: bool,
+    /// Only use calls of this modification code, calls of other modifications
+    /// are removed. By default any modification counts as modified.
+    #[arg(long)]
+    mod_code: Option<String>,
+    /// Maximum distance in base pairs between the two sites of a pair.
+    #[arg(long, short = 'd', default_value_t = 1_000)]
+    max_distance: u32,
+    /// Minimum number of reads with passing calls at both sites for a pair to
+    /// be reported.
+    #[arg(long, short = 'm', default_value_t = 10)]
+    min_coverage: u32,
+    /// Call methylation haplotype blocks and write them to this file. Blocks
+    /// are runs of consecutive sites where the r-squared between each pair of
+    /// adjacent sites is at least --block-min-r2.
+    #[arg(long)]
+    blocks: Option<PathBuf>,
+    /// Minimum r-squared between adjacent sites to extend a block.
+    #[arg(long, requires = "blocks", default_value_t = 0.5)]
+    block_min_r2: f64,
+    /// Minimum number of sites in a reported block.
+    #[arg(long, requires = "blocks", default_value_t = 3)]
+    block_min_sites: u32,
+
+    // processing args
+    /// Number of threads to use while processing chunks concurrently.
+    #[arg(short, long, default_value_t = 4)]
+    threads: usize,
+    /// Interval chunk size in base pairs to process concurrently. Smaller
+    /// interval chunk sizes will use less memory but incur more overhead.
+    #[arg(
+        short = 'i',
+        long,
+        default_value_t = 100_000,
+        hide_short_help = true
+    )]
+    interval_size: u32,
+    /// Hide the progress bar.
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    suppress_progress: bool,
+
+    #[command(flatten)]
+    mod_caller_args: ModCallerArgs,
+    // record filter args
+    #[command(flatten)]
+    record_filter_args: RecordFilterArgs,
+}
+
+impl MethylationLinkage {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        let header = bam::IndexedReader::from_path(&self.in_bam)
+            .map(|reader| reader.header().to_owned())
+            .context("failed to open input BAM, must be sorted and indexed")?;
+
+        if !(0f64..=1f64).contains(&self.block_min_r2) {
+            bail!("block minimum r-squared must be between 0 and 1")
+        }
+        if self.block_min_sites < 2 {
+            bail!("block minimum sites must be at least 2")
+        }
+        let region = self
+            .region
+            .as_ref()
+            .map(|raw_region| {
+                info!("parsing region {raw_region}");
+                Region::parse_str(raw_region, &header)
+            })
+            .transpose()?;
+        let edge_filter = self.mod_caller_args.edge_filter()?;
+        let collapse_method = self.mod_caller_args.collapse_method()?;
+        let mod_code = self
+            .mod_code
+            .as_ref()
+            .map(|raw_mod_code| ModCodeRepr::parse(raw_mod_code))
+            .transpose()?;
+        let record_filter = self.record_filter_args.to_record_filter()?;
+
+        let regex_motif = match (&self.motif, self.cpg) {
+            (Some(raw_motif_parts), _) => {
+                RegexMotif::from_raw_parts(raw_motif_parts, false)?
+                    .into_iter()
+                    .next()
+                    .ok_or(anyhow!("illegal number of parts for motif"))?
+            }
+            (None, true) => RegexMotif::parse_string("CG", 0).unwrap(),
+            (None, false) => bail!("need to specify either --motif or --cpg"),
+        };
+        let combine_strands_offset = self
+            .combine_strands
+            .then(|| regex_motif.combine_strands_offset())
+            .transpose()?;
+        let motif_base = regex_motif
+            .raw_motif
+            .chars()
+            .nth(regex_motif.forward_offset)
+            .ok_or(anyhow!("motif offset is out of bounds"))
+            .and_then(DnaBase::parse)
+            .context("motif base must be one of A, C, G, or T")?;
+
+        let out_fp_str = self.out_path.clone();
+        let mut writer: Box<dyn OutWriter<RegionLinkage>> =
+            match out_fp_str.as_str() {
+                "stdout" | "-" => {
+                    let writer = BufWriter::new(std::io::stdout());
+                    Box::new(LinkageWriter::new(writer))
+                }
+                _ => {
+                    create_out_directory(&out_fp_str)?;
+                    let fh = std::fs::File::create(out_fp_str)
+                        .context("failed to make output file")?;
+                    Box::new(LinkageWriter::new(BufWriter::new(fh)))
+                }
+            };
+        let mut block_writer = self
+            .blocks
+            .as_ref()
+            .map(|fp| {
+                create_out_directory(fp)?;
+                let fh = std::fs::File::create(fp)
+                    .context("failed to make blocks output file")?;
+                Ok::<_, anyhow::Error>(BlockWriter::new(
+                    BufWriter::new(fh),
+                    self.block_min_r2,
+                    self.block_min_sites,
+                ))
+            })
+            .transpose()?;
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .with_context(|| "failed to make threadpool")?;
+
+        let master_progress = MultiProgress::new();
+        if self.suppress_progress {
+            master_progress
+                .set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+
+        let tids = get_targets(&header, region.as_ref());
+        let names_to_tid = tids
+            .iter()
+            .map(|target| (target.name.as_str(), target.tid))
+            .collect::<HashMap<&str, u32>>();
+        let motif_locations = pool.install(|| {
+            MotifLocations::from_fasta(
+                &self.reference_fasta,
+                regex_motif,
+                &names_to_tid,
+                self.mask,
+                None,
+                &master_progress,
+            )
+        })?;
+        let tids = motif_locations.filter_reference_records(tids);
+        let motif_locations =
+            MultipleMotifLocations::new(vec![motif_locations]);
+
+        let threshold_caller = self.mod_caller_args.build_caller(
+            std::slice::from_ref(&self.in_bam),
+            &pool,
+            region.as_ref(),
+            edge_filter.as_ref(),
+            collapse_method.as_ref(),
+            record_filter.as_ref(),
+            self.suppress_progress,
+        )?;
+
+        let (snd, rx) = bounded(1_000);
+        let in_bam_fp = self.in_bam.clone();
+        let interval_size = self.interval_size;
+        let params = LinkageParams {
+            motif_base,
+            mod_code,
+            max_distance: self.max_distance,
+            min_coverage: self.min_coverage,
+            caller: threshold_caller,
+            collapse_method,
+            edge_filter,
+            record_filter,
+            force_allow: self.mod_caller_args.force_allow_implicit,
+        };
+
+        let tid_progress =
+            master_progress.add(get_master_progress_bar(tids.len()));
+        tid_progress.set_message("contigs");
+        let write_progress = master_progress.add(get_ticker());
+        write_progress.set_message("pairs written");
+        let skipped_reads = master_progress.add(get_ticker());
+        skipped_reads.set_message("~records skipped");
+        let processed_reads = master_progress.add(get_ticker());
+        processed_reads.set_message("~records processed");
+
+        std::thread::spawn(move || {
+            pool.install(|| {
+                for target in tids {
+                    let linkage_sites = LinkageSites::new(
+                        &motif_locations.motif_locations[0],
+                        target.tid,
+                        combine_strands_offset,
+                    );
+                    let intervals = IntervalChunks::new_with_multiple_motifs(
+                        target.start,
+                        target.length,
+                        interval_size,
+                        target.tid,
+                        Some(&motif_locations),
+                    )
+                    .collect::<Vec<(u32, u32)>>();
+                    let interval_progress = master_progress
+                        .add(get_subroutine_progress_bar(intervals.len()));
+                    interval_progress
+                        .set_message(format!("processing {}", &target.name));
+                    let results = intervals
+                        .into_par_iter()
+                        .progress_with(interval_progress)
+                        .map(|(start, end)| {
+                            process_region_linkage(
+                                &in_bam_fp,
+                                target.tid,
+                                start,
+                                end,
+                                &linkage_sites,
+                                &params,
+                            )
+                        })
+                        .collect::<Vec<Result<RegionLinkage, String>>>();
+                    for result in results {
+                        if let Err(e) = snd.send(result) {
+                            error!("failed to send results, {e}")
+                        }
+                    }
+                    tid_progress.inc(1);
+                }
+                tid_progress.finish_and_clear();
+            });
+        });
+
+        for result in rx.into_iter() {
+            match result {
+                Ok(region_linkage) => {
+                    processed_reads
+                        .inc(region_linkage.processed_records as u64);
+                    skipped_reads.inc(region_linkage.skipped_records as u64);
+                    if let Some(block_writer) = block_writer.as_mut() {
+                        block_writer.write(&region_linkage)?;
+                    }
+                    let rows_written = writer.write(region_linkage)?;
+                    write_progress.inc(rows_written);
+                }
+                Err(message) => {
+                    debug!("unexpected error {message}");
+                }
+            }
+        }
+        let n_blocks = block_writer
+            .as_mut()
+            .map(|block_writer| block_writer.finish())
+            .transpose()?;
+        let rows_processed = write_progress.position();
+        let n_skipped_reads = skipped_reads.position();
+        let n_processed_reads = processed_reads.position();
+        write_progress.finish_and_clear();
+        processed_reads.finish_and_clear();
+        skipped_reads.finish_and_clear();
+        if let Some(n_blocks) = n_blocks {
+            info!("wrote {n_blocks} methylation haplotype blocks");
+        }
+        info!(
+            "Done, wrote {rows_processed} pairs. Processed ~{n_processed_reads} \
+            reads and skipped ~{n_skipped_reads} reads."
+        );
+        Ok(())
+    }
+}
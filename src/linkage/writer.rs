@@ -0,0 +1,226 @@
+use std::io::{BufWriter, Write};
+
+use anyhow::Result as AnyhowResult;
+use rustc_hash::FxHashMap;
+
+use crate::linkage::RegionLinkage;
+use crate::util::Strand;
+use crate::writers::OutWriter;
+
+fn strand_label(strand: Option<Strand>) -> char {
+    strand.map(|s| s.to_char()).unwrap_or('.')
+}
+
+fn format_stat(stat: Option<f64>) -> String {
+    stat.map(|x| format!("{x}")).unwrap_or(".".to_string())
+}
+
+/// Writes a row for each pair of sites: chrom, position of the first site,
+/// position of the second site, strand, number of reads, the four joint
+/// state counts, r-squared, and D'.
+pub(crate) struct LinkageWriter<T: Write> {
+    buf_writer: BufWriter<T>,
+}
+
+impl<T: Write> LinkageWriter<T> {
+    pub(crate) fn new(buf_writer: BufWriter<T>) -> Self {
+        Self { buf_writer }
+    }
+}
+
+impl<T: Write> OutWriter<RegionLinkage> for LinkageWriter<T> {
+    fn write(&mut self, item: RegionLinkage) -> AnyhowResult<u64> {
+        let tab = '\t';
+        let chrom_name = &item.chrom_name;
+        let mut rows_written = 0u64;
+        for pair in item.pairs {
+            let [mm, mc, cm, cc] = pair.counts;
+            let row = format!(
+                "{chrom_name}{tab}\
+                {}{tab}\
+                {}{tab}\
+                {}{tab}\
+                {}{tab}\
+                {mm}{tab}\
+                {mc}{tab}\
+                {cm}{tab}\
+                {cc}{tab}\
+                {}{tab}\
+                {}\n",
+                pair.pos_a,
+                pair.pos_b,
+                strand_label(pair.strand),
+                pair.num_reads(),
+                format_stat(pair.r_squared()),
+                format_stat(pair.d_prime()),
+            );
+            self.buf_writer.write_all(row.as_bytes())?;
+            rows_written += 1;
+        }
+        Ok(rows_written)
+    }
+}
+
+struct OpenBlock {
+    start: u32,
+    last: u32,
+    num_sites: u32,
+    sum_r_squared: f64,
+}
+
+/// Calls methylation haplotype blocks, runs of consecutive sites where the
+/// r-squared between each pair of adjacent sites is at least `min_r_squared`,
+/// and writes them as BED-like rows: chrom, start, end, strand, number of
+/// sites, and mean r-squared of the adjacent pairs. Regions must be written
+/// in order, blocks are extended across regions.
+pub(crate) struct BlockWriter<T: Write> {
+    buf_writer: BufWriter<T>,
+    min_r_squared: f64,
+    min_sites: u32,
+    chrom_name: String,
+    open_blocks: FxHashMap<Option<Strand>, OpenBlock>,
+    blocks_written: u64,
+}
+
+impl<T: Write> BlockWriter<T> {
+    pub(crate) fn new(
+        buf_writer: BufWriter<T>,
+        min_r_squared: f64,
+        min_sites: u32,
+    ) -> Self {
+        Self {
+            buf_writer,
+            min_r_squared,
+            min_sites,
+            chrom_name: String::new(),
+            open_blocks: FxHashMap::default(),
+            blocks_written: 0,
+        }
+    }
+
+    fn close(
+        &mut self,
+        strand: Option<Strand>,
+        block: OpenBlock,
+    ) -> AnyhowResult<()> {
+        if block.num_sites < self.min_sites {
+            return Ok(());
+        }
+        let tab = '\t';
+        let mean_r_squared = block.sum_r_squared / (block.num_sites - 1) as f64;
+        let row = format!(
+            "{}{tab}{}{tab}{}{tab}{}{tab}{}{tab}{mean_r_squared}\n",
+            self.chrom_name,
+            block.start,
+            block.last + 1,
+            strand_label(strand),
+            block.num_sites,
+        );
+        self.buf_writer.write_all(row.as_bytes())?;
+        self.blocks_written += 1;
+        Ok(())
+    }
+
+    fn close_all(&mut self) -> AnyhowResult<()> {
+        let mut open_blocks =
+            self.open_blocks.drain().collect::<Vec<(_, OpenBlock)>>();
+        open_blocks.sort_by_key(|(_, block)| block.start);
+        for (strand, block) in open_blocks {
+            self.close(strand, block)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write(&mut self, item: &RegionLinkage) -> AnyhowResult<()> {
+        if item.chrom_name != self.chrom_name {
+            self.close_all()?;
+            self.chrom_name = item.chrom_name.clone();
+        }
+        for pair in item.pairs.iter().filter(|pair| pair.adjacent) {
+            let linked = pair
+                .r_squared()
+                .filter(|r_squared| *r_squared >= self.min_r_squared);
+            let extends = self
+                .open_blocks
+                .get(&pair.strand)
+                .map(|block| block.last == pair.pos_a && linked.is_some())
+                .unwrap_or(false);
+            if extends {
+                let block = self.open_blocks.get_mut(&pair.strand).unwrap();
+                block.last = pair.pos_b;
+                block.num_sites += 1;
+                block.sum_r_squared += linked.unwrap();
+                continue;
+            }
+            if let Some(block) = self.open_blocks.remove(&pair.strand) {
+                self.close(pair.strand, block)?;
+            }
+            if let Some(r_squared) = linked {
+                self.open_blocks.insert(
+                    pair.strand,
+                    OpenBlock {
+                        start: pair.pos_a,
+                        last: pair.pos_b,
+                        num_sites: 2,
+                        sum_r_squared: r_squared,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Write any blocks still open at the end of the last contig, returns
+    /// the number of blocks written.
+    pub(crate) fn finish(&mut self) -> AnyhowResult<u64> {
+        self.close_all()?;
+        self.buf_writer.flush()?;
+        Ok(self.blocks_written)
+    }
+}
+
+#[cfg(test)]
+mod linkage_writer_tests {
+    use std::io::BufWriter;
+
+    use crate::linkage::writer::BlockWriter;
+    use crate::linkage::{PairLinkage, RegionLinkage};
+
+    #[test]
+    fn test_block_writer() {
+        let linked = [5, 0, 0, 5];
+        let unlinked = [5, 5, 5, 5];
+        let region = |pairs: Vec<PairLinkage>| RegionLinkage {
+            chrom_name: "chr1".to_string(),
+            pairs,
+            processed_records: 0,
+            skipped_records: 0,
+        };
+        let mut out = Vec::new();
+        {
+            let mut writer = BlockWriter::new(BufWriter::new(&mut out), 0.5, 3);
+            writer
+                .write(&region(vec![
+                    PairLinkage::new(10, 20, None, linked, true),
+                    // not adjacent, ignored
+                    PairLinkage::new(10, 30, None, unlinked, false),
+                    PairLinkage::new(20, 30, None, linked, true),
+                    // only 2 sites
+                    PairLinkage::new(40, 50, None, linked, true),
+                    PairLinkage::new(50, 60, None, unlinked, true),
+                    PairLinkage::new(60, 70, None, linked, true),
+                ]))
+                .unwrap();
+            // the block is continued in the next region
+            writer
+                .write(&region(vec![
+                    PairLinkage::new(70, 80, None, linked, true),
+                    PairLinkage::new(80, 90, None, linked, true),
+                ]))
+                .unwrap();
+            assert_eq!(writer.finish().unwrap(), 2);
+        }
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out, "chr1\t10\t31\t.\t3\t1\nchr1\t60\t91\t.\t4\t1\n");
+    }
+}
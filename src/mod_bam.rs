@@ -18,6 +18,7 @@ use rustc_hash::FxHashMap;
 use crate::errs::{InputError, RunError};
 use crate::mod_base_code::{DnaBase, ModCodeRepr};
 use crate::position_filter::StrandedPositionFilter;
+use crate::record_filter::RecordFilter;
 use crate::util;
 use crate::util::{
     get_query_name_string, get_tag, record_is_secondary, Strand,
@@ -26,6 +27,7 @@ use crate::util::{
 pub(crate) struct TrackingModRecordIter<'a, T: bam::Read> {
     records: bam::Records<'a, T>,
     skip_unmapped: bool,
+    record_filter: Option<&'a RecordFilter>,
     pub(crate) num_used: usize,
     pub(crate) num_skipped: usize,
     pub(crate) num_failed: usize,
@@ -35,10 +37,12 @@ impl<'a, T: bam::Read> TrackingModRecordIter<'a, T> {
     pub(crate) fn new(
         records: bam::Records<'a, T>,
         skip_unmapped: bool,
+        record_filter: Option<&'a RecordFilter>,
     ) -> Self {
         Self {
             records,
             skip_unmapped,
+            record_filter,
             num_used: 0,
             num_skipped: 0,
             num_failed: 0,
@@ -59,6 +63,10 @@ impl<'a, T: bam::Read> Iterator for &mut TrackingModRecordIter<'a, T> {
                             .unwrap_or("utf-decode-failed".to_string());
                     if record_is_secondary(&record)
                         || (record.is_unmapped() && self.skip_unmapped)
+                        || self
+                            .record_filter
+                            .map(|filter| !filter.keep(&record))
+                            .unwrap_or(false)
                     {
                         self.num_skipped += 1;
                         continue;
@@ -132,9 +140,10 @@ impl<'a, T: bam::Read> Iterator for &mut TrackingModRecordIter<'a, T> {
     }
 }
 
-pub(crate) fn filter_records_iter<T: bam::Read>(
-    records: bam::Records<T>,
-) -> impl Iterator<Item = (bam::Record, ModBaseInfo)> + '_ {
+pub(crate) fn filter_records_iter<'a, T: bam::Read>(
+    records: bam::Records<'a, T>,
+    record_filter: Option<&'a RecordFilter>,
+) -> impl Iterator<Item = (bam::Record, ModBaseInfo)> + 'a {
     records
         .filter_map(|res| match res {
             Ok(rec) => Some(rec),
@@ -145,6 +154,11 @@ pub(crate) fn filter_records_iter<T: bam::Read>(
         })
         // skip non-primary
         .filter(|record| !record_is_secondary(&record))
+        .filter(move |record| {
+            record_filter
+                .map(|filter| filter.keep(record))
+                .unwrap_or(true)
+        })
         // skip records with empty sequences
         .filter(|record| {
             if record.seq_len() > 0 {
@@ -298,6 +312,24 @@ pub enum BaseModCall {
     Filtered,
 }
 
+impl BaseModCall {
+    /// Binary modification state of the call, `None` for filtered calls and
+    /// calls of modifications other than `mod_code` (when given).
+    pub(crate) fn is_modified(
+        &self,
+        mod_code: Option<ModCodeRepr>,
+    ) -> Option<bool> {
+        match self {
+            BaseModCall::Canonical(_) => Some(false),
+            BaseModCall::Modified(_, code) => match mod_code {
+                Some(mod_code) if mod_code != *code => None,
+                _ => Some(true),
+            },
+            BaseModCall::Filtered => None,
+        }
+    }
+}
+
 impl Eq for BaseModCall {}
 
 impl Ord for BaseModCall {
@@ -622,9 +654,14 @@ impl BaseModPositions {
             .chars()
             .peekable();
 
-        let canonical_base = header
+        // direct RNA tags may use U, the read sequence will always have T
+        let canonical_base = match header
             .nth(0)
-            .ok_or(InputError::new("failed to get canonical base"))?;
+            .ok_or(InputError::new("failed to get canonical base"))?
+        {
+            'U' => 'T',
+            base => base,
+        };
 
         let raw_stand = header
             .nth(0)
@@ -2418,7 +2455,7 @@ mod mod_bam_tests {
             }
         }
 
-        let mod_base_info_iter = filter_records_iter(records);
+        let mod_base_info_iter = filter_records_iter(records, None);
         for (record, mod_base_info) in mod_base_info_iter {
             let aligned_pairs = get_aligned_pairs_forward(&record)
                 .filter_map(|pair| pair.ok())
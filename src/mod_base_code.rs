@@ -31,7 +31,15 @@ pub const ANY_THYMINE: ModCodeRepr = ModCodeRepr::Code('T');
 pub const OXO_GUANINE: ModCodeRepr = ModCodeRepr::Code('o');
 pub const ANY_GUANINE: ModCodeRepr = ModCodeRepr::Code('G');
 
-pub const SUPPORTED_CODES: [ModCodeRepr; 14] = [
+// RNA mods, m6A and m5C use the same codes as DNA ('a' and 'm')
+pub const PSEUDOURIDINE: ModCodeRepr = ModCodeRepr::ChEbi(17802);
+pub const INOSINE: ModCodeRepr = ModCodeRepr::ChEbi(17596);
+pub const TWO_O_METHYL_ADENOSINE: ModCodeRepr = ModCodeRepr::ChEbi(69426);
+pub const TWO_O_METHYL_CYTIDINE: ModCodeRepr = ModCodeRepr::ChEbi(19228);
+pub const TWO_O_METHYL_GUANOSINE: ModCodeRepr = ModCodeRepr::ChEbi(19229);
+pub const TWO_O_METHYL_URIDINE: ModCodeRepr = ModCodeRepr::ChEbi(19227);
+
+pub const SUPPORTED_CODES: [ModCodeRepr; 20] = [
     METHYL_CYTOSINE,
     HYDROXY_METHYL_CYTOSINE,
     FOUR_METHYL_CYTOSINE,
@@ -46,6 +54,12 @@ pub const SUPPORTED_CODES: [ModCodeRepr; 14] = [
     ANY_THYMINE,
     OXO_GUANINE,
     ANY_GUANINE,
+    PSEUDOURIDINE,
+    INOSINE,
+    TWO_O_METHYL_ADENOSINE,
+    TWO_O_METHYL_CYTIDINE,
+    TWO_O_METHYL_GUANOSINE,
+    TWO_O_METHYL_URIDINE,
 ];
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, Hash)]
@@ -74,13 +88,21 @@ impl ModCodeRepr {
             | &FORMYL_CYTOSINE
             | &CARBOXY_CYTOSINE
             | &FOUR_METHYL_CYTOSINE
+            | &TWO_O_METHYL_CYTIDINE
             | &ANY_CYTOSINE => dna_base == DnaBase::C,
-            &SIX_METHYL_ADENINE | &ANY_ADENINE => dna_base == DnaBase::A,
+            &SIX_METHYL_ADENINE
+            | &INOSINE
+            | &TWO_O_METHYL_ADENOSINE
+            | &ANY_ADENINE => dna_base == DnaBase::A,
             &HYDROXY_METHYL_URACIL
             | &FORMYL_URACIL
             | &CARBOXY_URACIL
+            | &PSEUDOURIDINE
+            | &TWO_O_METHYL_URIDINE
             | &ANY_THYMINE => dna_base == DnaBase::T,
-            &OXO_GUANINE | &ANY_GUANINE => dna_base == DnaBase::G,
+            &OXO_GUANINE | &TWO_O_METHYL_GUANOSINE | &ANY_GUANINE => {
+                dna_base == DnaBase::G
+            }
             _ => false,
         }
     }
@@ -138,7 +160,9 @@ impl DnaBase {
             'A' => Ok(Self::A),
             'C' => Ok(Self::C),
             'G' => Ok(Self::G),
-            'T' => Ok(Self::T),
+            // uracil is treated the same as thymine, BAM sequences are
+            // always written with T
+            'T' | 'U' => Ok(Self::T),
             _ => Err(anyhow!("unknown? {nt}".to_string())),
         }
     }
@@ -25,8 +25,8 @@ fn iupac_to_regex(pattern: &str) -> String {
             'A' => "A",
             'C' => "C",
             'G' => "G",
-            'T' => "T",
-            'U' => "U",
+            // reference sequences are searched with U replaced by T
+            'T' | 'U' => "T",
             'M' => "[AC]",
             'R' => "[AG]",
             'W' => "[AT]",
@@ -210,6 +210,38 @@ impl RegexMotif {
             }
         }
     }
+
+    /// Offset to pass to [`motif_site`] to combine calls on both strands of
+    /// this motif at the positive strand position, the motif must be
+    /// palindromic.
+    pub(crate) fn combine_strands_offset(&self) -> AnyhowResult<i32> {
+        if !self.is_palendrome() {
+            bail!("motif {self} is not palindromic, cannot combine strands")
+        }
+        Ok(self.offset())
+    }
+}
+
+/// Site that a motif hit or call at `position` on `strand` is counted at.
+/// Without `combine_strands_offset` each strand is a separate site. With the
+/// offset (from [`RegexMotif::combine_strands_offset`]) negative strand
+/// positions are moved to the positive strand position of the same motif hit
+/// and the site has no strand, `None` when the moved position would be before
+/// the start of the contig.
+pub(crate) fn motif_site(
+    position: u32,
+    strand: Strand,
+    combine_strands_offset: Option<i32>,
+) -> Option<(u32, Option<Strand>)> {
+    match (combine_strands_offset, strand) {
+        (Some(_), Strand::Positive) => Some((position, None)),
+        (Some(offset), Strand::Negative) => {
+            u32::try_from(position as i64 - offset as i64)
+                .ok()
+                .map(|position| (position, None))
+        }
+        (None, strand) => Some((position, Some(strand))),
+    }
 }
 
 impl Display for RegexMotif {
@@ -467,6 +499,7 @@ pub fn get_masked_sequences(
         .filter_map(|(record, tid)| {
             String::from_utf8(record.seq().to_vec())
                 .map(|s| if mask { s } else { s.to_ascii_uppercase() })
+                .map(|s| s.replace('U', "T").replace('u', "t"))
                 .ok()
                 .map(|s| (s, tid))
         })
@@ -594,7 +627,7 @@ impl MotifLocations {
 
 #[cfg(test)]
 mod motif_bed_tests {
-    use crate::motif_bed::{find_motif_hits, RegexMotif};
+    use crate::motif_bed::{find_motif_hits, motif_site, RegexMotif};
     use crate::util::Strand;
 
     #[test]
@@ -640,6 +673,20 @@ mod motif_bed_tests {
         assert!(motif.negative_strand_position(0).is_none())
     }
 
+    #[test]
+    fn test_drach_motif() {
+        let motif = RegexMotif::parse_string("DRACH", 2).unwrap();
+        assert!(!motif.is_palendrome());
+        assert_eq!(motif.reverse_offset, 2);
+        // GGACT on the positive strand, AGTCC (GGACT) on the negative strand
+        let seq = "CGGACTCAGTCCC";
+        let hits = find_motif_hits(seq, &motif);
+        assert_eq!(hits, vec![(3, Strand::Positive), (9, Strand::Negative)]);
+        // U in a motif is the same as T
+        let rna_motif = RegexMotif::parse_string("GGACU", 2).unwrap();
+        assert_eq!(find_motif_hits(seq, &rna_motif), hits);
+    }
+
     #[test]
     fn test_overlapping_motifs() {
         let regex_motif = RegexMotif::parse_string("CHH", 0).unwrap();
@@ -680,4 +727,30 @@ mod motif_bed_tests {
         let gatc = RegexMotif::parse_string("GATC", 1).unwrap();
         assert!(gatc.is_palendrome());
     }
+
+    #[test]
+    fn test_motif_site_combine_strands() {
+        assert!(RegexMotif::parse_string("CHH", 0)
+            .unwrap()
+            .combine_strands_offset()
+            .is_err());
+        let offset = RegexMotif::parse_string("CG", 0)
+            .unwrap()
+            .combine_strands_offset()
+            .unwrap();
+        assert_eq!(offset, 1);
+        assert_eq!(
+            motif_site(11, Strand::Negative, None),
+            Some((11, Some(Strand::Negative)))
+        );
+        assert_eq!(
+            motif_site(10, Strand::Positive, Some(offset)),
+            Some((10, None))
+        );
+        assert_eq!(
+            motif_site(11, Strand::Negative, Some(offset)),
+            Some((10, None))
+        );
+        assert_eq!(motif_site(0, Strand::Negative, Some(offset)), None);
+    }
 }
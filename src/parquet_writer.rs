@@ -0,0 +1,84 @@
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use arrow::array::ArrayRef;
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+
+/// Row groups are only closed at the end of a batch of work (e.g. an interval
+/// chunk) once they have at least this many rows, so that small chunks and
+/// inputs processed one read at a time don't make tiny row groups.
+const MIN_ROW_GROUP_ROWS: usize = 10_000;
+
+/// Dictionary-encoded string column, used for low-cardinality columns like
+/// the chrom, strand, and mod code.
+pub(crate) fn categorical_field(name: &str, nullable: bool) -> Field {
+    Field::new(
+        name,
+        DataType::Dictionary(
+            Box::new(DataType::Int32),
+            Box::new(DataType::Utf8),
+        ),
+        nullable,
+    )
+}
+
+/// Writes typed tables to a (Snappy compressed) Parquet file.
+pub(crate) struct ParquetTableWriter {
+    writer: ArrowWriter<File>,
+    schema: SchemaRef,
+}
+
+impl ParquetTableWriter {
+    pub(crate) fn new(
+        path: &Path,
+        schema: Schema,
+        force: bool,
+    ) -> anyhow::Result<Self> {
+        if path.exists() && !force {
+            return Err(anyhow!(
+                "refusing to write over existing file {path:?}"
+            ));
+        }
+        let fh = File::create(path)
+            .with_context(|| format!("failed to make output file {path:?}"))?;
+        let schema = Arc::new(schema);
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let writer =
+            ArrowWriter::try_new(fh, schema.clone(), Some(properties))?;
+        Ok(Self { writer, schema })
+    }
+
+    /// Write the columns (in schema order) and close the current row group
+    /// if it's large enough.
+    pub(crate) fn write_columns(
+        &mut self,
+        columns: Vec<ArrayRef>,
+    ) -> anyhow::Result<u64> {
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+        let n_rows = batch.num_rows();
+        if n_rows > 0 {
+            self.writer.write(&batch)?;
+        }
+        if self.writer.in_progress_rows() >= MIN_ROW_GROUP_ROWS {
+            self.writer.flush()?;
+        }
+        Ok(n_rows as u64)
+    }
+
+    /// Write the remaining rows and the file footer, must be called for the
+    /// output to be a valid Parquet file.
+    pub(crate) fn finish(&mut self) -> anyhow::Result<()> {
+        self.writer
+            .finish()
+            .map(|_| ())
+            .context("failed to finish Parquet file")
+    }
+}
@@ -15,6 +15,7 @@ use crate::pileup::{
 };
 use crate::position_filter::StrandedPositionFilter;
 use crate::read_cache::DuplexReadCache;
+use crate::record_filter::RecordFilter;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::util::{record_is_secondary, Strand, StrandRule};
 
@@ -219,6 +220,7 @@ pub fn process_region_duplex<T: AsRef<Path>>(
     motif_locations: &MultipleMotifLocations,
     edge_filter: Option<&EdgeFilter>,
     position_filter: Option<&StrandedPositionFilter<()>>,
+    record_filter: Option<&RecordFilter>,
 ) -> anyhow::Result<DuplexModBasePileup> {
     let mut bam_reader = bam::IndexedReader::from_path(bam_fp)?;
     let chrom_name =
@@ -284,7 +286,11 @@ pub fn process_region_duplex<T: AsRef<Path>>(
                     false
                 } else {
                     let record = alignment.record();
-                    !(record_is_secondary(&record) || record.seq_len() == 0)
+                    !(record_is_secondary(&record)
+                        || record.seq_len() == 0
+                        || record_filter
+                            .map(|filter| !filter.keep(&record))
+                            .unwrap_or(false))
                 }
             });
 
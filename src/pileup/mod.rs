@@ -10,11 +10,12 @@ use rust_htslib::bam;
 use rust_htslib::bam::{FetchDefinition, Read};
 use rustc_hash::FxHashMap;
 
-use crate::mod_bam::{BaseModCall, CollapseMethod, EdgeFilter};
+use crate::mod_bam::{BaseModCall, BaseModProbs, CollapseMethod, EdgeFilter};
 use crate::mod_base_code::{BaseState, DnaBase, ModCodeRepr};
 use crate::motif_bed::MultipleMotifLocations;
 use crate::position_filter::StrandedPositionFilter;
 use crate::read_cache::ReadCache;
+use crate::record_filter::RecordFilter;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::util::{
     get_query_name_string, get_stringable_aux, record_is_secondary, SamTag,
@@ -22,6 +23,7 @@ use crate::util::{
 };
 
 pub(crate) mod duplex;
+pub mod stats;
 pub mod subcommand;
 
 #[derive(Debug, Copy, Clone)]
@@ -63,6 +65,18 @@ pub struct PileupFeatureCounts {
     pub n_diff: u32,
     pub n_nocall: u32,
     pub motif_idx: Option<usize>,
+    /// Sum of the modification probabilities for this mod code over all
+    /// of the calls, only present with soft counts.
+    #[new(default)]
+    pub expected_modified: Option<f32>,
+    /// Sum of the canonical probabilities over all of the calls, only
+    /// present with soft counts.
+    #[new(default)]
+    pub expected_canonical: Option<f32>,
+    /// (n_modified, filtered_coverage) on the positive and negative strands
+    /// when counts from the two strands have been combined.
+    #[new(default)]
+    pub strand_counts: Option<[(u32, u32); 2]>,
 }
 
 impl PileupFeatureCounts {
@@ -84,11 +98,14 @@ impl PileupFeatureCounts {
             n_filtered: 0,
             n_diff: 0,
             n_nocall: 0,
+            expected_modified: None,
+            expected_canonical: None,
+            strand_counts: None,
         }
     }
 
     // could make this moniod
-    fn combine_counts_ignore_strand(self, other: Self) -> Self {
+    pub(crate) fn combine_counts_ignore_strand(self, other: Self) -> Self {
         if self.raw_mod_code != other.raw_mod_code {
             error!(
                 "shouldn't be combining counts with different mod codes!\
@@ -115,10 +132,20 @@ impl PileupFeatureCounts {
         let n_diff = self.n_diff + other.n_diff;
         let n_nocall = self.n_nocall + other.n_nocall;
 
-        let fraction_modified = n_modified as f32 / filtered_coverage as f32;
+        let sum_expected = |a: Option<f32>, b: Option<f32>| match (a, b) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+        let expected_modified =
+            sum_expected(self.expected_modified, other.expected_modified);
+        let expected_canonical =
+            sum_expected(self.expected_canonical, other.expected_canonical);
+
+        let fraction_modified = expected_modified.unwrap_or(n_modified as f32)
+            / filtered_coverage as f32;
 
         let motif_idx = self.motif_idx;
-        Self::new(
+        let mut combined = Self::new(
             self.raw_strand,
             filtered_coverage,
             self.raw_mod_code,
@@ -131,7 +158,29 @@ impl PileupFeatureCounts {
             n_diff,
             n_nocall,
             motif_idx,
-        )
+        );
+        combined.expected_modified = expected_modified;
+        combined.expected_canonical = expected_canonical;
+        let [(a_pos_mod, a_pos_valid), (a_neg_mod, a_neg_valid)] =
+            self.counts_by_strand();
+        let [(b_pos_mod, b_pos_valid), (b_neg_mod, b_neg_valid)] =
+            other.counts_by_strand();
+        combined.strand_counts = Some([
+            (a_pos_mod + b_pos_mod, a_pos_valid + b_pos_valid),
+            (a_neg_mod + b_neg_mod, a_neg_valid + b_neg_valid),
+        ]);
+        combined
+    }
+
+    /// (n_modified, filtered_coverage) on each strand.
+    fn counts_by_strand(&self) -> [(u32, u32); 2] {
+        let counts = (self.n_modified, self.filtered_coverage);
+        match (self.strand_counts, self.strand()) {
+            (Some(strand_counts), _) => strand_counts,
+            (None, Some(Strand::Positive)) => [counts, (0, 0)],
+            (None, Some(Strand::Negative)) => [(0, 0), counts],
+            (None, None) => [(0, 0), (0, 0)],
+        }
     }
 
     fn strand(&self) -> Option<Strand> {
@@ -150,6 +199,8 @@ struct Tally {
     n_filtered: u32,
     basecall_counts: FxHashMap<DnaBase, u32>,
     modcall_counts: FxHashMap<DnaBase, FxHashMap<BaseState, u32>>,
+    /// summed probabilities of each base state, only used for soft counts
+    expected_counts: FxHashMap<DnaBase, FxHashMap<BaseState, f32>>,
 }
 
 impl Tally {
@@ -171,6 +222,30 @@ impl Tally {
         }
     }
 
+    fn add_probs(&mut self, base_mod_probs: &BaseModProbs, read_base: DnaBase) {
+        let expected = self.expected_counts.entry(read_base).or_default();
+        *expected
+            .entry(BaseState::Canonical(read_base))
+            .or_insert(0f32) += base_mod_probs.canonical_prob();
+        for (mod_code, prob) in base_mod_probs.iter_probs() {
+            *expected
+                .entry(BaseState::Modified(*mod_code))
+                .or_insert(0f32) += *prob;
+        }
+    }
+
+    /// Summed probability of the base state, None when there are no soft
+    /// counts for the primary base.
+    fn expected_count(
+        &self,
+        primary_base: &DnaBase,
+        base_state: &BaseState,
+    ) -> Option<f32> {
+        self.expected_counts
+            .get(primary_base)
+            .map(|expected| *expected.get(base_state).unwrap_or(&0f32))
+    }
+
     // all of the counts of calls (canonical and mod) that aren't
     // for the primary base of this mode code
     #[inline]
@@ -213,52 +288,82 @@ impl FeatureVector {
         Self::default()
     }
 
-    /// Add counts to the tally.
-    pub(crate) fn add_feature(
+    /// The tally to add a feature to, given the strand the read is aligned
+    /// to and the strand of the read the feature belongs to. None when the
+    /// strand rule excludes the feature.
+    fn tally_mut(
         &mut self,
         alignment_strand: Strand,
-        feature: Feature,
         read_strand: Strand,
         strand_rule: &StrandRule,
-    ) {
+    ) -> Option<&mut Tally> {
         match strand_rule {
             StrandRule::Both => match (alignment_strand, read_strand) {
                 (Strand::Positive, Strand::Positive) => {
-                    self.pos_tally.add_feature(feature)
+                    Some(&mut self.pos_tally)
                 }
                 (Strand::Negative, Strand::Positive) => {
-                    self.neg_tally.add_feature(feature)
+                    Some(&mut self.neg_tally)
                 }
-
                 (Strand::Positive, Strand::Negative) => {
-                    self.neg_tally.add_feature(feature)
+                    Some(&mut self.neg_tally)
                 }
                 (Strand::Negative, Strand::Negative) => {
-                    self.pos_tally.add_feature(feature)
+                    Some(&mut self.pos_tally)
                 }
             },
             StrandRule::Positive => match (alignment_strand, read_strand) {
                 (Strand::Positive, Strand::Positive) => {
-                    self.pos_tally.add_feature(feature)
+                    Some(&mut self.pos_tally)
                 }
                 (Strand::Negative, Strand::Negative) => {
-                    self.pos_tally.add_feature(feature)
+                    Some(&mut self.pos_tally)
                 }
-                _ => {}
+                _ => None,
             },
             StrandRule::Negative => match (alignment_strand, read_strand) {
                 (Strand::Negative, Strand::Positive) => {
-                    self.neg_tally.add_feature(feature)
+                    Some(&mut self.neg_tally)
                 }
-
                 (Strand::Positive, Strand::Negative) => {
-                    self.neg_tally.add_feature(feature)
+                    Some(&mut self.neg_tally)
                 }
-                _ => {}
+                _ => None,
             },
         }
     }
 
+    /// Add counts to the tally.
+    pub(crate) fn add_feature(
+        &mut self,
+        alignment_strand: Strand,
+        feature: Feature,
+        read_strand: Strand,
+        strand_rule: &StrandRule,
+    ) {
+        if let Some(tally) =
+            self.tally_mut(alignment_strand, read_strand, strand_rule)
+        {
+            tally.add_feature(feature)
+        }
+    }
+
+    /// Add the base modification probabilities of a call to the soft counts.
+    fn add_probs(
+        &mut self,
+        alignment_strand: Strand,
+        base_mod_probs: &BaseModProbs,
+        read_base: DnaBase,
+        read_strand: Strand,
+        strand_rule: &StrandRule,
+    ) {
+        if let Some(tally) =
+            self.tally_mut(alignment_strand, read_strand, strand_rule)
+        {
+            tally.add_probs(base_mod_probs, read_base)
+        }
+    }
+
     fn add_tally_to_counts(
         counts: &mut Vec<PileupFeatureCounts>,
         tally: &Tally,
@@ -292,6 +397,10 @@ impl FeatureVector {
 
             let total_num_modified = mod_calls.values().sum::<u32>();
             let filtered_coverage = total_num_modified + n_canonical;
+            let expected_canonical = tally.expected_count(
+                primary_base,
+                &BaseState::Canonical(*primary_base),
+            );
 
             match pileup_options {
                 PileupNumericOptions::Passthrough
@@ -307,8 +416,13 @@ impl FeatureVector {
                         let n_diff = tally.diff_calls_count(primary_base);
                         let n_other_mod =
                             total_num_modified.checked_sub(n_mod).unwrap_or(0);
-                        let percent_modified =
-                            n_mod as f32 / filtered_coverage as f32;
+                        let expected_modified = tally.expected_count(
+                            primary_base,
+                            &BaseState::Modified(mod_code),
+                        );
+                        let percent_modified = expected_modified
+                            .unwrap_or(n_mod as f32)
+                            / filtered_coverage as f32;
 
                         if let Some(idxs) = motif_idxs {
                             for &idx in idxs.iter() {
@@ -325,6 +439,9 @@ impl FeatureVector {
                                     n_diff,
                                     n_nocall,
                                     motif_idx: Some(idx),
+                                    expected_modified,
+                                    expected_canonical,
+                                    strand_counts: None,
                                 });
                             }
                         } else {
@@ -341,13 +458,31 @@ impl FeatureVector {
                                 n_diff,
                                 n_nocall,
                                 motif_idx: None,
+                                expected_modified,
+                                expected_canonical,
+                                strand_counts: None,
                             });
                         }
                     }
                 }
                 PileupNumericOptions::Combine => {
-                    let percent_modified =
-                        total_num_modified as f32 / filtered_coverage as f32;
+                    let expected_modified = tally
+                        .expected_counts
+                        .get(primary_base)
+                        .map(|expected| {
+                            expected
+                                .iter()
+                                .filter_map(
+                                    |(base_state, p)| match base_state {
+                                        BaseState::Modified(_) => Some(*p),
+                                        BaseState::Canonical(_) => None,
+                                    },
+                                )
+                                .sum::<f32>()
+                        });
+                    let percent_modified = expected_modified
+                        .unwrap_or(total_num_modified as f32)
+                        / filtered_coverage as f32;
                     let n_diff = tally.diff_calls_count(&primary_base);
                     if let Some(idxs) = motif_idxs.as_ref() {
                         for &idx in idxs.iter() {
@@ -366,6 +501,9 @@ impl FeatureVector {
                                 n_diff,
                                 n_nocall,
                                 motif_idx: Some(idx),
+                                expected_modified,
+                                expected_canonical,
+                                strand_counts: None,
                             })
                         }
                     } else {
@@ -384,6 +522,9 @@ impl FeatureVector {
                             n_diff,
                             n_nocall,
                             motif_idx: None,
+                            expected_modified,
+                            expected_canonical,
+                            strand_counts: None,
                         })
                     }
                 }
@@ -795,12 +936,94 @@ impl<'a> Iterator for PileupIter<'a> {
     }
 }
 
+/// Pileups at the same reference position from one or more BAMs, the
+/// `usize` is the index of the input BAM each pileup came from.
+struct MultiStrandPileup {
+    pos: u32,
+    strand_rule: StrandRule,
+    bam_pileups: Vec<(usize, bam::pileup::Pileup)>,
+}
+
+/// Steps through the pileups of several BAMs in lock-step, yielding the
+/// pileups from every BAM that has coverage at the next position.
+struct MultiPileupIter<'a> {
+    pileup_iters: Vec<std::iter::Peekable<PileupIter<'a>>>,
+}
+
+impl<'a> MultiPileupIter<'a> {
+    fn new(pileup_iters: Vec<PileupIter<'a>>) -> Self {
+        Self {
+            pileup_iters: pileup_iters
+                .into_iter()
+                .map(|it| it.peekable())
+                .collect(),
+        }
+    }
+}
+
+impl<'a> Iterator for MultiPileupIter<'a> {
+    type Item = MultiStrandPileup;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pos = self
+            .pileup_iters
+            .iter_mut()
+            .filter_map(|it| it.peek().map(|plp| plp.bam_pileup.pos()))
+            .min()?;
+        let mut strand_rule = StrandRule::Both;
+        let bam_pileups = self
+            .pileup_iters
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(idx, it)| {
+                it.next_if(|plp| plp.bam_pileup.pos() == pos).map(|plp| {
+                    // the strand rule only depends on the position, so
+                    // it's the same for all of the inputs
+                    strand_rule = plp.strand_rule;
+                    (idx, plp.bam_pileup)
+                })
+            })
+            .collect::<Vec<(usize, bam::pileup::Pileup)>>();
+
+        Some(MultiStrandPileup {
+            pos,
+            strand_rule,
+            bam_pileups,
+        })
+    }
+}
+
 #[derive(Debug, Hash, Eq, PartialEq, Copy, Clone, Ord, PartialOrd)]
 pub enum PartitionKey {
     NoKey,
     Key(usize),
 }
 
+/// The tags used to partition reads by haplotype, HP for the haplotype and
+/// PS for the phase block.
+pub(crate) fn haplotype_partition_tags() -> Vec<SamTag> {
+    vec![SamTag::new(*b"HP"), SamTag::new(*b"PS")]
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Haplotype {
+    Hp1 = 0,
+    Hp2 = 1,
+    Unphased = 2,
+}
+
+/// Which haplotype a partition key made from the `haplotype_partition_tags`
+/// belongs to, along with the phase block ID for phased reads.
+pub(crate) fn parse_haplotype_key(key: &str) -> (Haplotype, Option<&str>) {
+    let (hp, ps) = key.split_once('_').unwrap_or((key, "missing"));
+    let ps = if ps == "missing" { None } else { Some(ps) };
+    match hp {
+        "1" => (Haplotype::Hp1, ps),
+        "2" => (Haplotype::Hp2, ps),
+        _ => (Haplotype::Unphased, None),
+    }
+}
+
 fn get_forward_read_base(
     alignment: &bam::pileup::Alignment,
     record: &bam::Record,
@@ -865,7 +1088,7 @@ pub enum PileupNumericOptions {
 }
 
 impl PileupNumericOptions {
-    fn get_collapse_method(&self) -> Option<&CollapseMethod> {
+    pub(crate) fn get_collapse_method(&self) -> Option<&CollapseMethod> {
         match self {
             Self::Collapse(method) => Some(method),
             _ => None,
@@ -873,8 +1096,25 @@ impl PileupNumericOptions {
     }
 }
 
+/// How the reads in a region are partitioned, filtered and counted by
+/// `process_region`. The default puts all of the reads in one partition and
+/// counts the calls that pass the threshold.
+#[derive(new, Default, Copy, Clone)]
+pub struct PileupReadOptions<'a> {
+    partition_tags: Option<&'a Vec<SamTag>>,
+    partition_by_input: bool,
+    record_filter: Option<&'a RecordFilter>,
+    soft_counts: bool,
+}
+
+/// Pileup over `bam_fps` in the interval, when more than one BAM is given
+/// the counts from all of the BAMs are summed unless `partition_by_input`
+/// is set in the `read_options`, in which case the counts for each BAM are
+/// reported under `PartitionKey::Key(<index of the BAM>)`. With
+/// `soft_counts` the summed probabilities of each call are also reported
+/// (see `PileupFeatureCounts::expected_modified`).
 pub fn process_region<T: AsRef<Path>>(
-    bam_fp: T,
+    bam_fps: &[T],
     chrom_tid: u32,
     start_pos: u32,
     end_pos: u32,
@@ -885,52 +1125,85 @@ pub fn process_region<T: AsRef<Path>>(
     max_depth: u32,
     motif_locations: Option<&MultipleMotifLocations>,
     edge_filter: Option<&EdgeFilter>,
-    partition_tags: Option<&Vec<SamTag>>,
     position_filter: Option<&StrandedPositionFilter<()>>,
+    read_options: &PileupReadOptions,
 ) -> Result<ModBasePileup, String> {
-    let mut bam_reader =
-        bam::IndexedReader::from_path(bam_fp).map_err(|e| e.to_string())?;
-    let chrom_name =
-        String::from_utf8_lossy(bam_reader.header().tid2name(chrom_tid))
-            .to_string();
-    bam_reader
-        .fetch(FetchDefinition::Region(
-            chrom_tid as i32,
-            start_pos as i64,
-            end_pos as i64,
-        ))
-        .map_err(|e| e.to_string())?;
+    let PileupReadOptions {
+        partition_tags,
+        partition_by_input,
+        record_filter,
+        soft_counts,
+    } = *read_options;
+    let mut bam_readers = bam_fps
+        .iter()
+        .map(|bam_fp| {
+            let mut bam_reader = bam::IndexedReader::from_path(bam_fp)
+                .map_err(|e| e.to_string())?;
+            bam_reader
+                .fetch(FetchDefinition::Region(
+                    chrom_tid as i32,
+                    start_pos as i64,
+                    end_pos as i64,
+                ))
+                .map_err(|e| e.to_string())?;
+            Ok(bam_reader)
+        })
+        .collect::<Result<Vec<bam::IndexedReader>, String>>()?;
+    let chrom_name = bam_readers
+        .first()
+        .map(|bam_reader| {
+            String::from_utf8_lossy(bam_reader.header().tid2name(chrom_tid))
+                .to_string()
+        })
+        .ok_or_else(|| "no input BAMs".to_string())?;
 
     let motif_positions = motif_locations.map(|mls| {
         get_motif_locations_for_region(mls, chrom_tid, start_pos, end_pos)
     });
 
-    let mut read_cache = ReadCache::new(
-        pileup_numeric_options.get_collapse_method(),
-        caller,
-        edge_filter,
-        force_allow,
-    );
+    // one cache per input, the same read ID may be in more than one BAM
+    // with different calls
+    let mut read_caches = bam_fps
+        .iter()
+        .map(|_| {
+            let read_cache = ReadCache::new(
+                pileup_numeric_options.get_collapse_method(),
+                caller,
+                edge_filter,
+                force_allow,
+            );
+            if soft_counts {
+                read_cache.with_probs()
+            } else {
+                read_cache
+            }
+        })
+        .collect::<Vec<ReadCache>>();
     let mut position_feature_counts = HashMap::new();
     // collection of all partition keys encountered, ordered so
     // we can can use their index
     let mut partition_keys = IndexSet::new();
-    let hts_pileup = {
-        let mut tmp_pileup = bam_reader.pileup();
-        tmp_pileup.set_max_depth(max_depth);
-        tmp_pileup
-    };
-    let pileup_iter = PileupIter::new(
-        hts_pileup,
-        chrom_tid,
-        start_pos,
-        end_pos,
-        motif_positions.as_ref(),
-        position_filter,
-    );
+    let pileup_iters = bam_readers
+        .iter_mut()
+        .map(|bam_reader| {
+            let hts_pileup = {
+                let mut tmp_pileup = bam_reader.pileup();
+                tmp_pileup.set_max_depth(max_depth);
+                tmp_pileup
+            };
+            PileupIter::new(
+                hts_pileup,
+                chrom_tid,
+                start_pos,
+                end_pos,
+                motif_positions.as_ref(),
+                position_filter,
+            )
+        })
+        .collect::<Vec<PileupIter>>();
     let mut dupe_reads = HashMap::new(); // optimize
-    for pileup in pileup_iter {
-        let pos = pileup.bam_pileup.pos();
+    for pileup in MultiPileupIter::new(pileup_iters) {
+        let pos = pileup.pos;
 
         // make a mapping of partition keys to feature vectors for this position
         let mut feature_vectors = HashMap::new();
@@ -948,19 +1221,33 @@ pub fn process_region<T: AsRef<Path>>(
         // used for warning about dupes, could make this a bloom filter for better perf?
         let mut observed_read_ids_to_pos = HashMap::new(); // optimize
 
-        let alignment_iter =
-            pileup.bam_pileup.alignments().filter(|alignment| {
+        let alignment_iter = pileup
+            .bam_pileups
+            .iter()
+            .flat_map(|(input_idx, bam_pileup)| {
+                bam_pileup
+                    .alignments()
+                    .map(move |alignment| (*input_idx, alignment))
+            })
+            .filter(|(_, alignment)| {
                 if alignment.is_refskip() {
                     false
                 } else {
                     let record = alignment.record();
-                    !(record_is_secondary(&record) || record.seq_len() == 0)
+                    !(record_is_secondary(&record)
+                        || record.seq_len() == 0
+                        || record_filter
+                            .map(|filter| !filter.keep(&record))
+                            .unwrap_or(false))
                 }
             });
-        for alignment in alignment_iter {
+        for (input_idx, alignment) in alignment_iter {
             assert!(!alignment.is_refskip());
             let record = alignment.record();
-            let partition_key = if let Some(tags) = partition_tags {
+            let read_cache = &mut read_caches[input_idx];
+            let partition_key = if partition_by_input {
+                PartitionKey::Key(input_idx)
+            } else if let Some(tags) = partition_tags {
                 match parse_tags_from_record(&record, tags) {
                     Some(s) => {
                         if let Some(idx) = partition_keys.get_index_of(&s) {
@@ -1005,7 +1292,7 @@ pub fn process_region<T: AsRef<Path>>(
             // optimize, could use a smarter string implementation here
             if let Ok(read_name) = get_query_name_string(&record) {
                 (*observed_read_ids_to_pos
-                    .entry(read_name)
+                    .entry((input_idx, read_name))
                     .or_insert(0usize)) += 1
             }
 
@@ -1040,7 +1327,31 @@ pub fn process_region<T: AsRef<Path>>(
                 continue;
             };
 
-            match read_cache.get_mod_call(&record, pos, read_base.char()) {
+            let mod_calls =
+                read_cache.get_mod_call(&record, pos, read_base.char());
+            if soft_counts {
+                let (pos_probs, neg_probs) =
+                    read_cache.get_mod_probs(&record, pos, read_base.char());
+                if let Some(probs) = pos_probs {
+                    feature_vector.add_probs(
+                        alignment_strand,
+                        &probs,
+                        read_base,
+                        Strand::Positive,
+                        &pileup.strand_rule,
+                    );
+                }
+                if let Some(probs) = neg_probs {
+                    feature_vector.add_probs(
+                        alignment_strand,
+                        &probs,
+                        read_base.complement(),
+                        Strand::Negative,
+                        &pileup.strand_rule,
+                    );
+                }
+            }
+            match mod_calls {
                 // a read can report on the read-positive or read-negative
                 // strand (see the docs for .get_mod_call above) so the
                 // pos_call and neg_call below are _read oriented_, the
@@ -1170,11 +1481,16 @@ pub fn process_region<T: AsRef<Path>>(
         position_feature_counts
     };
 
-    let (processed_records, skipped_records) =
-        read_cache.get_records_used_and_skipped();
+    let (processed_records, skipped_records) = read_caches.iter().fold(
+        (0usize, 0usize),
+        |(processed, skipped), read_cache| {
+            let (p, s) = read_cache.get_records_used_and_skipped();
+            (processed + p, skipped + s)
+        },
+    );
 
     let should_warn = !dupe_reads.is_empty();
-    for (read_id, counts) in dupe_reads {
+    for ((_, read_id), counts) in dupe_reads {
         let avg_times =
             counts.iter().map(|c| *c as f32).sum::<f32>() / counts.len() as f32;
         debug!(
@@ -1208,8 +1524,8 @@ mod mod_pileup_tests {
     use rustc_hash::FxHashMap;
 
     use crate::pileup::{
-        parse_tags_from_record, DnaBase, Feature, FeatureVector,
-        PileupNumericOptions, StrandRule,
+        parse_haplotype_key, parse_tags_from_record, DnaBase, Feature,
+        FeatureVector, Haplotype, PileupNumericOptions, StrandRule,
     };
     use crate::util::{SamTag, Strand};
 
@@ -1355,6 +1671,20 @@ mod mod_pileup_tests {
         assert_eq!(count.n_modified, 1);
     }
 
+    #[test]
+    fn test_parse_haplotype_key() {
+        assert_eq!(
+            parse_haplotype_key("1_1000"),
+            (Haplotype::Hp1, Some("1000"))
+        );
+        assert_eq!(parse_haplotype_key("2_missing"), (Haplotype::Hp2, None));
+        assert_eq!(
+            parse_haplotype_key("missing_1000"),
+            (Haplotype::Unphased, None)
+        );
+        assert_eq!(parse_haplotype_key("0_1000"), (Haplotype::Unphased, None));
+    }
+
     #[test]
     fn test_parse_tags_from_record() {
         let mut reader = bam::Reader::from_path(
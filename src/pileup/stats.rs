@@ -0,0 +1,188 @@
+use clap::ValueEnum;
+use derive_new::new;
+use rv::dist::{Beta, Gaussian};
+use rv::traits::{Cdf, InverseCdf};
+
+use crate::pileup::PileupFeatureCounts;
+
+/// Method used to calculate the confidence interval on the fraction
+/// modified.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub enum IntervalMethod {
+    /// Wilson score interval.
+    wilson,
+    /// Equal-tailed interval of the Beta posterior with a Jeffreys prior.
+    beta,
+}
+
+/// Binomial confidence interval on the fraction of modified calls.
+#[derive(new, Debug, Copy, Clone)]
+pub struct ConfidenceInterval {
+    method: IntervalMethod,
+    /// Confidence level, for example 0.95.
+    level: f64,
+}
+
+impl ConfidenceInterval {
+    /// Lower and upper bounds on the fraction of modified calls given
+    /// `n_modified` of `n_valid` calls. None when there are no calls.
+    pub fn bounds(&self, n_modified: u32, n_valid: u32) -> Option<(f64, f64)> {
+        if n_valid == 0 {
+            return None;
+        }
+        let alpha = 1f64 - self.level;
+        let bounds = match self.method {
+            IntervalMethod::wilson => {
+                wilson_bounds(n_modified as f64, n_valid as f64, alpha)
+            }
+            IntervalMethod::beta => jeffreys_bounds(n_modified, n_valid, alpha),
+        };
+        Some(bounds)
+    }
+}
+
+fn wilson_bounds(n_modified: f64, n: f64, alpha: f64) -> (f64, f64) {
+    let z: f64 = Gaussian::standard().invcdf(1f64 - alpha / 2f64);
+    let z2 = z * z;
+    let p = n_modified / n;
+    let center = (p + z2 / (2f64 * n)) / (1f64 + z2 / n);
+    let half_width =
+        z / (1f64 + z2 / n) * (p * (1f64 - p) / n + z2 / (4f64 * n * n)).sqrt();
+    (
+        (center - half_width).max(0f64),
+        (center + half_width).min(1f64),
+    )
+}
+
+fn jeffreys_bounds(n_modified: u32, n: u32, alpha: f64) -> (f64, f64) {
+    let posterior =
+        Beta::new(n_modified as f64 + 0.5f64, (n - n_modified) as f64 + 0.5f64)
+            .unwrap();
+    // by convention the interval extends to the boundary when all or none
+    // of the calls are modified
+    let lower = if n_modified == 0 {
+        0f64
+    } else {
+        beta_quantile(&posterior, alpha / 2f64)
+    };
+    let upper = if n_modified == n {
+        1f64
+    } else {
+        beta_quantile(&posterior, 1f64 - alpha / 2f64)
+    };
+    (lower, upper)
+}
+
+/// Quantile of the Beta distribution by bisection on the CDF.
+fn beta_quantile(beta: &Beta, q: f64) -> f64 {
+    let (mut lo, mut hi) = (0f64, 1f64);
+    for _ in 0..60 {
+        let mid = (lo + hi) / 2f64;
+        if beta.cdf(&mid) < q {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    (lo + hi) / 2f64
+}
+
+/// Two-proportion z-statistic comparing the fraction modified on the positive
+/// strand to the negative strand, positive when the positive strand is more
+/// modified. Only available for counts that combine both strands, and None
+/// when either strand has no valid coverage.
+pub fn strand_imbalance(feature_counts: &PileupFeatureCounts) -> Option<f64> {
+    let [(pos_mod, pos_valid), (neg_mod, neg_valid)] =
+        feature_counts.strand_counts?;
+    if pos_valid == 0 || neg_valid == 0 {
+        return None;
+    }
+    let (pos_mod, pos_valid) = (pos_mod as f64, pos_valid as f64);
+    let (neg_mod, neg_valid) = (neg_mod as f64, neg_valid as f64);
+    let pooled = (pos_mod + neg_mod) / (pos_valid + neg_valid);
+    let variance =
+        pooled * (1f64 - pooled) * (1f64 / pos_valid + 1f64 / neg_valid);
+    if variance <= 0f64 {
+        // both strands are entirely modified or entirely canonical
+        Some(0f64)
+    } else {
+        Some((pos_mod / pos_valid - neg_mod / neg_valid) / variance.sqrt())
+    }
+}
+
+#[cfg(test)]
+mod stats_tests {
+    use crate::mod_base_code::METHYL_CYTOSINE;
+    use crate::pileup::stats::{
+        strand_imbalance, ConfidenceInterval, IntervalMethod,
+    };
+    use crate::pileup::PileupFeatureCounts;
+
+    #[test]
+    fn test_confidence_interval_bounds() {
+        let wilson = ConfidenceInterval::new(IntervalMethod::wilson, 0.95);
+        // reference values from statsmodels proportion_confint
+        let (lower, upper) = wilson.bounds(8, 10).unwrap();
+        assert!((lower - 0.4902).abs() < 1e-4, "{lower}");
+        assert!((upper - 0.9433).abs() < 1e-4, "{upper}");
+        let (lower, upper) = wilson.bounds(0, 10).unwrap();
+        assert!(lower.abs() < 1e-12, "{lower}");
+        assert!((upper - 0.2775).abs() < 1e-4, "{upper}");
+
+        let jeffreys = ConfidenceInterval::new(IntervalMethod::beta, 0.95);
+        let (lower, upper) = jeffreys.bounds(8, 10).unwrap();
+        assert!((lower - 0.4972).abs() < 1e-4, "{lower}");
+        assert!((upper - 0.9559).abs() < 1e-4, "{upper}");
+        let (lower, upper) = jeffreys.bounds(10, 10).unwrap();
+        assert!(lower > 0.7 && lower < 1f64, "{lower}");
+        assert_eq!(upper, 1f64);
+
+        assert!(wilson.bounds(0, 0).is_none());
+        // a wider interval at a higher confidence level
+        let (lower_99, upper_99) =
+            ConfidenceInterval::new(IntervalMethod::wilson, 0.99)
+                .bounds(8, 10)
+                .unwrap();
+        assert!(lower_99 < 0.4902 && upper_99 > 0.9433);
+    }
+
+    #[test]
+    fn test_strand_imbalance() {
+        let counts = |strand: char, n_mod: u32, n_valid: u32| {
+            PileupFeatureCounts::new(
+                strand,
+                n_valid,
+                METHYL_CYTOSINE,
+                n_mod as f32 / n_valid as f32,
+                n_valid - n_mod,
+                n_mod,
+                0,
+                0,
+                0,
+                0,
+                0,
+                None,
+            )
+        };
+        // not combined
+        assert!(strand_imbalance(&counts('+', 5, 10)).is_none());
+        let combined =
+            counts('+', 9, 10).combine_counts_ignore_strand(counts('-', 2, 10));
+        assert_eq!(combined.strand_counts, Some([(9, 10), (2, 10)]));
+        let z = strand_imbalance(&combined).unwrap();
+        // pooled p = 0.55
+        let expected = 0.7 / (0.55f64 * 0.45 * 0.2).sqrt();
+        assert!((z - expected).abs() < 1e-9, "{z}");
+        let flipped =
+            counts('+', 2, 10).combine_counts_ignore_strand(counts('-', 9, 10));
+        assert!((strand_imbalance(&flipped).unwrap() + expected).abs() < 1e-9);
+
+        let balanced =
+            counts('+', 10, 10).combine_counts_ignore_strand(counts('-', 5, 5));
+        assert_eq!(strand_imbalance(&balanced), Some(0f64));
+        let one_strand =
+            counts('+', 10, 10).combine_counts_ignore_strand(counts('+', 5, 5));
+        assert!(strand_imbalance(&one_strand).is_none());
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use std::io::BufWriter;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, bail, Context};
 use clap::{Args, ValueEnum};
@@ -23,23 +23,38 @@ use crate::motif_bed::{
     get_masked_sequences, MotifLocations, MultipleMotifLocations, RegexMotif,
 };
 use crate::pileup::duplex::{process_region_duplex, DuplexModBasePileup};
-use crate::pileup::{process_region, ModBasePileup, PileupNumericOptions};
+use crate::pileup::stats::{ConfidenceInterval, IntervalMethod};
+use crate::pileup::{
+    haplotype_partition_tags, process_region, ModBasePileup,
+    PileupNumericOptions, PileupReadOptions,
+};
 use crate::position_filter::StrandedPositionFilter;
 use crate::reads_sampler::sampling_schedule::IdxStats;
+use crate::record_filter::{RecordFilterArgs, RecordSelection};
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::transcriptome::TranscriptProjection;
 use crate::util::{
     create_out_directory, get_master_progress_bar, get_subroutine_progress_bar,
     get_targets, get_ticker, parse_partition_tags, reader_is_bam,
     ReferenceRecord, Region,
 };
 use crate::writers::{
-    BedGraphWriter, BedMethylWriter, PartitioningBedMethylWriter, PileupWriter,
+    BedGraphWriter, BedMethylParquetWriter, BedMethylStatistics,
+    BedMethylWriter, BgzfBedMethylWriter, BigWigWriter,
+    HaplotypeBedMethylWriter, PartitioningBedMethylWriter, PileupWriter,
+    ProjectedBedMethylWriter, SampleColumnsWriter,
 };
 
 #[derive(Args)]
 pub struct ModBamPileup {
     // running args
-    /// Input BAM, should be sorted and have associated index available.
-    in_bam: PathBuf,
+    /// Input BAM, should be sorted and have associated index available. More
+    /// than one BAM can be given, in which case the counts from all of the
+    /// BAMs are summed (see --sample-columns for per-sample counts). All of
+    /// the BAMs must be aligned to the same reference. Filter thresholds are
+    /// estimated from the reads of all of the BAMs together.
+    #[arg(required = true, num_args = 1..)]
+    in_bams: Vec<PathBuf>,
     /// Output file (or directory with --bedgraph option) to write results into.
     /// Specify "-" or "stdout" to direct output to stdout.
     out_bed: String,
@@ -221,6 +236,17 @@ pub struct ModBamPileup {
     /// provided.
     #[arg(long, requires = "reference_fasta", default_value_t = false)]
     cpg: bool,
+    /// Only output counts at DRACH motifs, for direct RNA m6A. Shorthand for
+    /// --motif DRACH 2, may be combined with other motifs. Because the
+    /// motif is not palindromic the counts are always strand-specific.
+    /// Requires a reference sequence to be provided.
+    #[arg(
+        long,
+        requires = "reference_fasta",
+        conflicts_with = "combine_strands",
+        default_value_t = false
+    )]
+    drach: bool,
     /// Reference sequence in FASTA format. Required for CpG motif filtering.
     #[arg(long = "ref", alias = "reference", short = 'r')]
     reference_fasta: Option<PathBuf>,
@@ -240,7 +266,7 @@ pub struct ModBamPileup {
     #[arg(
     long,
     requires = "reference_fasta",
-    conflicts_with_all = ["combine_mods", "cpg", "combine_strands", "ignore", "motif"],
+    conflicts_with_all = ["combine_mods", "cpg", "combine_strands", "ignore", "motif", "drach"],
     )]
     preset: Option<Presets>,
     /// Combine base modification calls, all counts of modified bases are summed together. See
@@ -269,6 +295,9 @@ pub struct ModBamPileup {
     /// 4 and last 8 bases.
     #[arg(long, requires = "edge_filter", default_value_t = false)]
     invert_edge_filter: bool,
+    // record filter args
+    #[command(flatten)]
+    record_filter_args: RecordFilterArgs,
 
     // output args
     /// For bedMethyl output, separate columns with only tabs. The default is
@@ -278,7 +307,7 @@ pub struct ModBamPileup {
     /// tabular data handlers that expect a single kind of separator.
     #[arg(
         long,
-        conflicts_with = "bedgraph",
+        conflicts_with_all = ["bedgraph", "bigwig"],
         default_value_t = false,
         hide_short_help = true
     )]
@@ -290,28 +319,186 @@ pub struct ModBamPileup {
     /// produced.
     #[arg(
         long,
-        conflicts_with = "only_tabs",
+        conflicts_with_all = ["only_tabs", "bigwig"],
         default_value_t = false,
         hide_short_help = true
     )]
     bedgraph: bool,
-    /// Prefix to prepend on bedgraph output file names. Without this option the files
-    /// will be <mod_code>_<strand>.bedgraph
+    /// Output bigWig format, the value at each position is the fraction modified. As with
+    /// --bedgraph, specify a directory for output files to be made in, the files are split by
+    /// modification code and strand the same way. The contig lengths are taken from the BAM
+    /// header.
+    #[arg(
+        long,
+        conflicts_with_all = ["only_tabs", "bedgraph"],
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    bigwig: bool,
+    /// Compress the bedMethyl output with bgzip and write a tabix index
+    /// (<out_bed>.tbi) next to it, the output can be used directly with
+    /// `modkit dmr`. When any contig is longer than 2^29 bases a CSI index
+    /// (<out_bed>.csi) is written instead. Cannot be used when writing to
+    /// stdout. With --partition-tag the partitioned files will be
+    /// compressed and indexed.
+    #[arg(
+        long,
+        conflicts_with_all = ["bedgraph", "bigwig"],
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    bgzf: bool,
+    /// Prefix to prepend on bedgraph (or bigWig) output file names. Without this option the
+    /// files will be <mod_code>_<strand>.bedgraph (or .bw)
     #[arg(long)]
     prefix: Option<String>,
     /// Partition output into multiple bedMethyl files based on tag-value pairs. The output
     /// will be multiple bedMethyl files with the format
     /// `<prefix>_<tag_value_1>_<tag_value_2>_<tag_value_n>.bed` prefix is optional and set
-    /// with the `--prefix` flag.
+    /// with the `--prefix` flag. With `--bgzf` the files will be compressed and indexed
+    /// (`.bed.gz`).
     #[arg(long)]
     partition_tag: Option<Vec<String>>,
+    /// When multiple BAMs are given, write a table with the number of modified
+    /// and canonical calls for each BAM in separate columns instead of summing
+    /// the counts. The output has the columns chrom, start, end, mod code,
+    /// strand followed by <sample>_n_mod and <sample>_n_canonical for each
+    /// input, positions are the union of positions with coverage in any input.
+    #[arg(
+        long,
+        conflicts_with_all = ["bedgraph", "bigwig", "bgzf", "partition_tag"],
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    sample_columns: bool,
+    /// Sample name to use in the --sample-columns header, specify once for
+    /// each input BAM in the same order. The default is to use the BAM file
+    /// names.
+    #[arg(long, requires = "sample_columns", hide_short_help = true)]
+    sample_name: Option<Vec<String>>,
+    /// Haplotype-aware pileup, uses the HP and PS tags on the reads to write
+    /// one row per position with the valid coverage, number of modified and
+    /// number of canonical calls for haplotype 1, haplotype 2 and unphased
+    /// reads side by side. The phase block column is the PS tag value of the
+    /// phased reads at the position ("." when there are none).
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "bedgraph", "bigwig", "bgzf", "partition_tag", "sample_columns"
+        ],
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    haplotypes: bool,
+    /// Probability-weighted ("soft") counts, sum the base modification
+    /// probabilities of the reads at each position instead of only counting
+    /// thresholded calls. No pass threshold is estimated and no calls are
+    /// filtered, the percent modified column is the mean modification
+    /// probability and two columns are added to the bedMethyl with the
+    /// expected number of modified and canonical calls.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "thresholds", "mod_thresholds", "sample_columns", "haplotypes"
+        ],
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    soft: bool,
+    /// Add columns with the lower and upper bounds of a binomial confidence
+    /// interval on the percent modified to the bedMethyl output. The interval
+    /// is calculated from N_mod and N_valid_cov with the Wilson score method
+    /// or as the equal-tailed interval of the Beta posterior (Jeffreys prior).
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "bedgraph", "bigwig", "sample_columns", "haplotypes"
+        ],
+        hide_short_help = true
+    )]
+    confidence_interval: Option<IntervalMethod>,
+    /// Confidence level of the interval calculated with
+    /// --confidence-interval.
+    #[arg(
+        long,
+        requires = "confidence_interval",
+        default_value_t = 0.95,
+        hide_short_help = true
+    )]
+    confidence_level: f64,
+    /// Add a column to the bedMethyl output with a z-statistic comparing the
+    /// percent modified on the positive and negative strands, positive values
+    /// mean the positive strand is more modified. Requires --combine-strands
+    /// (or a preset that combines strands), the column is '.' when either
+    /// strand has no valid coverage.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "bedgraph", "bigwig", "sample_columns", "haplotypes"
+        ],
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    strand_imbalance: bool,
+    /// GTF or GFF3 (optionally gzipped) annotation used to project pileup from
+    /// alignments to transcript sequences onto the genome. The BAM reference
+    /// sequences should be named by transcript ID. Counts from isoforms that
+    /// share a genomic position are merged and two columns are appended with
+    /// the gene IDs and transcript IDs covering each site.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "bedgraph", "bigwig", "bgzf", "partition_tag", "sample_columns",
+            "haplotypes", "combine_strands"
+        ],
+        hide_short_help = true
+    )]
+    annotation: Option<PathBuf>,
+    /// Write the bedMethyl table to a Parquet file with typed columns instead
+    /// of text. The columns are chrom, start, end, mod_code, strand,
+    /// n_valid_cov, percent_modified, n_mod, n_canonical, n_other_mod,
+    /// n_delete, n_fail, n_diff, and n_nocall followed by the optional
+    /// --soft, --confidence-interval, and --strand-imbalance columns. Row
+    /// groups are closed at interval chunk boundaries.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "bedgraph", "bigwig", "bgzf", "partition_tag", "sample_columns",
+            "haplotypes", "annotation"
+        ],
+        default_value_t = false,
+        hide_short_help = true
+    )]
+    parquet: bool,
 }
 
 impl ModBamPileup {
+    fn sample_names(&self) -> anyhow::Result<Vec<String>> {
+        match self.sample_name.as_ref() {
+            Some(names) if names.len() != self.in_bams.len() => {
+                bail!(
+                    "got {} sample names for {} input BAMs",
+                    names.len(),
+                    self.in_bams.len()
+                )
+            }
+            Some(names) => Ok(names.clone()),
+            None => Ok(self
+                .in_bams
+                .iter()
+                .map(|fp| {
+                    fp.file_stem()
+                        .map(|stem| stem.to_string_lossy().to_string())
+                        .unwrap_or_else(|| fp.to_string_lossy().to_string())
+                })
+                .collect()),
+        }
+    }
+
     pub fn run(&self) -> anyhow::Result<()> {
         let _handle = init_logging(self.log_filepath.as_ref());
         // do this first so we fail when the file isn't readable
-        let header = bam::IndexedReader::from_path(&self.in_bam)
+        let header = bam::IndexedReader::from_path(&self.in_bams[0])
             .map(|reader| {
                 if !reader_is_bam(&reader) {
                     info!("\
@@ -320,6 +507,43 @@ impl ModBamPileup {
                 }
                 reader.header().to_owned()
             })?;
+        for in_bam in self.in_bams.iter().skip(1) {
+            let other_header = bam::IndexedReader::from_path(in_bam)
+                .with_context(|| format!("failed to open {in_bam:?}"))?
+                .header()
+                .to_owned();
+            let same_references = other_header.target_names()
+                == header.target_names()
+                && (0..header.target_count()).all(|tid| {
+                    other_header.target_len(tid) == header.target_len(tid)
+                });
+            if !same_references {
+                bail!(
+                    "reference sequences in {in_bam:?} do not match those in \
+                    {:?}, all input BAMs must be aligned to the same reference",
+                    self.in_bams[0]
+                )
+            }
+        }
+        if self.in_bams.len() > 1 {
+            info!("piling up {} BAMs", self.in_bams.len());
+        } else if self.sample_columns {
+            warn!("--sample-columns with a single input BAM");
+        }
+        let mut projection = self
+            .annotation
+            .as_ref()
+            .map(|fp| {
+                let projection = TranscriptProjection::from_path(fp)?;
+                let reference_names = header
+                    .target_names()
+                    .into_iter()
+                    .map(|name| String::from_utf8_lossy(name).to_string())
+                    .collect::<Vec<String>>();
+                projection.check_references(&reference_names)?;
+                Ok::<_, anyhow::Error>(projection)
+            })
+            .transpose()?;
 
         // options parsing below
         let region = self
@@ -345,6 +569,7 @@ impl ModBamPileup {
                 parse_edge_filter_input(trims, self.invert_edge_filter)
             })
             .transpose()?;
+        let record_filter = self.record_filter_args.to_record_filter()?;
         let per_mod_thresholds = self
             .mod_thresholds
             .as_ref()
@@ -352,12 +577,20 @@ impl ModBamPileup {
                 parse_per_mod_thresholds(raw_per_mod_thresholds)
             })
             .transpose()?;
-        let partition_tags = self
-            .partition_tag
-            .as_ref()
-            .map(|raw_tags| parse_partition_tags(raw_tags))
-            .transpose()?;
-        let tids = get_targets(&header, region.as_ref());
+        let partition_tags = if self.haplotypes {
+            Some(haplotype_partition_tags())
+        } else {
+            self.partition_tag
+                .as_ref()
+                .map(|raw_tags| parse_partition_tags(raw_tags))
+                .transpose()?
+        };
+        let mut tids = get_targets(&header, region.as_ref());
+        if let Some(projection) = projection.as_ref() {
+            // the projected writer writes out the sites before each
+            // transcript, so the transcripts need to be in genomic order
+            projection.sort_by_genomic_start(&mut tids);
+        }
         let position_filter = self
             .include_bed
             .as_ref()
@@ -377,16 +610,20 @@ impl ModBamPileup {
             .transpose()?;
         // use the path here instead of passing the reader directly to avoid potentially
         // changing mutable internal state of the reader.
-        IdxStats::check_any_mapped_reads(
-            &self.in_bam,
-            region.as_ref(),
-            position_filter.as_ref(),
-        )
-        .context(
-            "\
-            did not find any mapped reads, perform alignment first or use \
-            modkit extract and/or modkit summary to inspect unaligned modBAMs",
-        )?;
+        for in_bam in self.in_bams.iter() {
+            IdxStats::check_any_mapped_reads(
+                in_bam,
+                region.as_ref(),
+                position_filter.as_ref(),
+            )
+            .with_context(|| {
+                format!(
+                    "did not find any mapped reads in {in_bam:?}, perform \
+                    alignment first or use modkit extract and/or modkit \
+                    summary to inspect unaligned modBAMs"
+                )
+            })?;
+        }
         let chunk_size = if let Some(chunk_size) = self.chunk_size {
             if chunk_size < self.threads {
                 warn!("chunk size {chunk_size} is less than number of threads ({}), \
@@ -451,6 +688,22 @@ impl ModBamPileup {
                 }
             };
 
+        if self.strand_imbalance && !combine_strands {
+            bail!("--strand-imbalance requires --combine-strands")
+        }
+        if projection.is_some() && combine_strands {
+            bail!("cannot combine strands when projecting to the genome")
+        }
+        if !(self.confidence_level > 0f64 && self.confidence_level < 1f64) {
+            bail!("confidence level must be between 0 and 1")
+        }
+        let statistics = BedMethylStatistics::new(
+            self.confidence_interval.map(|method| {
+                ConfidenceInterval::new(method, self.confidence_level)
+            }),
+            self.strand_imbalance,
+        );
+
         // motif handling
         let regex_motifs = if let Some(raw_motif_parts) = &self.motif {
             if self.preset.is_some() {
@@ -466,6 +719,20 @@ impl ModBamPileup {
         } else {
             None
         };
+        let regex_motifs = if self.drach {
+            let mut regex_motifs = regex_motifs.unwrap_or_default();
+            if regex_motifs.iter().any(|motif| {
+                motif.raw_motif == "DRACH" && motif.forward_offset == 2
+            }) {
+                info!("DRACH 2 motif already, ignoring --drach");
+            } else {
+                info!("--drach flag received, adding DRACH, 2 to motifs");
+                regex_motifs.push(RegexMotif::parse_string("DRACH", 2)?);
+            }
+            Some(regex_motifs)
+        } else {
+            regex_motifs
+        };
 
         // setup the writer here so we fail before doing any work (if there are problems).
         let out_fp_str = self.out_bed.clone();
@@ -478,8 +745,105 @@ impl ModBamPileup {
                     .collect::<Vec<String>>()
             })
             .unwrap_or(Vec::new());
+        let max_contig_length = (0..header.target_count())
+            .filter_map(|tid| header.target_len(tid))
+            .max()
+            .unwrap_or(0);
         let mut writer: Box<dyn PileupWriter<ModBasePileup>> =
             match (self.bedgraph, partition_tags.is_some()) {
+                _ if projection.is_some() => {
+                    let projection =
+                        projection.take().expect("checked projection");
+                    match out_fp_str.as_str() {
+                        "stdout" | "-" => {
+                            let writer = BufWriter::new(std::io::stdout());
+                            Box::new(ProjectedBedMethylWriter::new(
+                                writer,
+                                !self.only_tabs,
+                                statistics,
+                                projection,
+                            ))
+                        }
+                        _ => {
+                            create_out_directory(&out_fp_str)?;
+                            let fh = std::fs::File::create(&out_fp_str)
+                                .context("failed to make output file")?;
+                            Box::new(ProjectedBedMethylWriter::new(
+                                BufWriter::new(fh),
+                                !self.only_tabs,
+                                statistics,
+                                projection,
+                            ))
+                        }
+                    }
+                }
+                _ if self.parquet => match out_fp_str.as_str() {
+                    "stdout" | "-" => {
+                        bail!("cannot write parquet output to stdout")
+                    }
+                    _ => {
+                        create_out_directory(&out_fp_str)?;
+                        Box::new(BedMethylParquetWriter::new(
+                            Path::new(&out_fp_str),
+                            true,
+                            self.soft,
+                            statistics,
+                        )?)
+                    }
+                },
+                _ if self.haplotypes => match out_fp_str.as_str() {
+                    "stdout" | "-" => {
+                        let writer = BufWriter::new(std::io::stdout());
+                        Box::new(HaplotypeBedMethylWriter::new(writer)?)
+                    }
+                    _ => {
+                        create_out_directory(&out_fp_str)?;
+                        let fh = std::fs::File::create(&out_fp_str)
+                            .context("failed to make output file")?;
+                        Box::new(HaplotypeBedMethylWriter::new(
+                            BufWriter::new(fh),
+                        )?)
+                    }
+                },
+                _ if self.sample_columns => {
+                    let sample_names = self.sample_names()?;
+                    match out_fp_str.as_str() {
+                        "stdout" | "-" => {
+                            let writer = BufWriter::new(std::io::stdout());
+                            Box::new(SampleColumnsWriter::new(
+                                writer,
+                                &sample_names,
+                            )?)
+                        }
+                        _ => {
+                            create_out_directory(&out_fp_str)?;
+                            let fh = std::fs::File::create(&out_fp_str)
+                                .context("failed to make output file")?;
+                            Box::new(SampleColumnsWriter::new(
+                                BufWriter::new(fh),
+                                &sample_names,
+                            )?)
+                        }
+                    }
+                }
+                _ if self.bigwig => {
+                    let chrom_sizes = (0..header.target_count())
+                        .map(|tid| {
+                            let name =
+                                String::from_utf8_lossy(header.tid2name(tid))
+                                    .to_string();
+                            let size =
+                                header.target_len(tid).unwrap_or(0) as u32;
+                            (name, size)
+                        })
+                        .collect::<Vec<(String, u32)>>();
+                    Box::new(BigWigWriter::new(
+                        &out_fp_str,
+                        self.prefix.as_ref(),
+                        partition_tags.is_some(),
+                        chrom_sizes,
+                    )?)
+                }
                 (true, _) => Box::new(BedGraphWriter::new(
                     &out_fp_str,
                     self.prefix.as_ref(),
@@ -489,18 +853,40 @@ impl ModBamPileup {
                     &self.out_bed,
                     self.only_tabs,
                     self.prefix.as_ref(),
+                    self.bgzf.then_some(max_contig_length),
+                    statistics,
                 )?),
                 (false, false) => match out_fp_str.as_str() {
+                    "stdout" | "-" if self.bgzf => {
+                        bail!("cannot write bgzf output to stdout")
+                    }
                     "stdout" | "-" => {
                         let writer = BufWriter::new(std::io::stdout());
-                        Box::new(BedMethylWriter::new(writer, !self.only_tabs))
+                        Box::new(BedMethylWriter::new(
+                            writer,
+                            !self.only_tabs,
+                            statistics,
+                        ))
+                    }
+                    _ if self.bgzf => {
+                        create_out_directory(&out_fp_str)?;
+                        Box::new(BgzfBedMethylWriter::new(
+                            Path::new(&out_fp_str),
+                            max_contig_length,
+                            !self.only_tabs,
+                            statistics,
+                        )?)
                     }
                     _ => {
                         create_out_directory(&out_fp_str)?;
                         let fh = std::fs::File::create(out_fp_str)
                             .context("failed to make output file")?;
                         let writer = BufWriter::new(fh);
-                        Box::new(BedMethylWriter::new(writer, !self.only_tabs))
+                        Box::new(BedMethylWriter::new(
+                            writer,
+                            !self.only_tabs,
+                            statistics,
+                        ))
                     }
                 },
             };
@@ -566,32 +952,44 @@ impl ModBamPileup {
         };
 
         // start the actual work here
-        let threshold_caller =
-            if let Some(raw_threshold) = &self.filter_threshold {
-                parse_thresholds(raw_threshold, per_mod_thresholds)?
-            } else {
-                pool.install(|| {
-                    get_threshold_from_options(
-                        &self.in_bam,
-                        self.threads,
-                        self.sampling_interval_size,
-                        self.sampling_frac,
-                        self.num_reads,
-                        self.no_filtering,
-                        self.filter_percentile,
-                        self.seed,
-                        sampling_region.as_ref().or(region.as_ref()),
-                        per_mod_thresholds,
-                        edge_filter.as_ref(),
-                        threshold_collapse_method.as_ref(),
-                        position_filter.as_ref(),
+        let threshold_caller = if self.soft {
+            info!("calculating soft counts, not performing filtering");
+            MultipleThresholdModCaller::new_passthrough()
+        } else if let Some(raw_threshold) = &self.filter_threshold {
+            parse_thresholds(raw_threshold, per_mod_thresholds)?
+        } else {
+            if self.in_bams.len() > 1 && !self.no_filtering {
+                info!(
+                    "estimating filter thresholds from the reads of all {} \
+                     inputs",
+                    self.in_bams.len()
+                );
+            }
+            pool.install(|| {
+                get_threshold_from_options(
+                    &self.in_bams,
+                    self.threads,
+                    self.sampling_interval_size,
+                    self.sampling_frac,
+                    self.num_reads,
+                    self.no_filtering,
+                    self.filter_percentile,
+                    self.seed,
+                    sampling_region.as_ref().or(region.as_ref()),
+                    per_mod_thresholds,
+                    edge_filter.as_ref(),
+                    threshold_collapse_method.as_ref(),
+                    position_filter.as_ref(),
+                    RecordSelection::new(
                         !self.include_unmapped,
-                        self.suppress_progress,
-                    )
-                })?
-            };
+                        record_filter.as_ref(),
+                    ),
+                    self.suppress_progress,
+                )
+            })?
+        };
 
-        if !self.no_filtering {
+        if !(self.no_filtering || self.soft) {
             for (base, threshold) in threshold_caller.iter_thresholds() {
                 let base = base.char();
                 match (threshold * 100f32).ceil() as usize {
@@ -620,7 +1018,9 @@ impl ModBamPileup {
         }
 
         let (snd, rx) = bounded(1_000); // todo figure out sane default for this?
-        let in_bam_fp = self.in_bam.clone();
+        let in_bam_fps = self.in_bams.clone();
+        let partition_by_input = self.sample_columns;
+        let soft_counts = self.soft;
         let interval_size = self.interval_size;
 
         let master_progress = MultiProgress::new();
@@ -642,6 +1042,12 @@ impl ModBamPileup {
         let max_depth = self.max_depth;
 
         std::thread::spawn(move || {
+            let read_options = PileupReadOptions::new(
+                partition_tags.as_ref(),
+                partition_by_input,
+                record_filter.as_ref(),
+                soft_counts,
+            );
             pool.install(|| {
                 for target in tids {
                     let intervals = IntervalChunks::new_with_multiple_motifs(
@@ -681,7 +1087,7 @@ impl ModBamPileup {
                                     .progress_with(chunk_progress)
                                     .map(|(start, end)| {
                                         process_region(
-                                            &in_bam_fp,
+                                            &in_bam_fps,
                                             target.tid,
                                             *start,
                                             *end,
@@ -692,8 +1098,8 @@ impl ModBamPileup {
                                             max_depth,
                                             motif_locations.as_ref(),
                                             edge_filter.as_ref(),
-                                            partition_tags.as_ref(),
                                             position_filter.as_ref(),
+                                            &read_options,
                                         )
                                     })
                                     .collect::<Vec<Result<ModBasePileup, String>>>()
@@ -744,6 +1150,7 @@ impl ModBamPileup {
                 }
             }
         }
+        writer.finish()?;
         let rows_processed = write_progress.position();
         let n_skipped_reads = skipped_reads.position();
         let n_skipped_message = if n_skipped_reads == 0 {
@@ -988,6 +1395,9 @@ pub struct DuplexModBamPileup {
         hide_short_help = true
     )]
     invert_edge_filter: bool,
+    // record filter args
+    #[command(flatten)]
+    record_filter_args: RecordFilterArgs,
 
     // output args
     /// Separate bedMethyl columns with only tabs. The default is
@@ -1035,6 +1445,7 @@ impl DuplexModBamPileup {
             .as_ref()
             .map(|raw| parse_edge_filter_input(raw, self.invert_edge_filter))
             .transpose()?;
+        let record_filter = self.record_filter_args.to_record_filter()?;
         let per_mod_thresholds = self
             .mod_thresholds
             .as_ref()
@@ -1142,10 +1553,18 @@ impl DuplexModBamPileup {
                 let fh = std::fs::File::create(out_fp)
                     .context("failed to make output file")?;
                 let writer = BufWriter::new(fh);
-                Box::new(BedMethylWriter::new(writer, !self.only_tabs))
+                Box::new(BedMethylWriter::new(
+                    writer,
+                    !self.only_tabs,
+                    BedMethylStatistics::default(),
+                ))
             } else {
                 let writer = BufWriter::new(std::io::stdout());
-                Box::new(BedMethylWriter::new(writer, !self.only_tabs))
+                Box::new(BedMethylWriter::new(
+                    writer,
+                    !self.only_tabs,
+                    BedMethylStatistics::default(),
+                ))
             };
 
         let pool = rayon::ThreadPoolBuilder::new()
@@ -1200,7 +1619,7 @@ impl DuplexModBamPileup {
             } else {
                 pool.install(|| {
                     get_threshold_from_options(
-                        &self.in_bam,
+                        std::slice::from_ref(&self.in_bam),
                         self.threads,
                         self.sampling_interval_size,
                         self.sampling_frac,
@@ -1213,7 +1632,10 @@ impl DuplexModBamPileup {
                         edge_filter.as_ref(),
                         collapse_method.as_ref(),
                         position_filter.as_ref(),
-                        !self.include_unmapped,
+                        RecordSelection::new(
+                            !self.include_unmapped,
+                            record_filter.as_ref(),
+                        ),
                         self.suppress_progress,
                     )
                 })?
@@ -1323,6 +1745,7 @@ impl DuplexModBamPileup {
                                             &motif_locations,
                                             edge_filter.as_ref(),
                                             position_filter.as_ref(),
+                                            record_filter.as_ref(),
                                         )
                                     })
                                     .collect::<Vec<anyhow::Result<DuplexModBasePileup>>>()
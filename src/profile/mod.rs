@@ -0,0 +1,534 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use clap::ValueEnum;
+use derive_new::new;
+use log::debug;
+
+use crate::mod_bam::EdgeFilter;
+use crate::mod_base_code::ModCodeRepr;
+use crate::motif_bed::MultipleMotifLocations;
+use crate::pileup::{process_region, PileupNumericOptions, PileupReadOptions};
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::util::Strand;
+
+pub mod subcommand;
+mod writer;
+
+/// Position on each feature that the flanks are measured from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub enum ReferencePoint {
+    /// The 5' end of the feature (e.g. the TSS of a gene), strand-aware.
+    start,
+    /// The center of the feature.
+    center,
+    /// The 3' end of the feature, strand-aware.
+    end,
+}
+
+impl Display for ReferencePoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReferencePoint::start => write!(f, "start"),
+            ReferencePoint::center => write!(f, "center"),
+            ReferencePoint::end => write!(f, "end"),
+        }
+    }
+}
+
+/// A feature from the input BED, features without a strand are oriented as
+/// if they were on the positive strand.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Feature {
+    pub(crate) chrom: String,
+    pub(crate) start: u64,
+    pub(crate) stop: u64,
+    pub(crate) name: String,
+    pub(crate) strand: Option<Strand>,
+}
+
+impl Feature {
+    pub(crate) fn parse_bed_line(line: &str) -> anyhow::Result<Self> {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        if parts.len() < 3 {
+            bail!("BED line needs at least 3 columns, got {line}")
+        }
+        let chrom = parts[0].to_string();
+        let start = parts[1]
+            .parse::<u64>()
+            .map_err(|e| anyhow!("invalid start in {line}, {e}"))?;
+        let stop = parts[2]
+            .parse::<u64>()
+            .map_err(|e| anyhow!("invalid end in {line}, {e}"))?;
+        if stop <= start {
+            bail!("feature end must be greater than start, {line}")
+        }
+        let name = parts
+            .get(3)
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| format!("{chrom}:{start}-{stop}"));
+        let strand = match parts.get(5).map(|s| s.trim()) {
+            Some("+") => Some(Strand::Positive),
+            Some("-") => Some(Strand::Negative),
+            Some(".") | None => None,
+            Some(s) => bail!("invalid strand {s} in {line}"),
+        };
+        Ok(Self {
+            chrom,
+            start,
+            stop,
+            name,
+            strand,
+        })
+    }
+
+    fn is_reverse(&self) -> bool {
+        self.strand == Some(Strand::Negative)
+    }
+
+    fn strand_char(&self) -> char {
+        self.strand.map(|s| s.to_char()).unwrap_or('.')
+    }
+}
+
+pub(crate) fn parse_features_bed<P: AsRef<Path>>(
+    fp: P,
+) -> anyhow::Result<Vec<Feature>> {
+    let features = BufReader::new(File::open(fp)?)
+        .lines()
+        .map_while(Result::ok)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| Feature::parse_bed_line(&l))
+        .collect::<anyhow::Result<Vec<Feature>>>()?;
+    if features.is_empty() {
+        bail!("didn't parse any features")
+    }
+    Ok(features)
+}
+
+/// How positions around a feature are assigned to bins. With `body_bins`
+/// the feature body is scaled into that many bins between the upstream and
+/// downstream flanks, otherwise the flanks are measured from the
+/// `reference_point`.
+#[derive(new, Debug, Copy, Clone)]
+pub(crate) struct ProfileLayout {
+    flank: u64,
+    bin_size: u64,
+    body_bins: Option<usize>,
+    reference_point: ReferencePoint,
+}
+
+impl ProfileLayout {
+    fn flank_bins(&self) -> usize {
+        (self.flank / self.bin_size) as usize
+    }
+
+    pub(crate) fn n_bins(&self) -> usize {
+        self.flank_bins() * 2 + self.body_bins.unwrap_or(0)
+    }
+
+    /// Labels for each bin, flank bins are labeled with the offset of the
+    /// start of the bin in base pairs. When the body is scaled, downstream
+    /// bins are relative to the 3' end of the feature.
+    pub(crate) fn bin_labels(&self) -> Vec<String> {
+        let flank_bins = self.flank_bins() as i64;
+        let bin_size = self.bin_size as i64;
+        let upstream =
+            (0..flank_bins).map(|i| format!("{}", (i - flank_bins) * bin_size));
+        match self.body_bins {
+            Some(body_bins) => upstream
+                .chain((1..=body_bins).map(|i| format!("body_{i}")))
+                .chain((0..flank_bins).map(|i| format!("end+{}", i * bin_size)))
+                .collect(),
+            None => upstream
+                .chain((0..flank_bins).map(|i| format!("{}", i * bin_size)))
+                .collect(),
+        }
+    }
+
+    fn anchor(&self, feature: &Feature) -> u64 {
+        let (five_prime, three_prime) = if feature.is_reverse() {
+            (feature.stop - 1, feature.start)
+        } else {
+            (feature.start, feature.stop - 1)
+        };
+        match self.reference_point {
+            ReferencePoint::start => five_prime,
+            ReferencePoint::center => (feature.start + feature.stop - 1) / 2,
+            ReferencePoint::end => three_prime,
+        }
+    }
+
+    /// The genomic interval covered by the profile of the feature, clipped
+    /// to the start of the sequence.
+    pub(crate) fn window(&self, feature: &Feature) -> (u64, u64) {
+        match self.body_bins {
+            Some(_) => (
+                feature.start.saturating_sub(self.flank),
+                feature.stop + self.flank,
+            ),
+            None => {
+                let anchor = self.anchor(feature);
+                if feature.is_reverse() {
+                    // bins extend from anchor + flank down to anchor - flank
+                    (
+                        (anchor + 1).saturating_sub(self.flank),
+                        anchor + 1 + self.flank,
+                    )
+                } else {
+                    (anchor.saturating_sub(self.flank), anchor + self.flank)
+                }
+            }
+        }
+    }
+
+    /// Bin of a position around the feature, None when the position is
+    /// outside of the profile.
+    pub(crate) fn bin(
+        &self,
+        feature: &Feature,
+        position: u64,
+    ) -> Option<usize> {
+        let position = position as i64;
+        let flank = self.flank as i64;
+        let bin_size = self.bin_size as i64;
+        let flank_bins = self.flank_bins();
+        match self.body_bins {
+            Some(body_bins) => {
+                let (start, stop) = (feature.start as i64, feature.stop as i64);
+                // distance downstream of the 5' end and past the 3' end
+                let (from_five_prime, past_three_prime) =
+                    if feature.is_reverse() {
+                        (stop - 1 - position, start - 1 - position)
+                    } else {
+                        (position - start, position - stop)
+                    };
+                if from_five_prime < 0 {
+                    if from_five_prime < -flank {
+                        None
+                    } else {
+                        Some(((from_five_prime + flank) / bin_size) as usize)
+                    }
+                } else if past_three_prime >= 0 {
+                    if past_three_prime >= flank {
+                        None
+                    } else {
+                        Some(
+                            flank_bins
+                                + body_bins
+                                + (past_three_prime / bin_size) as usize,
+                        )
+                    }
+                } else {
+                    let length = stop - start;
+                    let body_bin = (from_five_prime as usize * body_bins)
+                        / length as usize;
+                    Some(flank_bins + body_bin)
+                }
+            }
+            None => {
+                let anchor = self.anchor(feature) as i64;
+                let offset = if feature.is_reverse() {
+                    anchor - position
+                } else {
+                    position - anchor
+                };
+                if offset < -flank || offset >= flank {
+                    None
+                } else {
+                    Some(((offset + flank) / bin_size) as usize)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct BinCounts {
+    pub(crate) n_modified: u64,
+    pub(crate) valid_coverage: u64,
+}
+
+impl BinCounts {
+    pub(crate) fn percent_modified(&self) -> Option<f64> {
+        if self.valid_coverage == 0 {
+            None
+        } else {
+            Some(self.n_modified as f64 / self.valid_coverage as f64 * 100f64)
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.n_modified += other.n_modified;
+        self.valid_coverage += other.valid_coverage;
+    }
+}
+
+/// Counts in each bin around a single feature, for each mod code.
+#[derive(Debug)]
+pub(crate) struct FeatureProfile {
+    pub(crate) feature: Feature,
+    pub(crate) bins: BTreeMap<ModCodeRepr, Vec<BinCounts>>,
+}
+
+/// Settings used to pileup the reads around each feature.
+#[derive(new)]
+pub(crate) struct ProfileParams {
+    caller: MultipleThresholdModCaller,
+    pileup_options: PileupNumericOptions,
+    force_allow: bool,
+    max_depth: u32,
+    motif_locations: Option<MultipleMotifLocations>,
+    edge_filter: Option<EdgeFilter>,
+}
+
+/// Pileup the reads around the feature and sum the counts of the positions
+/// in each bin. Both strands are counted.
+pub(crate) fn process_feature(
+    bam_fp: &Path,
+    chrom_tid: u32,
+    chrom_length: u64,
+    feature: &Feature,
+    layout: &ProfileLayout,
+    params: &ProfileParams,
+) -> anyhow::Result<FeatureProfile> {
+    let (start, end) = layout.window(feature);
+    let end = end.min(chrom_length);
+    if start >= end {
+        bail!(
+            "feature {} is outside of the reference sequence",
+            &feature.name
+        )
+    }
+    let pileup = process_region(
+        &[bam_fp],
+        chrom_tid,
+        start as u32,
+        end as u32,
+        &params.caller,
+        &params.pileup_options,
+        params.force_allow,
+        false,
+        params.max_depth,
+        params.motif_locations.as_ref(),
+        params.edge_filter.as_ref(),
+        None,
+        &PileupReadOptions::default(),
+    )
+    .map_err(|e| anyhow!("failed to pileup region, {e}"))?;
+
+    let n_bins = layout.n_bins();
+    let mut bins = BTreeMap::<ModCodeRepr, Vec<BinCounts>>::new();
+    for (pos, partitioned_counts) in pileup.iter_counts_sorted() {
+        let Some(bin) = layout.bin(feature, *pos as u64) else {
+            continue;
+        };
+        for feature_counts in partitioned_counts.values().flatten() {
+            let counts = BinCounts {
+                n_modified: feature_counts.n_modified as u64,
+                valid_coverage: feature_counts.filtered_coverage as u64,
+            };
+            bins.entry(feature_counts.raw_mod_code)
+                .or_insert_with(|| vec![BinCounts::default(); n_bins])[bin]
+                .add(&counts);
+        }
+    }
+    debug!(
+        "feature {} has counts for {} mod codes",
+        &feature.name,
+        bins.len()
+    );
+
+    Ok(FeatureProfile {
+        feature: feature.clone(),
+        bins,
+    })
+}
+
+/// Counts in each bin summed over all of the features, for one mod code.
+#[derive(Debug, Clone)]
+pub(crate) struct AggregateProfile {
+    pub(crate) counts: Vec<BinCounts>,
+    pub(crate) n_features: Vec<usize>,
+    pub(crate) feature_percent_sum: Vec<f64>,
+}
+
+impl AggregateProfile {
+    fn new(n_bins: usize) -> Self {
+        Self {
+            counts: vec![BinCounts::default(); n_bins],
+            n_features: vec![0; n_bins],
+            feature_percent_sum: vec![0f64; n_bins],
+        }
+    }
+
+    fn add(&mut self, feature_bins: &[BinCounts]) {
+        for (i, bin) in feature_bins.iter().enumerate() {
+            if let Some(percent) = bin.percent_modified() {
+                self.counts[i].add(bin);
+                self.n_features[i] += 1;
+                self.feature_percent_sum[i] += percent;
+            }
+        }
+    }
+}
+
+/// Accumulates the profiles of the features for each mod code.
+pub(crate) struct ProfileAccumulator {
+    n_bins: usize,
+    pub(crate) profiles: BTreeMap<ModCodeRepr, AggregateProfile>,
+}
+
+impl ProfileAccumulator {
+    pub(crate) fn new(n_bins: usize) -> Self {
+        Self {
+            n_bins,
+            profiles: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, feature_profile: &FeatureProfile) {
+        for (mod_code, bins) in feature_profile.bins.iter() {
+            self.profiles
+                .entry(*mod_code)
+                .or_insert_with(|| AggregateProfile::new(self.n_bins))
+                .add(bins);
+        }
+    }
+}
+
+#[cfg(test)]
+mod profile_tests {
+    use crate::mod_base_code::METHYL_CYTOSINE;
+    use crate::profile::FeatureProfile;
+    use crate::profile::{
+        BinCounts, Feature, ProfileAccumulator, ProfileLayout, ReferencePoint,
+    };
+    use crate::util::Strand;
+    use std::collections::BTreeMap;
+
+    fn feature(start: u64, stop: u64, strand: Option<Strand>) -> Feature {
+        Feature {
+            chrom: "chr1".to_string(),
+            start,
+            stop,
+            name: "f".to_string(),
+            strand,
+        }
+    }
+
+    #[test]
+    fn test_parse_feature() {
+        let f = Feature::parse_bed_line("chr1\t10\t20\tgene\t0\t-").unwrap();
+        assert_eq!(
+            f,
+            Feature {
+                chrom: "chr1".to_string(),
+                start: 10,
+                stop: 20,
+                name: "gene".to_string(),
+                strand: Some(Strand::Negative),
+            }
+        );
+        let f = Feature::parse_bed_line("chr1\t10\t20").unwrap();
+        assert_eq!(f.name, "chr1:10-20");
+        assert_eq!(f.strand, None);
+        assert!(Feature::parse_bed_line("chr1\t20\t10").is_err());
+        assert!(Feature::parse_bed_line("chr1\t10\t20\tx\t0\t*").is_err());
+    }
+
+    #[test]
+    fn test_reference_point_bins() {
+        let layout = ProfileLayout::new(100, 10, None, ReferencePoint::start);
+        assert_eq!(layout.n_bins(), 20);
+        let labels = layout.bin_labels();
+        assert_eq!(labels[0], "-100");
+        assert_eq!(labels[10], "0");
+        assert_eq!(labels[19], "90");
+
+        let pos = feature(1000, 2000, Some(Strand::Positive));
+        assert_eq!(layout.window(&pos), (900, 1100));
+        assert_eq!(layout.bin(&pos, 899), None);
+        assert_eq!(layout.bin(&pos, 900), Some(0));
+        assert_eq!(layout.bin(&pos, 1000), Some(10));
+        assert_eq!(layout.bin(&pos, 1099), Some(19));
+        assert_eq!(layout.bin(&pos, 1100), None);
+
+        // the TSS of a negative strand feature is the last base, upstream is
+        // to the right
+        let neg = feature(1000, 2000, Some(Strand::Negative));
+        assert_eq!(layout.window(&neg), (1900, 2100));
+        assert_eq!(layout.bin(&neg, 1999), Some(10));
+        assert_eq!(layout.bin(&neg, 2000), Some(9));
+        assert_eq!(layout.bin(&neg, 2099), Some(0));
+        assert_eq!(layout.bin(&neg, 1900), Some(19));
+        assert_eq!(layout.bin(&neg, 1899), None);
+
+        let center = ProfileLayout::new(100, 10, None, ReferencePoint::center);
+        assert_eq!(center.bin(&pos, 1499), Some(10));
+        let end = ProfileLayout::new(100, 10, None, ReferencePoint::end);
+        assert_eq!(end.bin(&pos, 1999), Some(10));
+        assert_eq!(end.bin(&neg, 1000), Some(10));
+        // clipped at the start of the sequence
+        assert_eq!(layout.window(&feature(50, 60, None)), (0, 150));
+    }
+
+    #[test]
+    fn test_scaled_body_bins() {
+        let layout =
+            ProfileLayout::new(100, 50, Some(4), ReferencePoint::start);
+        assert_eq!(layout.n_bins(), 8);
+        assert_eq!(
+            layout.bin_labels(),
+            vec![
+                "-100", "-50", "body_1", "body_2", "body_3", "body_4", "end+0",
+                "end+50"
+            ]
+        );
+        let pos = feature(1000, 1400, Some(Strand::Positive));
+        assert_eq!(layout.window(&pos), (900, 1500));
+        assert_eq!(layout.bin(&pos, 900), Some(0));
+        assert_eq!(layout.bin(&pos, 999), Some(1));
+        assert_eq!(layout.bin(&pos, 1000), Some(2));
+        assert_eq!(layout.bin(&pos, 1099), Some(2));
+        assert_eq!(layout.bin(&pos, 1100), Some(3));
+        assert_eq!(layout.bin(&pos, 1399), Some(5));
+        assert_eq!(layout.bin(&pos, 1400), Some(6));
+        assert_eq!(layout.bin(&pos, 1499), Some(7));
+        assert_eq!(layout.bin(&pos, 1500), None);
+
+        let neg = feature(1000, 1400, Some(Strand::Negative));
+        assert_eq!(layout.bin(&neg, 1499), Some(0));
+        assert_eq!(layout.bin(&neg, 1400), Some(1));
+        assert_eq!(layout.bin(&neg, 1399), Some(2));
+        assert_eq!(layout.bin(&neg, 1000), Some(5));
+        assert_eq!(layout.bin(&neg, 999), Some(6));
+        assert_eq!(layout.bin(&neg, 900), Some(7));
+        assert_eq!(layout.bin(&neg, 899), None);
+    }
+
+    #[test]
+    fn test_profile_accumulator() {
+        let counts = |n_modified, valid_coverage| BinCounts {
+            n_modified,
+            valid_coverage,
+        };
+        let profile = |bins: Vec<BinCounts>| FeatureProfile {
+            feature: feature(0, 10, None),
+            bins: BTreeMap::from([(METHYL_CYTOSINE, bins)]),
+        };
+        let mut acc = ProfileAccumulator::new(2);
+        acc.add(&profile(vec![counts(9, 10), counts(0, 0)]));
+        acc.add(&profile(vec![counts(0, 90), counts(1, 2)]));
+        let m = acc.profiles.get(&METHYL_CYTOSINE).unwrap();
+        assert_eq!(m.counts, vec![counts(9, 100), counts(1, 2)]);
+        assert_eq!(m.n_features, vec![2, 1]);
+        assert!((m.feature_percent_sum[0] - 90f64).abs() < 1e-9);
+        assert!((m.feature_percent_sum[1] - 50f64).abs() < 1e-9);
+    }
+}
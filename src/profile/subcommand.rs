@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context};
+use clap::Args;
+use indicatif::MultiProgress;
+use log::{debug, info};
+use rayon::prelude::*;
+use rust_htslib::bam::{self, Read};
+
+use crate::command_utils::ModCallerArgs;
+use crate::logging::init_logging;
+use crate::motif_bed::{MotifLocations, MultipleMotifLocations, RegexMotif};
+use crate::pileup::PileupNumericOptions;
+use crate::profile::writer::{write_aggregate_profile, MatrixWriter};
+use crate::profile::{
+    parse_features_bed, process_feature, FeatureProfile, ProfileAccumulator,
+    ProfileLayout, ProfileParams, ReferencePoint,
+};
+use crate::util::{get_master_progress_bar, get_targets, get_ticker};
+
+#[derive(Args)]
+pub struct MethylationProfile {
+    // running args
+    /// Input BAM, should be sorted and have associated index available.
+    in_bam: PathBuf,
+    /// Output directory, the aggregate profile is written to profile.tsv and
+    /// the matrix of features by bins to matrix.tsv.
+    out_dir: PathBuf,
+    /// Prefix to add to the output file names.
+    #[arg(long)]
+    prefix: Option<String>,
+    /// BED file of features, for example TSSs or CTCF sites. Requires chrom,
+    /// chromStart and chromEnd, the name (column 4) and strand (column 6) are
+    /// optional. Features on the negative strand are reversed so that
+    /// upstream is always to the left of the profile.
+    #[arg(long, short = 'r', alias = "regions")]
+    features_bed: PathBuf,
+    /// Size of the flanks on either side of the features in base pairs.
+    #[arg(long, default_value_t = 2000)]
+    flank: u64,
+    /// Size of the bins in the flanks in base pairs, must divide the flank
+    /// size.
+    #[arg(long, default_value_t = 100)]
+    bin_size: u64,
+    /// Scale the body of each feature (e.g. gene bodies) into this many bins
+    /// between the upstream and downstream flanks.
+    #[arg(long, conflicts_with = "reference_point")]
+    body_bins: Option<usize>,
+    /// Position of each feature the flanks are measured from when the body is
+    /// not scaled, strand-aware.
+    #[arg(long, default_value_t = ReferencePoint::start)]
+    reference_point: ReferencePoint,
+    /// Reference sequence in FASTA format, required with --cpg or --motif.
+    #[arg(long = "ref", alias = "reference")]
+    reference_fasta: Option<PathBuf>,
+    /// Only use CpG sites, short hand for --motif CG 0.
+    #[arg(long, group = "motif_options", default_value_t = false)]
+    cpg: bool,
+    /// Only use sites at this sequence motif. The first argument should be the
+    /// sequence motif and the second argument is the 0-based offset to the
+    /// base to use.
+    #[arg(long, group = "motif_options", num_args = 2)]
+    motif: Option<Vec<String>>,
+    /// Respect soft masking in the reference FASTA.
+    #[arg(long, short = 'k', default_value_t = false, hide_short_help = true)]
+    mask: bool,
+    /// Force overwrite of output files, if they already exist.
+    #[arg(long, default_value_t = false)]
+    force: bool,
+    /// Specify a file for debug logs to be written to, otherwise ignore them.
+    /// Setting a file is recommended. (alias: log)
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+
+    // processing args
+    /// Number of threads to use.
+    #[arg(short, long, default_value_t = 4)]
+    threads: usize,
+    /// Number of features to process concurrently. Default will be 50% more
+    /// than the number of threads assigned.
+    #[arg(long, alias = "batch")]
+    batch_size: Option<usize>,
+    /// Maximum number of records to use when calculating pileup. This argument is
+    /// passed to the pileup engine.
+    #[arg(long, default_value_t = 8000, hide_short_help = true)]
+    max_depth: u32,
+    /// Hide the progress bar.
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    suppress_progress: bool,
+
+    #[command(flatten)]
+    mod_caller_args: ModCallerArgs,
+}
+
+impl MethylationProfile {
+    fn out_file(&self, name: &str) -> anyhow::Result<File> {
+        let file_name = match self.prefix.as_ref() {
+            Some(prefix) => format!("{prefix}_{name}"),
+            None => name.to_string(),
+        };
+        let fp = self.out_dir.join(file_name);
+        if fp.exists() && !self.force {
+            bail!("refusing to overwrite existing file {fp:?}")
+        }
+        File::create(&fp).with_context(|| format!("failed to create {fp:?}"))
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        let header = bam::IndexedReader::from_path(&self.in_bam)
+            .map(|reader| reader.header().to_owned())
+            .context("failed to open input BAM, must be sorted and indexed")?;
+        if self.bin_size == 0
+            || self.flank / self.bin_size * self.bin_size != self.flank
+        {
+            bail!("bin size must be greater than 0 and divide the flank size")
+        }
+        if self.body_bins == Some(0) {
+            bail!("body bins must be greater than 0")
+        }
+        if self.flank == 0 && self.body_bins.is_none() {
+            bail!("flank must be greater than 0 without --body-bins")
+        }
+        let layout = ProfileLayout::new(
+            self.flank,
+            self.bin_size,
+            self.body_bins,
+            self.reference_point,
+        );
+        let bin_labels = layout.bin_labels();
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .context("failed to make threadpool")?;
+        let mpb = MultiProgress::new();
+        if self.suppress_progress {
+            mpb.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+
+        let edge_filter = self.mod_caller_args.edge_filter()?;
+        let collapse_method = self.mod_caller_args.collapse_method()?;
+        let pileup_options = match collapse_method.as_ref() {
+            Some(method) => PileupNumericOptions::Collapse(method.clone()),
+            None => PileupNumericOptions::Passthrough,
+        };
+
+        let targets = get_targets(&header, None);
+        let regex_motif = match (&self.motif, self.cpg) {
+            (Some(raw_motif_parts), _) => Some(
+                RegexMotif::from_raw_parts(raw_motif_parts, false)?
+                    .into_iter()
+                    .next()
+                    .ok_or(anyhow!("illegal number of parts for motif"))?,
+            ),
+            (None, true) => Some(RegexMotif::parse_string("CG", 0).unwrap()),
+            (None, false) => None,
+        };
+        let motif_locations = regex_motif
+            .map(|regex_motif| {
+                let reference_fasta =
+                    self.reference_fasta.as_ref().ok_or_else(|| {
+                        anyhow!("--ref is required with --cpg or --motif")
+                    })?;
+                let names_to_tid = targets
+                    .iter()
+                    .map(|target| (target.name.as_str(), target.tid))
+                    .collect::<HashMap<&str, u32>>();
+                pool.install(|| {
+                    MotifLocations::from_fasta(
+                        reference_fasta,
+                        regex_motif,
+                        &names_to_tid,
+                        self.mask,
+                        None,
+                        &mpb,
+                    )
+                })
+                .map(|locations| MultipleMotifLocations::new(vec![locations]))
+            })
+            .transpose()?;
+
+        let features = parse_features_bed(&self.features_bed)?;
+        info!("loaded {} features", features.len());
+        let failures = mpb.add(get_ticker());
+        failures.set_message("features failed to process");
+        let chrom_lengths = targets
+            .iter()
+            .map(|target| (target.name.as_str(), (target.tid, target.length)))
+            .collect::<HashMap<&str, (u32, u32)>>();
+        let work = features
+            .into_iter()
+            .filter_map(|feature| {
+                let tid_length = chrom_lengths.get(feature.chrom.as_str());
+                if tid_length.is_none() {
+                    debug!("{} not found in BAM header", &feature.chrom);
+                    failures.inc(1);
+                }
+                tid_length.map(|(tid, length)| (*tid, *length as u64, feature))
+            })
+            .collect::<Vec<_>>();
+
+        let threshold_caller = self.mod_caller_args.build_caller(
+            std::slice::from_ref(&self.in_bam),
+            &pool,
+            None,
+            edge_filter.as_ref(),
+            collapse_method.as_ref(),
+            None,
+            self.suppress_progress,
+        )?;
+        let params = ProfileParams::new(
+            threshold_caller,
+            pileup_options,
+            self.mod_caller_args.force_allow_implicit,
+            self.max_depth,
+            motif_locations,
+            edge_filter,
+        );
+
+        if !self.out_dir.exists() {
+            info!("creating directory at {:?}", &self.out_dir);
+            std::fs::create_dir_all(&self.out_dir)?;
+        }
+        let mut matrix_writer = MatrixWriter::new(
+            BufWriter::new(self.out_file("matrix.tsv")?),
+            &bin_labels,
+        )?;
+        let profile_fh = self.out_file("profile.tsv")?;
+
+        let batch_size = self
+            .batch_size
+            .unwrap_or_else(|| (self.threads as f32 * 1.5f32).floor() as usize)
+            .max(1);
+        let pb = mpb.add(get_master_progress_bar(work.len()));
+        pb.set_message("features processed");
+        let mut accumulator = ProfileAccumulator::new(layout.n_bins());
+        let mut matrix_rows = 0u64;
+        for batch in work.chunks(batch_size) {
+            let results = pool.install(|| {
+                batch
+                    .par_iter()
+                    .map(|(tid, length, feature)| {
+                        process_feature(
+                            &self.in_bam,
+                            *tid,
+                            *length,
+                            feature,
+                            &layout,
+                            &params,
+                        )
+                    })
+                    .collect::<Vec<anyhow::Result<FeatureProfile>>>()
+            });
+            for result in results {
+                match result {
+                    Ok(feature_profile) => {
+                        accumulator.add(&feature_profile);
+                        matrix_rows += matrix_writer.write(&feature_profile)?;
+                    }
+                    Err(e) => {
+                        debug!("feature failed, error: {e}");
+                        failures.inc(1);
+                    }
+                }
+                pb.inc(1);
+            }
+        }
+        pb.finish_and_clear();
+        matrix_writer.finish()?;
+        let profile_rows = write_aggregate_profile(
+            BufWriter::new(profile_fh),
+            &accumulator,
+            &bin_labels,
+        )?;
+        info!(
+            "wrote {matrix_rows} matrix rows and {profile_rows} profile rows, \
+            {} features failed",
+            failures.position()
+        );
+
+        Ok(())
+    }
+}
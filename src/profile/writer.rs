@@ -0,0 +1,100 @@
+use std::io::Write;
+
+use anyhow::Result as AnyhowResult;
+
+use crate::profile::{FeatureProfile, ProfileAccumulator};
+
+/// Writes one row per feature and mod code with the percent modified in each
+/// bin, '.' when a bin has no valid coverage.
+pub(super) struct MatrixWriter<T: Write> {
+    writer: T,
+}
+
+impl<T: Write> MatrixWriter<T> {
+    pub(super) fn new(
+        mut writer: T,
+        bin_labels: &[String],
+    ) -> AnyhowResult<Self> {
+        let header = ["chrom", "start", "end", "name", "strand", "mod_code"]
+            .into_iter()
+            .map(|s| s.to_string())
+            .chain(bin_labels.iter().cloned())
+            .collect::<Vec<String>>()
+            .join("\t");
+        writeln!(writer, "{header}")?;
+        Ok(Self { writer })
+    }
+
+    pub(super) fn write(
+        &mut self,
+        feature_profile: &FeatureProfile,
+    ) -> AnyhowResult<u64> {
+        let feature = &feature_profile.feature;
+        let mut rows_written = 0u64;
+        for (mod_code, bins) in feature_profile.bins.iter() {
+            let values = bins
+                .iter()
+                .map(|bin| {
+                    bin.percent_modified()
+                        .map(|p| format!("{p:.2}"))
+                        .unwrap_or(".".to_string())
+                })
+                .collect::<Vec<String>>()
+                .join("\t");
+            writeln!(
+                self.writer,
+                "{}\t{}\t{}\t{}\t{}\t{mod_code}\t{values}",
+                feature.chrom,
+                feature.start,
+                feature.stop,
+                feature.name,
+                feature.strand_char(),
+            )?;
+            rows_written += 1;
+        }
+        Ok(rows_written)
+    }
+
+    pub(super) fn finish(mut self) -> AnyhowResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Write the aggregate profile, one row per mod code and bin.
+pub(super) fn write_aggregate_profile<T: Write>(
+    mut writer: T,
+    accumulator: &ProfileAccumulator,
+    bin_labels: &[String],
+) -> AnyhowResult<u64> {
+    writeln!(
+        writer,
+        "bin\tlabel\tmod_code\tn_features\tvalid_coverage\tn_modified\t\
+        percent_modified\tmean_feature_percent_modified"
+    )?;
+    let mut rows_written = 0u64;
+    for (mod_code, profile) in accumulator.profiles.iter() {
+        for (bin, label) in bin_labels.iter().enumerate() {
+            let counts = &profile.counts[bin];
+            let n_features = profile.n_features[bin];
+            let (percent, mean_percent) = match counts.percent_modified() {
+                Some(percent) => (
+                    format!("{percent:.2}"),
+                    format!(
+                        "{:.2}",
+                        profile.feature_percent_sum[bin] / n_features as f64
+                    ),
+                ),
+                None => (".".to_string(), ".".to_string()),
+            };
+            writeln!(
+                writer,
+                "{bin}\t{label}\t{mod_code}\t{n_features}\t{}\t{}\t{percent}\t{mean_percent}",
+                counts.valid_coverage, counts.n_modified,
+            )?;
+            rows_written += 1;
+        }
+    }
+    writer.flush()?;
+    Ok(rows_written)
+}
@@ -6,8 +6,8 @@ use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::errs::RunError;
 use crate::mod_bam::{
-    BaseModCall, CollapseMethod, DuplexModCall, EdgeFilter, ModBaseInfo,
-    SeqPosBaseModProbs, SkipMode,
+    BaseModCall, BaseModProbs, CollapseMethod, DuplexModCall, EdgeFilter,
+    ModBaseInfo, SeqPosBaseModProbs, SkipMode,
 };
 use crate::mod_base_code::{DnaBase, ModCodeRepr};
 use crate::monoid::BorrowingMoniod;
@@ -18,6 +18,9 @@ use crate::util::{self, Strand};
 /// Mapping of _reference position_ to base mod calls as determined by the aligned pairs for the
 /// read
 type RefPosBaseModCalls = FxHashMap<u64, BaseModCall>;
+/// Mapping of _reference position_ to the base mod probabilities used to make
+/// the calls above, only kept when asked for
+type RefPosBaseModProbs = FxHashMap<u64, BaseModProbs>;
 type PrimaryBaseToModCodes = FxHashMap<DnaBase, HashSet<ModCodeRepr>>;
 
 pub(crate) struct ReadCache<'a> {
@@ -27,6 +30,11 @@ pub(crate) struct ReadCache<'a> {
         FxHashMap<String, FxHashMap<char, (RefPosBaseModCalls, SkipMode)>>,
     neg_reads:
         FxHashMap<String, FxHashMap<char, (RefPosBaseModCalls, SkipMode)>>,
+    /// Mapping of read_id to reference position <> base mod probabilities,
+    /// only populated when `keep_probs` is set
+    pos_read_probs: FxHashMap<String, FxHashMap<char, RefPosBaseModProbs>>,
+    neg_read_probs: FxHashMap<String, FxHashMap<char, RefPosBaseModProbs>>,
+    keep_probs: bool,
     /// these reads don't have mod tags or should be skipped for some other reason
     skip_set: HashSet<String>,
     /// mapping of read_id (query_name) to the mod codes contained in that read
@@ -51,6 +59,9 @@ impl<'a> ReadCache<'a> {
         Self {
             pos_reads: FxHashMap::default(),
             neg_reads: FxHashMap::default(),
+            pos_read_probs: FxHashMap::default(),
+            neg_read_probs: FxHashMap::default(),
+            keep_probs: false,
             skip_set: HashSet::new(),
             pos_mod_codes: FxHashMap::default(),
             neg_mod_codes: FxHashMap::default(),
@@ -61,6 +72,15 @@ impl<'a> ReadCache<'a> {
         }
     }
 
+    /// Also keep the base modification probabilities for each read, so that
+    /// they can be retrieved with `get_mod_probs`.
+    pub(crate) fn with_probs(self) -> Self {
+        Self {
+            keep_probs: true,
+            ..self
+        }
+    }
+
     /// Subroutine that adds read's mod base calls to the cache (or error),
     /// in the case of an error the caller could remove this read from
     /// future consideration
@@ -78,21 +98,30 @@ impl<'a> ReadCache<'a> {
             .filter_map(|ap| ap.ok())
             .collect::<FxHashMap<usize, u64>>();
 
-        let ref_pos_base_mod_calls = seq_pos_base_mod_probs
-            .pos_to_base_mod_probs
-            .into_iter() // par iter?
-            // here the q_pos is the forward-oriented position
-            .flat_map(|(q_pos, bmp)| {
-                if let Some(r_pos) = aligned_pairs.get(&q_pos) {
-                    // filtering happens here.
-                    let call = self.caller.call(&threshold_base, &bmp);
-                    Some((*r_pos, call))
-                } else {
-                    None
+        let mut ref_pos_base_mod_calls = RefPosBaseModCalls::default();
+        let mut ref_pos_base_mod_probs = RefPosBaseModProbs::default();
+        // here the q_pos is the forward-oriented position
+        for (q_pos, bmp) in seq_pos_base_mod_probs.pos_to_base_mod_probs {
+            if let Some(r_pos) = aligned_pairs.get(&q_pos) {
+                // filtering happens here.
+                let call = self.caller.call(&threshold_base, &bmp);
+                ref_pos_base_mod_calls.insert(*r_pos, call);
+                if self.keep_probs {
+                    ref_pos_base_mod_probs.insert(*r_pos, bmp);
                 }
-            })
-            .collect::<FxHashMap<u64, BaseModCall>>();
+            }
+        }
 
+        if self.keep_probs {
+            let probs_table = match mod_strand {
+                Strand::Positive => &mut self.pos_read_probs,
+                Strand::Negative => &mut self.neg_read_probs,
+            };
+            probs_table
+                .entry(record_name.to_owned())
+                .or_default()
+                .insert(canonical_base.char(), ref_pos_base_mod_probs);
+        }
         let read_table = match mod_strand {
             Strand::Positive => &mut self.pos_reads,
             Strand::Negative => &mut self.neg_reads,
@@ -327,6 +356,56 @@ impl<'a> ReadCache<'a> {
         }
     }
 
+    #[inline]
+    fn get_mod_probs_from_mapping(
+        strand_calls: Option<&FxHashMap<char, (RefPosBaseModCalls, SkipMode)>>,
+        strand_probs: Option<&FxHashMap<char, RefPosBaseModProbs>>,
+        canonical_base: char,
+        position: u32,
+    ) -> Option<BaseModProbs> {
+        let (_, skip_mode) = strand_calls?.get(&canonical_base)?;
+        let base_mod_probs = strand_probs
+            .and_then(|probs| probs.get(&canonical_base))
+            .and_then(|probs| probs.get(&(position as u64)))
+            .cloned();
+        match skip_mode {
+            SkipMode::Ambiguous => base_mod_probs,
+            SkipMode::ImplicitProbModified | SkipMode::ProbModified => {
+                Some(base_mod_probs.unwrap_or_else(|| {
+                    BaseModProbs::new_inferred_canonical::<ModCodeRepr>(&[])
+                }))
+            }
+        }
+    }
+
+    /// Get the base modification probabilities for a reference position from
+    /// a read, (+ strand, - strand) as with `get_mod_call`. Only reads
+    /// already in the cache are considered, so this should be called after
+    /// `get_mod_call` and requires `with_probs`.
+    pub(crate) fn get_mod_probs(
+        &self,
+        record: &bam::Record,
+        position: u32,
+        canonical_base: char,
+    ) -> (Option<BaseModProbs>, Option<BaseModProbs>) {
+        let read_id = String::from_utf8_lossy(record.qname());
+        let read_id = read_id.as_ref();
+        (
+            Self::get_mod_probs_from_mapping(
+                self.pos_reads.get(read_id),
+                self.pos_read_probs.get(read_id),
+                canonical_base,
+                position,
+            ),
+            Self::get_mod_probs_from_mapping(
+                self.neg_reads.get(read_id),
+                self.neg_read_probs.get(read_id),
+                canonical_base,
+                position,
+            ),
+        )
+    }
+
     pub(crate) fn add_mod_codes_for_record(
         &mut self,
         record: &bam::Record,
@@ -21,11 +21,12 @@ use crate::mod_base_code::{BaseState, DnaBase, ModCodeRepr};
 use crate::monoid::Moniod;
 use crate::position_filter::StrandedPositionFilter;
 use crate::reads_sampler::record_sampler::{Indicator, RecordSampler};
+use crate::record_filter::RecordSelection;
 use crate::record_processor::{RecordProcessor, WithRecords};
 use crate::util::{
     self, get_aligned_pairs_forward, get_forward_sequence,
     get_master_progress_bar, get_query_name_string, get_reference_mod_strand,
-    get_spinner, Kmer, Strand,
+    get_spinner, Kmer, ReadTag, Strand,
 };
 
 /// Read IDs mapped to their base modification probabilities, organized
@@ -186,16 +187,21 @@ impl RecordProcessor for ReadIdsToBaseModProbs {
         collapse_method: Option<&CollapseMethod>,
         edge_filter: Option<&EdgeFilter>,
         position_filter: Option<&StrandedPositionFilter<()>>,
-        only_mapped: bool,
+        record_selection: RecordSelection,
         _kmer_size: Option<usize>,
+        _tags: &[ReadTag],
     ) -> anyhow::Result<Self::Output> {
+        let RecordSelection {
+            only_mapped,
+            record_filter,
+        } = record_selection;
         let spinner = if with_progress {
             Some(record_sampler.get_progress_bar())
         } else {
             None
         };
-        let mod_base_info_iter =
-            filter_records_iter(records).filter(|(record, _)| {
+        let mod_base_info_iter = filter_records_iter(records, record_filter)
+            .filter(|(record, _)| {
                 if only_mapped || edge_filter.is_some() {
                     !record.is_unmapped()
                 } else {
@@ -407,35 +413,48 @@ impl ModProfile {
         )
     }
 
+    pub(crate) fn ref_kmer(
+        &self,
+        chrom_name: &str,
+        reference_seqs: &HashMap<String, Vec<u8>>,
+        kmer_size: usize,
+    ) -> Option<String> {
+        self.ref_position
+            .filter(|ref_pos| *ref_pos >= 0)
+            .and_then(|ref_pos| {
+                reference_seqs.get(chrom_name).map(|s| {
+                    Kmer::from_seq(s, ref_pos as usize, kmer_size).to_string()
+                })
+            })
+    }
+
+    pub(crate) fn modified_primary_base(&self) -> char {
+        if self.mod_strand == Strand::Negative {
+            self.canonical_base.complement().char()
+        } else {
+            self.canonical_base.char()
+        }
+    }
+
+    pub(crate) fn ref_mod_strand(&self) -> Option<Strand> {
+        self.alignment_strand
+            .map(|s| get_reference_mod_strand(self.mod_strand, s))
+    }
+
     pub(crate) fn to_row(
         &self,
         read_id: &str,
         chrom_name: &str,
         reference_seqs: &HashMap<String, Vec<u8>>,
         kmer_size: usize,
+        extra_columns: &str,
     ) -> String {
         let query_kmer = format!("{}", self.query_kmer);
-        let ref_kmer = if let Some(ref_pos) = self.ref_position {
-            if ref_pos < 0 {
-                ".".to_string()
-            } else {
-                reference_seqs
-                    .get(chrom_name)
-                    .map(|s| {
-                        Kmer::from_seq(s, ref_pos as usize, kmer_size)
-                            .to_string()
-                    })
-                    .unwrap_or(".".to_string())
-            }
-        } else {
-            ".".to_string()
-        };
+        let ref_kmer = self
+            .ref_kmer(chrom_name, reference_seqs, kmer_size)
+            .unwrap_or(".".to_string());
         let sep = '\t';
-        let modified_primary_base = if self.mod_strand == Strand::Negative {
-            self.canonical_base.complement().char()
-        } else {
-            self.canonical_base.char()
-        };
+        let modified_primary_base = self.modified_primary_base();
 
         let _within_alignment = self.within_alignment();
         format!(
@@ -457,14 +476,12 @@ impl ModProfile {
             {}{sep}\
             {}{sep}\
             {}{sep}\
-            {}\n",
+            {}{extra_columns}\n",
             self.query_position,
             self.ref_position.unwrap_or(-1),
             self.mod_strand.to_char(),
             self.alignment_strand.map(|s| s.to_char()).unwrap_or('.'),
-            self.alignment_strand
-                .map(|s| get_reference_mod_strand(self.mod_strand, s).to_char())
-                .unwrap_or('.'),
+            self.ref_mod_strand().map(|s| s.to_char()).unwrap_or('.'),
             self.num_soft_clipped_start,
             self.num_soft_clipped_end,
             self.read_length,
@@ -484,7 +501,12 @@ impl ModProfile {
 pub(crate) struct ReadBaseModProfile {
     pub(crate) record_name: String,
     pub(crate) chrom_id: Option<u32>,
+    /// 0-based start and exclusive end of the alignment, `None` when the
+    /// read is unmapped.
+    pub(crate) ref_span: Option<(i64, i64)>,
     pub(crate) profile: Vec<ModProfile>,
+    /// Values of the requested SAM tags, in the order they were requested.
+    pub(crate) tag_values: Vec<Option<String>>,
 }
 
 impl ReadBaseModProfile {
@@ -546,6 +568,7 @@ impl ReadBaseModProfile {
         collapse_method: Option<&CollapseMethod>,
         edge_filter: Option<&EdgeFilter>,
         kmer_size: usize,
+        tags: &[ReadTag],
     ) -> Result<Self, RunError> {
         let read_length = record.seq_len();
         let (num_clip_start, num_clip_end) =
@@ -701,17 +724,33 @@ impl ReadBaseModProfile {
             }
         });
 
+        let tag_values = tags.iter().map(|tag| tag.value(record)).collect();
+
+        let ref_span = if record.is_unmapped() {
+            None
+        } else {
+            Some((record.pos(), record.reference_end()))
+        };
+
         Ok(Self {
             record_name: record_name.to_owned(),
             chrom_id: chrom_tid,
+            ref_span,
             profile: mod_profiles,
+            tag_values,
         })
     }
 
     pub(crate) fn remove_inferred(self) -> Self {
         let profile =
             self.profile.into_iter().filter(|p| !p.inferred).collect();
-        Self::new(self.record_name, self.chrom_id, profile)
+        Self::new(
+            self.record_name,
+            self.chrom_id,
+            self.ref_span,
+            profile,
+            self.tag_values,
+        )
     }
 }
 
@@ -823,10 +862,15 @@ impl RecordProcessor for ReadsBaseModProfile {
         collapse_method: Option<&CollapseMethod>,
         edge_filter: Option<&EdgeFilter>,
         _position_filter: Option<&StrandedPositionFilter<()>>,
-        _only_mapped: bool,
+        record_selection: RecordSelection,
         kmer_size: Option<usize>,
+        tags: &[ReadTag],
     ) -> anyhow::Result<Self::Output> {
-        let mut mod_iter = TrackingModRecordIter::new(records, false);
+        let mut mod_iter = TrackingModRecordIter::new(
+            records,
+            false,
+            record_selection.record_filter,
+        );
         let mut agg = Vec::new();
         let mut seen = HashSet::new();
         let pb = if with_progress {
@@ -847,6 +891,7 @@ impl RecordProcessor for ReadsBaseModProfile {
                         collapse_method,
                         edge_filter,
                         kmer_size.unwrap_or(5),
+                        tags,
                     ) {
                         Ok(read_base_mod_profile) => {
                             if seen.contains(&record_name) {
@@ -0,0 +1,297 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+use derive_new::new;
+use log::debug;
+use rust_htslib::bam::{self, ext::BamRecordExtensions, FetchDefinition, Read};
+use rustc_hash::FxHashMap;
+
+use crate::mod_bam::{CollapseMethod, EdgeFilter};
+use crate::mod_base_code::{DnaBase, ModCodeRepr};
+use crate::motif_bed::motif_site;
+use crate::read_cache::ReadCache;
+use crate::record_filter::RecordFilter;
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::util::{
+    get_query_name_string, get_stringable_aux, record_is_secondary, SamTag,
+    Strand, StrandRule,
+};
+
+pub mod subcommand;
+mod writer;
+
+/// What to put in each cell of the matrix.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub enum MatrixValues {
+    /// 1 for a modified call, 0 for a canonical call, filtered calls are
+    /// missing.
+    call,
+    /// Probability of modification, not filtered.
+    probability,
+}
+
+/// A column of the matrix, the strand is `None` when calls on both strands
+/// of a palindromic motif are combined.
+#[derive(new, Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub(crate) struct MatrixSite {
+    pub(crate) position: u32,
+    pub(crate) strand: Option<Strand>,
+}
+
+/// Columns of the matrix and the mapping from (reference position, read
+/// strand) to column index.
+pub(crate) struct MatrixSites {
+    pub(crate) sites: Vec<MatrixSite>,
+    lookup: FxHashMap<(u32, Strand), usize>,
+}
+
+impl MatrixSites {
+    /// Make the columns from motif positions in [start, end). With
+    /// `combine_strands_offset` the two strands of a palindromic motif hit
+    /// are combined into a single column, see [`motif_site`].
+    pub(crate) fn new(
+        motif_positions: &FxHashMap<u32, StrandRule>,
+        start: u32,
+        end: u32,
+        combine_strands_offset: Option<i32>,
+    ) -> Self {
+        let stranded_positions = motif_positions
+            .iter()
+            .filter(|(position, _)| (start..end).contains(*position))
+            .flat_map(|(position, strand_rule)| {
+                strand_rule
+                    .strands()
+                    .into_iter()
+                    .map(move |strand| (*position, strand))
+            })
+            .collect::<Vec<(u32, Strand)>>();
+        let site_for = |position: u32, strand: Strand| {
+            motif_site(position, strand, combine_strands_offset)
+                .map(|(position, strand)| MatrixSite::new(position, strand))
+        };
+        let mut sites = stranded_positions
+            .iter()
+            .filter_map(|(position, strand)| site_for(*position, *strand))
+            .collect::<Vec<MatrixSite>>();
+        sites.sort();
+        sites.dedup();
+        let site_idxs = sites
+            .iter()
+            .enumerate()
+            .map(|(idx, site)| (*site, idx))
+            .collect::<FxHashMap<MatrixSite, usize>>();
+        let lookup = stranded_positions
+            .into_iter()
+            .filter_map(|(position, strand)| {
+                site_for(position, strand)
+                    .map(|site| ((position, strand), site_idxs[&site]))
+            })
+            .collect();
+        Self { sites, lookup }
+    }
+
+    fn get(&self, position: u32, strand: Strand) -> Option<usize> {
+        self.lookup.get(&(position, strand)).copied()
+    }
+}
+
+/// A row of the matrix, the values are (column index, value) for the sites
+/// with a call.
+#[derive(new, Debug)]
+pub(crate) struct MatrixRead {
+    pub(crate) read_id: String,
+    pub(crate) strand: Strand,
+    pub(crate) haplotype: Option<String>,
+    pub(crate) ref_start: i64,
+    pub(crate) ref_end: i64,
+    pub(crate) values: Vec<(usize, f32)>,
+}
+
+pub(crate) struct ReadMatrix {
+    pub(crate) chrom_name: String,
+    pub(crate) sites: Vec<MatrixSite>,
+    pub(crate) reads: Vec<MatrixRead>,
+}
+
+/// Which values go in the matrix and how reads are called.
+pub(crate) struct MatrixParams {
+    pub(crate) motif_base: DnaBase,
+    pub(crate) values: MatrixValues,
+    pub(crate) mod_code: Option<ModCodeRepr>,
+    pub(crate) min_sites: usize,
+    pub(crate) caller: MultipleThresholdModCaller,
+    pub(crate) collapse_method: Option<CollapseMethod>,
+    pub(crate) edge_filter: Option<EdgeFilter>,
+    pub(crate) record_filter: Option<RecordFilter>,
+    pub(crate) force_allow: bool,
+}
+
+pub(crate) fn read_matrix_for_region(
+    bam_fp: &Path,
+    chrom_tid: u32,
+    start: u32,
+    end: u32,
+    sites: MatrixSites,
+    params: &MatrixParams,
+) -> anyhow::Result<ReadMatrix> {
+    let motif_base = params.motif_base;
+    let values = params.values;
+    let mod_code = params.mod_code;
+    let mut bam_reader = bam::IndexedReader::from_path(bam_fp)?;
+    let chrom_name =
+        String::from_utf8_lossy(bam_reader.header().tid2name(chrom_tid))
+            .to_string();
+    bam_reader.fetch(FetchDefinition::Region(
+        chrom_tid as i32,
+        start as i64,
+        end as i64,
+    ))?;
+
+    let mut read_cache = ReadCache::new(
+        params.collapse_method.as_ref(),
+        &params.caller,
+        params.edge_filter.as_ref(),
+        params.force_allow,
+    );
+    if values == MatrixValues::probability {
+        read_cache = read_cache.with_probs();
+    }
+    let haplotype_tag = SamTag::new(*b"HP");
+    let mut reads = Vec::new();
+    for record in bam_reader.records().filter_map(|r| r.ok()) {
+        if record.is_unmapped()
+            || record_is_secondary(&record)
+            || record.seq_len() == 0
+            || !params
+                .record_filter
+                .as_ref()
+                .map(|f| f.keep(&record))
+                .unwrap_or(true)
+        {
+            continue;
+        }
+        let read_id = match get_query_name_string(&record) {
+            Ok(read_id) => read_id,
+            Err(_) => continue,
+        };
+        // the read cache is keyed on read ID, so only one alignment of each
+        // read can be used
+        if record.is_supplementary() {
+            debug!("skipping supplementary alignment of {read_id}");
+            continue;
+        }
+        let (strand, expected_base) = if record.is_reverse() {
+            (Strand::Negative, motif_base.complement())
+        } else {
+            (Strand::Positive, motif_base)
+        };
+        let seq = record.seq();
+        let site_positions = record
+            .aligned_pairs()
+            .filter(|[_, r_pos]| *r_pos >= start as i64 && *r_pos < end as i64)
+            .filter_map(|[q_pos, r_pos]| {
+                let base = DnaBase::parse(seq[q_pos as usize] as char).ok()?;
+                sites
+                    .get(r_pos as u32, strand)
+                    .filter(|_| base == expected_base)
+                    .map(|idx| (idx, r_pos as u32))
+            })
+            .collect::<Vec<(usize, u32)>>();
+        if site_positions.is_empty() {
+            continue;
+        }
+        let mut row = site_positions
+            .into_iter()
+            .filter_map(|(idx, position)| {
+                let base_mod_call = read_cache
+                    .get_mod_call(&record, position, motif_base.char())
+                    .0?;
+                let value = match values {
+                    MatrixValues::call => base_mod_call
+                        .is_modified(mod_code)
+                        .map(|modified| if modified { 1f32 } else { 0f32 }),
+                    MatrixValues::probability => read_cache
+                        .get_mod_probs(&record, position, motif_base.char())
+                        .0
+                        .map(|probs| match mod_code {
+                            Some(mod_code) => probs
+                                .iter_probs()
+                                .find(|(code, _)| **code == mod_code)
+                                .map(|(_, p)| *p)
+                                .unwrap_or(0f32),
+                            None => 1f32 - probs.canonical_prob(),
+                        }),
+                }?;
+                Some((idx, value))
+            })
+            .collect::<Vec<(usize, f32)>>();
+        if row.len() < params.min_sites.max(1) {
+            continue;
+        }
+        row.sort_by_key(|(idx, _)| *idx);
+        reads.push(MatrixRead::new(
+            read_id,
+            strand,
+            get_stringable_aux(&record, &haplotype_tag),
+            record.reference_start(),
+            record.reference_end(),
+            row,
+        ));
+    }
+
+    let (processed_records, skipped_records) =
+        read_cache.get_records_used_and_skipped();
+    debug!(
+        "processed {processed_records} reads, skipped {skipped_records} on \
+        {chrom_name}:{start}-{end}"
+    );
+
+    Ok(ReadMatrix {
+        chrom_name,
+        sites: sites.sites,
+        reads,
+    })
+}
+
+#[cfg(test)]
+mod read_matrix_tests {
+    use rustc_hash::FxHashMap;
+
+    use crate::read_matrix::{MatrixSite, MatrixSites};
+    use crate::util::{Strand, StrandRule};
+
+    #[test]
+    fn test_matrix_sites() {
+        // CpGs at 10 and 20, the negative strand C is one base downstream
+        let motif_positions = FxHashMap::from_iter([
+            (10, StrandRule::Positive),
+            (11, StrandRule::Negative),
+            (20, StrandRule::Positive),
+            (21, StrandRule::Negative),
+            (30, StrandRule::Both),
+        ]);
+        let sites = MatrixSites::new(&motif_positions, 0, 25, None);
+        assert_eq!(
+            sites.sites,
+            vec![
+                MatrixSite::new(10, Some(Strand::Positive)),
+                MatrixSite::new(11, Some(Strand::Negative)),
+                MatrixSite::new(20, Some(Strand::Positive)),
+                MatrixSite::new(21, Some(Strand::Negative)),
+            ]
+        );
+        assert_eq!(sites.get(11, Strand::Negative), Some(1));
+        assert_eq!(sites.get(11, Strand::Positive), None);
+        assert_eq!(sites.get(30, Strand::Positive), None);
+
+        let sites = MatrixSites::new(&motif_positions, 0, 25, Some(1));
+        assert_eq!(
+            sites.sites,
+            vec![MatrixSite::new(10, None), MatrixSite::new(20, None)]
+        );
+        assert_eq!(sites.get(10, Strand::Positive), Some(0));
+        assert_eq!(sites.get(11, Strand::Negative), Some(0));
+        assert_eq!(sites.get(21, Strand::Negative), Some(1));
+    }
+}
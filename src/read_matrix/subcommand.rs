@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use clap::Args;
+use indicatif::MultiProgress;
+use log::info;
+use rust_htslib::bam::{self, Read};
+
+use crate::command_utils::ModCallerArgs;
+use crate::logging::init_logging;
+use crate::mod_base_code::{DnaBase, ModCodeRepr};
+use crate::motif_bed::{MotifLocations, RegexMotif};
+use crate::read_matrix::writer::{DenseMatrixWriter, SparseMatrixWriter};
+use crate::read_matrix::{
+    read_matrix_for_region, MatrixParams, MatrixSites, MatrixValues, ReadMatrix,
+};
+use crate::record_filter::RecordFilterArgs;
+use crate::threshold_mod_caller::MultipleThresholdModCaller;
+use crate::util::{create_out_directory, Region};
+use crate::writers::OutWriter;
+
+#[derive(Args)]
+pub struct ReadByPositionMatrix {
+    // running args
+    /// Input BAM, should be sorted and have associated index available.
+    in_bam: PathBuf,
+    /// Output file to write the matrix into. Specify "-" or "stdout" to direct
+    /// output to stdout. With --sparse this is a directory.
+    out_path: String,
+    /// Specify a file for debug logs to be written to, otherwise ignore them.
+    /// Setting a file is recommended. (alias: log)
+    #[arg(long, alias = "log")]
+    log_filepath: Option<PathBuf>,
+    /// Region to make the matrix for. Format should be
+    /// <chrom_name>:<start>-<end> or <chrom_name>. Commas are allowed.
+    #[arg(long)]
+    region: String,
+    /// Reference sequence in FASTA format, required to find motif sites.
+    #[arg(long = "ref", alias = "reference", short = 'r')]
+    reference_fasta: PathBuf,
+    /// Use CpG sites as the columns of the matrix, short hand for --motif CG 0.
+    #[arg(long, group = "motif_options", default_value_t = false)]
+    cpg: bool,
+    /// Use sites of this sequence motif as the columns of the matrix. The
+    /// first argument should be the sequence motif and the second argument is
+    /// the 0-based offset to the base to use. For example: --motif CGCG 0
+    /// indicates to use the first C on the top strand and the last C
+    /// (complement to G) on the bottom strand.
+    #[arg(long, group = "motif_options", num_args = 2)]
+    motif: Option<Vec<String>>,
+    /// Respect soft masking in the reference FASTA.
+    #[arg(long, short = 'k', default_value_t = false, hide_short_help = true)]
+    mask: bool,
+    /// Combine calls on the positive and negative strands of a palindromic
+    /// motif (such as CpG) into a single column at the positive strand
+    /// position.
+    #[arg(long, default_value_t = false)]
+    combine_strands: bool,
+    /// Values to put in the matrix. "call" uses 1 for a modified call and 0
+    /// for a canonical call after thresholding, "probability" uses the
+    /// probability of modification without any filtering.
+    #[arg(long, value_enum, default_value_t = MatrixValues::call)]
+    values: MatrixValues,
+    /// Only report this modification code. Calls of other modifications are
+    /// missing values in "call" mode and the probability of this code is used
+    /// in "probability" mode. By default any modification counts as modified.
+    #[arg(long)]
+    mod_code: Option<String>,
+    /// Only include reads with at least this many sites with a value.
+    #[arg(long, default_value_t = 1)]
+    min_sites: usize,
+    /// Write a sparse matrix in Matrix Market format, with read and site
+    /// labels, to the output directory instead of a dense table.
+    #[arg(long, default_value_t = false)]
+    sparse: bool,
+    /// Force overwrite of output.
+    #[arg(long, default_value_t = false)]
+    force: bool,
+
+    // processing args
+    /// Number of threads to use when estimating the filter threshold.
+    #[arg(short, long, default_value_t = 4)]
+    threads: usize,
+    /// Hide the progress bar.
+    #[arg(long, default_value_t = false, hide_short_help = true)]
+    suppress_progress: bool,
+
+    #[command(flatten)]
+    mod_caller_args: ModCallerArgs,
+    // record filter args
+    #[command(flatten)]
+    record_filter_args: RecordFilterArgs,
+}
+
+impl ReadByPositionMatrix {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let _handle = init_logging(self.log_filepath.as_ref());
+        let header = bam::IndexedReader::from_path(&self.in_bam)
+            .map(|reader| reader.header().to_owned())
+            .context("failed to open input BAM, must be sorted and indexed")?;
+
+        let region = Region::parse_str(&self.region, &header)?;
+        let tid = header.tid(region.name.as_bytes()).ok_or(anyhow!(
+            "contig {} is not in the BAM header",
+            region.name
+        ))?;
+        let edge_filter = self.mod_caller_args.edge_filter()?;
+        let collapse_method = self.mod_caller_args.collapse_method()?;
+        let mod_code = self
+            .mod_code
+            .as_ref()
+            .map(|raw_mod_code| ModCodeRepr::parse(raw_mod_code))
+            .transpose()?;
+        let record_filter = self.record_filter_args.to_record_filter()?;
+
+        let regex_motif = match (&self.motif, self.cpg) {
+            (Some(raw_motif_parts), _) => {
+                RegexMotif::from_raw_parts(raw_motif_parts, false)?
+                    .into_iter()
+                    .next()
+                    .ok_or(anyhow!("illegal number of parts for motif"))?
+            }
+            (None, true) => RegexMotif::parse_string("CG", 0).unwrap(),
+            (None, false) => bail!("need to specify either --motif or --cpg"),
+        };
+        let combine_strands_offset = self
+            .combine_strands
+            .then(|| regex_motif.combine_strands_offset())
+            .transpose()?;
+        let motif_base = regex_motif
+            .raw_motif
+            .chars()
+            .nth(regex_motif.forward_offset)
+            .ok_or(anyhow!("motif offset is out of bounds"))
+            .and_then(DnaBase::parse)
+            .context("motif base must be one of A, C, G, or T")?;
+
+        // check the output before doing any work
+        let mut writer: Box<dyn OutWriter<ReadMatrix>> = if self.sparse {
+            match self.out_path.as_str() {
+                "stdout" | "-" => {
+                    bail!("cannot write a sparse matrix to stdout")
+                }
+                _ => Box::new(SparseMatrixWriter::new(
+                    Path::new(&self.out_path),
+                    self.force,
+                )?),
+            }
+        } else {
+            match self.out_path.as_str() {
+                "stdout" | "-" => {
+                    let writer = BufWriter::new(std::io::stdout());
+                    Box::new(DenseMatrixWriter::new(writer))
+                }
+                _ => {
+                    if Path::new(&self.out_path).exists() && !self.force {
+                        bail!(
+                            "refusing to write over existing file {}",
+                            self.out_path
+                        )
+                    }
+                    create_out_directory(&self.out_path)?;
+                    let fh = std::fs::File::create(&self.out_path)
+                        .context("failed to make output file")?;
+                    Box::new(DenseMatrixWriter::new(BufWriter::new(fh)))
+                }
+            }
+        };
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .with_context(|| "failed to make threadpool")?;
+        let master_progress = MultiProgress::new();
+        if self.suppress_progress {
+            master_progress
+                .set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+
+        let names_to_tid = HashMap::from([(region.name.as_str(), tid)]);
+        let motif_locations = pool.install(|| {
+            MotifLocations::from_fasta(
+                &self.reference_fasta,
+                regex_motif,
+                &names_to_tid,
+                self.mask,
+                None,
+                &master_progress,
+            )
+        })?;
+        if !motif_locations.references_with_hits().contains(&tid) {
+            bail!("no motif hits on {}", region.name)
+        }
+        let sites = MatrixSites::new(
+            motif_locations.get_locations_unchecked(tid),
+            region.start,
+            region.end,
+            combine_strands_offset,
+        );
+        info!("found {} sites in {}", sites.sites.len(), &self.region);
+
+        let caller = match self.values {
+            MatrixValues::probability => {
+                MultipleThresholdModCaller::new_passthrough()
+            }
+            MatrixValues::call => self.mod_caller_args.build_caller(
+                std::slice::from_ref(&self.in_bam),
+                &pool,
+                Some(&region),
+                edge_filter.as_ref(),
+                collapse_method.as_ref(),
+                record_filter.as_ref(),
+                self.suppress_progress,
+            )?,
+        };
+
+        let params = MatrixParams {
+            motif_base,
+            values: self.values,
+            mod_code,
+            min_sites: self.min_sites,
+            caller,
+            collapse_method,
+            edge_filter,
+            record_filter,
+            force_allow: self.mod_caller_args.force_allow_implicit,
+        };
+        let read_matrix = read_matrix_for_region(
+            &self.in_bam,
+            tid,
+            region.start,
+            region.end,
+            sites,
+            &params,
+        )?;
+        let n_sites = read_matrix.sites.len();
+        let n_reads = writer.write(read_matrix)?;
+        info!("Done, wrote matrix of {n_reads} reads by {n_sites} sites.");
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result as AnyhowResult};
+
+use crate::read_matrix::{MatrixRead, MatrixSite, ReadMatrix};
+use crate::writers::OutWriter;
+
+fn site_strand(site: &MatrixSite) -> char {
+    site.strand.map(|s| s.to_char()).unwrap_or('.')
+}
+
+fn read_metadata(read: &MatrixRead) -> String {
+    format!(
+        "{}\t{}\t{}\t{}\t{}",
+        read.read_id,
+        read.strand.to_char(),
+        read.haplotype.as_deref().unwrap_or("."),
+        read.ref_start,
+        read.ref_end
+    )
+}
+
+const READ_METADATA_HEADER: &str =
+    "read_id\tstrand\thaplotype\tref_start\tref_end";
+
+/// Writes the matrix as a table with one row per read, the read metadata
+/// columns followed by one column per site. Sites without a call are ".".
+pub(crate) struct DenseMatrixWriter<T: Write> {
+    buf_writer: BufWriter<T>,
+}
+
+impl<T: Write> DenseMatrixWriter<T> {
+    pub(crate) fn new(buf_writer: BufWriter<T>) -> Self {
+        Self { buf_writer }
+    }
+}
+
+impl<T: Write> OutWriter<ReadMatrix> for DenseMatrixWriter<T> {
+    fn write(&mut self, item: ReadMatrix) -> AnyhowResult<u64> {
+        let mut header = READ_METADATA_HEADER.to_string();
+        for site in item.sites.iter() {
+            header.push_str(&format!(
+                "\t{}:{}:{}",
+                item.chrom_name,
+                site.position,
+                site_strand(site)
+            ));
+        }
+        header.push('\n');
+        self.buf_writer.write_all(header.as_bytes())?;
+
+        let mut rows_written = 0u64;
+        for read in item.reads.iter() {
+            let mut cells = vec![".".to_string(); item.sites.len()];
+            for (idx, value) in read.values.iter() {
+                cells[*idx] = format!("{value}");
+            }
+            let row =
+                format!("{}\t{}\n", read_metadata(read), cells.join("\t"));
+            self.buf_writer.write_all(row.as_bytes())?;
+            rows_written += 1;
+        }
+        self.buf_writer.flush()?;
+        Ok(rows_written)
+    }
+}
+
+/// Writes the matrix in Matrix Market coordinate format (matrix.mtx) with
+/// the row and column labels in reads.tsv and sites.tsv, all in a single
+/// directory. Indices in matrix.mtx are 1-based and only sites with a call
+/// are written (so canonical calls, 0, are explicit entries).
+pub(crate) struct SparseMatrixWriter {
+    out_dir: PathBuf,
+}
+
+impl SparseMatrixWriter {
+    pub(crate) fn new(out_dir: &Path, force: bool) -> AnyhowResult<Self> {
+        let out_files = ["matrix.mtx", "reads.tsv", "sites.tsv"];
+        if out_dir.exists() {
+            if !out_dir.is_dir() {
+                bail!("{out_dir:?} exists and is not a directory")
+            }
+            if !force && out_files.iter().any(|f| out_dir.join(f).exists()) {
+                bail!("refusing to write over existing files in {out_dir:?}")
+            }
+        } else {
+            std::fs::create_dir_all(out_dir).with_context(|| {
+                format!("failed to make output directory {out_dir:?}")
+            })?;
+        }
+        Ok(Self {
+            out_dir: out_dir.to_path_buf(),
+        })
+    }
+
+    fn create(&self, name: &str) -> AnyhowResult<BufWriter<File>> {
+        let fp = self.out_dir.join(name);
+        File::create(&fp)
+            .map(BufWriter::new)
+            .with_context(|| format!("failed to make output file {fp:?}"))
+    }
+}
+
+impl OutWriter<ReadMatrix> for SparseMatrixWriter {
+    fn write(&mut self, item: ReadMatrix) -> AnyhowResult<u64> {
+        let mut sites_writer = self.create("sites.tsv")?;
+        sites_writer.write_all(b"chrom\tposition\tstrand\n")?;
+        for site in item.sites.iter() {
+            let row = format!(
+                "{}\t{}\t{}\n",
+                item.chrom_name,
+                site.position,
+                site_strand(site)
+            );
+            sites_writer.write_all(row.as_bytes())?;
+        }
+        sites_writer.flush()?;
+
+        let mut reads_writer = self.create("reads.tsv")?;
+        reads_writer
+            .write_all(format!("{READ_METADATA_HEADER}\n").as_bytes())?;
+        for read in item.reads.iter() {
+            reads_writer
+                .write_all(format!("{}\n", read_metadata(read)).as_bytes())?;
+        }
+        reads_writer.flush()?;
+
+        let mut matrix_writer = self.create("matrix.mtx")?;
+        let n_entries = item
+            .reads
+            .iter()
+            .map(|read| read.values.len())
+            .sum::<usize>();
+        matrix_writer.write_all(
+            format!(
+                "%%MatrixMarket matrix coordinate real general\n{} {} \
+                 {n_entries}\n",
+                item.reads.len(),
+                item.sites.len()
+            )
+            .as_bytes(),
+        )?;
+        for (row_idx, read) in item.reads.iter().enumerate() {
+            for (col_idx, value) in read.values.iter() {
+                let entry =
+                    format!("{} {} {value}\n", row_idx + 1, col_idx + 1);
+                matrix_writer.write_all(entry.as_bytes())?;
+            }
+        }
+        matrix_writer.flush()?;
+
+        Ok(item.reads.len() as u64)
+    }
+}
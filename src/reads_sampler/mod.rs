@@ -6,10 +6,11 @@ use crate::mod_bam::{CollapseMethod, EdgeFilter};
 use crate::monoid::Moniod;
 use crate::position_filter::StrandedPositionFilter;
 use crate::reads_sampler::sampling_schedule::SamplingSchedule;
+use crate::record_filter::RecordSelection;
 use crate::record_processor::{RecordProcessor, WithRecords};
 use crate::util::{
     get_master_progress_bar, get_subroutine_progress_bar, get_targets,
-    get_ticker, ReferenceRecord, Region,
+    get_ticker, ReadTag, ReferenceRecord, Region,
 };
 use anyhow::anyhow;
 use indicatif::{MultiProgress, ParallelProgressIterator};
@@ -30,12 +31,13 @@ pub(crate) fn get_sampled_read_ids_to_base_mod_probs<P: RecordProcessor>(
     collapse_method: Option<&CollapseMethod>,
     edge_filter: Option<&EdgeFilter>,
     position_filter: Option<&StrandedPositionFilter<()>>,
-    only_mapped: bool,
+    record_selection: RecordSelection,
     suppress_progress: bool,
 ) -> anyhow::Result<P::Output>
 where
     P::Output: Moniod + WithRecords,
 {
+    let only_mapped = record_selection.only_mapped;
     let use_regions = bam::IndexedReader::from_path(&bam_fp).is_ok();
     if use_regions {
         debug!("found BAM index, sampling reads in {interval_size} base pair chunks");
@@ -71,7 +73,7 @@ where
                 collapse_method,
                 position_filter,
                 &schedule,
-                only_mapped,
+                record_selection,
                 suppress_progress,
             )?;
         let should_sample_unmapped =
@@ -100,8 +102,9 @@ where
                 collapse_method,
                 edge_filter,
                 position_filter,
-                only_mapped,
+                record_selection,
                 None,
+                &[],
             )?;
             debug!(
                 "sampled {} unmapped records",
@@ -132,8 +135,9 @@ where
             collapse_method,
             edge_filter,
             position_filter,
-            only_mapped,
+            record_selection,
             None,
+            &[],
         )?;
         debug!("sampled {} records", read_ids_to_base_mod_probs.len());
         Ok(read_ids_to_base_mod_probs)
@@ -150,7 +154,7 @@ fn sample_reads_base_mod_calls_over_regions<P: RecordProcessor>(
     collapse_method: Option<&CollapseMethod>,
     position_filter: Option<&StrandedPositionFilter<()>>,
     sampling_schedule: &SamplingSchedule,
-    only_mapped: bool,
+    record_selection: RecordSelection,
     suppress_progress: bool,
 ) -> anyhow::Result<P::Output>
 where
@@ -232,8 +236,9 @@ where
                     collapse_method,
                     edge_filter,
                     position_filter,
-                    only_mapped,
+                    record_selection,
                     None,
+                    &[],
                 ) {
                     Ok(res) => {
                         let sampled_count = res.size();
@@ -271,8 +276,9 @@ pub(crate) fn sample_reads_from_interval<P: RecordProcessor>(
     collapse_method: Option<&CollapseMethod>,
     edge_filter: Option<&EdgeFilter>,
     position_filter: Option<&StrandedPositionFilter<()>>,
-    only_mapped: bool,
+    record_selection: RecordSelection,
     kmer_size: Option<usize>,
+    tags: &[ReadTag],
 ) -> anyhow::Result<P::Output>
 where
     P::Output: Moniod,
@@ -291,7 +297,8 @@ where
         collapse_method,
         edge_filter,
         position_filter,
-        only_mapped,
+        record_selection,
         kmer_size,
+        tags,
     )
 }
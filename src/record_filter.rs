@@ -0,0 +1,368 @@
+use anyhow::{anyhow, bail};
+use clap::Args;
+use rust_htslib::bam::{self, record::Aux, record::Cigar};
+
+use crate::util::get_query_name_string;
+
+const SAM_FLAG_NAMES: [(&str, u16); 12] = [
+    ("PAIRED", 0x1),
+    ("PROPER_PAIR", 0x2),
+    ("UNMAP", 0x4),
+    ("MUNMAP", 0x8),
+    ("REVERSE", 0x10),
+    ("MREVERSE", 0x20),
+    ("READ1", 0x40),
+    ("READ2", 0x80),
+    ("SECONDARY", 0x100),
+    ("QCFAIL", 0x200),
+    ("DUP", 0x400),
+    ("SUPPLEMENTARY", 0x800),
+];
+
+/// Parse a SAM flag mask given as a decimal integer, a hexadecimal integer
+/// prefixed with `0x`, or a comma-separated list of flag names (e.g.
+/// `DUP,QCFAIL,SUPPLEMENTARY`) like `samtools view`.
+pub(crate) fn parse_flag_mask(raw: &str) -> anyhow::Result<u16> {
+    let raw = raw.trim();
+    if let Some(hex) = raw.strip_prefix("0x").or_else(|| raw.strip_prefix("0X"))
+    {
+        return u16::from_str_radix(hex, 16)
+            .map_err(|e| anyhow!("invalid hexadecimal flag mask {raw}, {e}"));
+    }
+    if raw.chars().all(|c| c.is_ascii_digit()) {
+        return raw
+            .parse::<u16>()
+            .map_err(|e| anyhow!("invalid flag mask {raw}, {e}"));
+    }
+    raw.split(',').try_fold(0u16, |mask, name| {
+        let name = name.trim().to_ascii_uppercase();
+        SAM_FLAG_NAMES
+            .iter()
+            .find(|(flag_name, _)| *flag_name == name)
+            .map(|(_, bit)| mask | bit)
+            .ok_or_else(|| {
+                anyhow!(
+                    "unknown SAM flag {name}, should be one of {}",
+                    SAM_FLAG_NAMES
+                        .iter()
+                        .map(|(n, _)| *n)
+                        .collect::<Vec<&str>>()
+                        .join(",")
+                )
+            })
+    })
+}
+
+/// Fraction of alignment columns (matches, mismatches, insertions, and
+/// deletions) that are identical to the reference, calculated from the NM
+/// tag. Returns `None` for records without an NM tag or alignment.
+pub(crate) fn alignment_identity(record: &bam::Record) -> Option<f32> {
+    let edit_distance = match record.aux(b"NM").ok()? {
+        Aux::U8(x) => x as u32,
+        Aux::U16(x) => x as u32,
+        Aux::U32(x) => x,
+        Aux::I8(x) => x.max(0) as u32,
+        Aux::I16(x) => x.max(0) as u32,
+        Aux::I32(x) => x.max(0) as u32,
+        _ => return None,
+    };
+    let alignment_columns = record
+        .cigar()
+        .iter()
+        .map(|op| match op {
+            Cigar::Match(l)
+            | Cigar::Equal(l)
+            | Cigar::Diff(l)
+            | Cigar::Ins(l)
+            | Cigar::Del(l) => *l,
+            _ => 0,
+        })
+        .sum::<u32>();
+    if alignment_columns == 0 {
+        None
+    } else {
+        let identity = 1f32 - (edit_distance as f32 / alignment_columns as f32);
+        Some(identity.max(0f32))
+    }
+}
+
+/// Record-level filters applied before any base modification calls are
+/// used. Secondary and duplicate-marked records are always removed (see
+/// [`crate::util::record_is_secondary`]), these filters are in addition to
+/// that.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecordFilter {
+    min_mapq: Option<u8>,
+    include_flags: u16,
+    exclude_flags: u16,
+    min_read_length: Option<usize>,
+    max_read_length: Option<usize>,
+    min_identity: Option<f32>,
+}
+
+impl RecordFilter {
+    pub fn new(
+        min_mapq: Option<u8>,
+        include_flags: u16,
+        exclude_flags: u16,
+        min_read_length: Option<usize>,
+        max_read_length: Option<usize>,
+        min_identity: Option<f32>,
+    ) -> anyhow::Result<Self> {
+        if include_flags & exclude_flags != 0 {
+            bail!(
+                "include flags ({include_flags:#x}) and exclude flags \
+                 ({exclude_flags:#x}) cannot share bits"
+            )
+        }
+        if let (Some(min), Some(max)) = (min_read_length, max_read_length) {
+            if min > max {
+                bail!(
+                    "minimum read length ({min}) must be less than or equal \
+                     to the maximum read length ({max})"
+                )
+            }
+        }
+        if let Some(identity) = min_identity {
+            if !(0f32..=1f32).contains(&identity) {
+                bail!("minimum identity must be between 0 and 1")
+            }
+        }
+        Ok(Self {
+            min_mapq,
+            include_flags,
+            exclude_flags,
+            min_read_length,
+            max_read_length,
+            min_identity,
+        })
+    }
+
+    /// Returns true when the record passes all of the filters.
+    pub fn keep(&self, record: &bam::Record) -> bool {
+        let flags = record.flags();
+        let keep = flags & self.include_flags == self.include_flags
+            && flags & self.exclude_flags == 0
+            && self
+                .min_mapq
+                .map(|x| !record.is_unmapped() && record.mapq() >= x)
+                .unwrap_or(true)
+            && self
+                .min_read_length
+                .map(|x| record.seq_len() >= x)
+                .unwrap_or(true)
+            && self
+                .max_read_length
+                .map(|x| record.seq_len() <= x)
+                .unwrap_or(true)
+            && self
+                .min_identity
+                .map(|x| {
+                    alignment_identity(record)
+                        .map(|identity| identity >= x)
+                        .unwrap_or(false)
+                })
+                .unwrap_or(true);
+        if !keep {
+            log::trace!(
+                "record {} failed record filters",
+                get_query_name_string(record)
+                    .unwrap_or("'UTF-8 decode failure'".to_string())
+            );
+        }
+        keep
+    }
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct RecordFilterArgs {
+    /// Only use records with a mapping quality greater than or equal to this
+    /// value. Unmapped records are removed when this option is used.
+    #[arg(long, hide_short_help = true)]
+    min_mapq: Option<u8>,
+    /// Only use records with all of these SAM flag bits set. Can be an
+    /// integer (decimal or 0x-prefixed hex) or a comma-separated list of flag
+    /// names, e.g. PAIRED,READ1.
+    #[arg(long, value_parser = parse_flag_mask, hide_short_help = true)]
+    include_flags: Option<u16>,
+    /// Remove records with any of these SAM flag bits set. Can be an integer
+    /// (decimal or 0x-prefixed hex) or a comma-separated list of flag names,
+    /// e.g. QCFAIL,SUPPLEMENTARY. Secondary and duplicate records are always
+    /// removed.
+    #[arg(long, value_parser = parse_flag_mask, hide_short_help = true)]
+    exclude_flags: Option<u16>,
+    /// Only use records with a sequence length (SEQ, so not including
+    /// hard-clipped bases) greater than or equal to this value.
+    #[arg(long, hide_short_help = true)]
+    min_read_length: Option<usize>,
+    /// Only use records with a sequence length less than or equal to this
+    /// value.
+    #[arg(long, hide_short_help = true)]
+    max_read_length: Option<usize>,
+    /// Only use records with an alignment identity, 1 - NM / (matches +
+    /// mismatches + insertions + deletions), greater than or equal to this
+    /// value. Records without an NM tag (including unmapped records) are
+    /// removed when this option is used.
+    #[arg(long, hide_short_help = true)]
+    min_identity: Option<f32>,
+}
+
+impl RecordFilterArgs {
+    /// Returns `None` when no record filters have been requested.
+    pub fn to_record_filter(&self) -> anyhow::Result<Option<RecordFilter>> {
+        let filter = RecordFilter::new(
+            self.min_mapq,
+            self.include_flags.unwrap_or(0),
+            self.exclude_flags.unwrap_or(0),
+            self.min_read_length,
+            self.max_read_length,
+            self.min_identity,
+        )?;
+        if filter == RecordFilter::default() {
+            Ok(None)
+        } else {
+            Ok(Some(filter))
+        }
+    }
+}
+
+/// The records used when sampling reads, unmapped records are skipped when
+/// `only_mapped` is set and the `record_filter` (if any) is applied to the
+/// rest.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RecordSelection<'a> {
+    pub only_mapped: bool,
+    pub record_filter: Option<&'a RecordFilter>,
+}
+
+impl<'a> RecordSelection<'a> {
+    pub fn new(
+        only_mapped: bool,
+        record_filter: Option<&'a RecordFilter>,
+    ) -> Self {
+        Self {
+            only_mapped,
+            record_filter,
+        }
+    }
+}
+
+#[cfg(test)]
+mod record_filter_tests {
+    use rust_htslib::bam::{
+        self,
+        record::{Aux, Cigar, CigarString},
+    };
+
+    use crate::record_filter::{
+        alignment_identity, parse_flag_mask, RecordFilter,
+    };
+
+    fn make_record(
+        mapq: u8,
+        flags: u16,
+        cigar: Vec<Cigar>,
+        nm: Option<u8>,
+    ) -> bam::Record {
+        let seq_len = cigar
+            .iter()
+            .map(|op| match op {
+                Cigar::Match(l) | Cigar::Ins(l) | Cigar::SoftClip(l) => *l,
+                _ => 0,
+            })
+            .sum::<u32>() as usize;
+        let seq = vec![b'A'; seq_len];
+        let quals = vec![30u8; seq_len];
+        let mut record = bam::Record::new();
+        record.set(b"read", Some(&CigarString(cigar)), &seq, &quals);
+        record.set_mapq(mapq);
+        record.set_flags(flags);
+        if let Some(nm) = nm {
+            record.push_aux(b"NM", Aux::U8(nm)).unwrap();
+        }
+        record
+    }
+
+    #[test]
+    fn test_parse_flag_mask() {
+        assert_eq!(parse_flag_mask("1024").unwrap(), 0x400);
+        assert_eq!(parse_flag_mask("0xC00").unwrap(), 0xC00);
+        assert_eq!(parse_flag_mask("dup,QCFAIL").unwrap(), 0x600);
+        assert_eq!(parse_flag_mask("DUP,QCFAIL,SUPPLEMENTARY").unwrap(), 0xE00);
+        assert!(parse_flag_mask("DUPLICATE").is_err());
+        assert!(parse_flag_mask("0xZZ").is_err());
+        assert!(parse_flag_mask("70000").is_err());
+    }
+
+    #[test]
+    fn test_alignment_identity() {
+        let record = make_record(
+            60,
+            0,
+            vec![Cigar::Match(90), Cigar::Ins(5), Cigar::Del(5)],
+            Some(15),
+        );
+        assert_eq!(alignment_identity(&record), Some(0.85));
+        let record = make_record(60, 0, vec![Cigar::Match(100)], None);
+        assert_eq!(alignment_identity(&record), None);
+    }
+
+    #[test]
+    fn test_record_filter_keep() {
+        let record = make_record(
+            20,
+            0x800,
+            vec![Cigar::SoftClip(10), Cigar::Match(100)],
+            Some(10),
+        );
+        let filter = RecordFilter::default();
+        assert!(filter.keep(&record));
+
+        let filter =
+            RecordFilter::new(Some(30), 0, 0, None, None, None).unwrap();
+        assert!(!filter.keep(&record));
+        let filter =
+            RecordFilter::new(Some(20), 0, 0, None, None, None).unwrap();
+        assert!(filter.keep(&record));
+
+        let filter =
+            RecordFilter::new(None, 0, 0x800, None, None, None).unwrap();
+        assert!(!filter.keep(&record));
+        let filter =
+            RecordFilter::new(None, 0x800, 0x400, None, None, None).unwrap();
+        assert!(filter.keep(&record));
+        let filter =
+            RecordFilter::new(None, 0x801, 0, None, None, None).unwrap();
+        assert!(!filter.keep(&record));
+
+        let filter =
+            RecordFilter::new(None, 0, 0, Some(110), Some(110), None).unwrap();
+        assert!(filter.keep(&record));
+        let filter =
+            RecordFilter::new(None, 0, 0, Some(111), None, None).unwrap();
+        assert!(!filter.keep(&record));
+        let filter =
+            RecordFilter::new(None, 0, 0, None, Some(109), None).unwrap();
+        assert!(!filter.keep(&record));
+
+        let filter =
+            RecordFilter::new(None, 0, 0, None, None, Some(0.9)).unwrap();
+        assert!(filter.keep(&record));
+        let filter =
+            RecordFilter::new(None, 0, 0, None, None, Some(0.95)).unwrap();
+        assert!(!filter.keep(&record));
+        let no_nm = make_record(20, 0, vec![Cigar::Match(100)], None);
+        assert!(!filter.keep(&no_nm));
+    }
+
+    #[test]
+    fn test_record_filter_validation() {
+        assert!(
+            RecordFilter::new(None, 0x400, 0x400, None, None, None).is_err()
+        );
+        assert!(
+            RecordFilter::new(None, 0, 0, Some(100), Some(10), None).is_err()
+        );
+        assert!(RecordFilter::new(None, 0, 0, None, None, Some(1.5)).is_err());
+    }
+}
@@ -2,6 +2,8 @@ use crate::mod_bam::{CollapseMethod, EdgeFilter};
 use crate::monoid::Moniod;
 use crate::position_filter::StrandedPositionFilter;
 use crate::reads_sampler::record_sampler::RecordSampler;
+use crate::record_filter::RecordSelection;
+use crate::util::ReadTag;
 use rust_htslib::bam;
 
 pub(crate) trait RecordProcessor {
@@ -14,8 +16,9 @@ pub(crate) trait RecordProcessor {
         collapse_method: Option<&CollapseMethod>,
         edge_filter: Option<&EdgeFilter>,
         position_filter: Option<&StrandedPositionFilter<()>>,
-        only_mapped: bool,
+        record_selection: RecordSelection,
         kmer_size: Option<usize>,
+        tags: &[ReadTag],
     ) -> anyhow::Result<Self::Output>;
 }
 
@@ -13,6 +13,7 @@ use crate::monoid::Moniod;
 use crate::position_filter::StrandedPositionFilter;
 use crate::read_ids_to_base_mod_probs::ReadIdsToBaseModProbs;
 use crate::reads_sampler::get_sampled_read_ids_to_base_mod_probs;
+use crate::record_filter::RecordSelection;
 use crate::record_processor::WithRecords;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
 
@@ -69,7 +70,7 @@ pub fn summarize_modbam<'a>(
     collapse_method: Option<&CollapseMethod>,
     edge_filter: Option<&EdgeFilter>,
     position_filter: Option<&StrandedPositionFilter<()>>,
-    only_mapped: bool,
+    record_selection: RecordSelection,
     suppress_progress: bool,
 ) -> anyhow::Result<ModSummary<'a>> {
     let read_ids_to_base_mod_calls =
@@ -84,7 +85,7 @@ pub fn summarize_modbam<'a>(
             collapse_method,
             edge_filter,
             position_filter,
-            only_mapped,
+            record_selection,
             suppress_progress,
         )?;
 
@@ -4,7 +4,7 @@ use derive_new::new;
 use rustc_hash::FxHashMap;
 use std::collections::HashMap;
 
-#[derive(new)]
+#[derive(new, Clone)]
 pub struct MultipleThresholdModCaller {
     per_base_thresholds: HashMap<DnaBase, f32>,
     // todo maybe allow this per primary base?
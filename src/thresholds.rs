@@ -11,6 +11,7 @@ use crate::mod_base_code::{DnaBase, ModCodeRepr};
 use crate::position_filter::StrandedPositionFilter;
 use crate::read_ids_to_base_mod_probs::ReadIdsToBaseModProbs;
 use crate::reads_sampler::get_sampled_read_ids_to_base_mod_probs;
+use crate::record_filter::RecordSelection;
 use crate::threshold_mod_caller::MultipleThresholdModCaller;
 use crate::util::Region;
 
@@ -113,8 +114,11 @@ pub(crate) fn calc_thresholds_per_base(
     ))
 }
 
-pub fn calc_threshold_from_bam(
-    bam_fp: &PathBuf,
+/// Estimate a single threshold per canonical base from the pooled base
+/// modification probabilities of all of the BAMs, so that each sample is
+/// filtered the same way. `num_reads` is split evenly between the BAMs.
+pub fn calc_threshold_from_bams(
+    bam_fps: &[PathBuf],
     threads: usize,
     interval_size: u32,
     sample_frac: Option<f64>,
@@ -125,23 +129,41 @@ pub fn calc_threshold_from_bam(
     edge_filter: Option<&EdgeFilter>,
     collapse_method: Option<&CollapseMethod>,
     position_filter: Option<&StrandedPositionFilter<()>>,
-    only_mapped: bool,
+    record_selection: RecordSelection,
     suppress_progress: bool,
 ) -> AnyhowResult<HashMap<DnaBase, f32>> {
-    let mut can_base_probs = get_modbase_probs_from_bam(
-        bam_fp,
-        threads,
-        interval_size,
-        sample_frac,
-        num_reads,
-        seed,
-        region,
-        collapse_method,
-        edge_filter,
-        position_filter,
-        only_mapped,
-        suppress_progress,
-    )?;
+    let num_reads = num_reads
+        .map(|n| std::cmp::max(n / std::cmp::max(bam_fps.len(), 1), 1));
+    let mut can_base_probs = HashMap::<DnaBase, Vec<f32>>::new();
+    for bam_fp in bam_fps {
+        let probs = get_modbase_probs_from_bam(
+            bam_fp,
+            threads,
+            interval_size,
+            sample_frac,
+            num_reads,
+            seed,
+            region,
+            collapse_method,
+            edge_filter,
+            position_filter,
+            record_selection,
+            suppress_progress,
+        )?;
+        for (dna_base, mod_base_probs) in probs {
+            can_base_probs
+                .entry(dna_base)
+                .or_default()
+                .extend(mod_base_probs);
+        }
+    }
+    thresholds_from_probs(&mut can_base_probs, filter_percentile)
+}
+
+fn thresholds_from_probs(
+    can_base_probs: &mut HashMap<DnaBase, Vec<f32>>,
+    filter_percentile: f32,
+) -> AnyhowResult<HashMap<DnaBase, f32>> {
     can_base_probs
         .iter_mut()
         .map(|(dna_base, mod_base_probs)| {
@@ -164,7 +186,7 @@ pub fn get_modbase_probs_from_bam(
     collapse_method: Option<&CollapseMethod>,
     edge_filter: Option<&EdgeFilter>,
     position_filter: Option<&StrandedPositionFilter<()>>,
-    only_mapped: bool,
+    record_selection: RecordSelection,
     suppress_progress: bool,
 ) -> AnyhowResult<HashMap<DnaBase, Vec<f32>>> {
     get_sampled_read_ids_to_base_mod_probs::<ReadIdsToBaseModProbs>(
@@ -178,7 +200,7 @@ pub fn get_modbase_probs_from_bam(
         collapse_method,
         edge_filter,
         position_filter,
-        only_mapped,
+        record_selection,
         suppress_progress,
     )
     .map(|x| x.mle_probs_per_base())
@@ -0,0 +1,404 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context};
+use flate2::read::MultiGzDecoder;
+use log::{debug, info};
+
+use crate::util::{ReferenceRecord, Strand};
+
+/// The exons of a single transcript in genome coordinates, used to project
+/// positions on the transcript (as in a transcriptome alignment) to the
+/// genome.
+#[derive(Debug, Clone)]
+pub(crate) struct TranscriptModel {
+    pub(crate) transcript_id: String,
+    pub(crate) gene_id: String,
+    pub(crate) chrom: String,
+    pub(crate) strand: Strand,
+    /// 0-based, half-open genomic intervals sorted by start.
+    exons: Vec<(u64, u64)>,
+    length: u64,
+}
+
+impl TranscriptModel {
+    fn new(
+        transcript_id: String,
+        gene_id: String,
+        chrom: String,
+        strand: Strand,
+        mut exons: Vec<(u64, u64)>,
+    ) -> anyhow::Result<Self> {
+        exons.sort();
+        if exons.windows(2).any(|w| w[0].1 > w[1].0) {
+            bail!("transcript {transcript_id} has overlapping exons")
+        }
+        let length = exons.iter().map(|(start, end)| end - start).sum();
+        Ok(Self {
+            transcript_id,
+            gene_id,
+            chrom,
+            strand,
+            exons,
+            length,
+        })
+    }
+
+    /// Genomic position of the 0-based position along the (5' to 3')
+    /// transcript sequence, `None` if the position is past the end of the
+    /// transcript.
+    pub(crate) fn project(&self, transcript_pos: u64) -> Option<u64> {
+        if transcript_pos >= self.length {
+            return None;
+        }
+        let mut remaining = transcript_pos;
+        match self.strand {
+            Strand::Positive => {
+                for (start, end) in self.exons.iter() {
+                    let exon_length = end - start;
+                    if remaining < exon_length {
+                        return Some(start + remaining);
+                    }
+                    remaining -= exon_length;
+                }
+            }
+            Strand::Negative => {
+                for (start, end) in self.exons.iter().rev() {
+                    let exon_length = end - start;
+                    if remaining < exon_length {
+                        return Some(end - 1 - remaining);
+                    }
+                    remaining -= exon_length;
+                }
+            }
+        }
+        None
+    }
+
+    /// 0-based genomic start of the first exon.
+    pub(crate) fn start(&self) -> u64 {
+        self.exons.first().map(|(start, _)| *start).unwrap_or(0)
+    }
+
+    /// Genomic strand of a strand on the transcript.
+    pub(crate) fn project_strand(&self, strand: Strand) -> Strand {
+        match self.strand {
+            Strand::Positive => strand,
+            Strand::Negative => strand.opposite(),
+        }
+    }
+}
+
+fn parse_attributes(raw: &str) -> HashMap<&str, &str> {
+    raw.split(';')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .filter_map(|item| {
+            // GTF: key "value", GFF3: key=value
+            match item.split_once(' ') {
+                Some((key, value)) if !key.contains('=') => {
+                    Some((key, value.trim().trim_matches('"')))
+                }
+                _ => item.split_once('='),
+            }
+        })
+        .collect()
+}
+
+/// Ensembl GFF3 files prefix IDs with the feature type.
+fn strip_id_prefix(id: &str) -> &str {
+    id.strip_prefix("transcript:")
+        .or_else(|| id.strip_prefix("gene:"))
+        .unwrap_or(id)
+}
+
+/// Transcript models read from the exons in a GTF or GFF3 file, keyed by
+/// transcript ID.
+pub struct TranscriptProjection {
+    transcripts: HashMap<String, TranscriptModel>,
+}
+
+impl TranscriptProjection {
+    /// Load the exons from a GTF (`gene_id` and `transcript_id` attributes)
+    /// or GFF3 (exon `Parent` is the transcript, the transcript's `Parent`
+    /// is the gene) file, optionally gzip compressed.
+    pub fn from_path(fp: &PathBuf) -> anyhow::Result<Self> {
+        let fh = File::open(fp)
+            .with_context(|| format!("failed to open annotation at {fp:?}"))?;
+        let reader: Box<dyn BufRead> =
+            if fp.extension().map(|ext| ext == "gz").unwrap_or(false) {
+                Box::new(BufReader::new(MultiGzDecoder::new(fh)))
+            } else {
+                Box::new(BufReader::new(fh))
+            };
+        type Exons = (String, Strand, Vec<(u64, u64)>);
+        let mut transcript_exons = HashMap::<String, Exons>::new();
+        let mut transcript_to_gene = HashMap::<String, String>::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.context("failed to read annotation")?;
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let parts = line.split('\t').collect::<Vec<&str>>();
+            if parts.len() < 9 {
+                bail!("line {} of annotation has fewer than 9 columns", i + 1)
+            }
+            let attributes = parse_attributes(parts[8]);
+            let feature = parts[2];
+            if feature != "exon" {
+                // GFF3 transcripts point to their gene
+                if let (Some(id), Some(parent)) =
+                    (attributes.get("ID"), attributes.get("Parent"))
+                {
+                    transcript_to_gene.insert(
+                        strip_id_prefix(id).to_string(),
+                        strip_id_prefix(parent).to_string(),
+                    );
+                }
+                continue;
+            }
+            let strand = match Strand::parse_char(
+                parts[6].chars().next().unwrap_or('.'),
+            ) {
+                Ok(strand) => strand,
+                Err(_) => {
+                    debug!("skipping exon without strand on line {}", i + 1);
+                    continue;
+                }
+            };
+            let start = parts[3].parse::<u64>().map_err(|e| {
+                anyhow!("invalid start on line {} of annotation, {e}", i + 1)
+            })?;
+            let end = parts[4].parse::<u64>().map_err(|e| {
+                anyhow!("invalid end on line {} of annotation, {e}", i + 1)
+            })?;
+            if start == 0 || end < start {
+                bail!("invalid exon coordinates on line {}", i + 1)
+            }
+            // GTF and GFF are 1-based, inclusive
+            let exon = (start - 1, end);
+
+            let transcript_ids = match (
+                attributes.get("transcript_id"),
+                attributes.get("Parent"),
+            ) {
+                (Some(transcript_id), _) => {
+                    if let Some(gene_id) = attributes.get("gene_id") {
+                        transcript_to_gene.insert(
+                            transcript_id.to_string(),
+                            gene_id.to_string(),
+                        );
+                    }
+                    vec![transcript_id.to_string()]
+                }
+                (None, Some(parents)) => parents
+                    .split(',')
+                    .map(|p| strip_id_prefix(p).to_string())
+                    .collect(),
+                (None, None) => {
+                    debug!(
+                        "skipping exon without transcript on line {}",
+                        i + 1
+                    );
+                    continue;
+                }
+            };
+            for transcript_id in transcript_ids {
+                let (chrom, transcript_strand, exons) = transcript_exons
+                    .entry(transcript_id.clone())
+                    .or_insert_with(|| {
+                        (parts[0].to_string(), strand, Vec::new())
+                    });
+                if chrom != parts[0] || *transcript_strand != strand {
+                    bail!(
+                        "transcript {transcript_id} has exons on more than one \
+                         chromosome or strand"
+                    )
+                }
+                exons.push(exon);
+            }
+        }
+
+        let transcripts = transcript_exons
+            .into_iter()
+            .map(|(transcript_id, (chrom, strand, exons))| {
+                let gene_id = transcript_to_gene
+                    .get(&transcript_id)
+                    .cloned()
+                    .unwrap_or_else(|| transcript_id.clone());
+                TranscriptModel::new(
+                    transcript_id.clone(),
+                    gene_id,
+                    chrom,
+                    strand,
+                    exons,
+                )
+                .map(|model| (transcript_id, model))
+            })
+            .collect::<anyhow::Result<HashMap<String, TranscriptModel>>>()?;
+        if transcripts.is_empty() {
+            bail!("did not find any exons in annotation {fp:?}")
+        }
+        info!("loaded {} transcripts from annotation", transcripts.len());
+        Ok(Self { transcripts })
+    }
+
+    /// Look up a transcript by the name of the sequence it was aligned to.
+    /// Transcriptome FASTA headers are often '|'-delimited (e.g. GENCODE)
+    /// so the first field is also tried.
+    pub(crate) fn get(&self, reference_name: &str) -> Option<&TranscriptModel> {
+        self.transcripts.get(reference_name).or_else(|| {
+            reference_name
+                .split_once('|')
+                .and_then(|(transcript_id, _)| {
+                    self.transcripts.get(transcript_id)
+                })
+        })
+    }
+
+    /// Sort the reference sequences (transcripts) by the chrom and genomic
+    /// start of their transcript model, sequences not found in the
+    /// annotation are put at the end.
+    pub(crate) fn sort_by_genomic_start(
+        &self,
+        targets: &mut [ReferenceRecord],
+    ) {
+        targets.sort_by_cached_key(|target| {
+            self.get(&target.name)
+                .map(|transcript| {
+                    (transcript.chrom.clone(), transcript.start())
+                })
+                .ok_or(())
+        });
+    }
+
+    pub(crate) fn check_references<T: AsRef<str>>(
+        &self,
+        reference_names: &[T],
+    ) -> anyhow::Result<()> {
+        let n_found = reference_names
+            .iter()
+            .filter(|name| self.get(name.as_ref()).is_some())
+            .count();
+        if n_found == 0 && !reference_names.is_empty() {
+            bail!(
+                "none of the reference sequences in the BAM header were found \
+                 in the annotation, the BAM should be aligned to transcript \
+                 sequences named by transcript ID"
+            )
+        }
+        info!(
+            "{n_found} of {} reference sequences found in annotation",
+            reference_names.len()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod transcriptome_tests {
+    use std::io::Write;
+
+    use crate::transcriptome::{
+        parse_attributes, TranscriptModel, TranscriptProjection,
+    };
+    use crate::util::Strand;
+
+    #[test]
+    fn test_transcript_projection() {
+        // exons [10, 20) and [30, 35), length 15
+        let positive = TranscriptModel::new(
+            "t1".to_string(),
+            "g1".to_string(),
+            "chr1".to_string(),
+            Strand::Positive,
+            vec![(30, 35), (10, 20)],
+        )
+        .unwrap();
+        assert_eq!(positive.project(0), Some(10));
+        assert_eq!(positive.project(9), Some(19));
+        assert_eq!(positive.project(10), Some(30));
+        assert_eq!(positive.project(14), Some(34));
+        assert_eq!(positive.project(15), None);
+        assert_eq!(positive.project_strand(Strand::Negative), Strand::Negative);
+
+        let negative = TranscriptModel::new(
+            "t2".to_string(),
+            "g1".to_string(),
+            "chr1".to_string(),
+            Strand::Negative,
+            vec![(10, 20), (30, 35)],
+        )
+        .unwrap();
+        assert_eq!(negative.project(0), Some(34));
+        assert_eq!(negative.project(4), Some(30));
+        assert_eq!(negative.project(5), Some(19));
+        assert_eq!(negative.project(14), Some(10));
+        assert_eq!(negative.project(15), None);
+        assert_eq!(negative.project_strand(Strand::Positive), Strand::Negative);
+
+        assert!(TranscriptModel::new(
+            "t3".to_string(),
+            "g1".to_string(),
+            "chr1".to_string(),
+            Strand::Positive,
+            vec![(10, 20), (15, 25)],
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_attributes() {
+        let gtf = parse_attributes(
+            "gene_id \"ENSG1\"; transcript_id \"ENST1.2\"; exon_number 1;",
+        );
+        assert_eq!(gtf.get("gene_id"), Some(&"ENSG1"));
+        assert_eq!(gtf.get("transcript_id"), Some(&"ENST1.2"));
+        assert_eq!(gtf.get("exon_number"), Some(&"1"));
+        let gff = parse_attributes("ID=exon1;Parent=transcript:ENST1,ENST2");
+        assert_eq!(gff.get("ID"), Some(&"exon1"));
+        assert_eq!(gff.get("Parent"), Some(&"transcript:ENST1,ENST2"));
+    }
+
+    #[test]
+    fn test_load_gtf_and_gff() {
+        let gtf_fp = std::env::temp_dir().join("transcriptome_tests.gtf");
+        {
+            let mut fh = std::fs::File::create(&gtf_fp).unwrap();
+            writeln!(fh, "#comment").unwrap();
+            writeln!(fh, "chr1\tsrc\tgene\t11\t35\t.\t-\t.\tgene_id \"g1\";")
+                .unwrap();
+            writeln!(fh, "chr1\tsrc\texon\t11\t20\t.\t-\t.\tgene_id \"g1\"; transcript_id \"t1\";").unwrap();
+            writeln!(fh, "chr1\tsrc\texon\t31\t35\t.\t-\t.\tgene_id \"g1\"; transcript_id \"t1\";").unwrap();
+        }
+        let projection = TranscriptProjection::from_path(&gtf_fp).unwrap();
+        let model = projection.get("t1|g1|other").unwrap();
+        assert_eq!(model.gene_id, "g1");
+        assert_eq!(model.project(0), Some(34));
+
+        let gff_fp = std::env::temp_dir().join("transcriptome_tests.gff3");
+        {
+            let mut fh = std::fs::File::create(&gff_fp).unwrap();
+            writeln!(fh, "##gff-version 3").unwrap();
+            writeln!(fh, "chr1\tsrc\tgene\t11\t35\t.\t+\t.\tID=gene:g1")
+                .unwrap();
+            writeln!(fh, "chr1\tsrc\tmRNA\t11\t35\t.\t+\t.\tID=transcript:t1;Parent=gene:g1").unwrap();
+            writeln!(fh, "chr1\tsrc\tmRNA\t11\t35\t.\t+\t.\tID=transcript:t2;Parent=gene:g1").unwrap();
+            writeln!(fh, "chr1\tsrc\texon\t11\t20\t.\t+\t.\tParent=transcript:t1,transcript:t2").unwrap();
+            writeln!(
+                fh,
+                "chr1\tsrc\texon\t31\t35\t.\t+\t.\tParent=transcript:t1"
+            )
+            .unwrap();
+        }
+        let projection = TranscriptProjection::from_path(&gff_fp).unwrap();
+        let t1 = projection.get("t1").unwrap();
+        assert_eq!(t1.gene_id, "g1");
+        assert_eq!(t1.project(10), Some(30));
+        let t2 = projection.get("t2").unwrap();
+        assert_eq!(t2.project(10), None);
+        assert!(projection.get("t3").is_none());
+    }
+}
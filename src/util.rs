@@ -217,6 +217,14 @@ impl StrandRule {
             Self::Both
         }
     }
+
+    pub fn strands(&self) -> Vec<Strand> {
+        match self {
+            StrandRule::Positive => vec![Strand::Positive],
+            StrandRule::Negative => vec![Strand::Negative],
+            StrandRule::Both => vec![Strand::Positive, Strand::Negative],
+        }
+    }
 }
 
 impl From<Strand> for StrandRule {
@@ -517,6 +525,12 @@ pub struct SamTag {
     inner: [u8; 2],
 }
 
+impl Display for SamTag {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.inner[0] as char, self.inner[1] as char)
+    }
+}
+
 #[cfg(test)]
 impl SamTag {
     pub(crate) fn parse(chars: [char; 2]) -> Self {
@@ -546,19 +560,75 @@ pub(crate) fn get_stringable_aux(
     })
 }
 
+/// A value of a read to write as a column, either an aux tag or one of the
+/// MAPQ and FLAG fields of the record (passed as the pseudo-tags "MAPQ" and
+/// "FLAG").
+#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
+pub(crate) enum ReadTag {
+    Aux(SamTag),
+    Mapq,
+    Flag,
+}
+
+impl Display for ReadTag {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Aux(tag) => write!(f, "{tag}"),
+            Self::Mapq => write!(f, "MAPQ"),
+            Self::Flag => write!(f, "FLAG"),
+        }
+    }
+}
+
+impl ReadTag {
+    pub(crate) fn value(&self, record: &bam::Record) -> Option<String> {
+        match self {
+            Self::Aux(tag) => get_stringable_aux(record, tag),
+            Self::Mapq => Some(format!("{}", record.mapq())),
+            Self::Flag => Some(format!("{}", record.flags())),
+        }
+    }
+}
+
+fn parse_sam_tag(raw_tag: &str) -> anyhow::Result<SamTag> {
+    if raw_tag.len() != 2 {
+        bail!("illegal tag {raw_tag} should be length 2")
+    }
+    let raw_tag_parts = raw_tag.chars().collect::<Vec<char>>();
+    assert_eq!(raw_tag_parts.len(), 2);
+    let inner = [raw_tag_parts[0] as u8, raw_tag_parts[1] as u8];
+    Ok(SamTag::new(inner))
+}
+
+/// Parse aux tags and the "MAPQ" and "FLAG" pseudo-tags.
+pub(crate) fn parse_read_tags(
+    raw_tags: &[String],
+) -> anyhow::Result<Vec<ReadTag>> {
+    let mut tags_seen = HashSet::with_capacity(raw_tags.len());
+    let mut tags = Vec::with_capacity(raw_tags.len());
+    for raw_tag in raw_tags {
+        let tag = match raw_tag.as_str() {
+            "MAPQ" => ReadTag::Mapq,
+            "FLAG" => ReadTag::Flag,
+            _ => ReadTag::Aux(parse_sam_tag(raw_tag)?),
+        };
+        if tags_seen.insert(tag) {
+            tags.push(tag);
+        } else {
+            bail!("cannot repeat tags, got {raw_tag} twice")
+        }
+    }
+
+    Ok(tags)
+}
+
 pub(crate) fn parse_partition_tags(
     raw_tags: &[String],
 ) -> anyhow::Result<Vec<SamTag>> {
     let mut tags_seen = HashSet::with_capacity(raw_tags.len());
     let mut tags = Vec::with_capacity(raw_tags.len());
     for raw_tag in raw_tags {
-        if raw_tag.len() != 2 {
-            bail!("illegal tag {raw_tag} should be length 2")
-        }
-        let raw_tag_parts = raw_tag.chars().collect::<Vec<char>>();
-        assert_eq!(raw_tag_parts.len(), 2);
-        let inner = [raw_tag_parts[0] as u8, raw_tag_parts[1] as u8];
-        let tag = SamTag::new(inner);
+        let tag = parse_sam_tag(raw_tag)?;
 
         let inserted = tags_seen.insert(tag);
         if inserted {
@@ -695,7 +765,8 @@ mod utils_tests {
     use rust_htslib::bam::Read;
 
     use crate::util::{
-        get_query_name_string, get_stringable_aux, parse_partition_tags, SamTag,
+        get_query_name_string, get_stringable_aux, parse_partition_tags,
+        parse_read_tags, ReadTag, SamTag,
     };
 
     #[test]
@@ -737,4 +808,25 @@ mod utils_tests {
             vec![SamTag::parse(['H', 'P']), SamTag::parse(['R', 'G'])];
         assert_eq!(parsed, expected);
     }
+
+    #[test]
+    fn test_util_parse_read_tags() {
+        let raw_tags =
+            ["HP".to_string(), "MAPQ".to_string(), "FLAG".to_string()];
+        let parsed = parse_read_tags(&raw_tags).unwrap();
+        let expected = vec![
+            ReadTag::Aux(SamTag::parse(['H', 'P'])),
+            ReadTag::Mapq,
+            ReadTag::Flag,
+        ];
+        assert_eq!(parsed, expected);
+        assert_eq!(
+            parsed.iter().map(|t| t.to_string()).collect::<Vec<_>>(),
+            vec!["HP", "MAPQ", "FLAG"]
+        );
+        assert!(
+            parse_read_tags(&["FLAG".to_string(), "FLAG".to_string()]).is_err()
+        );
+        assert!(parse_read_tags(&["MAP".to_string()]).is_err());
+    }
 }
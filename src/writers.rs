@@ -1,141 +1,1042 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::File;
 use std::io::{BufWriter, Stdout, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use anyhow::{anyhow, Context, Result as AnyhowResult};
+use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
+use arrow::array::{
+    ArrayRef, Float32Builder, Float64Builder, StringDictionaryBuilder,
+    UInt32Builder,
+};
+use arrow::datatypes::{DataType, Field, Int32Type, Schema};
 use derive_new::new;
 use histo_fp::Histogram;
 use itertools::Itertools;
 use log::{debug, info, warn};
+use noodles::csi::index::header::ReferenceSequenceNames;
+use noodles::csi::index::reference_sequence::bin::Chunk as IndexChunk;
+use noodles::{bgzf, csi, tabix};
 use prettytable::format::FormatBuilder;
 use prettytable::{cell, row, Table};
 use rustc_hash::FxHashMap;
 
+use crate::bigwig::{self, BigWigFile};
 use crate::mod_base_code::{BaseState, DnaBase, ModCodeRepr};
+use crate::parquet_writer::{categorical_field, ParquetTableWriter};
 use crate::pileup::duplex::DuplexModBasePileup;
-use crate::pileup::{ModBasePileup, PartitionKey, PileupFeatureCounts};
+use crate::pileup::stats::{strand_imbalance, ConfidenceInterval};
+use crate::pileup::{
+    parse_haplotype_key, Haplotype, ModBasePileup, PartitionKey,
+    PileupFeatureCounts,
+};
 use crate::summarize::ModSummary;
 use crate::thresholds::Percentiles;
+use crate::transcriptome::TranscriptProjection;
+use crate::util::Strand;
 
 pub trait PileupWriter<T> {
     fn write(&mut self, item: T, motif_labels: &[String]) -> AnyhowResult<u64>;
+    /// Called once after all items have been written, for formats that
+    /// need to write an index or trailer.
+    fn finish(&mut self) -> AnyhowResult<()> {
+        Ok(())
+    }
 }
 
 pub trait OutWriter<T> {
     fn write(&mut self, item: T) -> AnyhowResult<u64>;
 }
 
+/// The name column for a feature count, the mod code and the motif when
+/// more than one motif is being used.
+fn feature_count_name(
+    feature_count: &PileupFeatureCounts,
+    motif_labels: &[String],
+) -> String {
+    let raw_code_only = motif_labels.len() < 2;
+    if raw_code_only {
+        format!("{}", feature_count.raw_mod_code)
+    } else {
+        feature_count
+            .motif_idx
+            .and_then(|i| motif_labels.get(i))
+            .map(|label| format!("{},{}", feature_count.raw_mod_code, label))
+            .unwrap_or(format!("{}", feature_count.raw_mod_code))
+    }
+}
+
+/// Expected number of modified and canonical calls, appended to the
+/// bedMethyl columns when the counts are soft counts.
+fn expected_counts_columns(
+    feature_count: &PileupFeatureCounts,
+    space: char,
+) -> String {
+    match (
+        feature_count.expected_modified,
+        feature_count.expected_canonical,
+    ) {
+        (Some(n_mod), n_canonical) => format!(
+            "{space}{n_mod:.2}{space}{:.2}",
+            n_canonical.unwrap_or(0f32)
+        ),
+        _ => String::new(),
+    }
+}
+
+/// Optional statistics columns appended to the bedMethyl rows.
+#[derive(new, Debug, Default, Copy, Clone)]
+pub struct BedMethylStatistics {
+    /// Lower and upper bounds on the percent modified.
+    confidence_interval: Option<ConfidenceInterval>,
+    /// Strand imbalance of the percent modified, see `strand_imbalance`.
+    strand_imbalance: bool,
+}
+
+impl BedMethylStatistics {
+    fn columns(
+        &self,
+        feature_count: &PileupFeatureCounts,
+        space: char,
+    ) -> String {
+        let mut columns = String::new();
+        if let Some(confidence_interval) = self.confidence_interval.as_ref() {
+            match confidence_interval.bounds(
+                feature_count.n_modified,
+                feature_count.filtered_coverage,
+            ) {
+                Some((lower, upper)) => columns.push_str(&format!(
+                    "{space}{:.2}{space}{:.2}",
+                    lower * 100f64,
+                    upper * 100f64
+                )),
+                None => columns.push_str(&format!("{space}.{space}.")),
+            }
+        }
+        if self.strand_imbalance {
+            match strand_imbalance(feature_count) {
+                Some(z) => columns.push_str(&format!("{space}{z:.4}")),
+                None => columns.push_str(&format!("{space}.")),
+            }
+        }
+        columns
+    }
+}
+
+#[inline]
+fn write_bedmethyl_feature_counts<W: Write>(
+    pos: u32,
+    chrom_name: &str,
+    feature_counts: &[PileupFeatureCounts],
+    writer: &mut W,
+    tabs_and_spaces: bool,
+    motif_labels: &[String],
+    statistics: &BedMethylStatistics,
+) -> AnyhowResult<u64> {
+    let tab = '\t';
+    let space = if tabs_and_spaces { ' ' } else { tab };
+    let mut rows_written = 0u64;
+    for feature_count in feature_counts {
+        let name = feature_count_name(feature_count, motif_labels);
+        let row = format!(
+            "{}{tab}\
+             {}{tab}\
+             {}{tab}\
+             {}{tab}\
+             {}{tab}\
+             {}{tab}\
+             {}{tab}\
+             {}{tab}\
+             {}{tab}\
+             {}{space}\
+             {}{space}\
+             {}{space}\
+             {}{space}\
+             {}{space}\
+             {}{space}\
+             {}{space}\
+             {}{space}\
+             {}{}{}\n",
+            chrom_name,
+            pos,
+            pos + 1,
+            name,
+            feature_count.filtered_coverage,
+            feature_count.raw_strand,
+            pos,
+            pos + 1,
+            "255,0,0",
+            feature_count.filtered_coverage,
+            format!("{:.2}", feature_count.fraction_modified * 100f32),
+            feature_count.n_modified,
+            feature_count.n_canonical,
+            feature_count.n_other_modified,
+            feature_count.n_delete,
+            feature_count.n_filtered,
+            feature_count.n_diff,
+            feature_count.n_nocall,
+            expected_counts_columns(feature_count, space),
+            statistics.columns(feature_count, space),
+        );
+        writer
+            .write(row.as_bytes())
+            .with_context(|| "failed to write row")?;
+        rows_written += 1;
+    }
+
+    Ok(rows_written)
+}
+
 pub struct BedMethylWriter<T: Write> {
     buf_writer: BufWriter<T>,
     tabs_and_spaces: bool,
+    statistics: BedMethylStatistics,
 }
 
 impl<T: Write + Sized> BedMethylWriter<T> {
-    pub fn new(buf_writer: BufWriter<T>, tabs_and_spaces: bool) -> Self {
+    pub fn new(
+        buf_writer: BufWriter<T>,
+        tabs_and_spaces: bool,
+        statistics: BedMethylStatistics,
+    ) -> Self {
         Self {
             buf_writer,
             tabs_and_spaces,
+            statistics,
         }
     }
+}
 
-    #[inline]
-    fn write_feature_counts(
-        pos: u32,
-        chrom_name: &str,
-        feature_counts: &[PileupFeatureCounts],
-        writer: &mut BufWriter<T>,
-        tabs_and_spaces: bool,
+impl<T: Write> PileupWriter<ModBasePileup> for BedMethylWriter<T> {
+    fn write(
+        &mut self,
+        item: ModBasePileup,
+        motif_labels: &[String],
+    ) -> AnyhowResult<u64> {
+        let mut rows_written = 0;
+        for (pos, feature_counts) in item.iter_counts_sorted() {
+            match feature_counts.get(&PartitionKey::NoKey) {
+                Some(feature_counts) => {
+                    rows_written += write_bedmethyl_feature_counts(
+                        *pos,
+                        &item.chrom_name,
+                        &feature_counts,
+                        &mut self.buf_writer,
+                        self.tabs_and_spaces,
+                        motif_labels,
+                        &self.statistics,
+                    )?;
+                }
+                None => {}
+            }
+        }
+        Ok(rows_written)
+    }
+}
+
+/// Writes one row per position, strand, and mod code with the number of
+/// modified and canonical calls from each input BAM in separate columns.
+/// Expects the counts for each input to be partitioned by
+/// `PartitionKey::Key(<input index>)`.
+pub struct SampleColumnsWriter<T: Write> {
+    buf_writer: BufWriter<T>,
+    num_samples: usize,
+}
+
+impl<T: Write> SampleColumnsWriter<T> {
+    pub fn new(
+        mut buf_writer: BufWriter<T>,
+        sample_names: &[String],
+    ) -> AnyhowResult<Self> {
+        let sample_columns = sample_names
+            .iter()
+            .map(|name| format!("{name}_n_mod\t{name}_n_canonical"))
+            .join("\t");
+        writeln!(
+            buf_writer,
+            "#chrom\tstart\tend\tname\tstrand\t{sample_columns}"
+        )
+        .context("failed to write header")?;
+        Ok(Self {
+            buf_writer,
+            num_samples: sample_names.len(),
+        })
+    }
+}
+
+impl<T: Write> PileupWriter<ModBasePileup> for SampleColumnsWriter<T> {
+    fn write(
+        &mut self,
+        item: ModBasePileup,
         motif_labels: &[String],
     ) -> AnyhowResult<u64> {
-        let tab = '\t';
-        let space = if tabs_and_spaces { ' ' } else { tab };
         let mut rows_written = 0u64;
-        let raw_code_only = motif_labels.len() < 2;
-        for feature_count in feature_counts {
-            let name = if raw_code_only {
-                format!("{}", feature_count.raw_mod_code)
-            } else {
-                feature_count
-                    .motif_idx
-                    .and_then(|i| motif_labels.get(i))
-                    .map(|label| {
-                        format!("{},{}", feature_count.raw_mod_code, label)
+        for (pos, partitioned_counts) in item.iter_counts_sorted() {
+            // (strand, mod code, motif) to the counts for each sample, sorted
+            // the same way as the bedMethyl rows
+            let mut rows = BTreeMap::<
+                (char, ModCodeRepr, Option<usize>),
+                Vec<Option<&PileupFeatureCounts>>,
+            >::new();
+            for (partition_key, feature_counts) in partitioned_counts {
+                let sample_idx = match partition_key {
+                    PartitionKey::Key(idx) if *idx < self.num_samples => *idx,
+                    _ => bail!("unexpected partition key {partition_key:?}"),
+                };
+                for feature_count in feature_counts {
+                    let key = (
+                        feature_count.raw_strand,
+                        feature_count.raw_mod_code,
+                        feature_count.motif_idx,
+                    );
+                    rows.entry(key)
+                        .or_insert_with(|| vec![None; self.num_samples])
+                        [sample_idx] = Some(feature_count);
+                }
+            }
+            for per_sample_counts in rows.values() {
+                let first = per_sample_counts.iter().find_map(|c| *c).unwrap();
+                let name = feature_count_name(first, motif_labels);
+                let sample_columns = per_sample_counts
+                    .iter()
+                    .map(|counts| {
+                        counts
+                            .map(|c| {
+                                format!("{}\t{}", c.n_modified, c.n_canonical)
+                            })
+                            .unwrap_or_else(|| "0\t0".to_string())
                     })
-                    .unwrap_or(format!("{}", feature_count.raw_mod_code))
+                    .join("\t");
+                writeln!(
+                    self.buf_writer,
+                    "{}\t{pos}\t{}\t{name}\t{}\t{sample_columns}",
+                    item.chrom_name,
+                    pos + 1,
+                    first.raw_strand,
+                )
+                .context("failed to write row")?;
+                rows_written += 1;
+            }
+        }
+        Ok(rows_written)
+    }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        self.buf_writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes one row per position, strand, and mod code with the counts for
+/// haplotype 1, haplotype 2, and unphased reads side by side and the phase
+/// block(s) of the phased reads. Expects the counts to be partitioned on
+/// the HP and PS tags (in that order).
+pub struct HaplotypeBedMethylWriter<T: Write> {
+    buf_writer: BufWriter<T>,
+}
+
+impl<T: Write> HaplotypeBedMethylWriter<T> {
+    pub fn new(mut buf_writer: BufWriter<T>) -> AnyhowResult<Self> {
+        let haplotype_columns = ["hp1", "hp2", "unphased"]
+            .iter()
+            .map(|hp| format!("{hp}_valid_cov\t{hp}_n_mod\t{hp}_n_canonical"))
+            .join("\t");
+        writeln!(
+            buf_writer,
+            "#chrom\tstart\tend\tname\tstrand\tphase_block\t\
+            {haplotype_columns}"
+        )
+        .context("failed to write header")?;
+        Ok(Self { buf_writer })
+    }
+}
+
+impl<T: Write> PileupWriter<ModBasePileup> for HaplotypeBedMethylWriter<T> {
+    fn write(
+        &mut self,
+        item: ModBasePileup,
+        motif_labels: &[String],
+    ) -> AnyhowResult<u64> {
+        let mut rows_written = 0u64;
+        for (pos, partitioned_counts) in item.iter_counts_sorted() {
+            let mut rows = BTreeMap::<
+                (char, ModCodeRepr, Option<usize>),
+                [Option<PileupFeatureCounts>; 3],
+            >::new();
+            let mut phase_blocks = BTreeSet::<&str>::new();
+            for (partition_key, feature_counts) in partitioned_counts {
+                let (column, phase_block) = match partition_key {
+                    PartitionKey::NoKey => (Haplotype::Unphased, None),
+                    PartitionKey::Key(idx) => item
+                        .partition_keys
+                        .get_index(*idx)
+                        .map(|key| parse_haplotype_key(key))
+                        .unwrap_or((Haplotype::Unphased, None)),
+                };
+                if let Some(phase_block) = phase_block {
+                    phase_blocks.insert(phase_block);
+                }
+                for feature_count in feature_counts {
+                    let key = (
+                        feature_count.raw_strand,
+                        feature_count.raw_mod_code,
+                        feature_count.motif_idx,
+                    );
+                    let counts = &mut rows.entry(key).or_insert([None; 3])
+                        [column as usize];
+                    *counts = match counts.take() {
+                        Some(existing) => Some(
+                            existing
+                                .combine_counts_ignore_strand(*feature_count),
+                        ),
+                        None => Some(*feature_count),
+                    };
+                }
+            }
+            let phase_block = if phase_blocks.is_empty() {
+                ".".to_string()
+            } else {
+                phase_blocks.iter().join(",")
             };
-            let row = format!(
-                "{}{tab}\
-                 {}{tab}\
-                 {}{tab}\
-                 {}{tab}\
-                 {}{tab}\
-                 {}{tab}\
-                 {}{tab}\
-                 {}{tab}\
-                 {}{tab}\
-                 {}{space}\
-                 {}{space}\
-                 {}{space}\
-                 {}{space}\
-                 {}{space}\
-                 {}{space}\
-                 {}{space}\
-                 {}{space}\
-                 {}\n",
-                chrom_name,
-                pos,
-                pos + 1,
-                name,
-                feature_count.filtered_coverage,
-                feature_count.raw_strand,
-                pos,
-                pos + 1,
-                "255,0,0",
-                feature_count.filtered_coverage,
-                format!("{:.2}", feature_count.fraction_modified * 100f32),
+            for haplotype_counts in rows.values() {
+                let first = haplotype_counts.iter().find_map(|c| *c).unwrap();
+                let name = feature_count_name(&first, motif_labels);
+                let haplotype_columns = haplotype_counts
+                    .iter()
+                    .map(|counts| {
+                        counts
+                            .map(|c| {
+                                format!(
+                                    "{}\t{}\t{}",
+                                    c.filtered_coverage,
+                                    c.n_modified,
+                                    c.n_canonical
+                                )
+                            })
+                            .unwrap_or_else(|| "0\t0\t0".to_string())
+                    })
+                    .join("\t");
+                writeln!(
+                    self.buf_writer,
+                    "{}\t{pos}\t{}\t{name}\t{}\t{phase_block}\t\
+                    {haplotype_columns}",
+                    item.chrom_name,
+                    pos + 1,
+                    first.raw_strand,
+                )
+                .context("failed to write row")?;
+                rows_written += 1;
+            }
+        }
+        Ok(rows_written)
+    }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        self.buf_writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes the bedMethyl columns to a Parquet file with typed columns, the
+/// compatibility columns (thick start, thick end, and color) are omitted.
+/// Row groups are closed at the end of a batch of pileup (interval chunk)
+/// once they have at least 10,000 rows.
+pub struct BedMethylParquetWriter {
+    writer: ParquetTableWriter,
+    soft: bool,
+    statistics: BedMethylStatistics,
+    chrom: StringDictionaryBuilder<Int32Type>,
+    start: UInt32Builder,
+    end: UInt32Builder,
+    mod_code: StringDictionaryBuilder<Int32Type>,
+    strand: StringDictionaryBuilder<Int32Type>,
+    n_valid_cov: UInt32Builder,
+    percent_modified: Float32Builder,
+    n_mod: UInt32Builder,
+    n_canonical: UInt32Builder,
+    n_other_mod: UInt32Builder,
+    n_delete: UInt32Builder,
+    n_fail: UInt32Builder,
+    n_diff: UInt32Builder,
+    n_nocall: UInt32Builder,
+    expected_mod: Float32Builder,
+    expected_canonical: Float32Builder,
+    ci_lower: Float64Builder,
+    ci_upper: Float64Builder,
+    strand_imbalance: Float64Builder,
+}
+
+impl BedMethylParquetWriter {
+    pub fn new(
+        path: &Path,
+        force: bool,
+        soft: bool,
+        statistics: BedMethylStatistics,
+    ) -> AnyhowResult<Self> {
+        let mut fields = vec![
+            categorical_field("chrom", false),
+            Field::new("start", DataType::UInt32, false),
+            Field::new("end", DataType::UInt32, false),
+            categorical_field("mod_code", false),
+            categorical_field("strand", false),
+        ];
+        fields.push(Field::new("n_valid_cov", DataType::UInt32, false));
+        fields.push(Field::new("percent_modified", DataType::Float32, false));
+        fields.extend(
+            [
+                "n_mod",
+                "n_canonical",
+                "n_other_mod",
+                "n_delete",
+                "n_fail",
+                "n_diff",
+                "n_nocall",
+            ]
+            .into_iter()
+            .map(|name| Field::new(name, DataType::UInt32, false)),
+        );
+        if soft {
+            fields.push(Field::new("expected_mod", DataType::Float32, true));
+            fields.push(Field::new(
+                "expected_canonical",
+                DataType::Float32,
+                true,
+            ));
+        }
+        if statistics.confidence_interval.is_some() {
+            fields.push(Field::new("ci_lower", DataType::Float64, true));
+            fields.push(Field::new("ci_upper", DataType::Float64, true));
+        }
+        if statistics.strand_imbalance {
+            fields.push(Field::new(
+                "strand_imbalance",
+                DataType::Float64,
+                true,
+            ));
+        }
+        let writer = ParquetTableWriter::new(path, Schema::new(fields), force)?;
+        Ok(Self {
+            writer,
+            soft,
+            statistics,
+            chrom: StringDictionaryBuilder::new(),
+            start: UInt32Builder::new(),
+            end: UInt32Builder::new(),
+            mod_code: StringDictionaryBuilder::new(),
+            strand: StringDictionaryBuilder::new(),
+            n_valid_cov: UInt32Builder::new(),
+            percent_modified: Float32Builder::new(),
+            n_mod: UInt32Builder::new(),
+            n_canonical: UInt32Builder::new(),
+            n_other_mod: UInt32Builder::new(),
+            n_delete: UInt32Builder::new(),
+            n_fail: UInt32Builder::new(),
+            n_diff: UInt32Builder::new(),
+            n_nocall: UInt32Builder::new(),
+            expected_mod: Float32Builder::new(),
+            expected_canonical: Float32Builder::new(),
+            ci_lower: Float64Builder::new(),
+            ci_upper: Float64Builder::new(),
+            strand_imbalance: Float64Builder::new(),
+        })
+    }
+
+    fn append(
+        &mut self,
+        pos: u32,
+        chrom_name: &str,
+        feature_count: &PileupFeatureCounts,
+        motif_labels: &[String],
+    ) {
+        self.chrom.append_value(chrom_name);
+        self.start.append_value(pos);
+        self.end.append_value(pos + 1);
+        self.mod_code
+            .append_value(feature_count_name(feature_count, motif_labels));
+        self.strand
+            .append_value(feature_count.raw_strand.to_string());
+        self.n_valid_cov
+            .append_value(feature_count.filtered_coverage);
+        self.percent_modified
+            .append_value(feature_count.fraction_modified * 100f32);
+        self.n_mod.append_value(feature_count.n_modified);
+        self.n_canonical.append_value(feature_count.n_canonical);
+        self.n_other_mod
+            .append_value(feature_count.n_other_modified);
+        self.n_delete.append_value(feature_count.n_delete);
+        self.n_fail.append_value(feature_count.n_filtered);
+        self.n_diff.append_value(feature_count.n_diff);
+        self.n_nocall.append_value(feature_count.n_nocall);
+        self.expected_mod
+            .append_option(feature_count.expected_modified);
+        self.expected_canonical
+            .append_option(feature_count.expected_canonical);
+        if let Some(confidence_interval) =
+            self.statistics.confidence_interval.as_ref()
+        {
+            let bounds = confidence_interval.bounds(
                 feature_count.n_modified,
-                feature_count.n_canonical,
-                feature_count.n_other_modified,
-                feature_count.n_delete,
-                feature_count.n_filtered,
-                feature_count.n_diff,
-                feature_count.n_nocall,
+                feature_count.filtered_coverage,
             );
-            writer
-                .write(row.as_bytes())
-                .with_context(|| "failed to write row")?;
-            rows_written += 1;
+            self.ci_lower.append_option(bounds.map(|(l, _)| l * 100f64));
+            self.ci_upper.append_option(bounds.map(|(_, u)| u * 100f64));
+        }
+        if self.statistics.strand_imbalance {
+            self.strand_imbalance
+                .append_option(strand_imbalance(feature_count));
         }
+    }
 
-        Ok(rows_written)
+    fn finish_columns(&mut self) -> Vec<ArrayRef> {
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(self.chrom.finish()),
+            Arc::new(self.start.finish()),
+            Arc::new(self.end.finish()),
+            Arc::new(self.mod_code.finish()),
+            Arc::new(self.strand.finish()),
+            Arc::new(self.n_valid_cov.finish()),
+            Arc::new(self.percent_modified.finish()),
+            Arc::new(self.n_mod.finish()),
+            Arc::new(self.n_canonical.finish()),
+            Arc::new(self.n_other_mod.finish()),
+            Arc::new(self.n_delete.finish()),
+            Arc::new(self.n_fail.finish()),
+            Arc::new(self.n_diff.finish()),
+            Arc::new(self.n_nocall.finish()),
+        ];
+        let expected_mod = Arc::new(self.expected_mod.finish());
+        let expected_canonical = Arc::new(self.expected_canonical.finish());
+        if self.soft {
+            columns.push(expected_mod);
+            columns.push(expected_canonical);
+        }
+        if self.statistics.confidence_interval.is_some() {
+            columns.push(Arc::new(self.ci_lower.finish()));
+            columns.push(Arc::new(self.ci_upper.finish()));
+        }
+        if self.statistics.strand_imbalance {
+            columns.push(Arc::new(self.strand_imbalance.finish()));
+        }
+        columns
     }
 }
 
-impl<T: Write> PileupWriter<ModBasePileup> for BedMethylWriter<T> {
+impl PileupWriter<ModBasePileup> for BedMethylParquetWriter {
     fn write(
         &mut self,
         item: ModBasePileup,
         motif_labels: &[String],
     ) -> AnyhowResult<u64> {
-        let mut rows_written = 0;
         for (pos, feature_counts) in item.iter_counts_sorted() {
-            match feature_counts.get(&PartitionKey::NoKey) {
-                Some(feature_counts) => {
-                    rows_written += BedMethylWriter::write_feature_counts(
+            if let Some(feature_counts) =
+                feature_counts.get(&PartitionKey::NoKey)
+            {
+                for feature_count in feature_counts {
+                    self.append(
                         *pos,
                         &item.chrom_name,
-                        &feature_counts,
-                        &mut self.buf_writer,
-                        self.tabs_and_spaces,
+                        feature_count,
                         motif_labels,
-                    )?;
+                    );
                 }
-                None => {}
+            }
+        }
+        let columns = self.finish_columns();
+        self.writer.write_columns(columns)
+    }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        self.writer.finish()
+    }
+}
+
+type ProjectedSiteKey = (String, u64, char, ModCodeRepr, Option<usize>);
+type ProjectedSite = (PileupFeatureCounts, BTreeSet<String>, BTreeSet<String>);
+
+/// Writes bedMethyl in genome coordinates from a pileup of alignments to
+/// transcript sequences. Counts from isoforms that share a genomic position
+/// are merged. Transcripts must be written in order of their genomic start
+/// (see [`TranscriptProjection::sort_by_genomic_start`]), rows before the
+/// start of the current transcript are written out since no later
+/// transcript can overlap them, so only the sites of overlapping transcripts
+/// are held in memory. The gene and transcript IDs contributing to each site
+/// are appended as two comma-separated columns.
+pub struct ProjectedBedMethylWriter<T: Write> {
+    buf_writer: BufWriter<T>,
+    tabs_and_spaces: bool,
+    statistics: BedMethylStatistics,
+    projection: TranscriptProjection,
+    sites: BTreeMap<ProjectedSiteKey, ProjectedSite>,
+    /// Genomic start of the last transcript, rows before it have been
+    /// written.
+    written_before: Option<(String, u64)>,
+    motif_labels: Vec<String>,
+    missing_transcripts: BTreeSet<String>,
+}
+
+impl<T: Write> ProjectedBedMethylWriter<T> {
+    pub fn new(
+        buf_writer: BufWriter<T>,
+        tabs_and_spaces: bool,
+        statistics: BedMethylStatistics,
+        projection: TranscriptProjection,
+    ) -> Self {
+        Self {
+            buf_writer,
+            tabs_and_spaces,
+            statistics,
+            projection,
+            sites: BTreeMap::new(),
+            written_before: None,
+            motif_labels: Vec::new(),
+            missing_transcripts: BTreeSet::new(),
+        }
+    }
+
+    fn write_site(
+        &mut self,
+        key: ProjectedSiteKey,
+        site: ProjectedSite,
+    ) -> AnyhowResult<()> {
+        let (chrom, pos, _, _, _) = key;
+        let (feature_count, gene_ids, transcript_ids) = site;
+        let pos = u32::try_from(pos).map_err(|_| {
+            anyhow!("genomic position {pos} on {chrom} is too large")
+        })?;
+        let space = if self.tabs_and_spaces { ' ' } else { '\t' };
+        let mut row = Vec::new();
+        write_bedmethyl_feature_counts(
+            pos,
+            &chrom,
+            &[feature_count],
+            &mut row,
+            self.tabs_and_spaces,
+            &self.motif_labels,
+            &self.statistics,
+        )?;
+        // replace the newline with the gene and transcript columns
+        row.pop();
+        row.extend_from_slice(
+            format!(
+                "{space}{}{space}{}\n",
+                gene_ids.iter().join(","),
+                transcript_ids.iter().join(",")
+            )
+            .as_bytes(),
+        );
+        self.buf_writer.write_all(&row)?;
+        Ok(())
+    }
+
+    /// Write the sites before `pos` on `chrom`, and all of the sites on
+    /// earlier chroms.
+    fn write_sites_before(
+        &mut self,
+        chrom: &str,
+        pos: u64,
+    ) -> AnyhowResult<()> {
+        while self
+            .sites
+            .first_key_value()
+            .map(|((site_chrom, site_pos, _, _, _), _)| {
+                (site_chrom.as_str(), *site_pos) < (chrom, pos)
+            })
+            .unwrap_or(false)
+        {
+            let (key, site) = self.sites.pop_first().expect("checked first");
+            self.write_site(key, site)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Write> PileupWriter<ModBasePileup> for ProjectedBedMethylWriter<T> {
+    fn write(
+        &mut self,
+        item: ModBasePileup,
+        motif_labels: &[String],
+    ) -> AnyhowResult<u64> {
+        if self.motif_labels.is_empty() {
+            self.motif_labels = motif_labels.to_vec();
+        }
+        let transcript_start = match self.projection.get(&item.chrom_name) {
+            Some(transcript) => (transcript.chrom.clone(), transcript.start()),
+            None => {
+                self.missing_transcripts.insert(item.chrom_name);
+                return Ok(0);
+            }
+        };
+        if let Some(written_before) = self.written_before.as_ref() {
+            if &transcript_start < written_before {
+                bail!(
+                    "{} is before the previous transcript on the genome, \
+                     transcripts must be in order of genomic start",
+                    item.chrom_name
+                )
+            }
+        }
+        self.write_sites_before(&transcript_start.0, transcript_start.1)?;
+        self.written_before = Some(transcript_start);
+        let transcript = self
+            .projection
+            .get(&item.chrom_name)
+            .expect("checked transcript");
+
+        let mut sites_added = 0u64;
+        for (pos, feature_counts) in item.iter_counts_sorted() {
+            let genome_pos = match transcript.project(*pos as u64) {
+                Some(genome_pos) => genome_pos,
+                None => continue,
+            };
+            let feature_counts = match feature_counts.get(&PartitionKey::NoKey)
+            {
+                Some(feature_counts) => feature_counts,
+                None => continue,
+            };
+            for feature_count in feature_counts {
+                let mut feature_count = *feature_count;
+                if let Ok(strand) = Strand::parse_char(feature_count.raw_strand)
+                {
+                    feature_count.raw_strand =
+                        transcript.project_strand(strand).to_char();
+                }
+                let key = (
+                    transcript.chrom.clone(),
+                    genome_pos,
+                    feature_count.raw_strand,
+                    feature_count.raw_mod_code,
+                    feature_count.motif_idx,
+                );
+                match self.sites.remove(&key) {
+                    Some((counts, mut gene_ids, mut transcript_ids)) => {
+                        let mut combined =
+                            counts.combine_counts_ignore_strand(feature_count);
+                        // all of the counts are on the same genomic strand
+                        combined.strand_counts = None;
+                        gene_ids.insert(transcript.gene_id.clone());
+                        transcript_ids.insert(transcript.transcript_id.clone());
+                        self.sites
+                            .insert(key, (combined, gene_ids, transcript_ids));
+                    }
+                    None => {
+                        self.sites.insert(
+                            key,
+                            (
+                                feature_count,
+                                BTreeSet::from([transcript.gene_id.clone()]),
+                                BTreeSet::from([transcript
+                                    .transcript_id
+                                    .clone()]),
+                            ),
+                        );
+                        sites_added += 1;
+                    }
+                }
+            }
+        }
+        Ok(sites_added)
+    }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        if !self.missing_transcripts.is_empty() {
+            warn!(
+                "{} reference sequence(s) were not found in the annotation, \
+                 pileup on these sequences was not output, e.g. {}",
+                self.missing_transcripts.len(),
+                self.missing_transcripts.iter().take(3).join(",")
+            );
+        }
+        for (key, site) in std::mem::take(&mut self.sites) {
+            self.write_site(key, site)?;
+        }
+        self.buf_writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes BGZF-compressed bedMethyl and a tabix index alongside it so that
+/// the output can be used directly by `dmr` and tools like `tabix`. When a
+/// contig is too long for the tabix binning scheme (>2^29 bases) a CSI index
+/// is written instead.
+pub(crate) struct IndexedBgzfWriter {
+    out_fp: PathBuf,
+    writer: Option<bgzf::Writer<File>>,
+    indexer: Option<csi::index::Indexer>,
+    reference_sequence_names: ReferenceSequenceNames,
+    depth: u8,
+}
+
+impl IndexedBgzfWriter {
+    const MIN_SHIFT: u8 = 14;
+    const TABIX_DEPTH: u8 = 5;
+
+    pub(crate) fn new(
+        out_fp: &Path,
+        max_contig_length: u64,
+    ) -> AnyhowResult<Self> {
+        let fh = File::create(out_fp).with_context(|| {
+            format!("failed to create output file at {out_fp:?}")
+        })?;
+        let max_tabix_length =
+            1u64 << (Self::MIN_SHIFT + 3 * Self::TABIX_DEPTH);
+        let depth = if max_contig_length > max_tabix_length {
+            let mut depth = Self::TABIX_DEPTH;
+            while (1u64 << (Self::MIN_SHIFT + 3 * depth)) < max_contig_length {
+                depth += 1;
+            }
+            info!(
+                "contig length {max_contig_length} is too long for tabix, \
+                writing CSI index with depth {depth}"
+            );
+            depth
+        } else {
+            Self::TABIX_DEPTH
+        };
+        Ok(Self {
+            out_fp: out_fp.to_path_buf(),
+            writer: Some(bgzf::Writer::new(fh)),
+            indexer: Some(csi::index::Indexer::new(Self::MIN_SHIFT, depth)),
+            reference_sequence_names: ReferenceSequenceNames::new(),
+            depth,
+        })
+    }
+
+    /// Write the `rows` for a single position, rows must be written in
+    /// order of contig then position.
+    pub(crate) fn write_position(
+        &mut self,
+        chrom_name: &str,
+        pos: u32,
+        rows: &[u8],
+    ) -> AnyhowResult<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let (writer, indexer) = match (&mut self.writer, &mut self.indexer) {
+            (Some(writer), Some(indexer)) => (writer, indexer),
+            _ => bail!("cannot write to finished bgzf writer"),
+        };
+        let reference_sequence_id =
+            match self.reference_sequence_names.get_index_of(chrom_name) {
+                Some(id) => id,
+                None => {
+                    self.reference_sequence_names.insert(chrom_name.to_owned());
+                    self.reference_sequence_names.len() - 1
+                }
+            };
+        let start_vp = writer.virtual_position();
+        writer.write_all(rows)?;
+        let end_vp = writer.virtual_position();
+        // tabix coordinates are 1-based, closed
+        let position = noodles::core::Position::try_from(pos as usize + 1)?;
+        indexer
+            .add_record(
+                Some((reference_sequence_id, position, position, true)),
+                IndexChunk::new(start_vp, end_vp),
+            )
+            .with_context(|| {
+                format!(
+                    "failed to index {chrom_name}:{pos}, output must be \
+                    sorted"
+                )
+            })?;
+        Ok(())
+    }
+
+    pub(crate) fn finish(&mut self) -> AnyhowResult<()> {
+        let (writer, indexer) = match (self.writer.take(), self.indexer.take())
+        {
+            (Some(writer), Some(indexer)) => (writer, indexer),
+            _ => return Ok(()),
+        };
+        writer.finish()?;
+        let header = csi::index::header::Builder::bed()
+            .set_reference_sequence_names(self.reference_sequence_names.clone())
+            .build();
+        // the indexer only moves the in-progress reference sequence into the
+        // index when a later one is started, so build with one extra to make
+        // sure the last contig is included
+        let reference_sequences = indexer
+            .build(self.reference_sequence_names.len() + 1)
+            .reference_sequences()
+            .to_vec();
+        // the built index doesn't carry the binning parameters of the
+        // indexer, set them here so that the CSI index is readable
+        let index = csi::Index::builder()
+            .set_min_shift(Self::MIN_SHIFT)
+            .set_depth(self.depth)
+            .set_header(header)
+            .set_reference_sequences(reference_sequences)
+            .build();
+        let use_csi = self.depth > Self::TABIX_DEPTH;
+        let extension = if use_csi { "csi" } else { "tbi" };
+        let mut index_fp = self.out_fp.clone().into_os_string();
+        index_fp.push(format!(".{extension}"));
+        if use_csi {
+            csi::write(&index_fp, &index)
+        } else {
+            tabix::write(&index_fp, &index)
+        }
+        .with_context(|| format!("failed to write index to {index_fp:?}"))?;
+        debug!("wrote index to {index_fp:?}");
+        Ok(())
+    }
+}
+
+pub struct BgzfBedMethylWriter {
+    writer: IndexedBgzfWriter,
+    tabs_and_spaces: bool,
+    statistics: BedMethylStatistics,
+}
+
+impl BgzfBedMethylWriter {
+    pub fn new(
+        out_fp: &Path,
+        max_contig_length: u64,
+        tabs_and_spaces: bool,
+        statistics: BedMethylStatistics,
+    ) -> AnyhowResult<Self> {
+        let writer = IndexedBgzfWriter::new(out_fp, max_contig_length)?;
+        Ok(Self {
+            writer,
+            tabs_and_spaces,
+            statistics,
+        })
+    }
+}
+
+impl PileupWriter<ModBasePileup> for BgzfBedMethylWriter {
+    fn write(
+        &mut self,
+        item: ModBasePileup,
+        motif_labels: &[String],
+    ) -> AnyhowResult<u64> {
+        let mut rows_written = 0;
+        let mut buffer = Vec::new();
+        for (pos, feature_counts) in item.iter_counts_sorted() {
+            if let Some(feature_counts) =
+                feature_counts.get(&PartitionKey::NoKey)
+            {
+                buffer.clear();
+                rows_written += write_bedmethyl_feature_counts(
+                    *pos,
+                    &item.chrom_name,
+                    feature_counts,
+                    &mut buffer,
+                    self.tabs_and_spaces,
+                    motif_labels,
+                    &self.statistics,
+                )?;
+                self.writer
+                    .write_position(&item.chrom_name, *pos, &buffer)?;
             }
         }
         Ok(rows_written)
     }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        self.writer.finish()
+    }
 }
 
 impl<T: Write> PileupWriter<DuplexModBasePileup> for BedMethylWriter<T> {
@@ -217,6 +1118,63 @@ struct BedGraphFileKey {
     mod_code_repr: ModCodeRepr,
 }
 
+fn bedgraph_file_name(
+    prefix: Option<&String>,
+    key: &BedGraphFileKey,
+    key_name: &str,
+    label: &str,
+    extension: &str,
+) -> String {
+    let delim = if key_name.is_empty() { "" } else { "_" };
+    let strand_label = match key.strand {
+        '+' => "positive",
+        '-' => "negative",
+        '.' => "combined",
+        _ => "_unknown",
+    };
+    if let Some(p) = prefix {
+        format!("{p}_{key_name}{delim}{label}_{strand_label}.{extension}")
+    } else {
+        format!("{key_name}{delim}{label}_{strand_label}.{extension}")
+    }
+}
+
+fn partition_key_name<'a>(
+    item: &'a ModBasePileup,
+    partition_key: &PartitionKey,
+    use_groupings: bool,
+) -> &'a str {
+    match partition_key {
+        PartitionKey::NoKey => {
+            if use_groupings {
+                UNGROUPED
+            } else {
+                ""
+            }
+        }
+        PartitionKey::Key(idx) => item
+            .partition_keys
+            .get_index(*idx)
+            .map(|s| s.as_str())
+            .unwrap_or(NOT_FOUND),
+    }
+}
+
+fn bedgraph_label(
+    key: &BedGraphFileKey,
+    feature_count: &PileupFeatureCounts,
+    motif_labels: &[String],
+) -> String {
+    if let Some(idx) = feature_count.motif_idx {
+        motif_labels
+            .get(idx)
+            .map(|l| format!("{}_{}", key.mod_code_repr, l.replace(",", "")))
+            .unwrap_or(format!("{}", key.mod_code_repr))
+    } else {
+        format!("{}", key.mod_code_repr)
+    }
+}
+
 pub struct BedGraphWriter {
     prefix: Option<String>,
     out_dir: PathBuf,
@@ -250,19 +1208,13 @@ impl BedGraphWriter {
         label: String,
     ) -> &mut BufWriter<File> {
         self.router.entry((key, label.clone())).or_insert_with(|| {
-            let strand = key.strand;
-            let delim = if key_name == "" { "" } else { "_" };
-            let strand_label = match strand {
-                '+' => "positive",
-                '-' => "negative",
-                '.' => "combined",
-                _ => "_unknown",
-            };
-            let filename = if let Some(p) = &self.prefix {
-                format!("{p}_{key_name}{delim}{label}_{strand_label}.bedgraph")
-            } else {
-                format!("{key_name}{delim}{label}_{strand_label}.bedgraph")
-            };
+            let filename = bedgraph_file_name(
+                self.prefix.as_ref(),
+                &key,
+                key_name,
+                &label,
+                "bedgraph",
+            );
             let fp = self.out_dir.join(filename);
             // todo(arand) danger, should remove this unwrap
             let fh = File::create(fp).unwrap();
@@ -282,40 +1234,19 @@ impl PileupWriter<ModBasePileup> for BedGraphWriter {
         // let raw_code_only = motif_labels.len() < 2;
         for (pos, feature_counts) in item.iter_counts_sorted() {
             for (partition_key, pileup_feature_counts) in feature_counts {
-                let key_name = match partition_key {
-                    PartitionKey::NoKey => {
-                        if self.use_groupings {
-                            UNGROUPED
-                        } else {
-                            ""
-                        }
-                    }
-                    PartitionKey::Key(idx) => item
-                        .partition_keys
-                        .get_index(*idx)
-                        .map(|s| s.as_str())
-                        .unwrap_or(NOT_FOUND),
-                };
+                let key_name = partition_key_name(
+                    &item,
+                    partition_key,
+                    self.use_groupings,
+                );
                 for feature_count in pileup_feature_counts {
                     let key = BedGraphFileKey::new(
                         *partition_key,
                         feature_count.raw_strand,
                         feature_count.raw_mod_code,
                     );
-                    let label = if let Some(idx) = feature_count.motif_idx {
-                        motif_labels
-                            .get(idx)
-                            .map(|l| {
-                                format!(
-                                    "{}_{}",
-                                    key.mod_code_repr,
-                                    l.replace(",", "")
-                                )
-                            })
-                            .unwrap_or(format!("{}", key.mod_code_repr))
-                    } else {
-                        format!("{}", key.mod_code_repr)
-                    };
+                    let label =
+                        bedgraph_label(&key, feature_count, motif_labels);
                     let fh =
                         self.get_writer_for_modstrand(key, key_name, label);
                     let row = format!(
@@ -340,6 +1271,117 @@ impl PileupWriter<ModBasePileup> for BedGraphWriter {
     }
 }
 
+/// Writes bigWig files with the fraction modified at each position, the
+/// files are split by partition, strand, and modification code the same way
+/// as with [`BedGraphWriter`].
+pub struct BigWigWriter {
+    prefix: Option<String>,
+    out_dir: PathBuf,
+    router: HashMap<(BedGraphFileKey, String), BigWigFile>,
+    use_groupings: bool,
+    chrom_sizes: Vec<(String, u32)>,
+    chrom_ids: FxHashMap<String, u32>,
+}
+
+impl BigWigWriter {
+    /// `chrom_sizes` should be the reference sequence names and lengths in
+    /// the order they are in the BAM header.
+    pub fn new(
+        out_dir: &str,
+        prefix: Option<&String>,
+        use_groupings: bool,
+        chrom_sizes: Vec<(String, u32)>,
+    ) -> AnyhowResult<Self> {
+        let out_dir_fp = Path::new(out_dir).to_path_buf();
+        if !out_dir_fp.exists() {
+            info!("creating directory for bigWig output at {out_dir}");
+            std::fs::create_dir_all(out_dir_fp.clone())?;
+        }
+        let chrom_ids = bigwig::chrom_ids(&chrom_sizes);
+        Ok(Self {
+            prefix: prefix.map(|s| s.to_owned()),
+            out_dir: out_dir_fp,
+            router: HashMap::new(),
+            use_groupings,
+            chrom_sizes,
+            chrom_ids,
+        })
+    }
+
+    fn get_writer_for_modstrand(
+        &mut self,
+        key: BedGraphFileKey,
+        key_name: &str,
+        label: String,
+    ) -> AnyhowResult<&mut BigWigFile> {
+        let entry = self.router.entry((key, label));
+        match entry {
+            std::collections::hash_map::Entry::Occupied(o) => Ok(o.into_mut()),
+            std::collections::hash_map::Entry::Vacant(v) => {
+                let filename = bedgraph_file_name(
+                    self.prefix.as_ref(),
+                    &key,
+                    key_name,
+                    &v.key().1,
+                    "bw",
+                );
+                let fp = self.out_dir.join(filename);
+                let bw = BigWigFile::create(fp, self.chrom_sizes.clone())?;
+                Ok(v.insert(bw))
+            }
+        }
+    }
+}
+
+impl PileupWriter<ModBasePileup> for BigWigWriter {
+    fn write(
+        &mut self,
+        item: ModBasePileup,
+        motif_labels: &[String],
+    ) -> AnyhowResult<u64> {
+        let mut rows_written = 0;
+        let chrom_id =
+            *self.chrom_ids.get(&item.chrom_name).ok_or_else(|| {
+                anyhow!("no size for contig {} in header", item.chrom_name)
+            })?;
+        for (pos, feature_counts) in item.iter_counts_sorted() {
+            for (partition_key, pileup_feature_counts) in feature_counts {
+                let key_name = partition_key_name(
+                    &item,
+                    partition_key,
+                    self.use_groupings,
+                );
+                for feature_count in pileup_feature_counts {
+                    let key = BedGraphFileKey::new(
+                        *partition_key,
+                        feature_count.raw_strand,
+                        feature_count.raw_mod_code,
+                    );
+                    let label =
+                        bedgraph_label(&key, feature_count, motif_labels);
+                    self.get_writer_for_modstrand(key, key_name, label)?
+                        .add_interval(
+                            chrom_id,
+                            *pos,
+                            pos + 1,
+                            feature_count.fraction_modified,
+                        )?;
+                    rows_written += 1;
+                }
+            }
+        }
+
+        Ok(rows_written)
+    }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        for (_, bigwig_file) in self.router.drain() {
+            bigwig_file.finish()?;
+        }
+        Ok(())
+    }
+}
+
 pub struct TableWriter<W: Write> {
     writer: BufWriter<W>,
 }
@@ -770,7 +1812,14 @@ pub struct PartitioningBedMethylWriter {
     prefix: Option<String>,
     out_dir: PathBuf,
     tabs_and_spaces: bool,
-    router: FxHashMap<String, BufWriter<File>>,
+    bgzf_max_contig_length: Option<u64>,
+    router: FxHashMap<String, PartitionSink>,
+    statistics: BedMethylStatistics,
+}
+
+enum PartitionSink {
+    Plain(BufWriter<File>),
+    Bgzf(Box<IndexedBgzfWriter>),
 }
 
 impl PartitioningBedMethylWriter {
@@ -778,6 +1827,8 @@ impl PartitioningBedMethylWriter {
         out_path: &String,
         only_tabs: bool,
         prefix: Option<&String>,
+        bgzf_max_contig_length: Option<u64>,
+        statistics: BedMethylStatistics,
     ) -> anyhow::Result<Self> {
         let dir_path = Path::new(out_path);
         if !dir_path.is_dir() {
@@ -792,21 +1843,40 @@ impl PartitioningBedMethylWriter {
             prefix,
             router,
             tabs_and_spaces: !only_tabs,
+            bgzf_max_contig_length,
+            statistics,
         })
     }
 
-    fn get_writer_for_key(&mut self, key_name: &str) -> &mut BufWriter<File> {
-        self.router.entry(key_name.to_owned()).or_insert_with(|| {
+    fn get_writer_for_key(
+        &mut self,
+        key_name: &str,
+    ) -> AnyhowResult<&mut PartitionSink> {
+        if !self.router.contains_key(key_name) {
+            let extension = if self.bgzf_max_contig_length.is_some() {
+                "bed.gz"
+            } else {
+                "bed"
+            };
             let filename = if let Some(prefix) = self.prefix.as_ref() {
-                format!("{prefix}_{key_name}.bed")
+                format!("{prefix}_{key_name}.{extension}")
             } else {
-                format!("{key_name}.bed")
+                format!("{key_name}.{extension}")
             };
             let fp = self.out_dir.join(filename);
-            let fh = File::create(fp).unwrap();
-
-            BufWriter::new(fh)
-        })
+            let sink = match self.bgzf_max_contig_length {
+                Some(max_contig_length) => PartitionSink::Bgzf(Box::new(
+                    IndexedBgzfWriter::new(&fp, max_contig_length)?,
+                )),
+                None => PartitionSink::Plain(BufWriter::new(
+                    File::create(&fp).with_context(|| {
+                        format!("failed to create output file at {fp:?}")
+                    })?,
+                )),
+            };
+            self.router.insert(key_name.to_owned(), sink);
+        }
+        Ok(self.router.get_mut(key_name).unwrap())
     }
 }
 
@@ -820,6 +1890,7 @@ impl PileupWriter<ModBasePileup> for PartitioningBedMethylWriter {
         motif_labels: &[String],
     ) -> AnyhowResult<u64> {
         let tabs_and_spaces = self.tabs_and_spaces;
+        let statistics = self.statistics;
         let mut rows_written = 0u64;
         for (&pos, partitioned_feature_counts) in item.iter_counts_sorted() {
             for (&partition_key, pileup_feature_counts) in
@@ -834,18 +1905,117 @@ impl PileupWriter<ModBasePileup> for PartitioningBedMethylWriter {
                         .unwrap_or(NOT_FOUND),
                 };
 
-                let writer = self.get_writer_for_key(key_name);
-                rows_written += BedMethylWriter::write_feature_counts(
-                    pos,
-                    &item.chrom_name,
-                    &pileup_feature_counts,
-                    writer,
-                    tabs_and_spaces,
-                    motif_labels,
-                )?;
+                match self.get_writer_for_key(key_name)? {
+                    PartitionSink::Plain(writer) => {
+                        rows_written += write_bedmethyl_feature_counts(
+                            pos,
+                            &item.chrom_name,
+                            pileup_feature_counts,
+                            writer,
+                            tabs_and_spaces,
+                            motif_labels,
+                            &statistics,
+                        )?;
+                    }
+                    PartitionSink::Bgzf(writer) => {
+                        let mut buffer = Vec::new();
+                        rows_written += write_bedmethyl_feature_counts(
+                            pos,
+                            &item.chrom_name,
+                            pileup_feature_counts,
+                            &mut buffer,
+                            tabs_and_spaces,
+                            motif_labels,
+                            &statistics,
+                        )?;
+                        writer.write_position(
+                            &item.chrom_name,
+                            pos,
+                            &buffer,
+                        )?;
+                    }
+                }
             }
         }
 
         Ok(rows_written)
     }
+
+    fn finish(&mut self) -> AnyhowResult<()> {
+        for sink in self.router.values_mut() {
+            match sink {
+                PartitionSink::Plain(writer) => writer.flush()?,
+                PartitionSink::Bgzf(writer) => writer.finish()?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod writers_tests {
+    use std::io::Read;
+
+    use noodles::{bgzf, csi, tabix};
+
+    use crate::writers::IndexedBgzfWriter;
+
+    fn write_rows(out_fp: &std::path::Path, max_contig_length: u64) {
+        let mut writer =
+            IndexedBgzfWriter::new(out_fp, max_contig_length).unwrap();
+        for (chrom, pos) in [("chr1", 10u32), ("chr1", 20), ("chr2", 5)] {
+            let row = format!("{chrom}\t{pos}\t{}\n", pos + 1);
+            writer.write_position(chrom, pos, row.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+        // finishing twice is a no-op
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_indexed_bgzf_writer_tabix() {
+        let out_fp =
+            std::env::temp_dir().join("test_indexed_bgzf_writer.bed.gz");
+        write_rows(&out_fp, 1000);
+        let mut decoded = String::new();
+        bgzf::Reader::new(std::fs::File::open(&out_fp).unwrap())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "chr1\t10\t11\nchr1\t20\t21\nchr2\t5\t6\n");
+
+        let index_fp = out_fp.with_extension("gz.tbi");
+        let index = tabix::read(index_fp).unwrap();
+        assert_eq!(index.reference_sequences().len(), 2);
+        let names = index.header().unwrap().reference_sequence_names();
+        assert_eq!(
+            names.iter().map(|s| s.as_str()).collect::<Vec<&str>>(),
+            vec!["chr1", "chr2"]
+        );
+    }
+
+    #[test]
+    fn test_indexed_bgzf_writer_csi_for_long_contigs() {
+        let out_fp =
+            std::env::temp_dir().join("test_indexed_bgzf_writer_csi.bed.gz");
+        write_rows(&out_fp, 1 << 30);
+        let index = csi::read(out_fp.with_extension("gz.csi")).unwrap();
+        assert_eq!(index.reference_sequences().len(), 2);
+        assert!(index.depth() > 5);
+    }
+
+    #[test]
+    fn test_indexed_bgzf_writer_unsorted() {
+        let out_fp = std::env::temp_dir()
+            .join("test_indexed_bgzf_writer_unsorted.bed.gz");
+        let mut writer = IndexedBgzfWriter::new(&out_fp, 1000).unwrap();
+        writer
+            .write_position("chr1", 10, b"chr1\t10\t11\n")
+            .unwrap();
+        writer
+            .write_position("chr2", 10, b"chr2\t10\t11\n")
+            .unwrap();
+        assert!(writer
+            .write_position("chr1", 20, b"chr1\t20\t21\n")
+            .is_err());
+    }
 }
@@ -2,6 +2,7 @@ use anyhow::{anyhow, Result as AnyhowResult};
 use derive_new::new;
 use mod_kit::mod_bam::{CollapseMethod, EdgeFilter};
 use mod_kit::position_filter::StrandedPositionFilter;
+use mod_kit::record_filter::RecordSelection;
 use mod_kit::summarize::{summarize_modbam, ModSummary};
 use mod_kit::threshold_mod_caller::MultipleThresholdModCaller;
 use std::cmp::Ordering;
@@ -51,7 +52,7 @@ fn run_summary<'a>(
             collapse_method,
             edge_filter,
             None,
-            false,
+            RecordSelection::new(false, None),
             true,
         )
     })
@@ -88,7 +89,7 @@ pub fn run_summary_with_include_positions<'a>(
             None,
             None,
             Some(&position_filter),
-            true,
+            RecordSelection::new(true, None),
             true,
         )
     })
@@ -133,6 +134,28 @@ pub fn check_against_expected_text_file(output_fp: &str, expected_fp: &str) {
         "{output_fp} is not the same as {expected_fp}"
     );
 }
+/// Read a tab-separated file into rows of columns.
+pub fn read_rows<P: AsRef<Path>>(fp: P) -> Vec<Vec<String>> {
+    BufReader::new(File::open(fp).unwrap())
+        .lines()
+        .map(|l| l.unwrap().split('\t').map(|x| x.to_string()).collect())
+        .collect()
+}
+
+/// Read a bedMethyl file into rows of columns, the columns after the 10th
+/// are space-separated unless pileup is run with `--only-tabs`.
+pub fn read_bedmethyl_rows<P: AsRef<Path>>(fp: P) -> Vec<Vec<String>> {
+    BufReader::new(File::open(fp).unwrap())
+        .lines()
+        .map(|l| {
+            l.unwrap()
+                .split_whitespace()
+                .map(|x| x.to_string())
+                .collect()
+        })
+        .collect()
+}
+
 #[derive(new, Eq, PartialEq, Debug)]
 pub struct ModData {
     pub q_pos: usize,
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+
+use crate::common::{read_rows, run_modkit};
+
+mod common;
+
+const BAM: &str = "tests/resources/bc_anchored_10_reads.sorted.bam";
+const REFERENCE: &str = "tests/resources/CGI_ladder_3.6kb_ref.fa";
+
+#[test]
+fn test_aggregate_help() {
+    let aggregate_help_args = ["aggregate", "--help"];
+    let _out = run_modkit(&aggregate_help_args).unwrap();
+}
+
+#[test]
+fn test_aggregate_bam_and_bedmethyl_regions() {
+    let regions_fp = std::env::temp_dir().join("test_aggregate_regions.bed");
+    {
+        let mut fh = File::create(&regions_fp).unwrap();
+        writeln!(fh, "oligo_1512_adapters\t0\t200\tfirst").unwrap();
+        writeln!(fh, "oligo_1512_adapters\t100\t400\tsecond").unwrap();
+        writeln!(fh, "not_a_contig\t0\t100\tmissing").unwrap();
+    }
+    let bam_out = std::env::temp_dir().join("test_aggregate_bam.tsv");
+    run_modkit(&[
+        "aggregate",
+        BAM,
+        "-r",
+        regions_fp.to_str().unwrap(),
+        "--cpg",
+        "--ref",
+        REFERENCE,
+        "--no-filtering",
+        "-o",
+        bam_out.to_str().unwrap(),
+        "-f",
+    ])
+    .unwrap();
+    let bam_rows = read_rows(&bam_out);
+    // one row for each of 5mC and 5hmC in each region with coverage
+    assert_eq!(bam_rows.len(), 4);
+
+    // the counts are the sum of the pileup counts over the region
+    let pileup_fp = std::env::temp_dir().join("test_aggregate_pileup.bed");
+    run_modkit(&[
+        "pileup",
+        BAM,
+        pileup_fp.to_str().unwrap(),
+        "--cpg",
+        "--ref",
+        REFERENCE,
+        "--no-filtering",
+        "--only-tabs",
+    ])
+    .unwrap();
+    let pileup_rows = read_rows(&pileup_fp);
+    // per-read calls at the same sites
+    let calls_fp = std::env::temp_dir().join("test_aggregate_calls.tsv");
+    run_modkit(&[
+        "extract",
+        BAM,
+        "null",
+        "--read-calls",
+        calls_fp.to_str().unwrap(),
+        "--cpg",
+        "--ref",
+        REFERENCE,
+        "--no-filtering",
+        "--force",
+    ])
+    .unwrap();
+    let call_rows = read_rows(&calls_fp);
+    for row in bam_rows.iter() {
+        assert_eq!(row.len(), 13, "{row:?}");
+        let start = row[1].parse::<u64>().unwrap();
+        let end = row[2].parse::<u64>().unwrap();
+        let mod_code = &row[4];
+        let sites = pileup_rows
+            .iter()
+            .filter(|p| {
+                let pos = p[1].parse::<u64>().unwrap();
+                pos >= start && pos < end && &p[3] == mod_code
+            })
+            .collect::<Vec<_>>();
+        let n_valid = sites
+            .iter()
+            .map(|p| p[9].parse::<u64>().unwrap())
+            .sum::<u64>();
+        let n_mod = sites
+            .iter()
+            .map(|p| p[11].parse::<u64>().unwrap())
+            .sum::<u64>();
+        let mean_site_percent = sites
+            .iter()
+            .map(|p| p[10].parse::<f64>().unwrap())
+            .sum::<f64>()
+            / sites.len() as f64;
+        assert_eq!(row[5].parse::<usize>().unwrap(), sites.len());
+        assert_eq!(row[6].parse::<u64>().unwrap(), n_valid);
+        assert_eq!(row[7].parse::<u64>().unwrap(), n_mod);
+        let percent = row[8].parse::<f64>().unwrap();
+        assert!(
+            (percent - n_mod as f64 / n_valid as f64 * 100f64).abs() < 0.01
+        );
+        let site_percent = row[9].parse::<f64>().unwrap();
+        assert!((site_percent - mean_site_percent).abs() < 0.01, "{row:?}");
+
+        // read_id to (number of calls, number of calls of the mod code)
+        let mut read_calls = HashMap::<&str, (usize, usize)>::new();
+        for call in call_rows.iter().skip(1) {
+            let pos = call[2].parse::<i64>().unwrap();
+            if pos < start as i64 || pos >= end as i64 {
+                continue;
+            }
+            let counts = read_calls.entry(call[0].as_str()).or_default();
+            counts.0 += 1;
+            if &call[11] == mod_code {
+                counts.1 += 1;
+            }
+        }
+        let read_fractions = read_calls
+            .values()
+            .map(|(n, n_mod)| *n_mod as f64 / *n as f64)
+            .collect::<Vec<f64>>();
+        let n_reads = read_fractions.len();
+        let mean_read_percent =
+            read_fractions.iter().sum::<f64>() / n_reads as f64 * 100f64;
+        let percent_reads_modified =
+            read_fractions.iter().filter(|f| **f > 0.5).count() as f64
+                / n_reads as f64
+                * 100f64;
+        assert_eq!(row[10].parse::<usize>().unwrap(), n_reads, "{row:?}");
+        let read_percent = row[11].parse::<f64>().unwrap();
+        assert!((read_percent - mean_read_percent).abs() < 0.01, "{row:?}");
+        let reads_modified = row[12].parse::<f64>().unwrap();
+        assert!(
+            (reads_modified - percent_reads_modified).abs() < 0.01,
+            "{row:?}"
+        );
+    }
+
+    // the same summary from a bedMethyl of the same reads
+    let bgzf_fp = std::env::temp_dir().join("test_aggregate_pileup.bed.gz");
+    run_modkit(&[
+        "pileup",
+        BAM,
+        bgzf_fp.to_str().unwrap(),
+        "--cpg",
+        "--ref",
+        REFERENCE,
+        "--no-filtering",
+        "--bgzf",
+    ])
+    .unwrap();
+    let bedmethyl_out =
+        std::env::temp_dir().join("test_aggregate_bedmethyl.tsv");
+    run_modkit(&[
+        "aggregate",
+        bgzf_fp.to_str().unwrap(),
+        "-r",
+        regions_fp.to_str().unwrap(),
+        "--ref",
+        REFERENCE,
+        "--base",
+        "C",
+        "-o",
+        bedmethyl_out.to_str().unwrap(),
+        "-f",
+    ])
+    .unwrap();
+    // the read-level columns need the reads
+    let bedmethyl_rows = read_rows(&bedmethyl_out);
+    assert_eq!(bedmethyl_rows.len(), bam_rows.len());
+    for (bedmethyl_row, bam_row) in bedmethyl_rows.iter().zip(bam_rows.iter()) {
+        assert_eq!(bedmethyl_row[..10], bam_row[..10]);
+        assert_eq!(bedmethyl_row[10..], [".", ".", "."]);
+    }
+    // bedMethyl input needs the modified bases
+    assert!(run_modkit(&[
+        "aggregate",
+        bgzf_fp.to_str().unwrap(),
+        "-r",
+        regions_fp.to_str().unwrap(),
+        "--ref",
+        REFERENCE,
+        "-o",
+        bedmethyl_out.to_str().unwrap(),
+        "-f",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_aggregate_tiles() {
+    let out_fp = std::env::temp_dir().join("test_aggregate_tiles.tsv");
+    run_modkit(&[
+        "aggregate",
+        BAM,
+        "--tile-size",
+        "100",
+        "--no-filtering",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "-f",
+    ])
+    .unwrap();
+    let rows = read_rows(&out_fp);
+    assert!(!rows.is_empty());
+    for row in rows {
+        let start = row[1].parse::<u64>().unwrap();
+        let end = row[2].parse::<u64>().unwrap();
+        assert_eq!(start % 100, 0);
+        assert!(end - start <= 100);
+        assert_eq!(row[3], format!("{}:{start}-{end}", row[0]));
+    }
+    // regions and tiles can't both be used
+    assert!(run_modkit(&[
+        "aggregate",
+        BAM,
+        "--tile-size",
+        "100",
+        "-r",
+        "tests/resources/some_regions.bed",
+        "-o",
+        out_fp.to_str().unwrap(),
+        "-f",
+    ])
+    .is_err());
+}
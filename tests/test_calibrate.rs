@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+
+use common::run_modkit;
+
+mod common;
+
+const MODIFIED_BAM: &str = "tests/resources/bc_anchored_10_reads.sorted.bam";
+const UNMODIFIED_BAM: &str = "tests/resources/CG_5mC_20230207_1700_6A_PAG66026_3c0abf27_oligo_741_adapters_modcalls_0th_sort_10_reads.bam";
+
+fn read_lines(fp: &PathBuf) -> Vec<String> {
+    BufReader::new(File::open(fp).unwrap())
+        .lines()
+        .map(|l| l.unwrap())
+        .collect()
+}
+
+/// (primary base, mod code, motif) to the calibrated probability of each ML
+/// value.
+fn parse_calibration(fp: &PathBuf) -> HashMap<String, Vec<f32>> {
+    let lines = read_lines(fp);
+    assert_eq!(
+        lines[0],
+        "primary_base\tmod_code\tmotif\tml_value\tn_modified\t\
+         n_unmodified\tcalibrated_prob"
+    );
+    let mut curves = HashMap::<String, Vec<f32>>::new();
+    for line in lines.iter().skip(1) {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 7, "{line}");
+        let key = parts[0..3].join(",");
+        let curve = curves.entry(key).or_default();
+        assert_eq!(parts[3].parse::<usize>().unwrap(), curve.len());
+        let prob = parts[6].parse::<f32>().unwrap();
+        assert!((0f32..=1f32).contains(&prob), "{line}");
+        curve.push(prob);
+    }
+    assert!(curves.values().all(|curve| curve.len() == 256));
+    curves
+}
+
+#[test]
+fn test_calibrate_help() {
+    run_modkit(&["calibrate", "--help"]).unwrap();
+}
+
+#[test]
+fn test_calibrate_table() {
+    let out_fp = std::env::temp_dir().join("test_calibrate_isotonic.tsv");
+    let motif_fp = std::env::temp_dir().join("test_calibrate_motif.tsv");
+    run_modkit(&[
+        "calibrate",
+        MODIFIED_BAM,
+        UNMODIFIED_BAM,
+        out_fp.to_str().unwrap(),
+        "--mod-code",
+        "m",
+        "--all-contexts",
+        "--force",
+    ])
+    .unwrap();
+    let curves = parse_calibration(&out_fp);
+    assert_eq!(curves.len(), 1);
+    let curve = curves.get("C,m,*").unwrap();
+    assert!(curve.windows(2).all(|w| w[0] <= w[1]), "{curve:?}");
+
+    run_modkit(&[
+        "calibrate",
+        MODIFIED_BAM,
+        UNMODIFIED_BAM,
+        motif_fp.to_str().unwrap(),
+        "--mod-code",
+        "m",
+        "--cpg",
+        "--method",
+        "binned",
+        "--num-bins",
+        "4",
+        "--force",
+    ])
+    .unwrap();
+    let curves = parse_calibration(&motif_fp);
+    assert_eq!(curves.len(), 1);
+    let curve = curves.get("C,m,CG,0").unwrap();
+    // 4 bins of 64 ML values each
+    for chunk in curve.chunks(64) {
+        assert!(chunk.iter().all(|p| *p == chunk[0]), "{chunk:?}");
+    }
+
+    // refuses to overwrite without --force
+    assert!(run_modkit(&[
+        "calibrate",
+        MODIFIED_BAM,
+        UNMODIFIED_BAM,
+        out_fp.to_str().unwrap(),
+        "--mod-code",
+        "m",
+    ])
+    .is_err());
+    // motifs or --all-contexts are required
+    assert!(run_modkit(&[
+        "calibrate",
+        MODIFIED_BAM,
+        UNMODIFIED_BAM,
+        out_fp.to_str().unwrap(),
+        "--mod-code",
+        "m",
+        "--force",
+    ])
+    .is_err());
+    // no calls for the mod code in the controls
+    assert!(run_modkit(&[
+        "calibrate",
+        MODIFIED_BAM,
+        UNMODIFIED_BAM,
+        out_fp.to_str().unwrap(),
+        "--mod-code",
+        "a",
+        "--all-contexts",
+        "--force",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_calibrate_adjust_mods() {
+    let calibration_fp =
+        std::env::temp_dir().join("test_calibrate_adjust_mods.tsv");
+    let adjusted_fp =
+        std::env::temp_dir().join("test_calibrate_adjust_mods.bam");
+    let before_fp =
+        std::env::temp_dir().join("test_calibrate_adjust_mods_before.tsv");
+    let after_fp =
+        std::env::temp_dir().join("test_calibrate_adjust_mods_after.tsv");
+    run_modkit(&[
+        "calibrate",
+        MODIFIED_BAM,
+        UNMODIFIED_BAM,
+        calibration_fp.to_str().unwrap(),
+        "--mod-code",
+        "m",
+        "--all-contexts",
+        "--force",
+    ])
+    .unwrap();
+    let curve = parse_calibration(&calibration_fp).remove("C,m,*").unwrap();
+    run_modkit(&[
+        "adjust-mods",
+        MODIFIED_BAM,
+        adjusted_fp.to_str().unwrap(),
+        "--calibration",
+        calibration_fp.to_str().unwrap(),
+    ])
+    .unwrap();
+    for (bam_fp, out_fp) in [
+        (MODIFIED_BAM, &before_fp),
+        (adjusted_fp.to_str().unwrap(), &after_fp),
+    ] {
+        run_modkit(&[
+            "extract",
+            bam_fp,
+            out_fp.to_str().unwrap(),
+            "--ignore-implicit",
+            "--force",
+        ])
+        .unwrap();
+    }
+
+    // (read_id, forward position) to mod code to probability
+    let parse_extract = |fp: &PathBuf| {
+        let mut probs =
+            HashMap::<(String, String), HashMap<String, f32>>::new();
+        for line in read_lines(fp).iter().skip(1) {
+            let parts = line.split('\t').collect::<Vec<&str>>();
+            probs
+                .entry((parts[0].to_string(), parts[1].to_string()))
+                .or_default()
+                .insert(parts[11].to_string(), parts[10].parse().unwrap());
+        }
+        probs
+    };
+    let before = parse_extract(&before_fp);
+    let after = parse_extract(&after_fp);
+    assert_eq!(before.len(), after.len());
+    let mut n_checked = 0usize;
+    for (site, probs) in before {
+        let adjusted = after.get(&site).unwrap();
+        let ml = (probs["m"] * 256f32).floor() as usize;
+        let calibrated_m = curve[ml];
+        // h isn't calibrated, positions that sum to more than 1 are scaled
+        let total = calibrated_m + probs["h"];
+        let (expected_m, expected_h) = if total > 1f32 {
+            (calibrated_m / total, probs["h"] / total)
+        } else {
+            (calibrated_m, probs["h"])
+        };
+        assert!((adjusted["m"] - expected_m).abs() <= 1f32 / 256f32);
+        assert!((adjusted["h"] - expected_h).abs() <= 1f32 / 256f32);
+        n_checked += 1;
+    }
+    assert!(n_checked > 0);
+}
@@ -1,9 +1,58 @@
-use crate::common::{check_against_expected_text_file, run_modkit};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use flate2::read::MultiGzDecoder;
+
+use rust_htslib::bam::{self, Read as BamRead};
+
+use crate::common::{check_against_expected_text_file, read_rows, run_modkit};
 
 mod common;
 
+/// Write a FASTA with a C at each positive-strand record and a G at each
+/// negative-strand record of CpG pileup bedMethyls, N everywhere else. For
+/// pileups made with --cpg this gives `dmr` the same positions as the full
+/// reference, without keeping the full reference in the repo.
+fn write_cpg_sites_reference(bedmethyls: &[&str], out_fp: &Path) {
+    let mut sequences = BTreeMap::<String, Vec<u8>>::new();
+    for fp in bedmethyls {
+        let reader =
+            BufReader::new(MultiGzDecoder::new(File::open(fp).unwrap()));
+        for line in reader.lines() {
+            let line = line.unwrap();
+            let parts = line.split('\t').collect::<Vec<&str>>();
+            let pos = parts[1].parse::<usize>().unwrap();
+            let base = if parts[5] == "+" { b'C' } else { b'G' };
+            let seq = sequences.entry(parts[0].to_string()).or_default();
+            if seq.len() <= pos {
+                seq.resize(pos + 1, b'N');
+            }
+            seq[pos] = base;
+        }
+    }
+    let mut writer = File::create(out_fp).unwrap();
+    for (name, seq) in sequences {
+        writeln!(writer, ">{name}").unwrap();
+        for line in seq.chunks(80) {
+            writer.write_all(line).unwrap();
+            writeln!(writer).unwrap();
+        }
+    }
+}
+
 #[test]
 fn test_dmr_regression() {
+    let reference_fp =
+        std::env::temp_dir().join("test_dmr_regression.cpg_sites.fa");
+    write_cpg_sites_reference(
+        &[
+            "tests/resources/lung_00733-m_adjacent-normal_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+            "tests/resources/lung_00733-m_primary-tumour_5mc-5hmc_chr20_cpg_pileup.bed.gz",
+        ],
+        &reference_fp,
+    );
     let out_bed = std::env::temp_dir().join("test_dmr_regression.bed");
     let _ = run_modkit( &[
         "dmr",
@@ -17,7 +66,7 @@ fn test_dmr_regression() {
         "-r",
         "tests/resources/cpg_chr20_with_orig_names_selection.bed",
         "--ref",
-        "tests/resources/GRCh38_chr20.fa",
+        reference_fp.to_str().unwrap(),
         "-f",
         "--base", "C",
         ]).expect("failed to run modkit dmr");
@@ -43,7 +92,7 @@ fn test_dmr_regression() {
         "-r",
         "tests/resources/cpg_chr20_with_orig_names_selection.bed",
         "--ref",
-        "tests/resources/GRCh38_chr20.fa",
+        reference_fp.to_str().unwrap(),
         "-f",
         "--base", "C",
     ]).expect("failed to run modkit dmr");
@@ -54,6 +103,423 @@ fn test_dmr_regression() {
     );
 }
 
+#[test]
+fn test_dmr_asm() {
+    // put all of the haplotagged reads in a single phase block
+    let phased_bam_fp = std::env::temp_dir().join("test_dmr_asm.phased.bam");
+    {
+        let mut reader = bam::Reader::from_path(
+            "tests/resources/bc_anchored_10_reads.haplotyped.sorted.bam",
+        )
+        .unwrap();
+        let header = bam::Header::from_template(reader.header());
+        let mut writer =
+            bam::Writer::from_path(&phased_bam_fp, &header, bam::Format::Bam)
+                .unwrap();
+        for record in reader.records() {
+            let mut record = record.unwrap();
+            if record.aux(b"HP").is_ok() {
+                record.push_aux(b"PS", bam::record::Aux::I32(1000)).unwrap();
+            }
+            writer.write(&record).unwrap();
+        }
+    }
+    bam::index::build(&phased_bam_fp, None, bam::index::Type::Bai, 1).unwrap();
+
+    let sites_fp = std::env::temp_dir().join("test_dmr_asm.sites.bed");
+    run_modkit(&[
+        "dmr",
+        "asm",
+        phased_bam_fp.to_str().unwrap(),
+        "-o",
+        sites_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--no-filtering",
+        "-f",
+    ])
+    .unwrap();
+    let site_rows = read_rows(&sites_fp);
+    assert!(!site_rows.is_empty());
+    // span of the scored sites on each contig
+    let mut spans = BTreeMap::new();
+    for row in site_rows.iter() {
+        assert_eq!(row.len(), 15, "{row:?}");
+        assert_eq!(row[14], "1000");
+        let p_value = row[11].parse::<f64>().unwrap();
+        let q_value = row[12].parse::<f64>().unwrap();
+        assert!((0f64..=1f64).contains(&p_value), "{row:?}");
+        assert!(q_value >= p_value && q_value <= 1f64, "{row:?}");
+        // the haplotypes have the same reads in this test data
+        assert!(row[13].split(',').all(|x| x.ends_with(":0.00")), "{row:?}");
+        let start = row[1].parse::<u64>().unwrap();
+        let stop = row[2].parse::<u64>().unwrap();
+        assert_eq!(stop, start + 1);
+        assert_eq!(row[3], format!("{}:{}-{}", row[0], start, stop));
+        let span = spans.entry(row[0].clone()).or_insert((start, stop));
+        span.0 = span.0.min(start);
+        span.1 = span.1.max(stop);
+    }
+
+    let regions_fp = std::env::temp_dir().join("test_dmr_asm.regions.bed");
+    {
+        let mut fh = File::create(&regions_fp).unwrap();
+        for (chrom, (start, stop)) in spans.iter() {
+            writeln!(fh, "{chrom}\t{start}\t{stop}\t{chrom}_region").unwrap();
+        }
+    }
+    let regions_out_fp =
+        std::env::temp_dir().join("test_dmr_asm.regions.out.bed");
+    run_modkit(&[
+        "dmr",
+        "asm",
+        phased_bam_fp.to_str().unwrap(),
+        "-o",
+        regions_out_fp.to_str().unwrap(),
+        "-r",
+        regions_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--no-filtering",
+        "-f",
+    ])
+    .unwrap();
+    let region_rows = read_rows(&regions_out_fp);
+    assert_eq!(region_rows.len(), spans.len());
+    for row in region_rows {
+        assert_eq!(row.len(), 15, "{row:?}");
+        assert_eq!(row[3], format!("{}_region", row[0]));
+        assert_eq!(row[14], "1000");
+        let score = row[4].parse::<f64>().unwrap();
+        assert!(score.is_finite());
+    }
+}
+
+#[test]
+fn test_dmr_pair_p_values() {
+    let bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let a_fp = std::env::temp_dir().join("test_dmr_pair_p_values.a.bed.gz");
+    let b_fp = std::env::temp_dir().join("test_dmr_pair_p_values.b.bed.gz");
+    for (fp, extra_args) in [
+        (&a_fp, vec!["--no-filtering"]),
+        (&b_fp, vec!["--filter-threshold", "0.9"]),
+    ] {
+        let mut args = vec![
+            "pileup",
+            bam,
+            fp.to_str().unwrap(),
+            "--bgzf",
+            "--cpg",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        ];
+        args.extend(extra_args);
+        run_modkit(&args).unwrap();
+    }
+    let out_fp = std::env::temp_dir().join("test_dmr_pair_p_values.bed");
+    run_modkit(&[
+        "dmr",
+        "pair",
+        "-a",
+        a_fp.to_str().unwrap(),
+        "-b",
+        b_fp.to_str().unwrap(),
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--base",
+        "C",
+        "-f",
+    ])
+    .unwrap();
+
+    let mut stats = BufReader::new(File::open(&out_fp).unwrap())
+        .lines()
+        .map(|l| {
+            let line = l.unwrap();
+            let parts = line.split('\t').collect::<Vec<&str>>();
+            assert_eq!(parts.len(), 14, "{line}");
+            let p_value = parts[11].parse::<f64>().unwrap();
+            let q_value = parts[12].parse::<f64>().unwrap();
+            assert!(p_value > 0f64 && p_value <= 1f64, "{line}");
+            assert!(q_value >= p_value && q_value <= 1f64, "{line}");
+            (p_value, q_value)
+        })
+        .collect::<Vec<(f64, f64)>>();
+    assert!(!stats.is_empty());
+    // q-values are monotonic in the p-values
+    stats.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    assert!(stats.windows(2).all(|w| w[0].1 <= w[1].1));
+}
+
+#[test]
+fn test_dmr_pair_segmentation() {
+    let bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let a_fp = std::env::temp_dir().join("test_dmr_pair_segment.a.bed.gz");
+    let b_fp = std::env::temp_dir().join("test_dmr_pair_segment.b.bed.gz");
+    // ignoring 5hmC in the second sample makes the samples different
+    for (fp, extra_args) in [(&a_fp, vec![]), (&b_fp, vec!["--ignore", "h"])] {
+        let mut args = vec![
+            "pileup",
+            bam,
+            fp.to_str().unwrap(),
+            "--bgzf",
+            "--cpg",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--no-filtering",
+        ];
+        args.extend(extra_args);
+        run_modkit(&args).unwrap();
+    }
+    let sites_fp = std::env::temp_dir().join("test_dmr_pair_segment.sites.bed");
+    let regions_fp =
+        std::env::temp_dir().join("test_dmr_pair_segment.regions.bed");
+    for (fp, extra_args) in
+        [(&sites_fp, vec![]), (&regions_fp, vec!["--segment"])]
+    {
+        let mut args = vec![
+            "dmr",
+            "pair",
+            "-a",
+            a_fp.to_str().unwrap(),
+            "-b",
+            b_fp.to_str().unwrap(),
+            "-o",
+            fp.to_str().unwrap(),
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--base",
+            "C",
+            "-f",
+        ];
+        args.extend(extra_args);
+        run_modkit(&args).unwrap();
+    }
+
+    let sites = read_rows(&sites_fp);
+    let regions = read_rows(&regions_fp);
+    for row in sites.iter().chain(regions.iter()) {
+        assert_eq!(row.len(), 14, "{row:?}");
+    }
+    assert!(!regions.is_empty());
+    assert!(regions.len() < sites.len());
+    for region in regions {
+        let start = region[1].parse::<u64>().unwrap();
+        let stop = region[2].parse::<u64>().unwrap();
+        assert_eq!(region[3], format!("{}:{}-{}", region[0], start, stop));
+        // the region counts are the sum of the counts of the sites in it
+        let sites_in_region = sites
+            .iter()
+            .filter(|site| {
+                let site_start = site[1].parse::<u64>().unwrap();
+                site[0] == region[0] && site_start >= start && site_start < stop
+            })
+            .collect::<Vec<&Vec<String>>>();
+        assert!(sites_in_region.len() >= 3);
+        for total_idx in [6, 8] {
+            let sites_total = sites_in_region
+                .iter()
+                .map(|site| site[total_idx].parse::<u64>().unwrap())
+                .sum::<u64>();
+            assert_eq!(
+                region[total_idx].parse::<u64>().unwrap(),
+                sites_total,
+                "{region:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_dmr_group_replicates() {
+    let bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let pileups = [
+        ("a1", vec![]),
+        ("a2", vec!["--filter-threshold", "0.7"]),
+        ("b1", vec!["--ignore", "h"]),
+        ("b2", vec!["--ignore", "h", "--filter-threshold", "0.7"]),
+    ]
+    .into_iter()
+    .map(|(name, extra_args)| {
+        let fp = std::env::temp_dir()
+            .join(format!("test_dmr_group_replicates.{name}.bed.gz"));
+        let mut args = vec![
+            "pileup",
+            bam,
+            fp.to_str().unwrap(),
+            "--bgzf",
+            "--cpg",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        ];
+        if extra_args.iter().all(|a| *a != "--filter-threshold") {
+            args.push("--no-filtering");
+        }
+        args.extend(extra_args);
+        run_modkit(&args).unwrap();
+        fp.to_str().unwrap().to_string()
+    })
+    .collect::<Vec<String>>();
+    let out_fp = std::env::temp_dir().join("test_dmr_group_replicates.bed");
+    run_modkit(&[
+        "dmr",
+        "group",
+        "-a",
+        &pileups[0],
+        "-a",
+        &pileups[1],
+        "-b",
+        &pileups[2],
+        "-b",
+        &pileups[3],
+        "-o",
+        out_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--base",
+        "C",
+        "-f",
+    ])
+    .unwrap();
+
+    let rows = BufReader::new(File::open(&out_fp).unwrap())
+        .lines()
+        .map(|l| {
+            l.unwrap()
+                .split('\t')
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>()
+        })
+        .collect::<Vec<Vec<String>>>();
+    assert!(!rows.is_empty());
+    let mut n_significant = 0usize;
+    for row in rows.iter() {
+        assert_eq!(row.len(), 17, "{row:?}");
+        // the score is the log likelihood ratio of the pooled counts
+        let score = row[4].parse::<f64>().unwrap();
+        let p_value = row[11].parse::<f64>().unwrap();
+        let q_value = row[12].parse::<f64>().unwrap();
+        assert!(p_value > 0f64 && p_value <= 1f64, "{row:?}");
+        assert!(q_value >= p_value && q_value <= 1f64, "{row:?}");
+        if score <= 0f64 {
+            assert_eq!(p_value, 1f64, "{row:?}");
+        }
+        for dispersion in row[14].split(',') {
+            let (_code, rho) = dispersion.split_once(':').unwrap();
+            let rho = rho.parse::<f64>().unwrap();
+            assert!((0f64..=1f64).contains(&rho), "{row:?}");
+        }
+        let statistic = row[15].parse::<f64>().unwrap();
+        let replicate_p_value = row[16].parse::<f64>().unwrap();
+        assert!(statistic >= 0f64, "{row:?}");
+        assert!(
+            replicate_p_value > 0f64 && replicate_p_value <= 1f64,
+            "{row:?}"
+        );
+        if replicate_p_value < 0.05 {
+            n_significant += 1;
+        }
+    }
+    assert!(n_significant > 0);
+}
+
+#[test]
+fn test_dmr_bam() {
+    let bam_fp = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let ref_fp = "tests/resources/CGI_ladder_3.6kb_ref.fa";
+    // one region per contig
+    let regions_fp = std::env::temp_dir().join("test_dmr_bam.regions.bed");
+    {
+        let mut writer = File::create(&regions_fp).unwrap();
+        let fai = BufReader::new(File::open(format!("{ref_fp}.fai")).unwrap());
+        for line in fai.lines() {
+            let line = line.unwrap();
+            let parts = line.split('\t').collect::<Vec<&str>>();
+            writeln!(writer, "{}\t0\t{}\t{}", parts[0], parts[1], parts[0])
+                .unwrap();
+        }
+    }
+    let pileup_fp = std::env::temp_dir().join("test_dmr_bam.pileup.bed");
+    run_modkit(&[
+        "pileup",
+        bam_fp,
+        pileup_fp.to_str().unwrap(),
+        "--ref",
+        ref_fp,
+        "--cpg",
+        "--no-filtering",
+    ])
+    .unwrap();
+    // valid coverage is repeated for each mod code at a position
+    let mut expected_totals = BTreeMap::new();
+    for line in BufReader::new(File::open(&pileup_fp).unwrap()).lines() {
+        let line = line.unwrap();
+        let parts = line.split_whitespace().collect::<Vec<&str>>();
+        if parts[3] == "m" {
+            *expected_totals
+                .entry(parts[0].to_string())
+                .or_insert(0usize) += parts[9].parse::<usize>().unwrap();
+        }
+    }
+    assert!(!expected_totals.is_empty());
+
+    let run_dmr = |name: &str, a: &[&str], b: &[&str]| {
+        let out_fp = std::env::temp_dir().join(format!("test_dmr_bam.{name}"));
+        let mut args = vec!["dmr", "bam"];
+        for fp in a {
+            args.extend_from_slice(&["-a", fp]);
+        }
+        for fp in b {
+            args.extend_from_slice(&["-b", fp]);
+        }
+        args.extend_from_slice(&[
+            "-r",
+            regions_fp.to_str().unwrap(),
+            "--ref",
+            ref_fp,
+            "--cpg",
+            "--no-filtering",
+            "-o",
+            out_fp.to_str().unwrap(),
+            "-f",
+        ]);
+        run_modkit(&args).unwrap();
+        BufReader::new(File::open(&out_fp).unwrap())
+            .lines()
+            .map(|l| {
+                l.unwrap()
+                    .split('\t')
+                    .map(|x| x.to_string())
+                    .collect::<Vec<String>>()
+            })
+            .collect::<Vec<Vec<String>>>()
+    };
+
+    let rows = run_dmr("same.bed", &[bam_fp], &[bam_fp]);
+    assert_eq!(rows.len(), expected_totals.len());
+    for row in rows.iter() {
+        assert_eq!(row.len(), 14, "{row:?}");
+        let a_total = row[6].parse::<usize>().unwrap();
+        let b_total = row[8].parse::<usize>().unwrap();
+        assert_eq!(a_total, expected_totals[&row[0]], "{row:?}");
+        assert_eq!(a_total, b_total);
+        assert_eq!(row[5], row[7]);
+        assert!(row[13].split(',').all(|x| x.ends_with(":0.00")), "{row:?}");
+    }
+
+    // reads from multiple BAMs are pooled into a sample
+    let rows = run_dmr("pooled.bed", &[bam_fp, bam_fp], &[bam_fp]);
+    for row in rows.iter() {
+        let a_total = row[6].parse::<usize>().unwrap();
+        let b_total = row[8].parse::<usize>().unwrap();
+        assert_eq!(a_total, b_total * 2, "{row:?}");
+    }
+}
+
 // todo
 //  test pair with explicit index
 //  test multi
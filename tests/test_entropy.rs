@@ -0,0 +1,53 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::common::run_modkit;
+
+mod common;
+
+#[test]
+fn test_entropy_help() {
+    let entropy_help_args = ["entropy", "--help"];
+    let _out = run_modkit(&entropy_help_args).unwrap();
+}
+
+#[test]
+fn test_entropy_cpg_windows() {
+    let temp_file = std::env::temp_dir().join("test_entropy_cpg_windows.bed");
+    let args = [
+        "entropy",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        temp_file.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--num-positions",
+        "4",
+        "--min-coverage",
+        "2",
+        "--no-filtering",
+    ];
+    run_modkit(&args).unwrap();
+
+    let reader = BufReader::new(File::open(temp_file).unwrap());
+    let mut n_rows = 0usize;
+    for line in reader.lines().map(|l| l.unwrap()) {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 7, "{line}");
+        let start = parts[1].parse::<u32>().unwrap();
+        let end = parts[2].parse::<u32>().unwrap();
+        assert!(start < end);
+        let entropy = parts[3].parse::<f32>().unwrap();
+        assert!((0f32..=1f32).contains(&entropy), "{line}");
+        assert!(parts[4] == "+" || parts[4] == "-");
+        let num_reads = parts[5].parse::<u32>().unwrap();
+        let num_patterns = parts[6].parse::<u32>().unwrap();
+        assert!(num_reads >= 2);
+        assert!(num_patterns >= 1 && num_patterns <= num_reads);
+        if num_patterns == 1 {
+            assert_eq!(entropy, 0f32);
+        }
+        n_rows += 1;
+    }
+    assert!(n_rows > 0);
+}
@@ -1,5 +1,5 @@
 use crate::common::{
-    check_against_expected_text_file, parse_mod_profile, ModData,
+    check_against_expected_text_file, parse_mod_profile, read_rows, ModData,
 };
 use anyhow::{anyhow, Context};
 use common::run_modkit;
@@ -430,3 +430,543 @@ fn test_extract_calls_regression() {
         "tests/resources/test_read_calls_estimate_thresh.tsv",
     );
 }
+
+#[test]
+fn test_extract_record_filters() {
+    let read_lengths = |fp: &PathBuf| {
+        BufReader::new(File::open(fp).unwrap())
+            .lines()
+            .skip(1)
+            .map(|l| {
+                let line = l.unwrap();
+                let parts = line.split('\t').collect::<Vec<&str>>();
+                (parts[0].to_string(), parts[9].parse::<usize>().unwrap())
+            })
+            .collect::<HashMap<String, usize>>()
+    };
+    let all_fp = std::env::temp_dir().join("test_extract_filters_all.tsv");
+    run_modkit(&[
+        "extract",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        all_fp.to_str().unwrap(),
+        "--force",
+    ])
+    .unwrap();
+    let all_reads = read_lengths(&all_fp);
+    assert_eq!(all_reads.len(), 10);
+
+    let filtered_fp =
+        std::env::temp_dir().join("test_extract_filters_lengths.tsv");
+    run_modkit(&[
+        "extract",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        filtered_fp.to_str().unwrap(),
+        "--min-read-length",
+        "140",
+        "--max-read-length",
+        "200",
+        "--force",
+    ])
+    .unwrap();
+    let filtered_reads = read_lengths(&filtered_fp);
+    let expected = all_reads
+        .into_iter()
+        .filter(|(_, length)| (140..=200).contains(length))
+        .collect::<HashMap<String, usize>>();
+    assert!(!expected.is_empty());
+    assert_eq!(filtered_reads, expected);
+}
+
+#[test]
+fn test_extract_transcriptome_projection() {
+    use rust_htslib::bam::{
+        self,
+        header::HeaderRecord,
+        record::{Aux, AuxArray, Cigar, CigarString},
+    };
+    use std::io::Write;
+
+    let annotation_fp =
+        std::env::temp_dir().join("test_extract_transcriptome.gff3");
+    {
+        let mut fh = File::create(&annotation_fp).unwrap();
+        writeln!(fh, "##gff-version 3").unwrap();
+        writeln!(fh, "chr1\ttest\tgene\t301\t320\t.\t-\t.\tID=gene:g1")
+            .unwrap();
+        writeln!(
+            fh,
+            "chr1\ttest\tmRNA\t301\t320\t.\t-\t.\tID=transcript:tx1;\
+             Parent=gene:g1"
+        )
+        .unwrap();
+        for (start, end) in [(301, 305), (316, 320)] {
+            writeln!(
+                fh,
+                "chr1\ttest\texon\t{start}\t{end}\t.\t-\t.\t\
+                 Parent=transcript:tx1"
+            )
+            .unwrap();
+        }
+    }
+    let bam_fp = std::env::temp_dir().join("test_extract_transcriptome.bam");
+    {
+        let mut header = bam::Header::new();
+        let mut sq = HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "tx1");
+        sq.push_tag(b"LN", 10);
+        header.push_record(&sq);
+        let mut writer =
+            bam::Writer::from_path(&bam_fp, &header, bam::Format::Bam).unwrap();
+        let mut record = bam::Record::new();
+        let cigar = CigarString(vec![Cigar::Match(8)]);
+        record.set(b"read", Some(&cigar), b"TTAAAATT", &[30; 8]);
+        record.set_tid(0);
+        record.set_pos(1);
+        record.set_mapq(60);
+        // calls on the A at transcript positions 3 and 6
+        record.push_aux(b"MM", Aux::String("A+a?,0,2;")).unwrap();
+        let ml_probs = vec![230u8, 20u8];
+        let ml: AuxArray<u8> = (&ml_probs).into();
+        record.push_aux(b"ML", Aux::ArrayU8(ml)).unwrap();
+        writer.write(&record).unwrap();
+    }
+    bam::index::build(&bam_fp, None, bam::index::Type::Bai, 1).unwrap();
+
+    let out_fp = std::env::temp_dir().join("test_extract_transcriptome.tsv");
+    run_modkit(&[
+        "extract",
+        bam_fp.to_str().unwrap(),
+        out_fp.to_str().unwrap(),
+        "--annotation",
+        annotation_fp.to_str().unwrap(),
+        "--force",
+    ])
+    .unwrap();
+    let mut lines =
+        BufReader::new(File::open(&out_fp).unwrap())
+            .lines()
+            .map(|l| {
+                l.unwrap()
+                    .split('\t')
+                    .map(|x| x.to_string())
+                    .collect::<Vec<String>>()
+            });
+    let header = lines.next().unwrap();
+    assert_eq!(
+        &header[header.len() - 5..],
+        &[
+            "genome_chrom",
+            "genome_position",
+            "genome_ref_mod_strand",
+            "gene_id",
+            "transcript_id"
+        ]
+    );
+    let rows = lines
+        .map(|row| {
+            assert_eq!(row.len(), header.len());
+            (row[2].clone(), row[row.len() - 5..].join(","))
+        })
+        .collect::<Vec<(String, String)>>();
+    // exons are [300, 305) and [315, 320) on the - strand, so transcript
+    // position 3 is at 316 and 6 is at 303
+    let expected = [("3", "chr1,316,-,g1,tx1"), ("6", "chr1,303,-,g1,tx1")]
+        .into_iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect::<Vec<(String, String)>>();
+    assert_eq!(rows, expected);
+}
+
+#[test]
+fn test_extract_parquet_output() {
+    use arrow::util::display::array_value_to_string;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let read_parquet = |fp: &PathBuf| {
+        let reader =
+            ParquetRecordBatchReaderBuilder::try_new(File::open(fp).unwrap())
+                .unwrap()
+                .build()
+                .unwrap();
+        let mut rows = Vec::new();
+        for batch in reader {
+            let batch = batch.unwrap();
+            for i in 0..batch.num_rows() {
+                let row = batch
+                    .columns()
+                    .iter()
+                    .map(|column| {
+                        let value = array_value_to_string(column, i).unwrap();
+                        // nulls are written as '.' in the TSV
+                        if value.is_empty() {
+                            ".".to_string()
+                        } else {
+                            value
+                        }
+                    })
+                    .collect::<Vec<String>>();
+                rows.push(row);
+            }
+        }
+        rows
+    };
+    let read_tsv = |fp: &PathBuf| {
+        BufReader::new(File::open(fp).unwrap())
+            .lines()
+            .skip(1)
+            .map(|l| {
+                l.unwrap()
+                    .split('\t')
+                    .map(|x| x.to_string())
+                    .collect::<Vec<String>>()
+            })
+            .collect::<Vec<Vec<String>>>()
+    };
+    let sort_rows = |mut rows: Vec<Vec<String>>| {
+        rows.sort();
+        rows
+    };
+
+    let tsv_fp = std::env::temp_dir().join("test_extract_parquet.tsv");
+    let calls_tsv_fp =
+        std::env::temp_dir().join("test_extract_parquet_calls.tsv");
+    run_modkit(&[
+        "extract",
+        bam,
+        tsv_fp.to_str().unwrap(),
+        "--read-calls",
+        calls_tsv_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--filter-threshold",
+        "0.7",
+        "--force",
+    ])
+    .unwrap();
+    let parquet_fp = std::env::temp_dir().join("test_extract.parquet");
+    let calls_parquet_fp =
+        std::env::temp_dir().join("test_extract_calls.parquet");
+    run_modkit(&[
+        "extract",
+        bam,
+        parquet_fp.to_str().unwrap(),
+        "--read-calls",
+        calls_parquet_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--filter-threshold",
+        "0.7",
+        "--force",
+        "--parquet",
+    ])
+    .unwrap();
+
+    let expected = sort_rows(read_tsv(&tsv_fp));
+    let rows = sort_rows(read_parquet(&parquet_fp));
+    assert!(!rows.is_empty());
+    assert_eq!(rows.len(), expected.len());
+    for (row, expected_row) in rows.iter().zip(expected.iter()) {
+        assert_eq!(row.len(), 18);
+        for (i, (value, expected_value)) in
+            row.iter().zip(expected_row.iter()).enumerate()
+        {
+            // mod_qual
+            if i == 10 {
+                let diff = value.parse::<f32>().unwrap()
+                    - expected_value.parse::<f32>().unwrap();
+                assert!(diff.abs() < 1e-6);
+            } else {
+                assert_eq!(value, expected_value);
+            }
+        }
+    }
+
+    let expected = sort_rows(read_tsv(&calls_tsv_fp));
+    let rows = sort_rows(read_parquet(&calls_parquet_fp));
+    assert!(!rows.is_empty());
+    assert_eq!(rows.len(), expected.len());
+    for (row, expected_row) in rows.iter().zip(expected.iter()) {
+        assert_eq!(row.len(), 20);
+        assert_eq!(&row[..10], &expected_row[..10]);
+        assert_eq!(&row[11..], &expected_row[11..]);
+    }
+}
+
+#[test]
+fn test_extract_tag_columns() {
+    use arrow::util::display::array_value_to_string;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use rust_htslib::bam::{self, Read};
+
+    let bam_fp = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let mut reader = bam::Reader::from_path(bam_fp).unwrap();
+    let expected = reader
+        .records()
+        .map(|r| r.unwrap())
+        .map(|record| {
+            let read_id = String::from_utf8(record.qname().to_vec()).unwrap();
+            let nm = match record.aux(b"NM").unwrap() {
+                bam::record::Aux::U8(x) => x as u32,
+                bam::record::Aux::U16(x) => x as u32,
+                bam::record::Aux::U32(x) => x,
+                aux => panic!("unexpected NM type {aux:?}"),
+            };
+            (read_id, (nm.to_string(), record.mapq(), record.flags()))
+        })
+        .collect::<HashMap<String, (String, u8, u16)>>();
+
+    let out_fp = std::env::temp_dir().join("test_extract_tag_columns.tsv");
+    let calls_fp =
+        std::env::temp_dir().join("test_extract_tag_columns_calls.tsv");
+    run_modkit(&[
+        "extract",
+        bam_fp,
+        out_fp.to_str().unwrap(),
+        "--read-calls",
+        calls_fp.to_str().unwrap(),
+        "--tag",
+        "NM",
+        "--tag",
+        "HP",
+        "--tag",
+        "MAPQ",
+        "--tag",
+        "FLAG",
+        "--filter-threshold",
+        "0.7",
+        "--force",
+    ])
+    .unwrap();
+
+    for (fp, n_columns) in [(out_fp, 18usize), (calls_fp, 20usize)] {
+        let mut lines = BufReader::new(File::open(fp).unwrap())
+            .lines()
+            .map(|l| l.unwrap());
+        let header = lines.next().unwrap();
+        let header = header.split('\t').collect::<Vec<&str>>();
+        assert_eq!(header.len(), n_columns + 4);
+        assert_eq!(&header[n_columns..], &["NM", "HP", "MAPQ", "FLAG"]);
+        let mut n_rows = 0usize;
+        for line in lines {
+            let parts = line.split('\t').collect::<Vec<&str>>();
+            assert_eq!(parts.len(), n_columns + 4, "{line}");
+            let (nm, mapq, flag) = &expected[parts[0]];
+            assert_eq!(parts[n_columns], nm, "{line}");
+            // no reads in this BAM are haplotagged
+            assert_eq!(parts[n_columns + 1], ".", "{line}");
+            assert_eq!(parts[n_columns + 2], mapq.to_string(), "{line}");
+            assert_eq!(parts[n_columns + 3], flag.to_string(), "{line}");
+            n_rows += 1;
+        }
+        assert!(n_rows > 0);
+    }
+
+    let parquet_fp =
+        std::env::temp_dir().join("test_extract_tag_columns.parquet");
+    run_modkit(&[
+        "extract",
+        bam_fp,
+        parquet_fp.to_str().unwrap(),
+        "--tag",
+        "NM",
+        "--tag",
+        "HP",
+        "--tag",
+        "MAPQ",
+        "--parquet",
+        "--force",
+    ])
+    .unwrap();
+    let reader = ParquetRecordBatchReaderBuilder::try_new(
+        File::open(parquet_fp).unwrap(),
+    )
+    .unwrap()
+    .build()
+    .unwrap();
+    for batch in reader {
+        let batch = batch.unwrap();
+        let schema = batch.schema();
+        let n_fields = schema.fields().len();
+        assert_eq!(schema.field(n_fields - 3).name(), "NM");
+        assert_eq!(schema.field(n_fields - 2).name(), "HP");
+        assert_eq!(schema.field(n_fields - 1).name(), "MAPQ");
+        let read_ids = batch.column(0);
+        let nm = batch.column(n_fields - 3);
+        let mapq = batch.column(n_fields - 1);
+        for i in 0..batch.num_rows() {
+            let read_id = array_value_to_string(read_ids, i).unwrap();
+            let (expected_nm, expected_mapq, _) = &expected[&read_id];
+            assert_eq!(&array_value_to_string(nm, i).unwrap(), expected_nm);
+            assert_eq!(
+                array_value_to_string(mapq, i).unwrap(),
+                expected_mapq.to_string()
+            );
+        }
+        assert_eq!(batch.column(n_fields - 2).null_count(), batch.num_rows());
+    }
+
+    assert!(run_modkit(&[
+        "extract", bam_fp, "null", "--tag", "NM", "--tag", "NM",
+    ])
+    .is_err());
+    assert!(run_modkit(&[
+        "extract", bam_fp, "null", "--tag", "MAPQ", "--tag", "MAPQ",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_extract_read_summary() {
+    let bam_fp = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let summary_fp = std::env::temp_dir().join("test_extract_read_summary.tsv");
+    let calls_fp =
+        std::env::temp_dir().join("test_extract_read_summary_calls.tsv");
+    run_modkit(&[
+        "extract",
+        bam_fp,
+        "null",
+        "--read-calls",
+        calls_fp.to_str().unwrap(),
+        "--read-summary",
+        summary_fp.to_str().unwrap(),
+        "--tag",
+        "NM",
+        "--filter-threshold",
+        "0.7",
+        "--force",
+    ])
+    .unwrap();
+
+    // (read_id, call_code) to number of calls, number of passing calls per
+    // read
+    let mut expected_n_mod = HashMap::<(String, String), usize>::new();
+    let mut expected_n_pass = HashMap::<String, usize>::new();
+    let mut lines = BufReader::new(File::open(calls_fp).unwrap())
+        .lines()
+        .map(|l| l.unwrap());
+    let _header = lines.next().unwrap();
+    for line in lines {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        let read_id = parts[0].to_string();
+        if parts[17] == "false" {
+            *expected_n_pass.entry(read_id.clone()).or_insert(0) += 1;
+            *expected_n_mod
+                .entry((read_id, parts[11].to_string()))
+                .or_insert(0) += 1;
+        }
+    }
+
+    let mut lines = BufReader::new(File::open(summary_fp).unwrap())
+        .lines()
+        .map(|l| l.unwrap());
+    let header = lines.next().unwrap();
+    assert_eq!(
+        header,
+        "read_id\tchrom\tref_start\tref_end\tref_strand\tread_length\t\
+         mod_codes\tn_calls\tmean_prob\tn_pass\tn_mod\tfrac_modified\tNM"
+    );
+    let mut n_rows = 0usize;
+    for line in lines {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 13, "{line}");
+        let read_id = parts[0].to_string();
+        assert_eq!(parts[1], "oligo_1512_adapters");
+        let ref_start = parts[2].parse::<i64>().unwrap();
+        let ref_end = parts[3].parse::<i64>().unwrap();
+        assert!(ref_start >= 0 && ref_start < ref_end, "{line}");
+        assert!(parts[4] == "+" || parts[4] == "-");
+        let list = |idx: usize| parts[idx].split(',').collect::<Vec<&str>>();
+        let mod_codes = list(6);
+        assert_eq!(mod_codes, vec!["h", "m"]);
+        let n_calls = list(7);
+        let mean_probs = list(8);
+        let n_pass = list(9);
+        let n_mod = list(10);
+        let frac_modified = list(11);
+        for (i, code) in mod_codes.iter().enumerate() {
+            let n_calls = n_calls[i].parse::<usize>().unwrap();
+            let n_pass = n_pass[i].parse::<usize>().unwrap();
+            let n_mod = n_mod[i].parse::<usize>().unwrap();
+            assert!(n_pass <= n_calls && n_mod <= n_pass, "{line}");
+            // every C position has a probability for both h and m
+            assert_eq!(n_pass, expected_n_pass[&read_id], "{line}");
+            let expected = expected_n_mod
+                .get(&(read_id.clone(), code.to_string()))
+                .copied()
+                .unwrap_or(0);
+            assert_eq!(n_mod, expected, "{line}");
+            let mean_prob = mean_probs[i].parse::<f32>().unwrap();
+            assert!((0f32..=1f32).contains(&mean_prob));
+            let frac = frac_modified[i].parse::<f32>().unwrap();
+            // written with 4 decimal places
+            assert!((frac - n_mod as f32 / n_pass as f32).abs() <= 5e-5);
+        }
+        n_rows += 1;
+    }
+    assert_eq!(n_rows, 10);
+}
+
+#[test]
+fn test_extract_bed12() {
+    let bam_fp = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let bed_fp = std::env::temp_dir().join("test_extract_bed12.bed");
+    let split_fp = std::env::temp_dir().join("test_extract_bed12_split.bed");
+    for (out_fp, split) in [(&bed_fp, false), (&split_fp, true)] {
+        let mut args = vec![
+            "extract",
+            bam_fp,
+            "null",
+            "--bed12",
+            out_fp.to_str().unwrap(),
+            "--cpg",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+            "--filter-threshold",
+            "0.7",
+            "--force",
+        ];
+        if split {
+            args.push("--bed12-split-states");
+        }
+        run_modkit(&args).unwrap();
+    }
+
+    let mut n_blocks = HashMap::<String, usize>::new();
+    let rows = read_rows(&bed_fp);
+    assert_eq!(rows.len(), 10);
+    for parts in rows {
+        assert_eq!(parts.len(), 12, "{parts:?}");
+        assert_eq!(parts[0], "oligo_1512_adapters");
+        let start = parts[1].parse::<i64>().unwrap();
+        let end = parts[2].parse::<i64>().unwrap();
+        assert_eq!((&parts[6], &parts[7]), (&parts[1], &parts[2]));
+        let score = parts[4].parse::<u32>().unwrap();
+        assert!(score <= 1000, "{parts:?}");
+        let block_count = parts[9].parse::<usize>().unwrap();
+        let sizes = parts[10].split(',').collect::<Vec<&str>>();
+        let starts = parts[11]
+            .split(',')
+            .map(|x| x.parse::<i64>().unwrap())
+            .collect::<Vec<i64>>();
+        assert_eq!(sizes.len(), block_count);
+        assert!(sizes.iter().all(|s| *s == "1"));
+        assert_eq!(starts.len(), block_count);
+        assert_eq!(starts[0], 0);
+        assert_eq!(*starts.last().unwrap() + 1, end - start);
+        assert!(starts.windows(2).all(|w| w[0] < w[1]));
+        n_blocks.insert(parts[3].to_string(), block_count);
+    }
+
+    // the modified and canonical features of each read have all of its sites
+    let mut n_split_blocks = HashMap::<String, usize>::new();
+    for parts in read_rows(&split_fp) {
+        match (parts[4].as_str(), parts[8].as_str()) {
+            ("1000", "215,48,39") | ("0", "69,117,180") => {}
+            _ => panic!("unexpected score and color {parts:?}"),
+        }
+        *n_split_blocks.entry(parts[3].to_string()).or_insert(0) +=
+            parts[9].parse::<usize>().unwrap();
+    }
+    assert_eq!(n_blocks, n_split_blocks);
+}
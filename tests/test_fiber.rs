@@ -0,0 +1,153 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::{self, Read};
+
+use crate::common::run_modkit;
+
+mod common;
+
+const FIBER_BAM: &str = "tests/resources/2_reads_all_context.bam";
+
+#[test]
+fn test_fiber_help() {
+    run_modkit(&["fiber", "--help"]).unwrap();
+}
+
+fn get_u32_array(record: &bam::Record, tag: &[u8]) -> Vec<u32> {
+    match record.aux(tag) {
+        Ok(Aux::ArrayU32(arr)) => arr.iter().collect(),
+        Ok(aux) => panic!("unexpected aux {aux:?}"),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[test]
+fn test_fiber_bam_tags() {
+    let out_bam = std::env::temp_dir().join("test_fiber_bam_tags.bam");
+    run_modkit(&[
+        "fiber",
+        FIBER_BAM,
+        out_bam.to_str().unwrap(),
+        "--no-filtering",
+        "--nucleosome-length",
+        "20",
+        "--max-nucleosome-length",
+        "60",
+        "--min-at-bases",
+        "10",
+    ])
+    .unwrap();
+
+    let mut reader = bam::Reader::from_path(out_bam).unwrap();
+    let mut n_records = 0usize;
+    let mut n_nucleosomes = 0usize;
+    for record in reader.records().map(|r| r.unwrap()) {
+        let nuc_starts = get_u32_array(&record, b"ns");
+        let nuc_lengths = get_u32_array(&record, b"nl");
+        let msp_starts = get_u32_array(&record, b"as");
+        let msp_lengths = get_u32_array(&record, b"al");
+        assert_eq!(nuc_starts.len(), nuc_lengths.len());
+        assert_eq!(msp_starts.len(), msp_lengths.len());
+        assert!(nuc_lengths.iter().all(|l| (20..=60).contains(l)));
+        let forward_seq = if record.is_reverse() {
+            bio::alphabets::dna::revcomp(record.seq().as_bytes())
+        } else {
+            record.seq().as_bytes()
+        };
+        for (start, length) in nuc_starts.iter().zip(nuc_lengths.iter()) {
+            let n_at = forward_seq[*start as usize..(start + length) as usize]
+                .iter()
+                .filter(|b| matches!(b, b'A' | b'T'))
+                .count();
+            assert!(n_at >= 10);
+        }
+        // MSPs are between consecutive nucleosomes
+        assert!(msp_starts.len() < nuc_starts.len().max(1));
+        for (start, length) in msp_starts.iter().zip(msp_lengths.iter()) {
+            let i = nuc_starts
+                .iter()
+                .zip(nuc_lengths.iter())
+                .position(|(s, l)| s + l == *start)
+                .unwrap();
+            assert_eq!(start + length, nuc_starts[i + 1]);
+        }
+        assert!(nuc_starts
+            .iter()
+            .zip(nuc_lengths.iter())
+            .all(|(s, l)| (s + l) as usize <= record.seq_len()));
+        n_nucleosomes += nuc_starts.len();
+        n_records += 1;
+    }
+    assert_eq!(n_records, 2);
+    assert!(n_nucleosomes > 0);
+}
+
+#[test]
+fn test_fiber_bed12_and_accessibility() {
+    let out_bed = std::env::temp_dir().join("test_fiber_segments.bed");
+    let accessibility =
+        std::env::temp_dir().join("test_fiber_accessibility.bed");
+    run_modkit(&[
+        "fiber",
+        FIBER_BAM,
+        out_bed.to_str().unwrap(),
+        "--bed12",
+        "--accessibility",
+        accessibility.to_str().unwrap(),
+        "--no-filtering",
+        "--nucleosome-length",
+        "20",
+        "--min-at-bases",
+        "10",
+    ])
+    .unwrap();
+
+    let read_lines = |fp: &std::path::Path| {
+        BufReader::new(File::open(fp).unwrap())
+            .lines()
+            .map(|l| l.unwrap())
+            .collect::<Vec<String>>()
+    };
+    let rows = read_lines(&out_bed);
+    assert!(!rows.is_empty());
+    for row in rows.iter() {
+        let parts = row.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 12, "{row}");
+        let start = parts[1].parse::<u64>().unwrap();
+        let end = parts[2].parse::<u64>().unwrap();
+        assert!(parts[8] == "169,169,169" || parts[8] == "147,112,219");
+        let n_blocks = parts[9].parse::<usize>().unwrap();
+        let sizes = parts[10]
+            .split(',')
+            .map(|x| x.parse::<u64>().unwrap())
+            .collect::<Vec<u64>>();
+        let starts = parts[11]
+            .split(',')
+            .map(|x| x.parse::<u64>().unwrap())
+            .collect::<Vec<u64>>();
+        assert_eq!(sizes.len(), n_blocks);
+        assert_eq!(starts.len(), n_blocks);
+        assert_eq!(starts[0], 0);
+        assert_eq!(starts[n_blocks - 1] + sizes[n_blocks - 1], end - start);
+    }
+
+    let rows = read_lines(&accessibility);
+    assert!(!rows.is_empty());
+    let mut prev_end = 0u64;
+    for row in rows.iter() {
+        let parts = row.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 6, "{row}");
+        let start = parts[1].parse::<u64>().unwrap();
+        let end = parts[2].parse::<u64>().unwrap();
+        assert!(start >= prev_end && start < end, "{row}");
+        let frac = parts[3].parse::<f32>().unwrap();
+        let n_msp = parts[4].parse::<u32>().unwrap();
+        let n_nucleosome = parts[5].parse::<u32>().unwrap();
+        assert!(n_msp + n_nucleosome > 0);
+        let expected = n_msp as f32 / (n_msp + n_nucleosome) as f32;
+        assert!((frac - expected).abs() < 1e-6, "{row}");
+        prev_end = end;
+    }
+}
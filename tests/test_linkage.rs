@@ -0,0 +1,128 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::common::run_modkit;
+
+mod common;
+
+#[test]
+fn test_linkage_help() {
+    let linkage_help_args = ["linkage", "--help"];
+    let _out = run_modkit(&linkage_help_args).unwrap();
+}
+
+fn check_linkage_rows(fp: &std::path::Path, max_distance: u32) -> usize {
+    let reader = BufReader::new(File::open(fp).unwrap());
+    let mut n_rows = 0usize;
+    for line in reader.lines().map(|l| l.unwrap()) {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 11, "{line}");
+        assert_eq!(parts[0], "oligo_1512_adapters");
+        let pos_a = parts[1].parse::<u32>().unwrap();
+        let pos_b = parts[2].parse::<u32>().unwrap();
+        assert!(pos_a < pos_b, "{line}");
+        assert!(pos_b - pos_a <= max_distance, "{line}");
+        let n_reads = parts[4].parse::<u32>().unwrap();
+        assert!(n_reads >= 2, "{line}");
+        let counts = parts[5..9]
+            .iter()
+            .map(|x| x.parse::<u32>().unwrap())
+            .sum::<u32>();
+        assert_eq!(counts, n_reads, "{line}");
+        if parts[9] != "." {
+            let r_squared = parts[9].parse::<f64>().unwrap();
+            assert!((0f64..=1f64).contains(&r_squared), "{line}");
+            let d_prime = parts[10].parse::<f64>().unwrap();
+            assert!((-1f64..=1f64).contains(&d_prime), "{line}");
+        }
+        n_rows += 1;
+    }
+    n_rows
+}
+
+#[test]
+fn test_linkage_cpg_pairs() {
+    let temp_file = std::env::temp_dir().join("test_linkage_cpg_pairs.tsv");
+    let args = [
+        "linkage",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        temp_file.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--max-distance",
+        "200",
+        "--min-coverage",
+        "2",
+        "--no-filtering",
+    ];
+    run_modkit(&args).unwrap();
+    let n_rows = check_linkage_rows(&temp_file, 200);
+    assert!(n_rows > 0);
+
+    let reader = BufReader::new(File::open(&temp_file).unwrap());
+    for line in reader.lines().map(|l| l.unwrap()) {
+        let strand = line.split('\t').nth(3).unwrap().to_string();
+        assert!(strand == "+" || strand == "-", "{line}");
+    }
+}
+
+#[test]
+fn test_linkage_combine_strands_blocks() {
+    let temp_file = std::env::temp_dir().join("test_linkage_combined.tsv");
+    let blocks_file = std::env::temp_dir().join("test_linkage_blocks.bed");
+    let args = [
+        "linkage",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        temp_file.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--combine-strands",
+        "--min-coverage",
+        "2",
+        "--no-filtering",
+        "--interval-size",
+        "500",
+        "--blocks",
+        blocks_file.to_str().unwrap(),
+        "--block-min-r2",
+        "0.0",
+        "--block-min-sites",
+        "2",
+    ];
+    run_modkit(&args).unwrap();
+    let n_rows = check_linkage_rows(&temp_file, 1000);
+    assert!(n_rows > 0);
+
+    let reader = BufReader::new(File::open(&blocks_file).unwrap());
+    let mut prev_end = 0u32;
+    for line in reader.lines().map(|l| l.unwrap()) {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 6, "{line}");
+        assert_eq!(parts[3], ".");
+        let start = parts[1].parse::<u32>().unwrap();
+        let end = parts[2].parse::<u32>().unwrap();
+        assert!(start >= prev_end && start < end, "{line}");
+        assert!(parts[4].parse::<u32>().unwrap() >= 2);
+        prev_end = end;
+    }
+}
+
+#[test]
+fn test_linkage_combine_strands_requires_palindrome() {
+    let temp_file =
+        std::env::temp_dir().join("test_linkage_not_palindrome.tsv");
+    let args = [
+        "linkage",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        temp_file.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--motif",
+        "CH",
+        "0",
+        "--combine-strands",
+    ];
+    assert!(run_modkit(&args).is_err());
+}
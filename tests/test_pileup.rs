@@ -1,13 +1,16 @@
 use anyhow::Context;
 use itertools::Itertools;
-use rust_htslib::bam;
+use rust_htslib::bam::{self, Read as BamRead};
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read};
 use std::path::PathBuf;
 
-use common::{check_against_expected_text_file, run_modkit};
+use common::{
+    check_against_expected_text_file, read_bedmethyl_rows, read_rows,
+    run_modkit,
+};
 use mod_kit::dmr::bedmethyl::BedMethylLine;
 use mod_kit::mod_base_code::{ModCodeRepr, METHYL_CYTOSINE};
 
@@ -847,3 +850,984 @@ fn test_chebi_code_same_output() {
         assert_eq!(expected, observed);
     }
 }
+
+fn bw_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn bw_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn bw_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+// walk the chromosome B+ tree, returning chrom id to (name, size)
+fn read_bigwig_chroms(
+    buf: &[u8],
+    node_offset: usize,
+    key_size: usize,
+    chroms: &mut HashMap<u32, (String, u32)>,
+) {
+    let is_leaf = buf[node_offset] == 1;
+    let count = bw_u16(buf, node_offset + 2) as usize;
+    let mut offset = node_offset + 4;
+    for _ in 0..count {
+        if is_leaf {
+            let name =
+                String::from_utf8(buf[offset..offset + key_size].to_vec())
+                    .unwrap()
+                    .trim_end_matches('\0')
+                    .to_string();
+            let chrom_id = bw_u32(buf, offset + key_size);
+            let size = bw_u32(buf, offset + key_size + 4);
+            chroms.insert(chrom_id, (name, size));
+        } else {
+            let child = bw_u64(buf, offset + key_size) as usize;
+            read_bigwig_chroms(buf, child, key_size, chroms);
+        }
+        offset += key_size + 8;
+    }
+}
+
+// walk the R tree, returning the (chrom_id, start, end, value) of every
+// bedGraph item
+fn read_bigwig_items(
+    buf: &[u8],
+    node_offset: usize,
+) -> Vec<(u32, u32, u32, f32)> {
+    let is_leaf = buf[node_offset] == 1;
+    let count = bw_u16(buf, node_offset + 2) as usize;
+    let mut items = Vec::new();
+    let mut offset = node_offset + 4;
+    for _ in 0..count {
+        if is_leaf {
+            let data_offset = bw_u64(buf, offset + 16) as usize;
+            let data_size = bw_u64(buf, offset + 24) as usize;
+            let mut raw = Vec::new();
+            flate2::read::ZlibDecoder::new(
+                &buf[data_offset..data_offset + data_size],
+            )
+            .read_to_end(&mut raw)
+            .unwrap();
+            let chrom_id = bw_u32(&raw, 0);
+            for i in 0..bw_u16(&raw, 22) as usize {
+                let item = 24 + i * 12;
+                items.push((
+                    chrom_id,
+                    bw_u32(&raw, item),
+                    bw_u32(&raw, item + 4),
+                    f32::from_le_bytes(
+                        raw[item + 8..item + 12].try_into().unwrap(),
+                    ),
+                ));
+            }
+            offset += 32;
+        } else {
+            let child = bw_u64(buf, offset + 16) as usize;
+            items.extend(read_bigwig_items(buf, child));
+            offset += 24;
+        }
+    }
+    items
+}
+
+/// Read back a bigWig as (chrom name, start, end, value) and the chrom list.
+fn read_bigwig(
+    fp: &PathBuf,
+) -> (Vec<(String, u32)>, Vec<(String, u32, u32, f32)>) {
+    let buf = std::fs::read(fp).unwrap();
+    assert_eq!(bw_u32(&buf, 0), 0x888F_FC26);
+    let chrom_tree_offset = bw_u64(&buf, 8) as usize;
+    let key_size = bw_u32(&buf, chrom_tree_offset + 8) as usize;
+    let mut chroms = HashMap::new();
+    read_bigwig_chroms(&buf, chrom_tree_offset + 32, key_size, &mut chroms);
+    let index_offset = bw_u64(&buf, 24) as usize;
+    let items = read_bigwig_items(&buf, index_offset + 48)
+        .into_iter()
+        .map(|(chrom_id, start, end, value)| {
+            (chroms[&chrom_id].0.clone(), start, end, value)
+        })
+        .collect();
+    let chrom_list = chroms
+        .into_values()
+        .sorted_by(|a, b| a.0.cmp(&b.0))
+        .collect();
+    (chrom_list, items)
+}
+
+#[test]
+fn test_pileup_bigwig_output() {
+    let bedgraph_dir =
+        std::env::temp_dir().join("test_pileup_bigwig_output_bedgraph");
+    let bigwig_dir =
+        std::env::temp_dir().join("test_pileup_bigwig_output_bigwig");
+    for (out_dir, flag) in
+        [(&bedgraph_dir, "--bedgraph"), (&bigwig_dir, "--bigwig")]
+    {
+        run_modkit(&[
+            "pileup",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_dir.to_str().unwrap(),
+            "--no-filtering",
+            flag,
+        ])
+        .unwrap();
+    }
+
+    // one bigWig for every bedGraph with the same intervals and percent
+    // modified values
+    let mut bedgraph_stems = bedgraph_dir
+        .read_dir()
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|fp| {
+            fp.extension().and_then(|e| e.to_str()) == Some("bedgraph")
+        })
+        .map(|fp| fp.file_stem().unwrap().to_str().unwrap().to_owned())
+        .collect::<Vec<String>>();
+    bedgraph_stems.sort();
+    let mut bigwig_stems = Vec::new();
+    for entry in bigwig_dir.read_dir().unwrap() {
+        let fp = entry.unwrap().path();
+        assert_eq!(fp.extension().and_then(|e| e.to_str()), Some("bw"));
+        let stem = fp.file_stem().unwrap().to_str().unwrap().to_owned();
+        let (chroms, items) = read_bigwig(&fp);
+        let header = bam::Reader::from_path(
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+        )
+        .unwrap()
+        .header()
+        .to_owned();
+        let expected_chroms = (0..header.target_count())
+            .map(|tid| {
+                (
+                    String::from_utf8(header.tid2name(tid).to_vec()).unwrap(),
+                    header.target_len(tid).unwrap() as u32,
+                )
+            })
+            .sorted_by(|a, b| a.0.cmp(&b.0))
+            .collect::<Vec<(String, u32)>>();
+        assert_eq!(chroms, expected_chroms);
+
+        let bedgraph_fp = bedgraph_dir.join(format!("{stem}.bedgraph"));
+        let expected = BufReader::new(File::open(bedgraph_fp).unwrap())
+            .lines()
+            .map(|l| {
+                let l = l.unwrap();
+                let parts = l.split('\t').collect::<Vec<&str>>();
+                (
+                    parts[0].to_string(),
+                    parts[1].parse::<u32>().unwrap(),
+                    parts[2].parse::<u32>().unwrap(),
+                    parts[3].parse::<f32>().unwrap(),
+                )
+            })
+            .collect::<Vec<(String, u32, u32, f32)>>();
+        assert!(!expected.is_empty());
+        assert_eq!(items, expected, "{stem}");
+        bigwig_stems.push(stem);
+    }
+    bigwig_stems.sort();
+    assert_eq!(bedgraph_stems, bigwig_stems);
+    assert!(!bigwig_stems.is_empty());
+}
+
+#[test]
+fn test_pileup_bgzf_output() {
+    let plain_fp = std::env::temp_dir().join("test_pileup_bgzf_output.bed");
+    let bgzf_fp = std::env::temp_dir().join("test_pileup_bgzf_output.bed.gz");
+    for (out_fp, extra_args) in
+        [(&plain_fp, vec![]), (&bgzf_fp, vec!["--bgzf"])]
+    {
+        let mut args = vec![
+            "pileup",
+            "tests/resources/bc_anchored_10_reads.sorted.bam",
+            out_fp.to_str().unwrap(),
+            "--no-filtering",
+            "--cpg",
+            "--ref",
+            "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        ];
+        args.extend(extra_args);
+        run_modkit(&args).unwrap();
+    }
+    let mut decoded = String::new();
+    noodles::bgzf::Reader::new(File::open(&bgzf_fp).unwrap())
+        .read_to_string(&mut decoded)
+        .unwrap();
+    let expected = std::fs::read_to_string(&plain_fp).unwrap();
+    assert_eq!(decoded, expected);
+
+    // the index can be used by dmr directly
+    let index_fp =
+        std::env::temp_dir().join("test_pileup_bgzf_output.bed.gz.tbi");
+    assert!(index_fp.exists());
+    let dmr_fp = std::env::temp_dir().join("test_pileup_bgzf_output_dmr.bed");
+    run_modkit(&[
+        "dmr",
+        "pair",
+        "-a",
+        bgzf_fp.to_str().unwrap(),
+        "-b",
+        bgzf_fp.to_str().unwrap(),
+        "-o",
+        dmr_fp.to_str().unwrap(),
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--base",
+        "C",
+        "-f",
+    ])
+    .unwrap();
+    let n_sites = expected
+        .lines()
+        .map(|l| l.split('\t').take(3).collect::<Vec<&str>>())
+        .unique()
+        .count();
+    let n_dmr_rows = std::fs::read_to_string(&dmr_fp).unwrap().lines().count();
+    assert_eq!(n_dmr_rows, n_sites);
+}
+
+#[test]
+fn test_pileup_multiple_bams() {
+    let single_fp = std::env::temp_dir().join("test_pileup_multi_single.bed");
+    let merged_fp = std::env::temp_dir().join("test_pileup_multi_merged.bed");
+    let wide_fp = std::env::temp_dir().join("test_pileup_multi_wide.tsv");
+    let bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    run_modkit(&["pileup", bam, single_fp.to_str().unwrap(), "--no-filtering"])
+        .unwrap();
+    run_modkit(&[
+        "pileup",
+        bam,
+        bam,
+        merged_fp.to_str().unwrap(),
+        "--no-filtering",
+    ])
+    .unwrap();
+    run_modkit(&[
+        "pileup",
+        bam,
+        bam,
+        wide_fp.to_str().unwrap(),
+        "--no-filtering",
+        "--sample-columns",
+        "--sample-name",
+        "a",
+        "--sample-name",
+        "b",
+    ])
+    .unwrap();
+
+    let parse_bedmethyl = |fp: &PathBuf| {
+        BufReader::new(File::open(fp).unwrap())
+            .lines()
+            .map(|l| BedMethylLine::parse(&l.unwrap()).unwrap())
+            .collect::<Vec<BedMethylLine>>()
+    };
+    let single = parse_bedmethyl(&single_fp);
+    let merged = parse_bedmethyl(&merged_fp);
+    assert_eq!(single.len(), merged.len());
+    for (s, m) in single.iter().zip(merged.iter()) {
+        assert_eq!(s.interval, m.interval);
+        assert_eq!(s.raw_mod_code, m.raw_mod_code);
+        assert_eq!(s.count_methylated * 2, m.count_methylated);
+        assert_eq!(s.valid_coverage * 2, m.valid_coverage);
+    }
+
+    let mut lines = BufReader::new(File::open(&wide_fp).unwrap())
+        .lines()
+        .map(|l| l.unwrap());
+    assert_eq!(
+        lines.next().unwrap(),
+        "#chrom\tstart\tend\tname\tstrand\ta_n_mod\ta_n_canonical\tb_n_mod\t\
+        b_n_canonical"
+    );
+    let single_rows = BufReader::new(File::open(&single_fp).unwrap())
+        .lines()
+        .map(|l| l.unwrap())
+        .collect::<Vec<String>>();
+    let mut n_rows = 0usize;
+    for (line, single_row) in lines.zip(single_rows.iter()) {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 9);
+        // chrom, start, end, mod code, strand, n_mod, n_canonical
+        let single_parts = single_row.split_whitespace().collect::<Vec<&str>>();
+        assert_eq!(&parts[0..3], &single_parts[0..3]);
+        assert_eq!(parts[3], single_parts[3]);
+        assert_eq!(parts[4], single_parts[5]);
+        assert_eq!(&parts[5..7], &single_parts[11..13]);
+        assert_eq!(&parts[7..9], &single_parts[11..13]);
+        n_rows += 1;
+    }
+    assert_eq!(n_rows, single_rows.len());
+}
+
+#[test]
+fn test_pileup_multiple_bams_shared_read_ids() {
+    // same reads with different calls, h converted to m
+    let bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let converted_bam =
+        std::env::temp_dir().join("test_pileup_multi_shared_ids.bam");
+    run_modkit(&[
+        "adjust-mods",
+        bam,
+        converted_bam.to_str().unwrap(),
+        "--convert",
+        "h",
+        "m",
+    ])
+    .unwrap();
+    bam::index::build(converted_bam.clone(), None, bam::index::Type::Bai, 1)
+        .unwrap();
+
+    let wide_fp =
+        std::env::temp_dir().join("test_pileup_multi_shared_ids_wide.tsv");
+    run_modkit(&[
+        "pileup",
+        bam,
+        converted_bam.to_str().unwrap(),
+        wide_fp.to_str().unwrap(),
+        "--no-filtering",
+        "--sample-columns",
+    ])
+    .unwrap();
+    // (start, mod code, strand) to (n_mod, n_canonical) of a single BAM
+    let single_counts = |bam_fp: &str, name: &str| {
+        let out_fp = std::env::temp_dir()
+            .join(format!("test_pileup_multi_shared_ids_{name}.bed"));
+        run_modkit(&[
+            "pileup",
+            bam_fp,
+            out_fp.to_str().unwrap(),
+            "--no-filtering",
+        ])
+        .unwrap();
+        BufReader::new(File::open(out_fp).unwrap())
+            .lines()
+            .map(|l| {
+                let l = l.unwrap();
+                let parts = l.split_whitespace().collect::<Vec<&str>>();
+                (
+                    (
+                        parts[1].to_string(),
+                        parts[3].to_string(),
+                        parts[5].to_string(),
+                    ),
+                    (parts[11].to_string(), parts[12].to_string()),
+                )
+            })
+            .collect::<HashMap<(String, String, String), (String, String)>>()
+    };
+    let original = single_counts(bam, "original");
+    let converted = single_counts(converted_bam.to_str().unwrap(), "converted");
+    assert_ne!(original, converted);
+
+    let mut n_checked = 0usize;
+    for line in BufReader::new(File::open(&wide_fp).unwrap())
+        .lines()
+        .skip(1)
+        .map(|l| l.unwrap())
+    {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        let key = (
+            parts[1].to_string(),
+            parts[3].to_string(),
+            parts[4].to_string(),
+        );
+        let zero = ("0".to_string(), "0".to_string());
+        let expected_a = original.get(&key).unwrap_or(&zero);
+        let expected_b = converted.get(&key).unwrap_or(&zero);
+        assert_eq!((parts[5], parts[6]), (&*expected_a.0, &*expected_a.1));
+        assert_eq!((parts[7], parts[8]), (&*expected_b.0, &*expected_b.1));
+        n_checked += 1;
+    }
+    assert!(n_checked > 0);
+}
+
+#[test]
+fn test_pileup_haplotypes() {
+    // add a phase block to the haplotype 1 reads and remove the haplotype
+    // from the read group C reads so that some reads are unphased
+    let phased_bam_fp =
+        std::env::temp_dir().join("test_pileup_haplotypes.phased.bam");
+    {
+        let mut reader = bam::Reader::from_path(
+            "tests/resources/bc_anchored_10_reads.haplotyped.sorted.bam",
+        )
+        .unwrap();
+        let header = bam::Header::from_template(reader.header());
+        let mut writer =
+            bam::Writer::from_path(&phased_bam_fp, &header, bam::Format::Bam)
+                .unwrap();
+        for record in reader.records() {
+            let mut record = record.unwrap();
+            let read_group = match record.aux(b"RG").unwrap() {
+                bam::record::Aux::String(rg) => rg.to_string(),
+                _ => panic!("expected string read group"),
+            };
+            if read_group == "C" {
+                record.remove_aux(b"HP").unwrap();
+            } else if record.aux(b"HP").unwrap() == bam::record::Aux::I8(1) {
+                record.push_aux(b"PS", bam::record::Aux::I32(1000)).unwrap();
+            }
+            writer.write(&record).unwrap();
+        }
+    }
+    bam::index::build(&phased_bam_fp, None, bam::index::Type::Bai, 1).unwrap();
+
+    let out_fp = std::env::temp_dir().join("test_pileup_haplotypes.tsv");
+    let control_fp =
+        std::env::temp_dir().join("test_pileup_haplotypes.control.bed");
+    for (fp, extra_args) in
+        [(&out_fp, vec!["--haplotypes"]), (&control_fp, vec![])]
+    {
+        let mut args = vec![
+            "pileup",
+            phased_bam_fp.to_str().unwrap(),
+            fp.to_str().unwrap(),
+            "--no-filtering",
+        ];
+        args.extend(extra_args);
+        run_modkit(&args).unwrap();
+    }
+
+    let control = BufReader::new(File::open(&control_fp).unwrap())
+        .lines()
+        .map(|l| BedMethylLine::parse(&l.unwrap()).unwrap())
+        .collect::<Vec<BedMethylLine>>();
+    let mut lines = BufReader::new(File::open(&out_fp).unwrap())
+        .lines()
+        .map(|l| l.unwrap());
+    let header = lines.next().unwrap();
+    assert!(header.starts_with("#chrom\tstart\tend\tname\tstrand\tphase_block"));
+    let mut n_rows = 0usize;
+    for (line, control_line) in lines.zip(control.iter()) {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 15);
+        assert_eq!(parts[1].parse::<u64>().unwrap(), control_line.start());
+        let counts = parts[6..]
+            .iter()
+            .map(|x| x.parse::<u64>().unwrap())
+            .collect::<Vec<u64>>();
+        let (hp1, hp2, unphased) =
+            (&counts[0..3], &counts[3..6], &counts[6..9]);
+        assert_eq!(
+            hp1[0] + hp2[0] + unphased[0],
+            control_line.valid_coverage,
+            "{line}"
+        );
+        assert_eq!(
+            hp1[1] + hp2[1] + unphased[1],
+            control_line.count_methylated,
+            "{line}"
+        );
+        // all haplotype 1 reads are in phase block 1000
+        if hp1[0] > 0 {
+            assert_eq!(parts[5], "1000");
+        } else {
+            assert_eq!(parts[5], ".");
+        }
+        assert!(unphased[0] > 0, "{line}");
+        n_rows += 1;
+    }
+    assert_eq!(n_rows, control.len());
+}
+
+#[test]
+fn test_pileup_soft_counts() {
+    let hard_fp = std::env::temp_dir().join("test_pileup_soft_hard.bed");
+    let soft_fp = std::env::temp_dir().join("test_pileup_soft_soft.bed");
+    let bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    run_modkit(&["pileup", bam, hard_fp.to_str().unwrap(), "--no-filtering"])
+        .unwrap();
+    run_modkit(&["pileup", bam, soft_fp.to_str().unwrap(), "--soft"]).unwrap();
+    // soft counts can't be combined with thresholds
+    assert!(run_modkit(&[
+        "pileup",
+        bam,
+        soft_fp.to_str().unwrap(),
+        "--soft",
+        "--filter-threshold",
+        "0.7",
+    ])
+    .is_err());
+
+    let hard_rows = read_bedmethyl_rows(&hard_fp);
+    let soft_rows = read_bedmethyl_rows(&soft_fp);
+    assert_eq!(hard_rows.len(), soft_rows.len());
+    // (chrom, position, strand) to summed expected counts and valid coverage
+    let mut expected_totals = HashMap::new();
+    for (hard, soft) in hard_rows.iter().zip(soft_rows.iter()) {
+        assert_eq!(hard.len(), 18);
+        assert_eq!(soft.len(), 20);
+        // no filtering, so the counts are the same as --no-filtering
+        assert_eq!(&hard[0..10], &soft[0..10]);
+        assert_eq!(&hard[11..18], &soft[11..18]);
+        let valid_coverage = soft[9].parse::<f32>().unwrap();
+        let percent_modified = soft[10].parse::<f32>().unwrap();
+        let expected_mod = soft[18].parse::<f32>().unwrap();
+        let expected_canonical = soft[19].parse::<f32>().unwrap();
+        // the percent modified is the mean probability
+        assert!(
+            (percent_modified / 100f32 * valid_coverage - expected_mod).abs()
+                < 0.01,
+            "{soft:?}"
+        );
+        let totals = expected_totals
+            .entry((soft[0].clone(), soft[1].clone(), soft[5].clone()))
+            .or_insert((expected_canonical, valid_coverage));
+        totals.0 += expected_mod;
+    }
+    assert!(!expected_totals.is_empty());
+    for (key, (expected_total, valid_coverage)) in expected_totals {
+        assert!(
+            (expected_total - valid_coverage).abs() < 0.05,
+            "{key:?} {expected_total} {valid_coverage}"
+        );
+    }
+
+    // the output is still valid bedMethyl
+    BufReader::new(File::open(&soft_fp).unwrap())
+        .lines()
+        .for_each(|l| {
+            BedMethylLine::parse(&l.unwrap()).unwrap();
+        });
+}
+
+#[test]
+fn test_pileup_confidence_interval_and_strand_imbalance() {
+    let bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let reference = "tests/resources/CGI_ladder_3.6kb_ref.fa";
+
+    let plain_fp = std::env::temp_dir().join("test_pileup_ci_plain.bed");
+    run_modkit(&["pileup", bam, plain_fp.to_str().unwrap(), "--no-filtering"])
+        .unwrap();
+    let plain_rows = read_bedmethyl_rows(&plain_fp);
+    for method in ["wilson", "beta"] {
+        let ci_fp =
+            std::env::temp_dir().join(format!("test_pileup_ci_{method}.bed"));
+        run_modkit(&[
+            "pileup",
+            bam,
+            ci_fp.to_str().unwrap(),
+            "--no-filtering",
+            "--confidence-interval",
+            method,
+            "--confidence-level",
+            "0.9",
+        ])
+        .unwrap();
+        let ci_rows = read_bedmethyl_rows(&ci_fp);
+        assert_eq!(ci_rows.len(), plain_rows.len());
+        for (plain, row) in plain_rows.iter().zip(ci_rows.iter()) {
+            assert_eq!(row.len(), 20);
+            assert_eq!(&row[0..18], &plain[0..18]);
+            let percent_modified = row[10].parse::<f64>().unwrap();
+            let lower = row[18].parse::<f64>().unwrap();
+            let upper = row[19].parse::<f64>().unwrap();
+            assert!(
+                lower <= percent_modified + 0.01
+                    && percent_modified <= upper + 0.01,
+                "{row:?}"
+            );
+            assert!(lower >= 0f64 && upper <= 100f64 && lower < upper);
+        }
+        BufReader::new(File::open(&ci_fp).unwrap())
+            .lines()
+            .for_each(|l| {
+                BedMethylLine::parse(&l.unwrap()).unwrap();
+            });
+    }
+    // level must be a fraction
+    assert!(run_modkit(&[
+        "pileup",
+        bam,
+        plain_fp.to_str().unwrap(),
+        "--confidence-interval",
+        "wilson",
+        "--confidence-level",
+        "95",
+    ])
+    .is_err());
+
+    // strand imbalance needs combined strands
+    let imbalance_fp =
+        std::env::temp_dir().join("test_pileup_strand_imbalance.bed");
+    assert!(run_modkit(&[
+        "pileup",
+        bam,
+        imbalance_fp.to_str().unwrap(),
+        "--cpg",
+        "--ref",
+        reference,
+        "--strand-imbalance",
+    ])
+    .is_err());
+    run_modkit(&[
+        "pileup",
+        bam,
+        imbalance_fp.to_str().unwrap(),
+        "--cpg",
+        "--ref",
+        reference,
+        "--combine-strands",
+        "--no-filtering",
+        "--strand-imbalance",
+    ])
+    .unwrap();
+    let rows = read_bedmethyl_rows(&imbalance_fp);
+    assert!(!rows.is_empty());
+    let mut n_scored = 0;
+    for row in rows {
+        assert_eq!(row.len(), 19);
+        if row[18] != "." {
+            let z = row[18].parse::<f64>().unwrap();
+            assert!(z.is_finite());
+            n_scored += 1;
+        }
+    }
+    assert!(n_scored > 0);
+}
+
+#[test]
+fn test_pileup_record_filters() {
+    let bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let plain_fp = std::env::temp_dir().join("test_pileup_filters_plain.bed");
+    run_modkit(&["pileup", bam, plain_fp.to_str().unwrap(), "--no-filtering"])
+        .unwrap();
+    let plain_rows = read_bedmethyl_rows(&plain_fp);
+    assert!(plain_rows.iter().any(|row| row[5] == "-"));
+
+    // removing reverse-strand alignments removes all of the negative strand
+    // calls and leaves the positive strand counts unchanged
+    let no_rev_fp = std::env::temp_dir().join("test_pileup_filters_no_rev.bed");
+    run_modkit(&[
+        "pileup",
+        bam,
+        no_rev_fp.to_str().unwrap(),
+        "--no-filtering",
+        "--exclude-flags",
+        "REVERSE",
+    ])
+    .unwrap();
+    let no_rev_rows = read_bedmethyl_rows(&no_rev_fp);
+    let plain_positive = plain_rows
+        .iter()
+        .filter(|row| row[5] == "+")
+        .collect::<Vec<&Vec<String>>>();
+    assert_eq!(no_rev_rows.iter().collect::<Vec<_>>(), plain_positive);
+
+    // all reads are shorter than 1000 bases
+    let long_fp = std::env::temp_dir().join("test_pileup_filters_long.bed");
+    run_modkit(&[
+        "pileup",
+        bam,
+        long_fp.to_str().unwrap(),
+        "--no-filtering",
+        "--min-read-length",
+        "1000",
+    ])
+    .unwrap();
+    assert!(read_bedmethyl_rows(&long_fp).is_empty());
+
+    for bad_args in
+        [["--exclude-flags", "NOT_A_FLAG"], ["--min-identity", "1.5"]]
+    {
+        let mut args =
+            vec!["pileup", bam, long_fp.to_str().unwrap(), "--no-filtering"];
+        args.extend(bad_args);
+        assert!(run_modkit(&args).is_err());
+    }
+}
+
+#[test]
+fn test_pileup_spliced_direct_rna() {
+    use rust_htslib::bam::header::HeaderRecord;
+    use rust_htslib::bam::record::{Aux, AuxArray, Cigar, CigarString};
+    use std::io::Write;
+
+    // exon 1: [0, 10), intron: [10, 30), exon 2: [30, 40), the reference is
+    // written with U and has a DRACH motif in the intron (A at 14) and in
+    // exon 2 (A at 34)
+    let exon_1 = "UUUUUUUUUU";
+    let intron = "CCGGACUCCCCCCCCCCCCC";
+    let exon_2 = "CCGGACUCCC";
+    let reference_fp = std::env::temp_dir().join("test_pileup_rna_ref.fa");
+    {
+        let mut fh = File::create(&reference_fp).unwrap();
+        writeln!(fh, ">rna_ref\n{exon_1}{intron}{exon_2}").unwrap();
+    }
+    let bam_fp = std::env::temp_dir().join("test_pileup_rna.bam");
+    {
+        let mut header = bam::Header::new();
+        let mut sq = HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "rna_ref");
+        sq.push_tag(b"LN", 40);
+        header.push_record(&sq);
+        let mut writer =
+            bam::Writer::from_path(&bam_fp, &header, bam::Format::Bam).unwrap();
+        let seq = format!("{exon_1}{exon_2}").replace('U', "T");
+        let cigar = CigarString(vec![
+            Cigar::Match(10),
+            Cigar::RefSkip(20),
+            Cigar::Match(10),
+        ]);
+        let mut record = bam::Record::new();
+        record.set(b"rna_read", Some(&cigar), seq.as_bytes(), &[30u8; 20]);
+        record.set_tid(0);
+        record.set_pos(0);
+        record.set_mapq(60);
+        // m6A on the only A and pseudouridine on the first U, the tag uses
+        // U as the canonical base
+        record
+            .push_aux(b"MM", Aux::String("A+a?,0;U+17802?,0;"))
+            .unwrap();
+        let ml_probs = vec![230u8, 200u8];
+        let ml: AuxArray<u8> = (&ml_probs).into();
+        record.push_aux(b"ML", Aux::ArrayU8(ml)).unwrap();
+        writer.write(&record).unwrap();
+    }
+    bam::index::build(&bam_fp, None, bam::index::Type::Bai, 1).unwrap();
+
+    let all_fp = std::env::temp_dir().join("test_pileup_rna_all.bed");
+    run_modkit(&[
+        "pileup",
+        bam_fp.to_str().unwrap(),
+        all_fp.to_str().unwrap(),
+        "--no-filtering",
+        "--only-tabs",
+    ])
+    .unwrap();
+    let rows = read_rows(&all_fp)
+        .into_iter()
+        .map(|row| {
+            (
+                row[1].clone(),
+                row[3].clone(),
+                row[5].clone(),
+                row[11].clone(),
+            )
+        })
+        .collect::<Vec<(String, String, String, String)>>();
+    let expected = [("0", "17802", "+", "1"), ("34", "a", "+", "1")]
+        .into_iter()
+        .map(|(a, b, c, d)| {
+            (a.to_string(), b.to_string(), c.to_string(), d.to_string())
+        })
+        .collect::<Vec<(String, String, String, String)>>();
+    assert_eq!(rows, expected);
+
+    // only the exonic DRACH site is covered by the read, the intron is
+    // skipped rather than counted as a deletion
+    let drach_fp = std::env::temp_dir().join("test_pileup_rna_drach.bed");
+    run_modkit(&[
+        "pileup",
+        bam_fp.to_str().unwrap(),
+        drach_fp.to_str().unwrap(),
+        "--no-filtering",
+        "--only-tabs",
+        "--drach",
+        "--ref",
+        reference_fp.to_str().unwrap(),
+    ])
+    .unwrap();
+    let rows = read_rows(&drach_fp);
+    assert_eq!(rows.len(), 1);
+    assert_eq!(&rows[0][1], "34");
+    assert_eq!(&rows[0][3], "a");
+    assert_eq!(&rows[0][9], "1");
+
+    // DRACH isn't palindromic so strands can't be combined
+    assert!(run_modkit(&[
+        "pileup",
+        bam_fp.to_str().unwrap(),
+        drach_fp.to_str().unwrap(),
+        "--drach",
+        "--combine-strands",
+        "--ref",
+        reference_fp.to_str().unwrap(),
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_pileup_transcriptome_projection() {
+    use rust_htslib::bam::header::HeaderRecord;
+    use rust_htslib::bam::record::{Aux, AuxArray, Cigar, CigarString};
+    use std::io::Write;
+
+    // tx1 and tx2 are isoforms of g1 (+ strand) that share the genomic
+    // position 201, tx3 is on the - strand. The BAM header isn't in genomic
+    // order.
+    let annotation_fp =
+        std::env::temp_dir().join("test_pileup_transcriptome.gtf");
+    {
+        let mut fh = File::create(&annotation_fp).unwrap();
+        for (transcript, gene, strand, start, end) in [
+            ("tx1", "g1", '+', 101, 105),
+            ("tx1", "g1", '+', 201, 205),
+            ("tx2", "g1", '+', 200, 209),
+            ("tx3", "g2", '-', 301, 310),
+        ] {
+            writeln!(
+                fh,
+                "chr1\ttest\texon\t{start}\t{end}\t.\t{strand}\t.\t\
+                 gene_id \"{gene}\"; transcript_id \"{transcript}\";"
+            )
+            .unwrap();
+        }
+    }
+    let bam_fp = std::env::temp_dir().join("test_pileup_transcriptome.bam");
+    {
+        let mut header = bam::Header::new();
+        for transcript in ["tx3", "tx1", "tx2"] {
+            let mut sq = HeaderRecord::new(b"SQ");
+            sq.push_tag(b"SN", transcript);
+            sq.push_tag(b"LN", 10);
+            header.push_record(&sq);
+        }
+        let mut writer =
+            bam::Writer::from_path(&bam_fp, &header, bam::Format::Bam).unwrap();
+        // (transcript id, As to skip before the call, ML probability)
+        for (tid, skip, prob) in [(0, 3, 230u8), (1, 6, 230u8), (2, 2, 20u8)] {
+            let mut record = bam::Record::new();
+            let name = format!("read_{tid}");
+            let cigar = CigarString(vec![Cigar::Match(10)]);
+            record.set(name.as_bytes(), Some(&cigar), b"AAAAAAAAAA", &[30; 10]);
+            record.set_tid(tid);
+            record.set_pos(0);
+            record.set_mapq(60);
+            record
+                .push_aux(b"MM", Aux::String(&format!("A+a?,{skip};")))
+                .unwrap();
+            let ml_probs = vec![prob];
+            let ml: AuxArray<u8> = (&ml_probs).into();
+            record.push_aux(b"ML", Aux::ArrayU8(ml)).unwrap();
+            writer.write(&record).unwrap();
+        }
+    }
+    bam::index::build(&bam_fp, None, bam::index::Type::Bai, 1).unwrap();
+
+    let out_fp = std::env::temp_dir().join("test_pileup_transcriptome.bed");
+    run_modkit(&[
+        "pileup",
+        bam_fp.to_str().unwrap(),
+        out_fp.to_str().unwrap(),
+        "--no-filtering",
+        "--only-tabs",
+        "--annotation",
+        annotation_fp.to_str().unwrap(),
+    ])
+    .unwrap();
+    let rows = BufReader::new(File::open(&out_fp).unwrap())
+        .lines()
+        .map(|l| {
+            let row = l
+                .unwrap()
+                .split('\t')
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>();
+            assert_eq!(row.len(), 20);
+            [0, 1, 3, 5, 9, 10, 11, 12, 18, 19]
+                .into_iter()
+                .map(|i| row[i].clone())
+                .collect::<Vec<String>>()
+        })
+        .collect::<Vec<Vec<String>>>();
+    let expected = [
+        [
+            "chr1", "201", "a", "+", "2", "50.00", "1", "1", "g1", "tx1,tx2",
+        ],
+        [
+            "chr1", "306", "a", "-", "1", "100.00", "1", "0", "g2", "tx3",
+        ],
+    ]
+    .into_iter()
+    .map(|row| row.into_iter().map(|x| x.to_string()).collect())
+    .collect::<Vec<Vec<String>>>();
+    assert_eq!(rows, expected);
+}
+
+#[test]
+fn test_pileup_parquet_output() {
+    use arrow::util::display::array_value_to_string;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let bam = "tests/resources/bc_anchored_10_reads.sorted.bam";
+    let bed_fp = std::env::temp_dir().join("test_pileup_parquet.bed");
+    let parquet_fp = std::env::temp_dir().join("test_pileup_parquet.parquet");
+    run_modkit(&[
+        "pileup",
+        bam,
+        bed_fp.to_str().unwrap(),
+        "--no-filtering",
+        "--only-tabs",
+    ])
+    .unwrap();
+    run_modkit(&[
+        "pileup",
+        bam,
+        parquet_fp.to_str().unwrap(),
+        "--no-filtering",
+        "--parquet",
+    ])
+    .unwrap();
+
+    let expected = BufReader::new(File::open(&bed_fp).unwrap())
+        .lines()
+        .map(|l| {
+            let row = l
+                .unwrap()
+                .split('\t')
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>();
+            // drop the compatibility columns
+            row.into_iter()
+                .enumerate()
+                .filter(|(i, _)| ![4, 6, 7, 8].contains(i))
+                .map(|(_, x)| x)
+                .collect::<Vec<String>>()
+        })
+        .collect::<Vec<Vec<String>>>();
+
+    let builder = ParquetRecordBatchReaderBuilder::try_new(
+        File::open(&parquet_fp).unwrap(),
+    )
+    .unwrap();
+    // the interval chunks are small, they're combined into one row group
+    // instead of a row group each
+    assert!(expected.len() < 10_000);
+    assert_eq!(builder.metadata().num_row_groups(), 1);
+    let reader = builder.build().unwrap();
+    let mut rows = Vec::new();
+    for batch in reader {
+        let batch = batch.unwrap();
+        assert_eq!(batch.num_columns(), 14);
+        assert_eq!(batch.schema().field(5).name(), "n_valid_cov");
+        for i in 0..batch.num_rows() {
+            let row = batch
+                .columns()
+                .iter()
+                .enumerate()
+                .map(|(j, column)| {
+                    let value = array_value_to_string(column, i).unwrap();
+                    if j == 6 {
+                        format!("{:.2}", value.parse::<f32>().unwrap())
+                    } else {
+                        value
+                    }
+                })
+                .collect::<Vec<String>>();
+            rows.push(row);
+        }
+    }
+    assert!(!rows.is_empty());
+    assert_eq!(rows, expected);
+
+    assert!(
+        run_modkit(&["pileup", bam, "-", "--no-filtering", "--parquet",])
+            .is_err()
+    );
+}
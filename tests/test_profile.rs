@@ -0,0 +1,159 @@
+use std::fs::File;
+use std::io::Write;
+
+use crate::common::{read_rows, run_modkit};
+
+mod common;
+
+const BAM: &str = "tests/resources/bc_anchored_10_reads.sorted.bam";
+const REFERENCE: &str = "tests/resources/CGI_ladder_3.6kb_ref.fa";
+
+#[test]
+fn test_profile_help() {
+    let profile_help_args = ["profile", "--help"];
+    let _out = run_modkit(&profile_help_args).unwrap();
+}
+
+#[test]
+fn test_profile_reference_point() {
+    let features_fp = std::env::temp_dir().join("test_profile_features.bed");
+    {
+        let mut fh = File::create(&features_fp).unwrap();
+        writeln!(fh, "oligo_1512_adapters\t60\t61\tplus\t0\t+").unwrap();
+        writeln!(fh, "oligo_1512_adapters\t60\t61\tminus\t0\t-").unwrap();
+        writeln!(fh, "not_a_contig\t60\t61\tmissing\t0\t+").unwrap();
+    }
+    let out_dir = std::env::temp_dir().join("test_profile_reference_point");
+    run_modkit(&[
+        "profile",
+        BAM,
+        out_dir.to_str().unwrap(),
+        "-r",
+        features_fp.to_str().unwrap(),
+        "--flank",
+        "50",
+        "--bin-size",
+        "10",
+        "--cpg",
+        "--ref",
+        REFERENCE,
+        "--no-filtering",
+        "--prefix",
+        "plus",
+        "--force",
+    ])
+    .unwrap();
+    let matrix = read_rows(out_dir.join("plus_matrix.tsv"));
+    assert_eq!(matrix[0].len(), 6 + 10);
+    assert_eq!(matrix[0][6], "-50");
+    assert_eq!(matrix[0][15], "40");
+    // 5mC and 5hmC rows for each feature on a known contig
+    assert_eq!(matrix.len(), 1 + 4);
+    assert!(matrix[1..].iter().all(|row| row.len() == 16));
+
+    let pileup_fp = std::env::temp_dir().join("test_profile_pileup.bed");
+    run_modkit(&[
+        "pileup",
+        BAM,
+        pileup_fp.to_str().unwrap(),
+        "--cpg",
+        "--ref",
+        REFERENCE,
+        "--no-filtering",
+        "--only-tabs",
+    ])
+    .unwrap();
+    let pileup_rows = read_rows(&pileup_fp);
+    let profile = read_rows(out_dir.join("plus_profile.tsv"));
+    assert_eq!(profile[0][0], "bin");
+    // the aggregate is the sum of the two features, the negative strand
+    // feature's bins are mirrored around position 60
+    for row in profile[1..].iter() {
+        let bin = row[0].parse::<u64>().unwrap();
+        let mod_code = &row[2];
+        let plus_start = 10 + bin * 10;
+        let minus_end = 110 - bin * 10;
+        let counts = pileup_rows
+            .iter()
+            .filter(|p| &p[3] == mod_code)
+            .filter_map(|p| {
+                let pos = p[1].parse::<u64>().unwrap();
+                let in_plus = pos >= plus_start && pos < plus_start + 10;
+                let in_minus = pos > minus_end - 10 && pos <= minus_end;
+                let n = in_plus as u64 + in_minus as u64;
+                (n > 0).then(|| {
+                    (
+                        n * p[9].parse::<u64>().unwrap(),
+                        n * p[11].parse::<u64>().unwrap(),
+                    )
+                })
+            })
+            .fold((0, 0), |(a, b), (c, d)| (a + c, b + d));
+        assert_eq!(row[4].parse::<u64>().unwrap(), counts.0, "{row:?}");
+        assert_eq!(row[5].parse::<u64>().unwrap(), counts.1, "{row:?}");
+    }
+
+    // refuse to overwrite without --force
+    assert!(run_modkit(&[
+        "profile",
+        BAM,
+        out_dir.to_str().unwrap(),
+        "-r",
+        features_fp.to_str().unwrap(),
+        "--no-filtering",
+        "--prefix",
+        "plus",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_profile_scaled_body() {
+    let features_fp = std::env::temp_dir().join("test_profile_genes.bed");
+    {
+        let mut fh = File::create(&features_fp).unwrap();
+        writeln!(fh, "oligo_1512_adapters\t40\t100\tgene\t0\t+").unwrap();
+    }
+    let out_dir = std::env::temp_dir().join("test_profile_scaled_body");
+    run_modkit(&[
+        "profile",
+        BAM,
+        out_dir.to_str().unwrap(),
+        "-r",
+        features_fp.to_str().unwrap(),
+        "--flank",
+        "20",
+        "--bin-size",
+        "10",
+        "--body-bins",
+        "3",
+        "--no-filtering",
+        "--force",
+    ])
+    .unwrap();
+    let profile = read_rows(out_dir.join("profile.tsv"));
+    let labels = profile[1..]
+        .iter()
+        .filter(|row| row[2] == "m")
+        .map(|row| row[1].as_str())
+        .collect::<Vec<&str>>();
+    assert_eq!(
+        labels,
+        vec!["-20", "-10", "body_1", "body_2", "body_3", "end+0", "end+10"]
+    );
+    // the flank size must be a multiple of the bin size
+    assert!(run_modkit(&[
+        "profile",
+        BAM,
+        out_dir.to_str().unwrap(),
+        "-r",
+        features_fp.to_str().unwrap(),
+        "--flank",
+        "25",
+        "--bin-size",
+        "10",
+        "--no-filtering",
+        "--force",
+    ])
+    .is_err());
+}
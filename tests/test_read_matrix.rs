@@ -0,0 +1,154 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::common::run_modkit;
+
+mod common;
+
+#[test]
+fn test_read_matrix_help() {
+    let read_matrix_help_args = ["read-matrix", "--help"];
+    let _out = run_modkit(&read_matrix_help_args).unwrap();
+}
+
+#[test]
+fn test_read_matrix_dense_cpg() {
+    let temp_file = std::env::temp_dir().join("test_read_matrix_dense.tsv");
+    let args = [
+        "read-matrix",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        temp_file.to_str().unwrap(),
+        "--region",
+        "oligo_1512_adapters",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--no-filtering",
+        "--force",
+    ];
+    run_modkit(&args).unwrap();
+
+    let reader = BufReader::new(File::open(temp_file).unwrap());
+    let mut lines = reader.lines().map(|l| l.unwrap());
+    let header = lines.next().unwrap();
+    let columns = header.split('\t').collect::<Vec<&str>>();
+    assert_eq!(
+        &columns[..5],
+        &["read_id", "strand", "haplotype", "ref_start", "ref_end"]
+    );
+    let site_strands = columns[5..]
+        .iter()
+        .map(|column| {
+            let parts = column.split(':').collect::<Vec<&str>>();
+            assert_eq!(parts.len(), 3, "{column}");
+            assert_eq!(parts[0], "oligo_1512_adapters");
+            parts[2]
+        })
+        .collect::<Vec<&str>>();
+    assert!(!site_strands.is_empty());
+
+    let mut n_rows = 0usize;
+    let mut n_negative = 0usize;
+    for line in lines {
+        let parts = line.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), columns.len(), "{line}");
+        let read_strand = parts[1];
+        assert!(read_strand == "+" || read_strand == "-");
+        if read_strand == "-" {
+            n_negative += 1;
+        }
+        assert_eq!(parts[2], ".");
+        let mut n_values = 0usize;
+        for (value, site_strand) in parts[5..].iter().zip(site_strands.iter()) {
+            if *value == "." {
+                continue;
+            }
+            // reads only have calls on their own strand
+            assert_eq!(*site_strand, read_strand, "{line}");
+            assert!(*value == "0" || *value == "1", "{line}");
+            n_values += 1;
+        }
+        assert!(n_values >= 1);
+        n_rows += 1;
+    }
+    assert_eq!(n_rows, 10);
+    assert!(n_negative > 0);
+}
+
+#[test]
+fn test_read_matrix_sparse_combined_probabilities() {
+    let temp_dir = std::env::temp_dir().join("test_read_matrix_sparse");
+    let args = [
+        "read-matrix",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        temp_dir.to_str().unwrap(),
+        "--region",
+        "oligo_1512_adapters:0-80",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--cpg",
+        "--combine-strands",
+        "--values",
+        "probability",
+        "--sparse",
+        "--force",
+    ];
+    run_modkit(&args).unwrap();
+
+    let read_lines = |name: &str| {
+        BufReader::new(File::open(temp_dir.join(name)).unwrap())
+            .lines()
+            .map(|l| l.unwrap())
+            .collect::<Vec<String>>()
+    };
+    let sites = read_lines("sites.tsv");
+    assert_eq!(sites[0], "chrom\tposition\tstrand");
+    for site in &sites[1..] {
+        let parts = site.split('\t').collect::<Vec<&str>>();
+        assert_eq!(parts[2], ".", "{site}");
+        assert!(parts[1].parse::<u32>().unwrap() < 80);
+    }
+    let n_sites = sites.len() - 1;
+    let n_reads = read_lines("reads.tsv").len() - 1;
+    assert_eq!(n_reads, 10);
+
+    let matrix = read_lines("matrix.mtx");
+    assert_eq!(matrix[0], "%%MatrixMarket matrix coordinate real general");
+    let dims = matrix[1]
+        .split(' ')
+        .map(|x| x.parse::<usize>().unwrap())
+        .collect::<Vec<usize>>();
+    assert_eq!(dims[0], n_reads);
+    assert_eq!(dims[1], n_sites);
+    assert_eq!(dims[2], matrix.len() - 2);
+    for entry in &matrix[2..] {
+        let parts = entry.split(' ').collect::<Vec<&str>>();
+        let row = parts[0].parse::<usize>().unwrap();
+        let col = parts[1].parse::<usize>().unwrap();
+        let prob = parts[2].parse::<f32>().unwrap();
+        assert!(row >= 1 && row <= n_reads);
+        assert!(col >= 1 && col <= n_sites);
+        assert!((0f32..=1f32).contains(&prob), "{entry}");
+    }
+}
+
+#[test]
+fn test_read_matrix_combine_strands_requires_palindrome() {
+    let temp_file =
+        std::env::temp_dir().join("test_read_matrix_not_palindrome.tsv");
+    let args = [
+        "read-matrix",
+        "tests/resources/bc_anchored_10_reads.sorted.bam",
+        temp_file.to_str().unwrap(),
+        "--region",
+        "oligo_1512_adapters",
+        "--ref",
+        "tests/resources/CGI_ladder_3.6kb_ref.fa",
+        "--motif",
+        "CH",
+        "0",
+        "--combine-strands",
+        "--force",
+    ];
+    assert!(run_modkit(&args).is_err());
+}